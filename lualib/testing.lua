@@ -0,0 +1,474 @@
+-- Script tests, run by `trahl test-scripts` on *_test.lua files:
+--
+--	local t = require("testing")
+--
+--	t.test("transcodes h264 to hevc", function()
+--		t.mock_ffprobe(t.fixture("fixtures/h264.json"))
+--		t.file("/library/movie.mkv", { size = 1000 })
+--		t.run("../transcode.lua", { SRCFILE = "/library/movie.mkv" })
+--		t.assert_contains(t.ffmpeg_args(), "libx265")
+--		t.assert_eq(t.output().mode, _trahl.O_OVERWRITE)
+--	end)
+--
+-- Under test the tool, http and _trahl.fs bindings never run: each call is
+-- recorded (t.calls) and answered by its mock (t.mock). Commands succeed
+-- without doing anything, _trahl.fs works on a virtual tree filled with
+-- t.file and t.dir, and ffprobe, mediainfo, exiftool, mkv_identify and
+-- http_request fail until they are mocked. Mocks, files, calls, outputs and
+-- vars are reset before every test.
+
+local json = require("json")
+local native = ...
+
+local _M = {}
+
+local files = {}
+local logs = {}
+local probes = {}
+local initial_vars = {}
+for k, v in pairs(_trahl.vars) do
+	initial_vars[k] = v
+end
+
+-- Relative paths are taken from the directory of the test file
+function _M.path(path)
+	if path:sub(1, 1) == "/" or not _trahl.vars.TESTDIR then
+		return path
+	end
+	return _trahl.vars.TESTDIR .. "/" .. path
+end
+
+-- Contents of a fixture file, decoded when it is JSON
+function _M.fixture(path)
+	local f = assert(io.open(_M.path(path), "rb"))
+	local content = f:read("a")
+	f:close()
+	if path:match("%.json$") then
+		return json.decode(content)
+	end
+	return content
+end
+
+local function dump(v, seen)
+	if type(v) == "string" then
+		return string.format("%q", v)
+	elseif type(v) ~= "table" then
+		return tostring(v)
+	end
+	seen = seen or {}
+	if seen[v] then
+		return "<cycle>"
+	end
+	seen[v] = true
+
+	local keys = {}
+	for k in pairs(v) do
+		table.insert(keys, k)
+	end
+	table.sort(keys, function(a, b) return tostring(a) < tostring(b) end)
+
+	local parts = {}
+	for _, k in ipairs(keys) do
+		if math.type(k) == "integer" and k >= 1 and k <= #v then
+			table.insert(parts, dump(v[k], seen))
+		else
+			table.insert(parts, tostring(k) .. " = " .. dump(v[k], seen))
+		end
+	end
+	seen[v] = nil
+	return "{ " .. table.concat(parts, ", ") .. " }"
+end
+_M.dump = dump
+
+local function equals(a, b)
+	if a == b then
+		return true
+	end
+	if type(a) ~= "table" or type(b) ~= "table" then
+		return false
+	end
+	for k, v in pairs(a) do
+		if not equals(v, b[k]) then
+			return false
+		end
+	end
+	for k in pairs(b) do
+		if a[k] == nil then
+			return false
+		end
+	end
+	return true
+end
+
+local function fail(msg, default)
+	error(msg and (msg .. ": " .. default) or default, 3)
+end
+
+-- Virtual filesystem
+
+local function parent(path)
+	return path:match("^(.+)/[^/]*$") or (path:sub(1, 1) == "/" and "/" or nil)
+end
+
+local function base_name(path)
+	return path:match("([^/]+)$") or path
+end
+
+local function add_dirs(path)
+	while path and not files[path] do
+		files[path] = { size = 0, is_file = false, is_dir = true, mode = 16877, modified = 0 }
+		path = parent(path)
+	end
+end
+
+-- attrs: { size = 0, modified = 0, mode = 420 }
+function _M.file(path, attrs)
+	attrs = attrs or {}
+	add_dirs(parent(path))
+	files[path] = {
+		size = attrs.size or 0,
+		is_file = true,
+		is_dir = false,
+		mode = attrs.mode or 33188,
+		modified = attrs.modified or 0,
+	}
+end
+
+function _M.dir(path)
+	add_dirs(path)
+end
+
+function _M.exists(path)
+	return files[path] ~= nil
+end
+
+local function copy_entry(entry)
+	local c = {}
+	for k, v in pairs(entry) do
+		c[k] = v
+	end
+	return c
+end
+
+local function children(dir)
+	local prefix = dir == "/" and "/" or dir .. "/"
+	local found = {}
+	for path in pairs(files) do
+		if path ~= dir and path:sub(1, #prefix) == prefix then
+			table.insert(found, path)
+		end
+	end
+	table.sort(found)
+	return found
+end
+
+-- *, ? and ** as in _trahl.fs.glob, where **/ may also match nothing
+local function glob_patterns(glob)
+	local a, b = glob:find("**/", 1, true)
+	if a then
+		local rest = glob_patterns(glob:sub(b + 1))
+		local found = {}
+		for _, p in ipairs(rest) do
+			table.insert(found, glob:sub(1, a - 1) .. p)
+			table.insert(found, glob:sub(1, a - 1) .. "\1/" .. p)
+		end
+		return found
+	end
+	return { glob }
+end
+
+local function glob_to_pattern(glob)
+	local out = glob:gsub("[%^%$%(%)%%%.%[%]%+%-]", "%%%0")
+		:gsub("%*%*", "\1")
+		:gsub("%*", "[^/]*")
+		:gsub("%?", "[^/]")
+		:gsub("\1", ".*")
+	return "^" .. out .. "$"
+end
+
+local fs_defaults = {
+	stat = function(path)
+		return files[path] and copy_entry(files[path]) or nil
+	end,
+	list = function(dir)
+		local entry = files[dir]
+		if not entry or not entry.is_dir then
+			error("fs.list: " .. dir .. ": not a directory")
+		end
+		local result = {}
+		for _, path in ipairs(children(dir)) do
+			if parent(path) == dir then
+				local e = copy_entry(files[path])
+				e.name = base_name(path)
+				e.path = path
+				table.insert(result, e)
+			end
+		end
+		return result
+	end,
+	glob = function(glob)
+		local patterns = {}
+		for _, g in ipairs(glob_patterns(glob)) do
+			table.insert(patterns, glob_to_pattern(g))
+		end
+		local result = {}
+		for path in pairs(files) do
+			for _, p in ipairs(patterns) do
+				if path:match(p) then
+					table.insert(result, path)
+					break
+				end
+			end
+		end
+		table.sort(result)
+		return result
+	end,
+	mkdir_p = function(path)
+		add_dirs(path)
+	end,
+	copy = function(src, dst)
+		local entry = files[src]
+		if not entry or not entry.is_file then
+			error("fs.copy: " .. src .. ": not found")
+		end
+		_M.file(dst, entry)
+		return entry.size
+	end,
+	move = function(src, dst)
+		local entry = files[src]
+		if not entry then
+			error("fs.move: " .. src .. ": not found")
+		end
+		for _, path in ipairs(children(src)) do
+			files[dst .. path:sub(#src + 1)] = files[path]
+			files[path] = nil
+		end
+		files[src] = nil
+		add_dirs(parent(dst))
+		files[dst] = entry
+	end,
+	remove = function(path, opts)
+		if not files[path] then
+			return false
+		end
+		local nested = children(path)
+		if #nested > 0 and not (opts and opts.recursive) then
+			error("fs.remove: " .. path .. ": directory not empty")
+		end
+		for _, p in ipairs(nested) do
+			files[p] = nil
+		end
+		files[path] = nil
+		return true
+	end,
+	free_space = function()
+		return 1 << 40
+	end,
+}
+
+local function noop() end
+
+local function install_defaults()
+	for k in pairs(native.mocks) do
+		native.mocks[k] = nil
+	end
+	for _, name in ipairs({ "ffmpeg", "handbrake", "mkvpropedit", "ccextractor" }) do
+		native.mocks[name] = noop
+	end
+	native.mocks.exec = function()
+		return { code = 0, stdout = "", stderr = "" }
+	end
+	for name, fn in pairs(fs_defaults) do
+		native.mocks["fs." .. name] = fn
+	end
+end
+
+local function reset()
+	install_defaults()
+	for k in pairs(native.calls) do
+		native.calls[k] = nil
+	end
+	files = {}
+	logs = {}
+	probes = {}
+	for k in pairs(_trahl.vars) do
+		_trahl.vars[k] = nil
+	end
+	for k, v in pairs(initial_vars) do
+		_trahl.vars[k] = v
+	end
+	native.reset()
+end
+
+-- Mocks
+
+-- name is a binding, e.g. "ffprobe", "http_request" or "fs.stat". A
+-- function is called with the binding arguments, any other value is returned.
+function _M.mock(name, value)
+	native.mocks[name] = value
+end
+
+-- ffprobe output for one path, or for every path when only json is given
+function _M.mock_ffprobe(path, json)
+	if json == nil then
+		path, json = "*", path
+	end
+	probes[path] = json
+	native.mocks.ffprobe = function(p)
+		local found = probes[p] or probes["*"]
+		if found == nil then
+			error("ffprobe is not mocked for " .. p)
+		end
+		return found
+	end
+end
+
+-- Every request gets this answer
+function _M.mock_http(status, body)
+	native.mocks.http_request = function()
+		return status, body or ""
+	end
+end
+
+-- Calls
+
+-- Arguments of each call to a binding, with n for their count
+function _M.calls(name)
+	return native.calls[name] or {}
+end
+
+-- Arguments of call i of _trahl.ffmpeg, the last one by default
+function _M.ffmpeg_args(i)
+	local calls = _M.calls("ffmpeg")
+	local call = calls[i or #calls]
+	if not call then
+		error("ffmpeg was not called", 2)
+	end
+	if type(call[1]) == "table" then
+		return call[1]
+	end
+	return call[2]
+end
+
+function _M.logs()
+	return logs
+end
+
+-- Outputs registered by the script, main first
+function _M.outputs()
+	return native.outputs() or {}
+end
+
+function _M.output()
+	local first = _M.outputs()[1]
+	if first and first.role == "main" then
+		return first
+	end
+	return nil
+end
+
+function _M.validation()
+	return native.validation()
+end
+
+-- Runs a script with extra vars, errors are raised
+function _M.run(script, vars)
+	for k, v in pairs(vars or {}) do
+		_trahl.vars[k] = v
+	end
+	local chunk, err = loadfile(_M.path(script))
+	if not chunk then
+		error(err, 2)
+	end
+	return chunk()
+end
+
+function _M.test(name, fn)
+	reset()
+	local ok, err = pcall(fn)
+	table.insert(native.results, {
+		name = name,
+		passed = ok,
+		message = not ok and tostring(err) or nil,
+	})
+end
+
+-- Assertions, with an optional message
+
+function _M.assert_eq(actual, expected, msg)
+	if not equals(actual, expected) then
+		fail(msg, "expected " .. dump(expected) .. ", got " .. dump(actual))
+	end
+end
+
+function _M.assert_ne(actual, unexpected, msg)
+	if equals(actual, unexpected) then
+		fail(msg, "got " .. dump(actual))
+	end
+end
+
+function _M.assert_true(v, msg)
+	if not v then
+		fail(msg, "expected a true value, got " .. dump(v))
+	end
+end
+
+function _M.assert_nil(v, msg)
+	if v ~= nil then
+		fail(msg, "expected nil, got " .. dump(v))
+	end
+end
+
+-- A substring of a string, or an element of a list
+function _M.assert_contains(haystack, needle, msg)
+	if type(haystack) == "string" then
+		if not haystack:find(needle, 1, true) then
+			fail(msg, dump(haystack) .. " does not contain " .. dump(needle))
+		end
+		return
+	end
+	for _, v in ipairs(haystack or {}) do
+		if equals(v, needle) then
+			return
+		end
+	end
+	fail(msg, dump(haystack) .. " does not contain " .. dump(needle))
+end
+
+-- fn must fail, with a message matching the Lua pattern when given
+function _M.assert_error(fn, pattern, msg)
+	local ok, err = pcall(fn)
+	if ok then
+		fail(msg, "expected an error")
+	end
+	if pattern and not tostring(err):match(pattern) then
+		fail(msg, "error " .. dump(tostring(err)) .. " does not match " .. dump(pattern))
+	end
+	return err
+end
+
+-- Called at least once, or exactly count times
+function _M.assert_called(name, count, msg)
+	local n = #_M.calls(name)
+	if (count == nil and n == 0) or (count ~= nil and n ~= count) then
+		fail(msg, name .. " called " .. n .. " times, expected " .. (count or "at least 1"))
+	end
+end
+
+function _M.assert_logged(pattern, msg)
+	for _, l in ipairs(logs) do
+		if l.msg:match(pattern) then
+			return
+		end
+	end
+	fail(msg, "no log line matches " .. dump(pattern))
+end
+
+-- Scripts log to the test, not to the worker
+_trahl.log = function(level, msg)
+	table.insert(logs, { level = level, msg = tostring(msg) })
+end
+
+install_defaults()
+
+return _M
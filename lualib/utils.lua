@@ -56,4 +56,48 @@ function _M.matches_regex(text, pattern)
     return ok and matched
 end
 
+-- True if the worker can use the encoder. Without capabilities (ffmpeg
+-- could not be probed) every encoder is assumed to be available.
+function _M.has_encoder(name)
+	local caps = _trahl.capabilities
+	if not caps or not caps.probed then
+		return true
+	end
+	for _, e in ipairs(caps.encoders) do
+		if e == name then
+			return true
+		end
+	end
+	return false
+end
+
+-- First encoder of the list the worker supports, e.g.
+-- utils.pick_encoder({"hevc_nvenc", "hevc_qsv", "libx265"})
+function _M.pick_encoder(encoders)
+	for _, name in ipairs(encoders) do
+		if _M.has_encoder(name) then
+			return name
+		end
+	end
+	return nil
+end
+
+-- Runs ffmpeg with each supported encoder in order until one succeeds.
+-- build_args(encoder) returns the ffmpeg args, opts is passed to
+-- _trahl.ffmpeg. Returns the encoder that worked.
+function _M.ffmpeg_with_fallback(encoders, build_args, opts)
+	local last_err = "no supported encoder in list"
+	for _, name in ipairs(encoders) do
+		if _M.has_encoder(name) then
+			local ok, err = pcall(_trahl.ffmpeg, build_args(name), opts)
+			if ok then
+				return name
+			end
+			last_err = tostring(err)
+			_trahl.log(_trahl.WARN, "Encoding with " .. name .. " failed, trying next encoder: " .. last_err)
+		end
+	end
+	_M.panic("All encoders failed: " .. last_err)
+end
+
 return _M
@@ -0,0 +1,2 @@
+-- Jobs with higher priority are dispatched first
+ALTER TABLE job ADD COLUMN priority INTEGER NOT NULL DEFAULT 0;
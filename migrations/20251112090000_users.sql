@@ -0,0 +1,27 @@
+-- Web interface and API accounts
+CREATE TABLE users (
+	id				INTEGER PRIMARY KEY AUTOINCREMENT,
+	username		TEXT NOT NULL UNIQUE,
+	password_hash	TEXT NOT NULL, -- argon2 PHC string
+	role			TEXT NOT NULL, -- admin, readonly
+	created_at		DATETIME NOT NULL DEFAULT CURRENT_TIMESTAMP
+);
+
+-- Browser sessions, referenced by the session cookie
+CREATE TABLE sessions (
+	id				INTEGER PRIMARY KEY AUTOINCREMENT,
+	token_hash		TEXT NOT NULL UNIQUE, -- sha256 of the cookie value
+	user_id			INTEGER NOT NULL REFERENCES users(id) ON DELETE CASCADE,
+	created_at		DATETIME NOT NULL DEFAULT CURRENT_TIMESTAMP,
+	expires_at		DATETIME NOT NULL
+);
+
+-- Bearer tokens for automation, acting with the role of their owner
+CREATE TABLE api_tokens (
+	id				INTEGER PRIMARY KEY AUTOINCREMENT,
+	name			TEXT NOT NULL,
+	token_hash		TEXT NOT NULL UNIQUE, -- sha256 of the token
+	user_id			INTEGER NOT NULL REFERENCES users(id) ON DELETE CASCADE,
+	created_at		DATETIME NOT NULL DEFAULT CURRENT_TIMESTAMP,
+	last_used_at	DATETIME
+);
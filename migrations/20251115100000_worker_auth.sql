@@ -0,0 +1,3 @@
+-- Worker admission state and optional per-worker authentication token
+ALTER TABLE workers ADD COLUMN status TEXT NOT NULL DEFAULT 'approved'; -- pending, approved, revoked
+ALTER TABLE workers ADD COLUMN auth_token TEXT; -- shared secret for the HMAC challenge, overrides worker_psk
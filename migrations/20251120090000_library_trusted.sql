@@ -0,0 +1,2 @@
+-- Trusted libraries run their scripts without the Lua sandbox
+ALTER TABLE library ADD COLUMN trusted INTEGER NOT NULL DEFAULT 0;
@@ -0,0 +1,12 @@
+-- Every file a job placed, the main output is also kept in job.output_file
+CREATE TABLE job_output (
+    id              INTEGER PRIMARY KEY AUTOINCREMENT,
+    job_id          INTEGER NOT NULL REFERENCES job(id) ON DELETE CASCADE,
+    path            TEXT NOT NULL,
+    role            TEXT NOT NULL,
+    size            INTEGER,
+    created_at      DATETIME NOT NULL DEFAULT CURRENT_TIMESTAMP
+);
+
+CREATE INDEX idx_job_output_job ON job_output(job_id);
+CREATE INDEX idx_job_output_path ON job_output(path);
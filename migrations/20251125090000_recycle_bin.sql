@@ -0,0 +1,16 @@
+-- Per library recycle bin for files replaced by job outputs
+ALTER TABLE library ADD COLUMN recycle_dir TEXT;
+ALTER TABLE library ADD COLUMN recycle_days INTEGER;
+ALTER TABLE library ADD COLUMN recycle_max_bytes INTEGER;
+
+CREATE TABLE recycle_entry (
+    id              INTEGER PRIMARY KEY AUTOINCREMENT,
+    library_id      INTEGER NOT NULL REFERENCES library(id) ON DELETE CASCADE,
+    job_id          INTEGER REFERENCES job(id) ON DELETE SET NULL,
+    original_path   TEXT NOT NULL,
+    recycled_path   TEXT NOT NULL,
+    size            INTEGER,
+    created_at      DATETIME NOT NULL DEFAULT CURRENT_TIMESTAMP
+);
+
+CREATE INDEX idx_recycle_entry_library ON recycle_entry(library_id, created_at);
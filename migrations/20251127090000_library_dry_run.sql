@@ -0,0 +1,2 @@
+-- Dry-run libraries have their scripts run with every change stubbed
+ALTER TABLE library ADD COLUMN dry_run INTEGER NOT NULL DEFAULT 0;
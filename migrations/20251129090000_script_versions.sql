@@ -0,0 +1,10 @@
+-- Every script version is kept: a changed file adds a row, numbered per name and source
+ALTER TABLE script ADD COLUMN version INTEGER NOT NULL DEFAULT 1;
+
+-- Version that ran the job, set when it is sent to a worker
+ALTER TABLE job ADD COLUMN script_id INTEGER REFERENCES script(id);
+
+-- Version run instead of the current one, for pinning and rollbacks
+ALTER TABLE library ADD COLUMN pinned_script_id INTEGER REFERENCES script(id);
+
+CREATE INDEX idx_script_name_source ON script(name, source, version);
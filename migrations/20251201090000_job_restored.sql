@@ -0,0 +1,2 @@
+-- Set when the file a job replaced is restored from the recycle bin
+ALTER TABLE job ADD COLUMN restored_at DATETIME;
@@ -0,0 +1,58 @@
+-- Only queued and processing jobs stay unique per file, finished ones are
+-- history and may repeat, like several cancelled runs of the same file.
+-- SQLite cannot drop a table constraint, so the table is rebuilt.
+CREATE TABLE job_new (
+    id              INTEGER PRIMARY KEY AUTOINCREMENT,
+    file_id         INTEGER NOT NULL REFERENCES file_entry(id) ON DELETE CASCADE,
+    worker_id       INTEGER REFERENCES workers(id),
+    status          TEXT NOT NULL, -- queued, processing, success, failure, cancelled, dry_run
+    log_path        TEXT,
+    output_file     TEXT,
+    output_size     INTEGER,
+    created_at      DATETIME NOT NULL DEFAULT CURRENT_TIMESTAMP,
+    started_at      DATETIME,
+    finished_at     DATETIME,
+    priority        INTEGER NOT NULL DEFAULT 0,
+    script_id       INTEGER REFERENCES script(id),
+    restored_at     DATETIME
+);
+
+INSERT INTO job_new (id, file_id, worker_id, status, log_path, output_file, output_size,
+    created_at, started_at, finished_at, priority, script_id, restored_at)
+SELECT id, file_id, worker_id, status, log_path, output_file, output_size,
+    created_at, started_at, finished_at, priority, script_id, restored_at
+FROM job;
+
+-- Dropping job cascades to its outputs and unlinks the recycle bin, migrations
+-- run in a transaction where foreign keys cannot be turned off, so both are
+-- kept aside and put back
+CREATE TEMP TABLE saved_job_output AS SELECT * FROM job_output;
+CREATE TEMP TABLE saved_recycle_job AS SELECT id, job_id FROM recycle_entry WHERE job_id IS NOT NULL;
+CREATE TEMP TABLE saved_job_seq AS SELECT seq FROM sqlite_sequence WHERE name = 'job';
+
+DROP TABLE job;
+ALTER TABLE job_new RENAME TO job;
+
+INSERT INTO job_output SELECT * FROM saved_job_output;
+UPDATE recycle_entry
+SET job_id = (SELECT s.job_id FROM saved_recycle_job s WHERE s.id = recycle_entry.id)
+WHERE id IN (SELECT id FROM saved_recycle_job);
+
+-- Ids of deleted jobs are not handed out again
+DELETE FROM sqlite_sequence WHERE name = 'job';
+INSERT INTO sqlite_sequence (name, seq)
+SELECT 'job', seq FROM (
+    SELECT MAX(seq) AS seq FROM (
+        SELECT seq FROM saved_job_seq
+        UNION ALL
+        SELECT MAX(id) FROM job
+    )
+)
+WHERE seq IS NOT NULL;
+
+DROP TABLE saved_job_output;
+DROP TABLE saved_recycle_job;
+DROP TABLE saved_job_seq;
+
+CREATE UNIQUE INDEX idx_job_active_file ON job(file_id, status) WHERE status IN ('queued', 'processing');
+CREATE INDEX idx_job_file ON job(file_id);
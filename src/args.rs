@@ -8,6 +8,31 @@ pub struct StartupArgs {
     pub master_mode: bool,
     pub config_test: bool,
     pub config_file: PathBuf,
+    pub command: Option<Command>,
+}
+
+#[derive(Debug, PartialEq)]
+pub enum Command {
+    RunScript(RunScriptArgs),
+    TestScripts(TestScriptsArgs),
+}
+
+// Runs a script against a local file like a worker would, without a master
+#[derive(Debug, PartialEq)]
+pub struct RunScriptArgs {
+    pub script: PathBuf,
+    pub file: PathBuf,
+    pub vars: Vec<(String, String)>,
+    pub dst_dir: Option<PathBuf>,
+    pub library_root: Option<PathBuf>,
+    pub trusted: bool,
+    pub dry_run: bool,
+}
+
+// Runs the *_test.lua files found under each path
+#[derive(Debug, PartialEq)]
+pub struct TestScriptsArgs {
+    pub paths: Vec<PathBuf>,
 }
 
 #[derive(Debug)]
@@ -21,6 +46,17 @@ impl std::fmt::Display for CustomError {
 
 impl std::error::Error for CustomError {}
 
+fn custom_error(err: &str) -> lexopt::Error {
+    lexopt::Error::Custom(Box::new(CustomError(err.to_string())))
+}
+
+fn parse_var(var: &str) -> Result<(String, String), lexopt::Error> {
+    match var.split_once('=') {
+        Some((key, value)) if !key.is_empty() => Ok((key.to_string(), value.to_string())),
+        _ => Err(custom_error(&format!("Invalid variable \"{}\", expected KEY=VALUE", var))),
+    }
+}
+
 pub fn parse_args() -> Result<StartupArgs, lexopt::Error> {
     parse_args_from(std::env::args_os())
 }
@@ -34,10 +70,30 @@ where
     let mut ct = false;
     let mut cf: Option<PathBuf> = None;
 
+    let mut script: Option<PathBuf> = None;
+    let mut file: Option<PathBuf> = None;
+    let mut vars: Vec<(String, String)> = Vec::new();
+    let mut dst_dir: Option<PathBuf> = None;
+    let mut library_root: Option<PathBuf> = None;
+    let mut trusted = false;
+    let mut dry_run = false;
+    let mut run_script = false;
+    let mut test_scripts = false;
+    let mut paths: Vec<PathBuf> = Vec::new();
+
     let mut parser = lexopt::Parser::from_iter(args);
 
     while let Some(arg) = parser.next()? {
         match arg {
+            Value(cmd) if !run_script && !test_scripts && cmd == "run-script" => {
+                run_script = true;
+            }
+            Value(cmd) if !run_script && !test_scripts && cmd == "test-scripts" => {
+                test_scripts = true;
+            }
+            Value(path) if test_scripts => {
+                paths.push(PathBuf::from(path));
+            }
             Short('m') | Long("master") => {
                 mm = true;
             }
@@ -51,6 +107,27 @@ where
                 let path = parser.value()?;
                 cf = Some(PathBuf::from(path));
             }
+            Long("script") if run_script => {
+                script = Some(PathBuf::from(parser.value()?));
+            }
+            Long("file") if run_script => {
+                file = Some(PathBuf::from(parser.value()?));
+            }
+            Long("var") if run_script => {
+                vars.push(parse_var(&parser.value()?.string()?)?);
+            }
+            Long("dst") if run_script => {
+                dst_dir = Some(PathBuf::from(parser.value()?));
+            }
+            Long("library-root") if run_script => {
+                library_root = Some(PathBuf::from(parser.value()?));
+            }
+            Long("trusted") if run_script => {
+                trusted = true;
+            }
+            Long("dry-run") if run_script => {
+                dry_run = true;
+            }
             Short('h') | Long("help") => {
                 print_usage();
                 std::process::exit(0);
@@ -59,29 +136,66 @@ where
         }
     }
 
-    if !wm && !mm {
-        let err = "You must specify at lest one working mode";
-        return Err(lexopt::Error::Custom(Box::new(CustomError(err.to_string()))));
+    if (run_script || test_scripts) && (wm || mm) {
+        return Err(custom_error("Commands cannot be combined with a working mode"));
+    }
+
+    let command = if run_script {
+        Some(Command::RunScript(RunScriptArgs {
+            script: script.ok_or("Missing --script")?,
+            file: file.ok_or("Missing --file")?,
+            vars,
+            dst_dir,
+            library_root,
+            trusted,
+            dry_run,
+        }))
+    } else if test_scripts {
+        if paths.is_empty() {
+            paths.push(PathBuf::from("."));
+        }
+        Some(Command::TestScripts(TestScriptsArgs { paths }))
+    } else {
+        None
+    };
+
+    if !wm && !mm && command.is_none() {
+        return Err(custom_error("You must specify at lest one working mode"));
     }
 
     Ok(StartupArgs {
         worker_mode: wm,
         master_mode: mm,
         config_test: ct,
-        config_file: cf.ok_or("Missing configuration file")?
+        config_file: cf.ok_or("Missing configuration file")?,
+        command,
     })
 }
 
 fn print_usage() {
     let msg = indoc!{r#"
         Usage: trahl [-m|--master] [-w|--worker] [-t] -c|--conf=file
+               trahl run-script -c|--conf=file --script=file --file=file [--var=KEY=VALUE ...]
+               trahl test-scripts -c|--conf=file [path ...]
 
         Options:
             -m, --master    Run in master mode
             -w, --worker    Run in worker mode
             -t              Test configuration and exit
             -c, --config    Configuration file (required)
-            -h, --help      Print this help message"#
+            -h, --help      Print this help message
+
+        run-script runs a script on a local file with the worker settings, without a master:
+            --script        Lua script to run
+            --file          Source file, SRCFILE
+            --var           Extra job variable, can be repeated
+            --dst           Destination directory, DSTDIR (default: the library root)
+            --library-root  Library root, LIBRARYROOT (default: the source directory)
+            --trusted       Run the script outside the sandbox
+            --dry-run       Run the library dry-run mode, nothing is written outside CACHEDIR
+
+        test-scripts runs the *_test.lua files under each path, the current directory by default,
+        with mocked tools, http and filesystem"#
     };
 
     eprintln!("{}", msg);
@@ -89,7 +203,7 @@ fn print_usage() {
 
 #[cfg(test)]
 mod tests {
-    use super::{StartupArgs, parse_args_from};
+    use super::{Command, RunScriptArgs, StartupArgs, TestScriptsArgs, parse_args_from};
     use std::ffi::OsString;
 
     fn parse_args_from_string(s: &str) -> Result<StartupArgs, lexopt::Error> 
@@ -133,6 +247,7 @@ mod tests {
             worker_mode: true,
             config_file: "dummy.toml".into(),
             config_test: false,
+            command: None,
         });
     }
     
@@ -146,6 +261,7 @@ mod tests {
             worker_mode: true,
             config_file: "dummy.toml".into(),
             config_test: false,
+            command: None,
         });
     }
     
@@ -159,6 +275,43 @@ mod tests {
             worker_mode: true,
             config_file: "dummy.toml".into(),
             config_test: false,
+            command: None,
         });
     }
+
+    #[test]
+    fn test_args_run_script() {
+        let args = "run-script -c dummy.toml --script x.lua --file movie.mkv --var A=1 --var=B=x=y --dry-run";
+        let parsed = parse_args_from_string(args).unwrap();
+
+        assert_eq!(parsed.command, Some(Command::RunScript(RunScriptArgs {
+            script: "x.lua".into(),
+            file: "movie.mkv".into(),
+            vars: vec![("A".into(), "1".into()), ("B".into(), "x=y".into())],
+            dst_dir: None,
+            library_root: None,
+            trusted: false,
+            dry_run: true,
+        })));
+        assert!(!parsed.master_mode && !parsed.worker_mode);
+
+        assert!(parse_args_from_string("run-script -c dummy.toml --file movie.mkv").is_err());
+        assert!(parse_args_from_string("run-script -c dummy.toml --script x.lua --file movie.mkv --var A").is_err());
+        assert!(parse_args_from_string("run-script -w -c dummy.toml --script x.lua --file movie.mkv").is_err());
+        assert!(parse_args_from_string("-m -c dummy.toml --script x.lua").is_err());
+    }
+
+    #[test]
+    fn test_args_test_scripts() {
+        let parsed = parse_args_from_string("test-scripts -c dummy.toml scripts tests/a_test.lua").unwrap();
+        assert_eq!(parsed.command, Some(Command::TestScripts(TestScriptsArgs {
+            paths: vec!["scripts".into(), "tests/a_test.lua".into()],
+        })));
+
+        let parsed = parse_args_from_string("test-scripts -c dummy.toml").unwrap();
+        assert_eq!(parsed.command, Some(Command::TestScripts(TestScriptsArgs { paths: vec![".".into()] })));
+
+        assert!(parse_args_from_string("test-scripts -m -c dummy.toml").is_err());
+        assert!(parse_args_from_string("test-scripts -c dummy.toml --script x.lua").is_err());
+    }
 }
@@ -19,6 +19,14 @@ pub struct MasterConfig {
     pub orch_bind_addr: SocketAddr,
     pub web_bind_addr: SocketAddr,
     pub db_path: PathBuf,
+    pub job_log_dir: PathBuf,
+    pub admin_password: Option<String>,
+    pub session_ttl_hours: u32,
+    pub worker_psk: Option<String>,
+    pub auto_approve_workers: bool,
+    pub orch_secret_key_file: Option<PathBuf>,
+    pub orch_require_encryption: bool,
+    pub max_jobs_per_worker: Option<u8>,
 }
 
 #[cfg_attr(test, derive(PartialEq, Eq))]
@@ -30,6 +38,13 @@ pub struct JobConfig {
     pub destination_path: PathBuf,
     pub lua_script: PathBuf,
     pub variables: HashMap<String, String>,
+    #[serde(default)]
+    pub trusted: bool,                  // Run the script without the Lua sandbox
+    #[serde(default)]
+    pub dry_run: bool,                  // Report what the script would do without doing it
+    pub recycle_dir: Option<PathBuf>,   // Master path keeping the files job outputs replace
+    pub recycle_days: Option<u32>,      // Purge recycled files older than this
+    pub recycle_max_gb: Option<u64>,    // Purge the oldest recycled files above this total
 }
 
 #[cfg_attr(test, derive(PartialEq, Eq))]
@@ -48,6 +63,30 @@ pub struct WorkerConfig {
     pub ccextractor_path: PathBuf,
     pub ffprobe_path: PathBuf,
     pub mkvpropedit_path: PathBuf,
+    pub mkvmerge_path: PathBuf,
+    pub exec_allowlist: Vec<PathBuf>,   // Programs scripts may run with _trahl.exec
+    pub lua_memory_limit_mb: usize,     // Per job, 0 disables the limit
+    pub lua_instruction_limit: u64,     // Per job, 0 disables the limit
+    pub recycle_dir: Option<PathBuf>,   // Files replaced by job outputs are kept here
+    pub validation: ValidationConfig,
+    pub auth_key: Option<String>,
+    pub master_public_key_file: Option<PathBuf>,
+}
+
+// Checks on the main output of a job before anything is placed.
+// Scripts can override them with _trahl.set_validation.
+#[cfg_attr(test, derive(PartialEq, Eq))]
+#[derive(Deserialize, Debug, Clone)]
+#[serde(default)]
+pub struct ValidationConfig {
+    pub enabled: bool,
+    pub check_duration: bool,
+    pub duration_tolerance_secs: u32,
+    pub min_video_streams: usize,
+    pub min_audio_streams: Option<usize>,   // Default: 1 when the source has audio
+    pub min_subtitle_streams: usize,
+    pub min_size_percent: u32,              // Of the source size, 0 disables
+    pub decode_seconds: u32,                // Decode test length, 0 disables
 }
 
 #[cfg_attr(test, derive(PartialEq, Eq))]
@@ -78,13 +117,21 @@ impl Default for WorkerConfig {
             fs_remaps: None,
             cache_dir: PathBuf::from("./trahl-cache"),
             parallel_jobs: 1,
-            handbrake_path: PathBuf::from("handbrake"),
+            handbrake_path: PathBuf::from("HandBrakeCLI"),
             ffmpeg_path: PathBuf::from("ffmpeg"),
             exiftool_path: PathBuf::from("exiftool"),
             mediainfo_path: PathBuf::from("mediainfo"),
             ccextractor_path: PathBuf::from("ccextractor"),
             ffprobe_path: PathBuf::from("ffprobe"),
             mkvpropedit_path: PathBuf::from("mkvpropedit"),
+            mkvmerge_path: PathBuf::from("mkvmerge"),
+            exec_allowlist: Vec::new(),
+            lua_memory_limit_mb: 512,
+            lua_instruction_limit: 10_000_000_000,
+            recycle_dir: None,
+            validation: ValidationConfig::default(),
+            auth_key: None,
+            master_public_key_file: None,
         }
     }
 }
@@ -95,6 +142,43 @@ impl Default for MasterConfig {
             orch_bind_addr: "0.0.0.0:1849".parse().expect("Error setting orch_bind_addr"),
             web_bind_addr: "0.0.0.0:1850".parse().expect("Error setting web_bind_addr"),
             db_path: "sqlite.db".into(),
+            job_log_dir: "job-logs".into(),
+            admin_password: None,
+            session_ttl_hours: 168,
+            worker_psk: None,
+            auto_approve_workers: true,
+            orch_secret_key_file: None,
+            orch_require_encryption: false,
+            max_jobs_per_worker: None,
+        }
+    }
+}
+
+impl ValidationConfig {
+    // Keys _trahl.set_validation accepts, kept in the order of the fields
+    pub const FIELDS: &[&str] = &[
+        "enabled",
+        "check_duration",
+        "duration_tolerance_secs",
+        "min_video_streams",
+        "min_audio_streams",
+        "min_subtitle_streams",
+        "min_size_percent",
+        "decode_seconds",
+    ];
+}
+
+impl Default for ValidationConfig {
+    fn default() -> Self {
+        ValidationConfig {
+            enabled: false,
+            check_duration: true,
+            duration_tolerance_secs: 2,
+            min_video_streams: 1,
+            min_audio_streams: None,
+            min_subtitle_streams: 0,
+            min_size_percent: 1,
+            decode_seconds: 0,
         }
     }
 }
@@ -237,6 +321,10 @@ mod tests {
             source_path = "/media/source/tv"
             destination_path = "/media/destination/tv"
             lua_script = "/configs/scripts/tv.lua"
+            trusted = true
+            dry_run = true
+            recycle_dir = "/media/recycle/tv"
+            recycle_days = 30
 
             [jobs.variables]
             QUALITY = "720p"
@@ -258,6 +346,11 @@ mod tests {
                 variables: HashMap::from([
                     ("EXCLUDECODEC".to_string(), "h265".to_string()),
                 ]),
+                trusted: false,
+                dry_run: false,
+                recycle_dir: None,
+                recycle_days: None,
+                recycle_max_gb: None,
             },
             JobConfig {
                 name: "Transcode TV Shows".to_string(),
@@ -270,6 +363,11 @@ mod tests {
                     ("CODEC".to_string(), "hevc".to_string()),
                     ("PRESET".to_string(), "medium".to_string()),
                 ]),
+                trusted: true,
+                dry_run: true,
+                recycle_dir: Some("/media/recycle/tv".into()),
+                recycle_days: Some(30),
+                recycle_max_gb: None,
             },
         ];
 
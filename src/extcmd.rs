@@ -1 +1,6 @@
 pub mod ffprobe;
+pub mod tools;
+pub mod handbrake;
+pub mod probe;
+pub mod ffmpeg;
+pub mod hwaccel;
@@ -0,0 +1,472 @@
+use std::collections::BTreeMap;
+
+// Helpers to work out how long an ffmpeg run will be from its arguments,
+// so progress can be reported without the script passing a duration
+
+// Parses ffmpeg time durations: "[-][HH:]MM:SS[.m...]" or "S[.m...][s|ms|us]"
+pub fn parse_time(s: &str) -> Option<f64> {
+    let s = s.trim();
+    let (neg, s) = match s.strip_prefix('-') {
+        Some(rest) => (true, rest),
+        None => (false, s),
+    };
+
+    let secs = if s.contains(':') {
+        let parts: Vec<&str> = s.split(':').collect();
+        if parts.len() > 3 {
+            return None;
+        }
+        let mut secs = 0.0;
+        for part in parts {
+            secs = secs * 60.0 + part.parse::<f64>().ok()?;
+        }
+        secs
+    } else if let Some(ms) = s.strip_suffix("ms") {
+        ms.parse::<f64>().ok()? / 1_000.0
+    } else if let Some(us) = s.strip_suffix("us") {
+        us.parse::<f64>().ok()? / 1_000_000.0
+    } else {
+        s.strip_suffix('s').unwrap_or(s).parse::<f64>().ok()?
+    };
+
+    if !secs.is_finite() {
+        return None;
+    }
+    Some(if neg { -secs } else { secs })
+}
+
+#[derive(Debug, Default, PartialEq)]
+struct Trim {
+    ss: Option<f64>,
+    t: Option<f64>,
+    to: Option<f64>,
+}
+
+impl Trim {
+    fn set(&mut self, opt: &str, value: &str) {
+        let slot = match opt {
+            "-ss" => &mut self.ss,
+            "-t" => &mut self.t,
+            "-to" => &mut self.to,
+            _ => return,
+        };
+        *slot = parse_time(value);
+    }
+
+    // Length left of a timeline of `len` seconds once this trim is applied.
+    // As in ffmpeg, -t wins over -to.
+    fn apply(&self, len: f64) -> f64 {
+        let start = self.ss.unwrap_or(0.0).max(0.0);
+        let end = match (self.t, self.to) {
+            (Some(t), _) => start + t,
+            (None, Some(to)) => to,
+            (None, None) => len,
+        };
+        (end.min(len) - start).max(0.0)
+    }
+}
+
+// Options before the first -i apply to that input, options after the
+// last input apply to the output. Anything in between belongs to other
+// inputs and doesn't change the length.
+fn trims(args: &[String]) -> (Trim, Trim) {
+    let mut input = Trim::default();
+    let mut output = Trim::default();
+
+    let first_input = args.iter().position(|a| a == "-i").unwrap_or(args.len());
+    let last_input = args.iter().rposition(|a| a == "-i").unwrap_or(args.len());
+
+    let mut i = 0;
+    while i + 1 < args.len() {
+        let (opt, value) = (args[i].as_str(), args[i + 1].as_str());
+        if i < first_input {
+            input.set(opt, value);
+        } else if i > last_input + 1 {
+            output.set(opt, value);
+        }
+        i += if matches!(opt, "-ss" | "-t" | "-to" | "-i") { 2 } else { 1 };
+    }
+
+    (input, output)
+}
+
+pub fn input_file(args: &[String]) -> Option<&str> {
+    args.iter()
+        .position(|a| a == "-i")
+        .and_then(|i| args.get(i + 1))
+        .map(String::as_str)
+}
+
+// Duration of the output given the duration of the first input
+pub fn output_duration(args: &[String], input_duration: f64) -> f64 {
+    let (input, output) = trims(args);
+    output.apply(input.apply(input_duration))
+}
+
+// Bits per second from "2500000", "128k" or "4.5M"
+pub fn parse_bitrate(s: &str) -> Option<f64> {
+    let s = s.trim();
+    let (num, mult) = match s.char_indices().last()? {
+        (i, 'k' | 'K') => (&s[..i], 1e3),
+        (i, 'm' | 'M') => (&s[..i], 1e6),
+        (i, 'g' | 'G') => (&s[..i], 1e9),
+        _ => (s, 1.0),
+    };
+    num.parse::<f64>().ok().filter(|b| b.is_finite() && *b >= 0.0).map(|b| b * mult)
+}
+
+// Output size from the bitrates given after the last input, -b for every
+// stream it applies to or -maxrate when a stream has no -b. Runs driven by
+// quality settings like -crf have no estimate.
+pub fn estimated_size(args: &[String], duration: f64) -> Option<u64> {
+    let last_input = args.iter().rposition(|a| a == "-i")?;
+    let mut bitrates: BTreeMap<&str, f64> = BTreeMap::new();
+    let mut maxrates: BTreeMap<&str, f64> = BTreeMap::new();
+
+    for pair in args.get(last_input + 2..).unwrap_or_default().windows(2) {
+        let (opt, value) = (pair[0].as_str(), pair[1].as_str());
+        let (name, stream) = opt.split_once(':').unwrap_or((opt, ""));
+        let target = match name {
+            "-b" => &mut bitrates,
+            "-maxrate" => &mut maxrates,
+            _ => continue,
+        };
+        if let Some(bitrate) = parse_bitrate(value) {
+            target.insert(stream, bitrate);
+        }
+    }
+    for (stream, rate) in maxrates {
+        bitrates.entry(stream).or_insert(rate);
+    }
+
+    if bitrates.is_empty() {
+        return None;
+    }
+    Some((bitrates.values().sum::<f64>() * duration.max(0.0) / 8.0) as u64)
+}
+
+// Shell command line that reproduces a run, for logs
+pub fn quote_command(program: &str, args: &[String]) -> String {
+    std::iter::once(program)
+        .chain(args.iter().map(String::as_str))
+        .map(shell_quote)
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+#[derive(Debug, Default, Clone)]
+pub struct Input {
+    pub path: String,
+    pub trim: Vec<(String, String)>,        // -ss, -t, -to
+    pub options: BTreeMap<String, String>,
+}
+
+#[derive(Debug, Default, Clone)]
+pub struct StreamMap {
+    pub spec: String,
+    pub codec: Option<String>,
+    pub filter: Option<String>,
+    pub options: BTreeMap<String, String>,
+    pub metadata: BTreeMap<String, String>,
+    pub disposition: Option<String>,
+}
+
+impl StreamMap {
+    fn has_stream_options(&self) -> bool {
+        self.codec.is_some()
+            || self.filter.is_some()
+            || !self.options.is_empty()
+            || !self.metadata.is_empty()
+            || self.disposition.is_some()
+    }
+}
+
+// Describes an ffmpeg run and turns it into arguments. Per-stream settings
+// use the position of the map in the output, so a map with settings must
+// select exactly one stream.
+#[derive(Debug, Default, Clone)]
+pub struct FfmpegCommand {
+    pub inputs: Vec<Input>,
+    pub maps: Vec<StreamMap>,
+    pub filter_complex: Option<String>,
+    pub metadata: BTreeMap<String, String>,
+    pub options: BTreeMap<String, String>,
+    pub output: Option<String>,
+}
+
+// "0", "0:1", "0:v", "0:a:1", "[out]"... with an optional leading '-' for
+// negative maps and a trailing '?' for optional ones
+fn parse_map(spec: &str) -> Result<(Option<usize>, bool), String> {
+    if spec.starts_with('[') && spec.ends_with(']') {
+        return Ok((None, true));
+    }
+
+    let body = spec.trim_start_matches('-').trim_end_matches('?');
+    let mut parts = body.split(':');
+    let input = parts.next()
+        .and_then(|p| p.parse::<usize>().ok())
+        .ok_or_else(|| format!("invalid map \"{}\": must start with an input index", spec))?;
+
+    let rest: Vec<&str> = parts.collect();
+    let single = match rest.as_slice() {
+        [] => false,
+        [idx] if idx.parse::<usize>().is_ok() => true,
+        [kind] if matches!(*kind, "v" | "V" | "a" | "s" | "d" | "t") => false,
+        [kind, idx] if matches!(*kind, "v" | "V" | "a" | "s" | "d" | "t") && idx.parse::<usize>().is_ok() => true,
+        _ => return Err(format!("invalid map \"{}\"", spec)),
+    };
+
+    Ok((Some(input), single))
+}
+
+impl FfmpegCommand {
+    pub fn to_args(&self) -> Result<Vec<String>, String> {
+        if self.inputs.is_empty() {
+            return Err("no input".to_string());
+        }
+        let Some(output) = &self.output else {
+            return Err("no output".to_string());
+        };
+
+        let mut args = Vec::new();
+        for input in &self.inputs {
+            for (opt, value) in &input.trim {
+                if parse_time(value).is_none() {
+                    return Err(format!("invalid time for {}: \"{}\"", opt, value));
+                }
+                args.push(opt.clone());
+                args.push(value.clone());
+            }
+            for (key, value) in &input.options {
+                args.push(format!("-{}", key));
+                args.push(value.clone());
+            }
+            args.push("-i".to_string());
+            args.push(input.path.clone());
+        }
+
+        if let Some(graph) = &self.filter_complex {
+            args.push("-filter_complex".to_string());
+            args.push(graph.clone());
+        }
+
+        let mut out_index = 0;
+        for map in &self.maps {
+            let (input, single) = parse_map(&map.spec)?;
+            if let Some(input) = input && input >= self.inputs.len() {
+                return Err(format!("map \"{}\" refers to missing input {}", map.spec, input));
+            }
+            args.push("-map".to_string());
+            args.push(map.spec.clone());
+
+            if map.spec.starts_with('-') {
+                if map.has_stream_options() {
+                    return Err(format!("negative map \"{}\" cannot have stream settings", map.spec));
+                }
+                continue;
+            }
+            if !map.has_stream_options() {
+                // Nothing refers to the stream, so a multi-stream map is fine
+                // as long as no later map has stream settings
+                if !single {
+                    out_index = usize::MAX;
+                } else {
+                    out_index = out_index.saturating_add(1);
+                }
+                continue;
+            }
+            if !single {
+                return Err(format!("map \"{}\" selects several streams, stream settings need a single one", map.spec));
+            }
+            if out_index == usize::MAX {
+                return Err(format!("map \"{}\" follows a multi-stream map, its output index is unknown", map.spec));
+            }
+
+            if let Some(codec) = &map.codec {
+                args.push(format!("-c:{}", out_index));
+                args.push(codec.clone());
+            }
+            if let Some(filter) = &map.filter {
+                args.push(format!("-filter:{}", out_index));
+                args.push(filter.clone());
+            }
+            for (key, value) in &map.options {
+                args.push(format!("-{}:{}", key, out_index));
+                args.push(value.clone());
+            }
+            for (key, value) in &map.metadata {
+                args.push(format!("-metadata:s:{}", out_index));
+                args.push(format!("{}={}", key, value));
+            }
+            if let Some(disposition) = &map.disposition {
+                args.push(format!("-disposition:{}", out_index));
+                args.push(disposition.clone());
+            }
+            out_index += 1;
+        }
+
+        for (key, value) in &self.metadata {
+            args.push("-metadata".to_string());
+            args.push(format!("{}={}", key, value));
+        }
+        for (key, value) in &self.options {
+            args.push(format!("-{}", key));
+            args.push(value.clone());
+        }
+        args.push(output.clone());
+
+        Ok(args)
+    }
+
+    // Shell command line that reproduces the run, for logs
+    pub fn command_line(&self, program: &str) -> Result<String, String> {
+        Ok(quote_command(program, &self.to_args()?))
+    }
+}
+
+fn shell_quote(s: &str) -> String {
+    let safe = !s.is_empty() && s.chars().all(|c| c.is_ascii_alphanumeric() || "-_./:=,+@%".contains(c));
+    if safe {
+        s.to_string()
+    } else {
+        format!("'{}'", s.replace('\'', "'\\''"))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn args(s: &str) -> Vec<String> {
+        s.split_whitespace().map(str::to_string).collect()
+    }
+
+    #[test]
+    fn test_parse_time() {
+        assert_eq!(parse_time("90"), Some(90.0));
+        assert_eq!(parse_time("1.5s"), Some(1.5));
+        assert_eq!(parse_time("250ms"), Some(0.25));
+        assert_eq!(parse_time("01:30"), Some(90.0));
+        assert_eq!(parse_time("01:00:02.5"), Some(3602.5));
+        assert_eq!(parse_time("-5"), Some(-5.0));
+        assert_eq!(parse_time("abc"), None);
+        assert_eq!(parse_time("1:2:3:4"), None);
+    }
+
+    #[test]
+    fn test_output_duration() {
+        let plain = args("-i in.mkv -c:v libx265 out.mkv");
+        assert_eq!(input_file(&plain), Some("in.mkv"));
+        assert_eq!(output_duration(&plain, 100.0), 100.0);
+
+        let input_seek = args("-ss 10 -t 30 -i in.mkv out.mkv");
+        assert_eq!(output_duration(&input_seek, 100.0), 30.0);
+
+        let input_to = args("-ss 00:01:00 -to 00:01:30 -i in.mkv out.mkv");
+        assert_eq!(output_duration(&input_to, 600.0), 30.0);
+
+        let output_trim = args("-i in.mkv -ss 20 -to 50 out.mkv");
+        assert_eq!(output_duration(&output_trim, 100.0), 30.0);
+
+        let past_end = args("-ss 90 -t 30 -i in.mkv out.mkv");
+        assert_eq!(output_duration(&past_end, 100.0), 10.0);
+
+        // Trims of a second input don't change the first input
+        let two_inputs = args("-i in.mkv -ss 5 -i subs.srt -map 0 -map 1 out.mkv");
+        assert_eq!(input_file(&two_inputs), Some("in.mkv"));
+        assert_eq!(output_duration(&two_inputs, 100.0), 100.0);
+    }
+
+    #[test]
+    fn test_estimated_size() {
+        assert_eq!(parse_bitrate("128k"), Some(128_000.0));
+        assert_eq!(parse_bitrate("4.5M"), Some(4_500_000.0));
+        assert_eq!(parse_bitrate("fast"), None);
+
+        let bitrates = args("-i in.mkv -c:v libx265 -b:v 4M -c:a aac -b:a 128k out.mkv");
+        assert_eq!(estimated_size(&bitrates, 100.0), Some(51_600_000));
+
+        // -maxrate only counts for streams without -b
+        let capped = args("-i in.mkv -crf 22 -maxrate:v 2M -bufsize 4M -b:a 192k -maxrate:a 1M out.mkv");
+        assert_eq!(estimated_size(&capped, 10.0), Some(2_740_000));
+
+        // Input bitrates and quality driven runs give no estimate
+        assert_eq!(estimated_size(&args("-b:v 4M -i in.mkv -crf 22 out.mkv"), 100.0), None);
+        assert_eq!(estimated_size(&args("-crf 22 out.mkv"), 100.0), None);
+        assert_eq!(estimated_size(&args("-i"), 100.0), None);
+        assert_eq!(estimated_size(&args("-i in.mkv"), 100.0), None);
+    }
+
+    #[test]
+    fn test_command_args() {
+        let mut input = Input {
+            path: "/lib/My Movie.mkv".to_string(),
+            ..Input::default()
+        };
+        input.trim.push(("-ss".to_string(), "00:01:00".to_string()));
+
+        let cmd = FfmpegCommand {
+            inputs: vec![input],
+            maps: vec![
+                StreamMap {
+                    spec: "0:v:0".to_string(),
+                    codec: Some("libx265".to_string()),
+                    options: BTreeMap::from([("crf".to_string(), "22".to_string())]),
+                    ..StreamMap::default()
+                },
+                StreamMap {
+                    spec: "0:a:1".to_string(),
+                    codec: Some("copy".to_string()),
+                    metadata: BTreeMap::from([("title".to_string(), "Director's cut".to_string())]),
+                    disposition: Some("default".to_string()),
+                    ..StreamMap::default()
+                },
+                StreamMap {
+                    spec: "0:s?".to_string(),
+                    ..StreamMap::default()
+                },
+            ],
+            output: Some("/out/movie.mkv".to_string()),
+            ..FfmpegCommand::default()
+        };
+
+        assert_eq!(cmd.to_args().unwrap(), args(
+            "-ss 00:01:00 -i PATH -map 0:v:0 -c:0 libx265 -crf:0 22 \
+             -map 0:a:1 -c:1 copy -metadata:s:1 TITLE -disposition:1 default \
+             -map 0:s? /out/movie.mkv"
+        ).into_iter().map(|a| match a.as_str() {
+            "PATH" => "/lib/My Movie.mkv".to_string(),
+            "TITLE" => "title=Director's cut".to_string(),
+            _ => a,
+        }).collect::<Vec<_>>());
+
+        assert_eq!(
+            cmd.command_line("ffmpeg").unwrap().split(" -map").next().unwrap(),
+            "ffmpeg -ss 00:01:00 -i '/lib/My Movie.mkv'"
+        );
+    }
+
+    #[test]
+    fn test_command_validation() {
+        let input = Input { path: "in.mkv".to_string(), ..Input::default() };
+        let mut cmd = FfmpegCommand {
+            inputs: vec![input],
+            output: Some("out.mkv".to_string()),
+            ..FfmpegCommand::default()
+        };
+        assert!(cmd.to_args().is_ok());
+
+        cmd.maps = vec![StreamMap { spec: "1:a".to_string(), ..StreamMap::default() }];
+        assert!(cmd.to_args().unwrap_err().contains("missing input"));
+
+        cmd.maps = vec![StreamMap { spec: "0:a".to_string(), codec: Some("aac".to_string()), ..StreamMap::default() }];
+        assert!(cmd.to_args().unwrap_err().contains("several streams"));
+
+        cmd.maps = vec![StreamMap { spec: "0:x:1".to_string(), ..StreamMap::default() }];
+        assert!(cmd.to_args().is_err());
+
+        cmd.maps.clear();
+        cmd.output = None;
+        assert_eq!(cmd.to_args().unwrap_err(), "no output");
+    }
+}
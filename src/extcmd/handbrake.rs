@@ -0,0 +1,166 @@
+use serde_json::Value as JsonValue;
+use std::time::Duration;
+
+use crate::rpc::TranscodeProgress;
+
+// HandBrakeCLI --json prints multi-line blocks such as `Progress: { ... }`
+// on stdout. Lines are collected until the braces balance, then the block
+// is parsed.
+#[derive(Default)]
+pub struct ProgressParser {
+    label: Option<String>,
+    buf: String,
+    depth: i32,
+}
+
+impl ProgressParser {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn push_line(&mut self, line: &str) -> Option<TranscodeProgress> {
+        if self.label.is_none() {
+            let (label, rest) = line.split_once(": ")?;
+            if !rest.trim_start().starts_with('{') {
+                return None;
+            }
+            self.label = Some(label.trim().to_string());
+            self.buf.clear();
+            self.depth = 0;
+            self.push_json(rest);
+        } else {
+            self.push_json(line);
+        }
+
+        if self.depth > 0 {
+            return None;
+        }
+
+        let label = self.label.take()?;
+        if label != "Progress" {
+            return None;
+        }
+
+        let json: JsonValue = serde_json::from_str(&self.buf).ok()?;
+        progress_from_json(&json)
+    }
+
+    fn push_json(&mut self, s: &str) {
+        for c in s.chars() {
+            match c {
+                '{' => self.depth += 1,
+                '}' => self.depth -= 1,
+                _ => {}
+            }
+        }
+        self.buf.push_str(s);
+        self.buf.push('\n');
+    }
+}
+
+fn progress_from_json(json: &JsonValue) -> Option<TranscodeProgress> {
+    if json.get("State")?.as_str()? != "WORKING" {
+        return None;
+    }
+
+    let working = json.get("Working")?;
+    let progress = working.get("Progress")?.as_f64()?;
+
+    // Spread multi-pass encodes over a single 0-100 range
+    let pass_count = working.get("PassCount")
+        .and_then(JsonValue::as_u64)
+        .filter(|c| *c > 0)
+        .unwrap_or(1);
+    let pass = working.get("Pass")
+        .and_then(JsonValue::as_u64)
+        .unwrap_or(1)
+        .clamp(1, pass_count);
+    let overall = ((pass - 1) as f64 + progress) / pass_count as f64;
+
+    let fps = working.get("Rate")
+        .and_then(JsonValue::as_f64)
+        .map(|f| f.round() as u64);
+    let eta = working.get("ETASeconds")
+        .and_then(JsonValue::as_u64)
+        .map(Duration::from_secs);
+
+    Some(TranscodeProgress {
+        frame: None,
+        fps,
+        cur_time: None,
+        percentage: Some((overall * 100.0).min(100.0).ceil()),
+        eta,
+        bitrate: None,
+        speed: None,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const OUTPUT: &str = r#"Version: {
+    "Arch": "x86_64",
+    "Name": "HandBrake",
+    "VersionString": "1.7.2"
+}
+Progress: {
+    "State": "SCANNING",
+    "Scanning": {
+        "Preview": 0,
+        "PreviewCount": 10,
+        "Progress": 0.0,
+        "SequenceID": 0,
+        "Title": 1,
+        "TitleCount": 1
+    }
+}
+Progress: {
+    "State": "WORKING",
+    "Working": {
+        "ETASeconds": 42,
+        "Hours": 0,
+        "Minutes": 0,
+        "Pass": 2,
+        "PassCount": 2,
+        "PassID": 1,
+        "Paused": 0,
+        "Progress": 0.5,
+        "Rate": 59.6,
+        "RateAvg": 58.1,
+        "Seconds": 42,
+        "SequenceID": 1
+    }
+}
+Progress: {
+    "State": "WORKDONE",
+    "WorkDone": {
+        "Error": 0,
+        "SequenceID": 1
+    }
+}
+"#;
+
+    #[test]
+    fn test_progress_parser() {
+        let mut parser = ProgressParser::new();
+        let updates: Vec<TranscodeProgress> = OUTPUT
+            .lines()
+            .filter_map(|l| parser.push_line(l))
+            .collect();
+
+        assert_eq!(updates.len(), 1);
+        let p = &updates[0];
+        assert_eq!(p.percentage, Some(75.0));
+        assert_eq!(p.fps, Some(60));
+        assert_eq!(p.eta, Some(Duration::from_secs(42)));
+    }
+
+    #[test]
+    fn test_progress_parser_ignores_logs() {
+        let mut parser = ProgressParser::new();
+        assert!(parser.push_line("Encode done!").is_none());
+        assert!(parser.push_line("HandBrake has exited.").is_none());
+        assert!(parser.push_line("x264 [info]: profile High, level 4.0").is_none());
+    }
+}
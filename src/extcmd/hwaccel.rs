@@ -0,0 +1,182 @@
+use serde::Serialize;
+use std::path::Path;
+use std::process::Stdio;
+use tokio::process::Command;
+use tokio::time::{timeout, Duration};
+use tracing::{debug, info};
+
+use crate::rpc::ToolInfo;
+
+const PROBE_TIMEOUT: Duration = Duration::from_secs(20);
+
+// Hardware encoders worth a test encode. Being listed by `ffmpeg -encoders`
+// only means ffmpeg was built with them, not that a device is present.
+const HW_ENCODERS: &[&str] = &[
+    "h264_nvenc", "hevc_nvenc", "av1_nvenc",
+    "h264_qsv", "hevc_qsv", "av1_qsv",
+    "h264_vaapi", "hevc_vaapi", "av1_vaapi",
+    "h264_videotoolbox", "hevc_videotoolbox",
+];
+
+const VAAPI_DEVICE: &str = "/dev/dri/renderD128";
+
+#[derive(Debug, Default, Clone, Serialize)]
+pub struct Capabilities {
+    pub probed: bool,               // false when ffmpeg could not be run
+    pub hwaccels: Vec<String>,
+    pub encoders: Vec<String>,      // usable encoders, software and tested hardware
+    pub hardware: Vec<String>,      // hardware encoders that passed the test encode
+}
+
+async fn run(ffmpeg: &Path, args: &[&str]) -> Option<std::process::Output> {
+    let cmd = Command::new(ffmpeg)
+        .args(args)
+        .stdin(Stdio::null())
+        .kill_on_drop(true)
+        .output();
+    timeout(PROBE_TIMEOUT, cmd).await.ok()?.ok()
+}
+
+// ` V....D libx264   libx264 H.264 / AVC ...` lines, after the legend
+fn parse_encoders(output: &str) -> Vec<String> {
+    output
+        .lines()
+        .skip_while(|l| !l.trim_start().starts_with("------"))
+        .skip(1)
+        .filter_map(|l| {
+            let mut parts = l.split_whitespace();
+            let flags = parts.next()?;
+            let name = parts.next()?;
+            (flags.len() == 6).then(|| name.to_string())
+        })
+        .collect()
+}
+
+fn parse_hwaccels(output: &str) -> Vec<String> {
+    output
+        .lines()
+        .skip_while(|l| !l.starts_with("Hardware acceleration methods"))
+        .skip(1)
+        .map(str::trim)
+        .filter(|l| !l.is_empty())
+        .map(str::to_string)
+        .collect()
+}
+
+async fn test_encode(ffmpeg: &Path, encoder: &str) -> bool {
+    let mut args = vec!["-hide_banner", "-v", "error"];
+    if encoder.ends_with("_vaapi") {
+        args.extend(["-vaapi_device", VAAPI_DEVICE]);
+    }
+    args.extend(["-f", "lavfi", "-i", "color=black:s=256x256:d=0.1", "-frames:v", "1"]);
+    if encoder.ends_with("_vaapi") {
+        args.extend(["-vf", "format=nv12,hwupload"]);
+    }
+    args.extend(["-c:v", encoder, "-f", "null", "-"]);
+
+    match run(ffmpeg, &args).await {
+        Some(out) if out.status.success() => true,
+        Some(out) => {
+            debug!("Test encode with {} failed: {}", encoder, String::from_utf8_lossy(&out.stderr).trim());
+            false
+        },
+        None => false,
+    }
+}
+
+// Tool report entry listing the hardware encoders that passed the test encode
+pub fn report(ffmpeg: &Path, capabilities: &Capabilities) -> ToolInfo {
+    let hardware = if capabilities.hardware.is_empty() {
+        "none".to_string()
+    } else {
+        capabilities.hardware.join(", ")
+    };
+    ToolInfo {
+        name: "hardware encoders".to_string(),
+        path: ffmpeg.to_string_lossy().into_owned(),
+        version: capabilities.probed.then_some(hardware),
+        error: (!capabilities.probed).then(|| "ffmpeg could not be run".to_string()),
+    }
+}
+
+pub async fn probe(ffmpeg: &Path) -> Capabilities {
+    let Some(out) = run(ffmpeg, &["-hide_banner", "-encoders"]).await else {
+        return Capabilities::default();
+    };
+    let listed = parse_encoders(&String::from_utf8_lossy(&out.stdout));
+
+    let hwaccels = match run(ffmpeg, &["-hide_banner", "-hwaccels"]).await {
+        Some(out) => parse_hwaccels(&String::from_utf8_lossy(&out.stdout)),
+        None => Vec::new(),
+    };
+
+    let mut encoders = Vec::new();
+    let mut hardware = Vec::new();
+    for name in listed {
+        if HW_ENCODERS.contains(&name.as_str()) {
+            if test_encode(ffmpeg, &name).await {
+                hardware.push(name.clone());
+                encoders.push(name);
+            }
+        } else {
+            encoders.push(name);
+        }
+    }
+
+    if hardware.is_empty() {
+        info!("No working hardware encoder, using software encoding only");
+    } else {
+        info!("Working hardware encoders: {}", hardware.join(", "));
+    }
+
+    Capabilities {
+        probed: true,
+        hwaccels,
+        encoders,
+        hardware,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_encoders() {
+        let out = "Encoders:
+ V..... = Video
+ A..... = Audio
+ ------
+ V....D libx264              libx264 H.264 / AVC / MPEG-4 AVC (codec h264)
+ V....D hevc_nvenc           NVIDIA NVENC hevc encoder (codec hevc)
+ A....D aac                  AAC (Advanced Audio Coding)
+";
+        assert_eq!(parse_encoders(out), vec!["libx264", "hevc_nvenc", "aac"]);
+    }
+
+    #[test]
+    fn test_parse_hwaccels() {
+        let out = "Hardware acceleration methods:\nvdpau\ncuda\nvaapi\n\n";
+        assert_eq!(parse_hwaccels(out), vec!["vdpau", "cuda", "vaapi"]);
+    }
+
+    #[tokio::test]
+    async fn test_probe_without_ffmpeg() {
+        let caps = probe(Path::new("/nonexistent/ffmpeg")).await;
+        assert!(!caps.probed);
+        assert!(caps.encoders.is_empty());
+        assert!(report(Path::new("/nonexistent/ffmpeg"), &caps).error.is_some());
+    }
+
+    #[test]
+    fn test_report() {
+        let caps = Capabilities {
+            probed: true,
+            hardware: vec!["h264_nvenc".to_string(), "hevc_nvenc".to_string()],
+            ..Capabilities::default()
+        };
+        let info = report(Path::new("ffmpeg"), &caps);
+        assert_eq!(info.version.as_deref(), Some("h264_nvenc, hevc_nvenc"));
+        assert!(info.error.is_none());
+    }
+}
@@ -0,0 +1,280 @@
+use serde::Serialize;
+use serde_json::Value as JsonValue;
+
+// Normalized view of ffprobe output, so scripts don't have to dig
+// through the raw stream list themselves
+#[derive(Debug, Default, Serialize, PartialEq)]
+pub struct MediaInfo {
+    pub container: Option<String>,
+    pub duration: Option<f64>,
+    pub bit_rate: Option<u64>,
+    pub size: Option<u64>,
+    pub video: Vec<VideoStream>,
+    pub audio: Vec<AudioStream>,
+    pub subtitles: Vec<SubtitleStream>,
+}
+
+#[derive(Debug, Serialize, PartialEq)]
+pub struct VideoStream {
+    pub index: u64,
+    pub codec: Option<String>,
+    pub profile: Option<String>,
+    pub width: Option<u64>,
+    pub height: Option<u64>,
+    pub bit_depth: Option<u8>,
+    pub pix_fmt: Option<String>,
+    pub frame_rate: Option<f64>,
+    pub hdr: Option<String>,                // "HDR10", "HDR10+", "HLG" or "DolbyVision"
+    pub dolby_vision_profile: Option<u64>,
+    pub default: bool,
+}
+
+#[derive(Debug, Serialize, PartialEq)]
+pub struct AudioStream {
+    pub index: u64,
+    pub codec: Option<String>,
+    pub language: Option<String>,
+    pub title: Option<String>,
+    pub channels: Option<u64>,
+    pub channel_layout: Option<String>,
+    pub default: bool,
+}
+
+#[derive(Debug, Serialize, PartialEq)]
+pub struct SubtitleStream {
+    pub index: u64,
+    pub codec: Option<String>,
+    pub language: Option<String>,
+    pub title: Option<String>,
+    pub forced: bool,
+    pub default: bool,
+}
+
+fn str_field(v: &JsonValue, key: &str) -> Option<String> {
+    v.get(key).and_then(JsonValue::as_str).map(str::to_string)
+}
+
+// ffprobe prints most numbers as strings
+fn num_field<T: std::str::FromStr>(v: &JsonValue, key: &str) -> Option<T> {
+    match v.get(key)? {
+        JsonValue::String(s) => s.parse().ok(),
+        n => n.to_string().parse().ok(),
+    }
+}
+
+fn tag(v: &JsonValue, key: &str) -> Option<String> {
+    v.get("tags").and_then(|t| str_field(t, key))
+}
+
+fn disposition(v: &JsonValue, key: &str) -> bool {
+    v.get("disposition")
+        .and_then(|d| d.get(key))
+        .and_then(JsonValue::as_i64)
+        .is_some_and(|d| d != 0)
+}
+
+fn parse_rate(rate: &str) -> Option<f64> {
+    let (num, den) = rate.split_once('/')?;
+    let (num, den): (f64, f64) = (num.parse().ok()?, den.parse().ok()?);
+    (num > 0.0 && den > 0.0).then(|| num / den)
+}
+
+fn bit_depth(stream: &JsonValue) -> Option<u8> {
+    if let Some(bits) = num_field::<u8>(stream, "bits_per_raw_sample") {
+        return Some(bits);
+    }
+
+    let pix_fmt = str_field(stream, "pix_fmt")?;
+    Some(if pix_fmt.contains("12") {
+        12
+    } else if pix_fmt.contains("10") {
+        10
+    } else {
+        8
+    })
+}
+
+// Dolby Vision wins over the transfer function, since profile 8 streams
+// also carry HDR10 or HLG signaling for compatibility
+fn hdr_format(stream: &JsonValue) -> (Option<String>, Option<u64>) {
+    let side_data = stream.get("side_data_list")
+        .and_then(JsonValue::as_array)
+        .map(Vec::as_slice)
+        .unwrap_or_default();
+
+    for sd in side_data {
+        let kind = str_field(sd, "side_data_type").unwrap_or_default();
+        if kind.contains("DOVI") {
+            return (Some("DolbyVision".to_string()), num_field(sd, "dv_profile"));
+        }
+    }
+
+    let hdr10_plus = side_data.iter().any(|sd| {
+        str_field(sd, "side_data_type").is_some_and(|k| k.contains("HDR10+") || k.contains("SMPTE2094-40"))
+    });
+
+    let hdr = match str_field(stream, "color_transfer").as_deref() {
+        Some("smpte2084") if hdr10_plus => Some("HDR10+"),
+        Some("smpte2084") => Some("HDR10"),
+        Some("arib-std-b67") => Some("HLG"),
+        _ => None,
+    };
+    (hdr.map(str::to_string), None)
+}
+
+impl MediaInfo {
+    pub fn from_ffprobe(json: &JsonValue) -> Self {
+        let mut info = MediaInfo::default();
+
+        if let Some(format) = json.get("format") {
+            info.container = str_field(format, "format_name");
+            info.duration = num_field(format, "duration");
+            info.bit_rate = num_field(format, "bit_rate");
+            info.size = num_field(format, "size");
+        }
+
+        let streams = json.get("streams")
+            .and_then(JsonValue::as_array)
+            .map(Vec::as_slice)
+            .unwrap_or_default();
+
+        for stream in streams {
+            let index = num_field(stream, "index").unwrap_or_default();
+            match str_field(stream, "codec_type").as_deref() {
+                Some("video") => {
+                    // Cover art is exposed as a video stream
+                    if disposition(stream, "attached_pic") {
+                        continue;
+                    }
+                    let (hdr, dolby_vision_profile) = hdr_format(stream);
+                    info.video.push(VideoStream {
+                        index,
+                        codec: str_field(stream, "codec_name"),
+                        profile: str_field(stream, "profile"),
+                        width: num_field(stream, "width"),
+                        height: num_field(stream, "height"),
+                        bit_depth: bit_depth(stream),
+                        pix_fmt: str_field(stream, "pix_fmt"),
+                        frame_rate: str_field(stream, "avg_frame_rate")
+                            .and_then(|r| parse_rate(&r))
+                            .or_else(|| str_field(stream, "r_frame_rate").and_then(|r| parse_rate(&r))),
+                        hdr,
+                        dolby_vision_profile,
+                        default: disposition(stream, "default"),
+                    });
+                },
+                Some("audio") => info.audio.push(AudioStream {
+                    index,
+                    codec: str_field(stream, "codec_name"),
+                    language: tag(stream, "language"),
+                    title: tag(stream, "title"),
+                    channels: num_field(stream, "channels"),
+                    channel_layout: str_field(stream, "channel_layout"),
+                    default: disposition(stream, "default"),
+                }),
+                Some("subtitle") => info.subtitles.push(SubtitleStream {
+                    index,
+                    codec: str_field(stream, "codec_name"),
+                    language: tag(stream, "language"),
+                    title: tag(stream, "title"),
+                    forced: disposition(stream, "forced"),
+                    default: disposition(stream, "default"),
+                }),
+                _ => {}
+            }
+        }
+
+        // Some containers have no duration in the format section
+        if info.duration.is_none() {
+            info.duration = streams.iter().filter_map(|s| num_field::<f64>(s, "duration")).reduce(f64::max);
+        }
+
+        info
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn test_from_ffprobe() {
+        let probe = json!({
+            "streams": [
+                {
+                    "index": 0,
+                    "codec_name": "hevc",
+                    "codec_type": "video",
+                    "profile": "Main 10",
+                    "width": 3840,
+                    "height": 2160,
+                    "pix_fmt": "yuv420p10le",
+                    "color_transfer": "smpte2084",
+                    "avg_frame_rate": "24000/1001",
+                    "disposition": { "default": 1, "attached_pic": 0 },
+                    "side_data_list": [
+                        { "side_data_type": "DOVI configuration record", "dv_profile": 8 }
+                    ]
+                },
+                {
+                    "index": 1,
+                    "codec_name": "eac3",
+                    "codec_type": "audio",
+                    "channels": 6,
+                    "channel_layout": "5.1(side)",
+                    "disposition": { "default": 1 },
+                    "tags": { "language": "eng", "title": "Surround" }
+                },
+                {
+                    "index": 2,
+                    "codec_name": "subrip",
+                    "codec_type": "subtitle",
+                    "disposition": { "default": 0, "forced": 1 },
+                    "tags": { "language": "ita" }
+                },
+                {
+                    "index": 3,
+                    "codec_name": "mjpeg",
+                    "codec_type": "video",
+                    "disposition": { "attached_pic": 1 }
+                }
+            ],
+            "format": {
+                "format_name": "matroska,webm",
+                "duration": "5400.123000",
+                "size": "1000000",
+                "bit_rate": "20000000"
+            }
+        });
+
+        let info = MediaInfo::from_ffprobe(&probe);
+        assert_eq!(info.container.as_deref(), Some("matroska,webm"));
+        assert_eq!(info.duration, Some(5400.123));
+        assert_eq!(info.bit_rate, Some(20_000_000));
+
+        assert_eq!(info.video.len(), 1);
+        let v = &info.video[0];
+        assert_eq!(v.codec.as_deref(), Some("hevc"));
+        assert_eq!(v.bit_depth, Some(10));
+        assert_eq!(v.hdr.as_deref(), Some("DolbyVision"));
+        assert_eq!(v.dolby_vision_profile, Some(8));
+        assert!((v.frame_rate.unwrap() - 23.976).abs() < 0.001);
+
+        assert_eq!(info.audio[0].language.as_deref(), Some("eng"));
+        assert_eq!(info.audio[0].channels, Some(6));
+        assert!(info.subtitles[0].forced);
+        assert!(!info.subtitles[0].default);
+    }
+
+    #[test]
+    fn test_hdr_formats() {
+        let hdr10 = json!({ "color_transfer": "smpte2084" });
+        let hlg = json!({ "color_transfer": "arib-std-b67" });
+        let sdr = json!({ "color_transfer": "bt709" });
+
+        assert_eq!(hdr_format(&hdr10).0.as_deref(), Some("HDR10"));
+        assert_eq!(hdr_format(&hlg).0.as_deref(), Some("HLG"));
+        assert_eq!(hdr_format(&sdr).0, None);
+    }
+}
@@ -0,0 +1,138 @@
+use std::path::PathBuf;
+use std::process::Stdio;
+use tokio::process::Command;
+use tokio::time::{timeout, Duration};
+
+use crate::config::WorkerConfig;
+use crate::rpc::ToolInfo;
+
+const VERSION_TIMEOUT: Duration = Duration::from_secs(10);
+
+// External binaries used by the Lua bindings, as configured on the worker
+#[derive(Debug, Clone, PartialEq)]
+pub struct ToolPaths {
+    pub ffmpeg: PathBuf,
+    pub ffprobe: PathBuf,
+    pub handbrake: PathBuf,
+    pub mkvpropedit: PathBuf,
+    pub mkvmerge: PathBuf,
+    pub exiftool: PathBuf,
+    pub mediainfo: PathBuf,
+    pub ccextractor: PathBuf,
+    pub exec_allowlist: Vec<PathBuf>,
+}
+
+impl From<&WorkerConfig> for ToolPaths {
+    fn from(cfg: &WorkerConfig) -> Self {
+        Self {
+            ffmpeg: cfg.ffmpeg_path.clone(),
+            ffprobe: cfg.ffprobe_path.clone(),
+            handbrake: cfg.handbrake_path.clone(),
+            mkvpropedit: cfg.mkvpropedit_path.clone(),
+            mkvmerge: cfg.mkvmerge_path.clone(),
+            exiftool: cfg.exiftool_path.clone(),
+            mediainfo: cfg.mediainfo_path.clone(),
+            ccextractor: cfg.ccextractor_path.clone(),
+            exec_allowlist: cfg.exec_allowlist.clone(),
+        }
+    }
+}
+
+impl Default for ToolPaths {
+    fn default() -> Self {
+        Self::from(&WorkerConfig::default())
+    }
+}
+
+impl ToolPaths {
+    // Name, path and the argument that prints the version
+    fn entries(&self) -> [(&'static str, &PathBuf, &'static str); 8] {
+        [
+            ("ffmpeg", &self.ffmpeg, "-version"),
+            ("ffprobe", &self.ffprobe, "-version"),
+            ("handbrake", &self.handbrake, "--version"),
+            ("mkvpropedit", &self.mkvpropedit, "--version"),
+            ("mkvmerge", &self.mkvmerge, "--version"),
+            ("exiftool", &self.exiftool, "-ver"),
+            ("mediainfo", &self.mediainfo, "--Version"),
+            ("ccextractor", &self.ccextractor, "--version"),
+        ]
+    }
+}
+
+// First line with a number in it, skipping the timestamped log lines
+// HandBrake prints before its version
+fn parse_version(output: &str) -> Option<String> {
+    output
+        .lines()
+        .map(str::trim)
+        .filter(|l| !l.starts_with('['))
+        .find(|l| l.chars().any(|c| c.is_ascii_digit()))
+        .map(str::to_string)
+}
+
+async fn tool_version(path: &PathBuf, arg: &str) -> Result<String, String> {
+    let cmd = Command::new(path)
+        .arg(arg)
+        .stdin(Stdio::null())
+        .kill_on_drop(true)
+        .output();
+
+    let out = match timeout(VERSION_TIMEOUT, cmd).await {
+        Ok(Ok(out)) => out,
+        Ok(Err(e)) => return Err(e.to_string()),
+        Err(_) => return Err("timed out".to_string()),
+    };
+
+    let stdout = String::from_utf8_lossy(&out.stdout);
+    let stderr = String::from_utf8_lossy(&out.stderr);
+    parse_version(&stdout)
+        .or_else(|| parse_version(&stderr))
+        .ok_or_else(|| format!("no version in output (exit status {})", out.status))
+}
+
+pub async fn check_tools(tools: &ToolPaths) -> Vec<ToolInfo> {
+    let mut report = Vec::new();
+    for (name, path, arg) in tools.entries() {
+        let (version, error) = match tool_version(path, arg).await {
+            Ok(v) => (Some(v), None),
+            Err(e) => (None, Some(e)),
+        };
+        report.push(ToolInfo {
+            name: name.to_string(),
+            path: path.to_string_lossy().into_owned(),
+            version,
+            error,
+        });
+    }
+    report
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_version() {
+        let ffmpeg = "ffmpeg version 6.1.1 Copyright (c) 2000-2023\nbuilt with gcc 13\n";
+        assert_eq!(parse_version(ffmpeg).unwrap(), "ffmpeg version 6.1.1 Copyright (c) 2000-2023");
+
+        let handbrake = "[12:00:00] hb_init: starting libhb thread\n\nHandBrake 1.7.2\n";
+        assert_eq!(parse_version(handbrake).unwrap(), "HandBrake 1.7.2");
+
+        assert_eq!(parse_version("\n12.76\n").unwrap(), "12.76");
+        assert!(parse_version("usage: tool\n").is_none());
+    }
+
+    #[tokio::test]
+    async fn test_missing_tool() {
+        let tools = ToolPaths {
+            ffmpeg: PathBuf::from("/nonexistent/ffmpeg"),
+            ..ToolPaths::default()
+        };
+        let report = check_tools(&tools).await;
+        let ffmpeg = report.iter().find(|t| t.name == "ffmpeg").unwrap();
+        assert!(ffmpeg.version.is_none());
+        assert!(ffmpeg.error.is_some());
+    }
+}
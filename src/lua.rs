@@ -3,10 +3,17 @@ mod serialization;
 mod time;
 mod media;
 mod regex;
+mod metadata;
+mod exec;
+mod ffmpeg;
+mod sandbox;
+mod fs;
+mod dry_run;
+mod testing;
 
-use std::{collections::HashMap, sync::Weak};
+use std::{collections::HashMap, path::{Path, PathBuf}, sync::{Mutex, Weak}};
 
-use mlua::{AnyUserData, Error, Lua, LuaOptions, Result, StdLib, Table};
+use mlua::{AnyUserData, Error, Lua, LuaOptions, LuaSerdeExt, Result, StdLib, Table, Value};
 use tracing::{info, warn, error, debug};
 use tokio::sync::mpsc;
 use std::sync::Arc;
@@ -20,11 +27,29 @@ use time::{
 use media::{
     _ffprobe,
     _ffmpeg,
+    _handbrake,
 };
 use regex::{
     _regex_match
 };
+use exec::_exec;
+use metadata::{
+    _ccextractor,
+    _exiftool,
+    _mediainfo,
+    _mkv_identify,
+    _mkvpropedit,
+};
+
+pub use sandbox::Sandbox;
+pub use dry_run::DryRunLog;
+pub use testing::TestResult;
 
+use sandbox::Roots;
+
+use crate::config::{FsRemap, ValidationConfig};
+use crate::extcmd::hwaccel::Capabilities;
+use crate::extcmd::tools::ToolPaths;
 use crate::rpc::JobStatusMsg;
 
 const UTILS_LUA: &str = include_str!("../lualib/utils.lua");
@@ -33,6 +58,12 @@ const INTEGRATIONS_LUA: &str = include_str!("../lualib/integrations.lua");
 pub struct TrahlRuntimeCtx {
     status_tx: mpsc::Sender<JobStatusMsg>,
     job_id: i64,
+    tools: ToolPaths,
+    cache_dir: Option<PathBuf>,
+    remaps: Option<Vec<FsRemap>>,
+    roots: Option<Arc<Roots>>,      // Set when the script is sandboxed
+    dry_run: Option<Mutex<DryRunLog>>,
+    testing: bool,                  // Bindings answer with the mocks of require("testing")
 }
 
 impl TrahlRuntimeCtx {
@@ -49,28 +80,82 @@ impl TrahlRuntimeCtx {
 
 pub struct TrahlRuntimeBuilder {
     vars: HashMap<String, String>,
-    public: Arc<TrahlRuntimeCtx>,
+    public: TrahlRuntimeCtx,
+    capabilities: Arc<Capabilities>,
+    sandbox: Option<Sandbox>,
     code: String,
+    chunk_name: Option<String>,
 }
 
 impl TrahlRuntimeBuilder {
     pub fn new(job_id: i64, status_tx: mpsc::Sender<JobStatusMsg>, code: String) -> Self {
         Self {
             vars: HashMap::new(),
-            public: Arc::new(TrahlRuntimeCtx {
+            public: TrahlRuntimeCtx {
                 status_tx,
                 job_id,
-            }),
+                tools: ToolPaths::default(),
+                cache_dir: None,
+                remaps: None,
+                roots: None,
+                dry_run: None,
+                testing: false,
+            },
+            capabilities: Arc::new(Capabilities::default()),
+            sandbox: None,
             code,
+            chunk_name: None,
         }
     }
 
     pub fn add_vars(mut self, vars: HashMap<String, String>) -> Self {
         self.vars.extend(vars);
+        self.public.cache_dir = self.vars.get("CACHEDIR").map(PathBuf::from);
+        self
+    }
+
+    pub fn with_tools(mut self, tools: ToolPaths) -> Self {
+        self.public.tools = tools;
+        self
+    }
+
+    pub fn with_capabilities(mut self, capabilities: Arc<Capabilities>) -> Self {
+        self.capabilities = capabilities;
         self
     }
 
-    pub fn build(self) -> anyhow::Result<TrahlRuntime> {
+    // Master paths given to _trahl.fs are translated with these
+    pub fn with_remaps(mut self, remaps: Option<Vec<FsRemap>>) -> Self {
+        self.public.remaps = remaps;
+        self
+    }
+
+    // Confines io to the job paths and bounds memory and instructions
+    pub fn with_sandbox(mut self, sandbox: Sandbox) -> Self {
+        self.sandbox = Some(sandbox);
+        self
+    }
+
+    // Commands, fs writes outside CACHEDIR and tools that edit files are
+    // recorded instead of run
+    pub fn with_dry_run(mut self) -> Self {
+        self.public.dry_run = Some(Mutex::default());
+        self
+    }
+
+    // Script tests: tools, http and fs calls are recorded and mocked
+    pub fn with_testing(mut self) -> Self {
+        self.public.testing = true;
+        self
+    }
+
+    // Shown in errors instead of the loader location, usually the script path
+    pub fn with_chunk_name(mut self, name: String) -> Self {
+        self.chunk_name = Some(name);
+        self
+    }
+
+    pub fn build(mut self) -> anyhow::Result<TrahlRuntime> {
         let luactx = Lua::new_with(
             StdLib::TABLE
             | StdLib::IO
@@ -87,7 +172,12 @@ impl TrahlRuntimeBuilder {
         let package: Table = globals.get("package")?;
         let preload: Table = package.get("preload")?;
 
-        let public_vars = Arc::downgrade(&self.public);
+        if self.sandbox.is_some() {
+            self.public.roots = Some(Arc::new(Roots::from_vars(&self.vars)));
+        }
+
+        let public = Arc::new(self.public);
+        let public_vars = Arc::downgrade(&public);
         luactx.set_named_registry_value("__trahl_runtime", 
             luactx.create_any_userdata(public_vars)?
         )?;
@@ -103,7 +193,12 @@ impl TrahlRuntimeBuilder {
         };
 
         register_module("utils", UTILS_LUA)?;
+        preload.set("media", luactx.create_function(|lua, ()| media::create_module(lua))?)?;
+        preload.set("ffmpeg", luactx.create_function(|lua, ()| ffmpeg::create_module(lua))?)?;
         register_module("integrations", INTEGRATIONS_LUA)?;
+        if public.testing {
+            testing::setup(&luactx, &preload)?;
+        }
 
         let table_trahl = luactx.create_table()?;
         let table_vars = luactx.create_table()?;
@@ -113,11 +208,26 @@ impl TrahlRuntimeBuilder {
 
         globals.set("_trahl", &table_trahl)?;
         table_trahl.set("vars", table_vars)?;
+        table_trahl.set("capabilities", luactx.to_value(&*self.capabilities)?)?;
+        table_trahl.set("dry_run", public.dry_run.is_some())?;
+
+        if let Some(sandbox) = &self.sandbox {
+            sandbox::apply(&luactx, sandbox)?;
+        }
+        // Dry runs keep io writes in CACHEDIR, trusted scripts included
+        let io_roots = match &public.dry_run {
+            Some(_) => Some(Arc::new(Roots::dry_run(&self.vars, self.sandbox.is_some()))),
+            None => public.roots.clone(),
+        };
+        if let Some(roots) = io_roots {
+            sandbox::restrict_io(&luactx, roots)?;
+        }
 
         Ok(TrahlRuntime {
-            _public: self.public,
+            _public: public,
             luactx: luactx,
             code: self.code,
+            chunk_name: self.chunk_name,
         })
     }
 }
@@ -126,24 +236,71 @@ pub struct TrahlRuntime {
     _public: Arc<TrahlRuntimeCtx>,
     luactx: Lua,
     code: String,
+    chunk_name: Option<String>,
 }
 
 impl TrahlRuntime {
     pub async fn exec(&self) -> anyhow::Result<()> {
-        self.luactx.load(&self.code)
-            .exec_async()
-            .await?;
+        let mut chunk = self.luactx.load(&self.code);
+        if let Some(name) = &self.chunk_name {
+            chunk = chunk.set_name(format!("@{}", name));
+        }
+        chunk.exec_async().await?;
         Ok(())
     }
 
-    pub fn get_output(&self) -> Result<String> {
-        self.luactx.named_registry_value::<String>("output")
+    // The main output, when set, comes first
+    pub fn get_outputs(&self) -> Result<Vec<OutputSpec>> {
+        let Some(outputs) = self.luactx.named_registry_value::<Option<Table>>("outputs")? else {
+            return Ok(Vec::new());
+        };
+        outputs
+            .sequence_values::<Table>()
+            .map(|entry| {
+                let entry = entry?;
+                Ok(OutputSpec {
+                    path: entry.get("path")?,
+                    mode: entry.get("mode")?,
+                    role: entry.get("role")?,
+                })
+            })
+            .collect()
     }
-    pub fn get_output_mode(&self) -> Result<u8> {
-        self.luactx.named_registry_value::<u8>("output_mode")
+
+    // None unless the runtime was built for a dry run
+    pub fn take_dry_run_log(&self) -> Option<DryRunLog> {
+        self._public.dry_run
+            .as_ref()
+            .map(|log| std::mem::take(&mut *log.lock().unwrap()))
+    }
+
+    // Tests recorded by require("testing"), in the order they ran
+    pub fn test_results(&self) -> Result<Vec<TestResult>> {
+        testing::results(&self.luactx)
+    }
+
+    // None when the script left the validation settings alone
+    pub fn get_validation(&self) -> Result<Option<ValidationConfig>> {
+        match self.luactx.named_registry_value::<Value>("validation")? {
+            Value::Nil => Ok(None),
+            Value::Table(spec) => validation_spec(&self.luactx, spec).map(Some),
+            _ => Ok(Some(ValidationConfig { enabled: false, ..ValidationConfig::default() })),
+        }
     }
 }
 
+pub const O_PRESERVE_DIR: u8 = 1;
+pub const O_FLAT: u8 = 2;
+pub const O_OVERWRITE: u8 = 3;
+pub const ROLE_MAIN: &str = "main";
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct OutputSpec {
+    pub path: String,
+    pub mode: u8,
+    pub role: String,
+}
+
 fn create_ffis(luactx: &Lua, table: &Table) -> Result<()> {
     let ffi_log = luactx.create_async_function(_log)?;
     let ffi_delay_msec = luactx.create_async_function(_delay_msec)?;
@@ -152,7 +309,16 @@ fn create_ffis(luactx: &Lua, table: &Table) -> Result<()> {
     let ffi_time = luactx.create_function(_time)?;
     let ffi_ffprobe = luactx.create_async_function(_ffprobe)?;
     let ffi_ffmpeg = luactx.create_async_function(_ffmpeg)?;
+    let ffi_handbrake = luactx.create_async_function(_handbrake)?;
+    let ffi_mediainfo = luactx.create_async_function(_mediainfo)?;
+    let ffi_exiftool = luactx.create_async_function(_exiftool)?;
+    let ffi_mkv_identify = luactx.create_async_function(_mkv_identify)?;
+    let ffi_mkvpropedit = luactx.create_async_function(_mkvpropedit)?;
+    let ffi_ccextractor = luactx.create_async_function(_ccextractor)?;
+    let ffi_exec = luactx.create_async_function(_exec)?;
     let ffi_setoutput = luactx.create_async_function(_set_output)?;
+    let ffi_addoutput = luactx.create_async_function(_add_output)?;
+    let ffi_setvalidation = luactx.create_function(_set_validation)?;
     let ffi_milestone = luactx.create_async_function(_milestone)?;
     let ffi_regex_match = luactx.create_function(_regex_match)?;
 
@@ -168,13 +334,23 @@ fn create_ffis(luactx: &Lua, table: &Table) -> Result<()> {
     table.set("time", ffi_time)?;
     table.set("ffprobe", ffi_ffprobe)?;
     table.set("ffmpeg", ffi_ffmpeg)?;
+    table.set("handbrake", ffi_handbrake)?;
+    table.set("mediainfo", ffi_mediainfo)?;
+    table.set("exiftool", ffi_exiftool)?;
+    table.set("mkv_identify", ffi_mkv_identify)?;
+    table.set("mkvpropedit", ffi_mkvpropedit)?;
+    table.set("ccextractor", ffi_ccextractor)?;
+    table.set("exec", ffi_exec)?;
     table.set("milestone", ffi_milestone)?;
     table.set("regex_match", ffi_regex_match)?;
+    table.set("fs", fs::create_table(luactx)?)?;
     
-    table.set("O_PRESERVE_DIR", 1)?;
-    table.set("O_FLAT", 2)?;
-    table.set("O_OVERWRITE", 3)?;
+    table.set("O_PRESERVE_DIR", O_PRESERVE_DIR)?;
+    table.set("O_FLAT", O_FLAT)?;
+    table.set("O_OVERWRITE", O_OVERWRITE)?;
     table.set("set_output", ffi_setoutput)?;
+    table.set("add_output", ffi_addoutput)?;
+    table.set("set_validation", ffi_setvalidation)?;
 
     Ok(())
 }
@@ -201,12 +377,81 @@ async fn _log(luactx: Lua, (level, msg): (u8, String)) -> Result<()> {
     Ok(())
 }
 
+// Outputs are moved away when placed, so they must be writable
+fn output_entry(lua: &Lua, file: String, mode: u8, role: String) -> Result<Table> {
+    if !(O_PRESERVE_DIR..=O_OVERWRITE).contains(&mode) {
+        return Err(Error::external(format!("Unknown output mode {} for {}", mode, file)));
+    }
+    let ctx = TrahlRuntimeCtx::get_ref(lua)?;
+    sandbox::check(&ctx, Path::new(&file), true)?;
+    let entry = lua.create_table()?;
+    entry.set("path", file)?;
+    entry.set("mode", mode)?;
+    entry.set("role", role)?;
+    Ok(entry)
+}
+
+fn outputs_table(lua: &Lua) -> Result<Table> {
+    if let Some(outputs) = lua.named_registry_value::<Option<Table>>("outputs")? {
+        return Ok(outputs);
+    }
+    let outputs = lua.create_table()?;
+    lua.set_named_registry_value("outputs", &outputs)?;
+    Ok(outputs)
+}
+
+// Sets the main output, replacing a previous one
 async fn _set_output(lua: Lua, (file, mode): (String, u8)) -> Result<()> {
-    lua.set_named_registry_value("output", file)?;
-    lua.set_named_registry_value("output_mode", mode)?;
+    let entry = output_entry(&lua, file, mode, ROLE_MAIN.to_string())?;
+    let outputs = outputs_table(&lua)?;
+    let first: Option<Table> = outputs.get(1)?;
+    if first.is_some_and(|f| f.get::<String>("role").is_ok_and(|r| r == ROLE_MAIN)) {
+        outputs.set(1, entry)?;
+    } else {
+        outputs.raw_insert(1, entry)?;
+    }
     Ok(()) 
 }
 
+// Extra files like subtitles or thumbnails, role defaults to "sidecar"
+async fn _add_output(lua: Lua, (file, mode, role): (String, u8, Option<String>)) -> Result<()> {
+    let role = role.unwrap_or_else(|| "sidecar".to_string());
+    let entry = output_entry(&lua, file, mode, role)?;
+    outputs_table(&lua)?.push(entry)
+}
+
+// A table enables validation unless it says otherwise, a misspelled key is an error
+// rather than a check that silently keeps its default
+fn validation_spec(lua: &Lua, spec: Table) -> Result<ValidationConfig> {
+    for pair in spec.pairs::<Value, Value>() {
+        let (key, _) = pair?;
+        let known = key.as_string().is_some_and(|k| ValidationConfig::FIELDS.contains(&k.to_string_lossy().as_str()));
+        if !known {
+            return Err(Error::external(format!(
+                "set_validation: unknown key {}, expected one of {}",
+                key.to_string()?,
+                ValidationConfig::FIELDS.join(", ")
+            )));
+        }
+    }
+    let enabled: Option<bool> = spec.get("enabled")?;
+    let mut config: ValidationConfig = lua.from_value(Value::Table(spec))?;
+    config.enabled = enabled.unwrap_or(true);
+    Ok(config)
+}
+
+// false disables validation for this job, a table replaces the worker settings
+fn _set_validation(lua: &Lua, spec: Value) -> Result<()> {
+    match spec {
+        Value::Boolean(false) => lua.set_named_registry_value("validation", false),
+        Value::Table(table) => {
+            validation_spec(lua, table.clone())?;
+            lua.set_named_registry_value("validation", table)
+        },
+        other => Err(Error::external(format!("set_validation expects false or a table, got {}", other.type_name()))),
+    }
+}
+
 async fn _milestone(lua: Lua, descr: String) -> Result<()> {
     let runtimectx = TrahlRuntimeCtx::get_ref(&lua)?.clone();
     info!("JOB {}: new milestone: {}", runtimectx.job_id, descr);
@@ -316,6 +561,355 @@ mod tests {
         Ok(())
     }
 
+    #[tokio::test]
+    async fn test_media_module() -> anyhow::Result<()> {
+        init_tracing();
+        let (
+            tx,
+            mut rx
+        ) = mpsc::channel::<JobStatusMsg>(10);
+
+        tokio::spawn(async move {
+            while rx.recv().await.is_some() {}
+        });
+
+        let code = r#"
+            local media = require("media")
+            local info = media.from_ffprobe({
+                streams = {
+                    { index = 0, codec_type = "video", codec_name = "h264", pix_fmt = "yuv420p", color_transfer = "bt709" },
+                    { index = 1, codec_type = "audio", codec_name = "aac", channels = 2, tags = { language = "eng" } },
+                },
+                format = { format_name = "mov,mp4,m4a,3gp,3g2,mj2", duration = "10.5" },
+            })
+            assert(info.duration == 10.5, "Wrong duration")
+            assert(info.video[1].codec == "h264", "Wrong video codec")
+            assert(info.video[1].bit_depth == 8, "Wrong bit depth")
+            assert(info.video[1].hdr == nil, "SDR reported as HDR")
+            assert(info.audio[1].language == "eng", "Wrong audio language")
+            assert(#info.subtitles == 0, "Unexpected subtitles")
+        "#;
+
+        let lua = TrahlRuntimeBuilder::new(
+            1,
+            tx.clone(),
+            code.to_string()
+        )
+        .build()?;
+
+        lua.exec().await?;
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_ffmpeg_builder() -> anyhow::Result<()> {
+        init_tracing();
+        let (
+            tx,
+            mut rx
+        ) = mpsc::channel::<JobStatusMsg>(10);
+
+        tokio::spawn(async move {
+            while rx.recv().await.is_some() {}
+        });
+
+        let code = r#"
+            local ffmpeg = require("ffmpeg")
+            local cmd = ffmpeg.new()
+                :input("/lib/in.mkv", { ss = "10" })
+                :map("0:v:0", { codec = "libx265", options = { crf = 22 } })
+                :map("0:a:0", { codec = "copy", metadata = { language = "eng" } })
+                :metadata("title", "Movie")
+                :output("/out/out.mkv")
+
+            local args = table.concat(cmd:args(), " ")
+            assert(args == "-ss 10 -i /lib/in.mkv -map 0:v:0 -c:0 libx265 -crf:0 22 "
+                .. "-map 0:a:0 -c:1 copy -metadata:s:1 language=eng -metadata title=Movie /out/out.mkv", args)
+            assert(cmd:command():sub(1, 7) == "ffmpeg ", cmd:command())
+
+            local bad = ffmpeg.new():input("/lib/in.mkv"):map("0:a", { codec = "aac" }):output("/out/out.mkv")
+            assert(not pcall(bad.args, bad), "Multi-stream map with settings accepted")
+        "#;
+
+        let lua = TrahlRuntimeBuilder::new(
+            1,
+            tx.clone(),
+            code.to_string()
+        )
+        .build()?;
+
+        lua.exec().await?;
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_pick_encoder() -> anyhow::Result<()> {
+        init_tracing();
+        let (
+            tx,
+            mut rx
+        ) = mpsc::channel::<JobStatusMsg>(10);
+
+        tokio::spawn(async move {
+            while rx.recv().await.is_some() {}
+        });
+
+        let code = r#"
+            local utils = require("utils")
+            assert(#_trahl.capabilities.hardware == 0, "Unexpected hardware encoder")
+            assert(utils.pick_encoder({"hevc_nvenc", "hevc_qsv", "libx265"}) == "libx265", "Wrong fallback")
+            assert(utils.pick_encoder({"hevc_nvenc"}) == nil, "Unsupported encoder picked")
+        "#;
+
+        let capabilities = Capabilities {
+            probed: true,
+            encoders: vec!["libx264".to_string(), "libx265".to_string()],
+            ..Capabilities::default()
+        };
+
+        let lua = TrahlRuntimeBuilder::new(
+            1,
+            tx.clone(),
+            code.to_string()
+        )
+        .with_capabilities(Arc::new(capabilities))
+        .build()?;
+
+        lua.exec().await?;
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_sandbox() -> anyhow::Result<()> {
+        init_tracing();
+        let (
+            tx,
+            mut rx
+        ) = mpsc::channel::<JobStatusMsg>(10);
+
+        tokio::spawn(async move {
+            while rx.recv().await.is_some() {}
+        });
+
+        let dir = tempfile::tempdir()?;
+        let cache_dir = dir.path().canonicalize()?;
+        let vars = HashMap::from([
+            ("CACHEDIR".to_string(), cache_dir.to_string_lossy().into_owned()),
+        ]);
+        let sandbox = Sandbox {
+            memory_limit: 64 * 1024 * 1024,
+            instruction_limit: 1_000_000,
+        };
+
+        let code = r#"
+            local f = assert(io.open(_trahl.vars.CACHEDIR .. "/out.txt", "w"))
+            f:write("ok")
+            f:close()
+            assert(io.open(_trahl.vars.CACHEDIR .. "/out.txt"):read("a") == "ok", "Cannot read back")
+
+            assert(io.open("/etc/passwd") == nil, "Read outside the job paths")
+            assert(io.open(_trahl.vars.CACHEDIR .. "/../escape.txt", "w") == nil, "Wrote outside CACHEDIR")
+            assert(not pcall(io.lines, "/etc/passwd"), "io.lines outside the job paths")
+            assert(io.popen == nil and loadfile == nil and dofile == nil, "Unsafe functions available")
+            assert(require("utils") ~= nil, "Preloaded modules must still load")
+
+            local function denied(fn, ...)
+                local ok, err = pcall(fn, ...)
+                return not ok and tostring(err):find("access denied by sandbox", 1, true) ~= nil
+            end
+            local cached = _trahl.vars.CACHEDIR .. "/out.txt"
+            assert(denied(_trahl.ffmpeg, { "-i", cached, "/etc/evil.mkv" }), "ffmpeg wrote outside the job paths")
+            assert(denied(_trahl.handbrake, { "-i", cached, "-o", "/etc/evil.mkv" }), "HandBrake wrote outside the job paths")
+            assert(denied(_trahl.mkvpropedit, "/etc/movie.mkv", {}), "mkvpropedit outside the job paths")
+            assert(denied(_trahl.ccextractor, cached, "/etc/cc.srt"), "ccextractor outside the job paths")
+            assert(denied(_trahl.set_output, "/etc/passwd", _trahl.O_FLAT), "Output outside the job paths")
+            assert(denied(_trahl.add_output, "/etc/passwd", _trahl.O_FLAT), "Sidecar outside the job paths")
+            _trahl.set_output(_trahl.vars.CACHEDIR .. "/out.txt", _trahl.O_FLAT)
+        "#;
+        TrahlRuntimeBuilder::new(1, tx.clone(), code.to_string())
+            .add_vars(vars.clone())
+            .with_sandbox(sandbox)
+            .build()?
+            .exec()
+            .await?;
+
+        let looping = TrahlRuntimeBuilder::new(1, tx.clone(), "while true do end".to_string())
+            .add_vars(vars.clone())
+            .with_sandbox(sandbox)
+            .build()?;
+        let err = looping.exec().await.unwrap_err();
+        assert!(err.to_string().contains("instructions"), "{}", err);
+
+        let hungry = TrahlRuntimeBuilder::new(1, tx.clone(), "local t = {} for i = 1, 1e8 do t[i] = i end".to_string())
+            .add_vars(vars)
+            .with_sandbox(Sandbox { instruction_limit: 0, ..sandbox })
+            .build()?;
+        let err = hungry.exec().await.unwrap_err();
+        assert!(err.to_string().contains("memory"), "{}", err);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_fs() -> anyhow::Result<()> {
+        init_tracing();
+        let (
+            tx,
+            mut rx
+        ) = mpsc::channel::<JobStatusMsg>(10);
+
+        tokio::spawn(async move {
+            while rx.recv().await.is_some() {}
+        });
+
+        let dir = tempfile::tempdir()?;
+        let cache_dir = dir.path().canonicalize()?;
+        std::fs::write(cache_dir.join("movie.mkv"), b"0123456789")?;
+
+        let code = r#"
+            local fs = _trahl.fs
+            local cache = _trahl.vars.CACHEDIR
+
+            assert(fs.stat(cache .. "/movie.mkv").size == 10, "Wrong size")
+            assert(fs.stat(cache .. "/missing") == nil, "Missing file has a stat")
+
+            fs.mkdir_p(cache .. "/a/b")
+            assert(fs.stat(cache .. "/a/b").is_dir, "mkdir_p failed")
+            assert(fs.copy(cache .. "/movie.mkv", cache .. "/a/b/copy.mkv") == 10, "Wrong copy size")
+            fs.move(cache .. "/a/b/copy.mkv", cache .. "/a/moved.mkv")
+            assert(not pcall(fs.copy, cache .. "/movie.mkv", cache .. "/a/../movie.mkv"), "Copied a file onto itself")
+            assert(fs.stat(cache .. "/movie.mkv").size == 10, "Copy onto itself truncated the source")
+
+            local entries = fs.list(cache .. "/a")
+            assert(#entries == 2 and entries[1].name == "b" and entries[2].name == "moved.mkv", "Wrong listing")
+            local found = fs.glob(cache .. "/**/*.mkv")
+            assert(#found == 2 and found[1] == cache .. "/a/moved.mkv", "Wrong glob")
+
+            assert(fs.remove(cache .. "/a/moved.mkv"), "Nothing removed")
+            assert(not fs.remove(cache .. "/a/moved.mkv"), "Removed twice")
+            assert(not pcall(fs.remove, cache .. "/a"), "Removed a non empty directory")
+            assert(fs.remove(cache .. "/a", { recursive = true }), "Recursive remove failed")
+
+            assert(fs.free_space(cache) > 0, "No free space")
+            assert(not pcall(fs.stat, "/etc/passwd"), "Sandbox bypassed")
+            assert(not pcall(fs.mkdir_p, "/tmp/outside-cachedir"), "Sandbox bypassed")
+        "#;
+
+        TrahlRuntimeBuilder::new(1, tx.clone(), code.to_string())
+            .add_vars(HashMap::from([
+                ("CACHEDIR".to_string(), cache_dir.to_string_lossy().into_owned()),
+            ]))
+            .with_sandbox(Sandbox::default())
+            .build()?
+            .exec()
+            .await?;
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_outputs() -> anyhow::Result<()> {
+        let (tx, _rx) = mpsc::channel::<JobStatusMsg>(10);
+
+        let code = r#"
+            _trahl.add_output("/cache/movie.srt", _trahl.O_FLAT, "subtitle")
+            _trahl.set_output("/cache/first.mkv", _trahl.O_FLAT)
+            _trahl.set_output("/cache/movie.mkv", _trahl.O_PRESERVE_DIR)
+            _trahl.add_output("/cache/thumb.jpg", _trahl.O_FLAT)
+            assert(not pcall(_trahl.add_output, "/cache/bad", 9), "Unknown mode accepted")
+        "#;
+
+        let lua = TrahlRuntimeBuilder::new(1, tx, code.to_string()).build()?;
+        lua.exec().await?;
+
+        let outputs = lua.get_outputs()?;
+        let summary: Vec<(&str, u8, &str)> = outputs
+            .iter()
+            .map(|o| (o.path.as_str(), o.mode, o.role.as_str()))
+            .collect();
+        assert_eq!(summary, vec![
+            ("/cache/movie.mkv", O_PRESERVE_DIR, "main"),
+            ("/cache/movie.srt", O_FLAT, "subtitle"),
+            ("/cache/thumb.jpg", O_FLAT, "sidecar"),
+        ]);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_dry_run() -> anyhow::Result<()> {
+        let (tx, mut rx) = mpsc::channel::<JobStatusMsg>(10);
+        let cache = tempfile::tempdir()?;
+        let outside = tempfile::tempdir()?;
+
+        let code = format!(r#"
+            assert(_trahl.dry_run, "Not a dry run")
+            local cache = _trahl.vars.CACHEDIR
+            _trahl.ffmpeg({{ "-i", "in.mkv", "-b:v", "1M", cache .. "/out.mkv" }}, {{ duration = 8 }})
+            _trahl.ffmpeg({{ "-i" }}, {{ duration = 10 }})
+            _trahl.fs.mkdir_p(cache .. "/work")
+            _trahl.fs.mkdir_p("{}/new")
+
+            local f = assert(io.open(cache .. "/notes.txt", "w"))
+            f:write("ok")
+            f:close()
+            assert(io.open("{}/notes.txt", "w") == nil, "io wrote outside CACHEDIR")
+        "#, outside.path().display(), outside.path().display());
+
+        let lua = TrahlRuntimeBuilder::new(1, tx, code)
+            .add_vars(HashMap::from([
+                ("CACHEDIR".to_string(), cache.path().to_string_lossy().into_owned()),
+            ]))
+            .with_dry_run()
+            .build()?;
+        lua.exec().await?;
+
+        assert!(cache.path().join("work").is_dir());
+        assert!(!outside.path().join("new").exists());
+        assert!(cache.path().join("notes.txt").is_file());
+        assert!(!outside.path().join("notes.txt").exists());
+
+        let log = lua.take_dry_run_log().unwrap();
+        assert_eq!(log.actions.len(), 3);
+        assert!(log.actions[0].ends_with("(estimated output 976.6 KB)"), "{}", log.actions[0]);
+        assert_eq!(log.sizes.get(&cache.path().join("out.mkv")), Some(&1_000_000));
+
+        let first = rx.recv().await.unwrap();
+        assert!(matches!(first.status, crate::rpc::JobStatus::Log(l) if l.starts_with("DRY RUN: ")));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_validation() -> anyhow::Result<()> {
+        let (tx, _rx) = mpsc::channel::<JobStatusMsg>(10);
+
+        let lua = TrahlRuntimeBuilder::new(1, tx.clone(), String::new()).build()?;
+        lua.exec().await?;
+        assert_eq!(lua.get_validation()?, None);
+
+        let code = r#"
+            assert(not pcall(_trahl.set_validation, "yes"), "String accepted")
+            assert(not pcall(_trahl.set_validation, { min_size_percent = "ten" }), "Bad field accepted")
+            local ok, err = pcall(_trahl.set_validation, { enable = false })
+            assert(not ok and tostring(err):find("unknown key enable", 1, true), "Unknown key accepted")
+            assert(not pcall(_trahl.set_validation, { 1 }), "Array accepted")
+            _trahl.set_validation({ min_audio_streams = 2, decode_seconds = 30 })
+        "#;
+        let lua = TrahlRuntimeBuilder::new(1, tx, code.to_string()).build()?;
+        lua.exec().await?;
+        assert_eq!(lua.get_validation()?, Some(ValidationConfig {
+            enabled: true,
+            min_audio_streams: Some(2),
+            decode_seconds: 30,
+            ..ValidationConfig::default()
+        }));
+
+        Ok(())
+    }
+
     #[tokio::test]
     async fn test_stdlibs() -> anyhow::Result<()> {
         init_tracing();
@@ -0,0 +1,46 @@
+use mlua::{Error, Result};
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use tracing::info;
+
+use crate::lua::TrahlRuntimeCtx;
+use crate::rpc::JobStatusMsg;
+
+// What a dry run skipped, and the size estimated for the files the skipped
+// commands would have written
+#[derive(Debug, Default)]
+pub struct DryRunLog {
+    pub actions: Vec<String>,
+    pub sizes: HashMap<PathBuf, u64>,
+}
+
+// Logs a skipped action in the job log and keeps it for the report
+pub async fn record(ctx: &TrahlRuntimeCtx, action: String) -> Result<()> {
+    if let Some(log) = &ctx.dry_run {
+        log.lock().unwrap().actions.push(action.clone());
+    }
+    info!("JOB {}: dry run skipped {}", ctx.job_id, action);
+    ctx.status_tx
+        .send(JobStatusMsg::job_log(ctx.job_id, format!("DRY RUN: {}", action)))
+        .await
+        .map_err(Error::external)
+}
+
+pub fn estimate(ctx: &TrahlRuntimeCtx, path: &Path, size: u64) {
+    if let Some(log) = &ctx.dry_run {
+        log.lock().unwrap().sizes.insert(path.to_path_buf(), size);
+    }
+}
+
+// Writes inside CACHEDIR are harmless and still happen, so scripts can
+// prepare their work files as usual
+pub fn skips_write(ctx: &TrahlRuntimeCtx, path: &Path) -> bool {
+    if ctx.dry_run.is_none() {
+        return false;
+    }
+    let cache_dir = ctx.cache_dir.as_deref().and_then(super::sandbox::resolve);
+    match (cache_dir, super::sandbox::resolve(path)) {
+        (Some(cache_dir), Some(path)) => !path.starts_with(cache_dir),
+        _ => true,
+    }
+}
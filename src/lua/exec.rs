@@ -0,0 +1,214 @@
+use mlua::{Error, Lua, Result, Table};
+use std::path::{Path, PathBuf};
+use std::process::Stdio;
+use std::time::Duration;
+use tokio::io::{AsyncBufReadExt, BufReader};
+use tokio::process::Command;
+use tokio::time::timeout;
+use tracing::info;
+
+use crate::extcmd::ffmpeg::quote_command;
+use crate::lua::{dry_run, testing, TrahlRuntimeCtx};
+use crate::rpc::JobStatusMsg;
+
+const DEFAULT_TIMEOUT_SECS: f64 = 3600.0;
+
+// Programs are allowed by exact match, or by file name when the allowlist
+// holds a full path, in which case that path is executed
+fn resolve_program(allowlist: &[PathBuf], cmd: &str) -> Option<PathBuf> {
+    allowlist
+        .iter()
+        .find(|p| p.as_os_str() == cmd || p.file_name().is_some_and(|n| n == cmd))
+        .cloned()
+}
+
+// Working directories must stay inside the job cache directory
+fn resolve_cwd(cache_dir: &Path, cwd: Option<String>) -> Result<PathBuf> {
+    let root = cache_dir.canonicalize()?;
+    let Some(cwd) = cwd else {
+        return Ok(root);
+    };
+
+    let cwd = Path::new(&cwd);
+    let cwd = if cwd.is_absolute() { cwd.to_path_buf() } else { root.join(cwd) };
+    let cwd = cwd.canonicalize()?;
+
+    if !cwd.starts_with(&root) {
+        return Err(Error::external(format!("exec: {} is outside CACHEDIR", cwd.display())));
+    }
+    Ok(cwd)
+}
+
+pub async fn _exec(luactx: Lua, (cmd, args, opts): (String, Option<Table>, Option<Table>)) -> Result<Table> {
+    if let Some(mocked) = testing::intercept(&luactx, "exec", (cmd.as_str(), args.clone(), opts.clone())).await? {
+        return luactx.unpack_multi(mocked);
+    }
+
+    let runtimectx = TrahlRuntimeCtx::get_ref(&luactx)?.clone();
+
+    let program = resolve_program(&runtimectx.tools.exec_allowlist, &cmd)
+        .ok_or_else(|| Error::external(format!("exec: {} is not in exec_allowlist", cmd)))?;
+
+    let args: Vec<String> = match args {
+        Some(t) => t.sequence_values::<String>().collect::<Result<_>>()?,
+        None => Vec::new(),
+    };
+
+    let (secs, capture, cwd) = match &opts {
+        Some(o) => (
+            o.get::<Option<f64>>("timeout")?.unwrap_or(DEFAULT_TIMEOUT_SECS),
+            o.get::<Option<bool>>("capture")?.unwrap_or(false),
+            o.get::<Option<String>>("cwd")?,
+        ),
+        None => (DEFAULT_TIMEOUT_SECS, false, None),
+    };
+    let limit = Duration::try_from_secs_f64(secs)
+        .ok()
+        .filter(|d| !d.is_zero())
+        .ok_or_else(|| Error::external(format!("exec: invalid timeout {}", secs)))?;
+
+    // Any allowed program could write anywhere, a dry run only reports it
+    if runtimectx.dry_run.is_some() {
+        dry_run::record(&runtimectx, quote_command(&program.to_string_lossy(), &args)).await?;
+        let result = luactx.create_table()?;
+        result.set("code", 0)?;
+        if capture {
+            result.set("stdout", "")?;
+            result.set("stderr", "")?;
+        }
+        return Ok(result);
+    }
+
+    let cache_dir = runtimectx.cache_dir
+        .as_ref()
+        .ok_or_else(|| Error::external("exec: no CACHEDIR for this job"))?;
+    let cwd = resolve_cwd(cache_dir, cwd)?;
+
+    let mut child = Command::new(&program)
+        .args(&args)
+        .current_dir(&cwd)
+        .stdin(Stdio::null())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .kill_on_drop(true)
+        .spawn()
+        .map_err(|e| Error::external(format!("exec: cannot run {}: {}", program.display(), e)))?;
+
+    info!("Started {}: {:?}", program.display(), args);
+
+    let stdout = child.stdout.take().expect("Stdout is piped");
+    let stderr = child.stderr.take().expect("Stderr is piped");
+
+    // Both streams are logged, and kept for the script when capture is set
+    let forward = |stream: Box<dyn tokio::io::AsyncRead + Unpin + Send>| {
+        let runtimectx = runtimectx.clone();
+        async move {
+            let mut captured = String::new();
+            let mut lines = BufReader::new(stream).lines();
+            while let Ok(Some(line)) = lines.next_line().await {
+                if capture {
+                    captured.push_str(&line);
+                    captured.push('\n');
+                }
+                if line.trim().is_empty() {
+                    continue;
+                }
+                let _ = runtimectx.status_tx
+                    .send(JobStatusMsg::job_log(runtimectx.job_id, line))
+                    .await;
+            }
+            captured
+        }
+    };
+
+    let run = async {
+        let (out, err) = tokio::join!(
+            forward(Box::new(stdout)),
+            forward(Box::new(stderr)),
+        );
+        let status = child.wait().await?;
+        Ok::<_, std::io::Error>((status, out, err))
+    };
+
+    let (status, out, err) = match timeout(limit, run).await {
+        Ok(res) => res?,
+        Err(_) => return Err(Error::external(format!("exec: {} timed out after {}s", cmd, secs))),
+    };
+
+    let result = luactx.create_table()?;
+    result.set("code", status.code())?;
+    if capture {
+        result.set("stdout", out)?;
+        result.set("stderr", err)?;
+    }
+    Ok(result)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashMap;
+    use tokio::sync::mpsc;
+    use crate::extcmd::tools::ToolPaths;
+    use crate::lua::TrahlRuntimeBuilder;
+
+    #[test]
+    fn test_resolve_program() {
+        let allowlist = vec![PathBuf::from("/usr/local/bin/dovi_tool"), PathBuf::from("mp4box")];
+
+        assert_eq!(resolve_program(&allowlist, "dovi_tool").unwrap(), PathBuf::from("/usr/local/bin/dovi_tool"));
+        assert_eq!(resolve_program(&allowlist, "/usr/local/bin/dovi_tool").unwrap(), PathBuf::from("/usr/local/bin/dovi_tool"));
+        assert_eq!(resolve_program(&allowlist, "mp4box").unwrap(), PathBuf::from("mp4box"));
+        assert!(resolve_program(&allowlist, "/bin/dovi_tool").is_none());
+        assert!(resolve_program(&allowlist, "sh").is_none());
+    }
+
+    #[test]
+    fn test_resolve_cwd() {
+        let dir = tempfile::tempdir().unwrap();
+        std::fs::create_dir(dir.path().join("sub")).unwrap();
+        let root = dir.path().canonicalize().unwrap();
+
+        assert_eq!(resolve_cwd(dir.path(), None).unwrap(), root);
+        assert_eq!(resolve_cwd(dir.path(), Some("sub".into())).unwrap(), root.join("sub"));
+        assert!(resolve_cwd(dir.path(), Some("..".into())).is_err());
+        assert!(resolve_cwd(dir.path(), Some("/".into())).is_err());
+    }
+
+    #[tokio::test]
+    async fn test_exec() -> anyhow::Result<()> {
+        let (tx, mut rx) = mpsc::channel::<JobStatusMsg>(10);
+        tokio::spawn(async move {
+            while rx.recv().await.is_some() {}
+        });
+
+        let dir = tempfile::tempdir()?;
+        let cache_dir = dir.path().canonicalize()?;
+        let tools = ToolPaths {
+            exec_allowlist: vec![PathBuf::from("sh")],
+            ..ToolPaths::default()
+        };
+
+        let code = r#"
+            local r = _trahl.exec("sh", {"-c", "pwd; exit 3"}, { capture = true })
+            assert(r.code == 3, "Wrong exit code")
+            assert(r.stdout == _trahl.vars.CACHEDIR .. "\n", "Wrong cwd " .. r.stdout)
+
+            assert(not pcall(_trahl.exec, "rm", {"-rf", "/"}), "Program not in allowlist ran")
+            assert(not pcall(_trahl.exec, "sh", {}, { cwd = "/" }), "Ran outside CACHEDIR")
+            assert(not pcall(_trahl.exec, "sh", {"-c", "sleep 5"}, { timeout = 0.2 }), "No timeout")
+            assert(not pcall(_trahl.exec, "sh", {"-c", "true"}, { timeout = -1 }), "Negative timeout")
+            assert(not pcall(_trahl.exec, "sh", {"-c", "true"}, { timeout = 0/0 }), "NaN timeout")
+        "#;
+
+        let lua = TrahlRuntimeBuilder::new(1, tx, code.to_string())
+            .add_vars(HashMap::from([
+                ("CACHEDIR".to_string(), cache_dir.to_string_lossy().into_owned()),
+            ]))
+            .with_tools(tools)
+            .build()?;
+
+        lua.exec().await?;
+        Ok(())
+    }
+}
@@ -0,0 +1,112 @@
+use mlua::{AnyUserData, Error, Lua, Result, Table, UserData, UserDataMethods, UserDataRef, Value};
+use std::collections::BTreeMap;
+use tracing::info;
+
+use crate::extcmd::ffmpeg::{FfmpegCommand, Input, StreamMap};
+use crate::lua::TrahlRuntimeCtx;
+use crate::lua::media::_ffmpeg;
+
+// Backs require("ffmpeg"). Setters return the builder so calls chain:
+//   ffmpeg.new():input(src):map("0:v:0", { codec = "libx265" }):output(dst):run()
+pub fn create_module(luactx: &Lua) -> Result<Table> {
+    let module = luactx.create_table()?;
+    module.set("new", luactx.create_function(|_, ()| Ok(FfmpegBuilder(FfmpegCommand::default())))?)?;
+    Ok(module)
+}
+
+pub struct FfmpegBuilder(FfmpegCommand);
+
+fn string_map(table: Option<Table>) -> Result<BTreeMap<String, String>> {
+    match table {
+        Some(t) => t.pairs::<String, String>().collect(),
+        None => Ok(BTreeMap::new()),
+    }
+}
+
+fn opt_get<T: mlua::FromLua>(opts: &Option<Table>, key: &str) -> Result<Option<T>> {
+    match opts {
+        Some(t) => t.get::<Option<T>>(key),
+        None => Ok(None),
+    }
+}
+
+impl FfmpegBuilder {
+    fn args(&self) -> Result<Vec<String>> {
+        self.0.to_args().map_err(|e| Error::external(format!("ffmpeg builder: {}", e)))
+    }
+}
+
+impl UserData for FfmpegBuilder {
+    fn add_methods<M: UserDataMethods<Self>>(methods: &mut M) {
+        // opts: { ss = "00:01:00", t = ..., to = ..., options = { key = value } }
+        methods.add_function("input", |_, (ud, path, opts): (AnyUserData, String, Option<Table>)| {
+            let mut input = Input { path, ..Input::default() };
+            for key in ["ss", "t", "to"] {
+                if let Some(value) = opt_get::<String>(&opts, key)? {
+                    input.trim.push((format!("-{}", key), value));
+                }
+            }
+            input.options = string_map(opt_get(&opts, "options")?)?;
+            ud.borrow_mut::<Self>()?.0.inputs.push(input);
+            Ok(ud)
+        });
+
+        // opts: { codec, filter, options = {}, metadata = {}, disposition }
+        methods.add_function("map", |_, (ud, spec, opts): (AnyUserData, String, Option<Table>)| {
+            let map = StreamMap {
+                spec,
+                codec: opt_get(&opts, "codec")?,
+                filter: opt_get(&opts, "filter")?,
+                options: string_map(opt_get(&opts, "options")?)?,
+                metadata: string_map(opt_get(&opts, "metadata")?)?,
+                disposition: opt_get(&opts, "disposition")?,
+            };
+            ud.borrow_mut::<Self>()?.0.maps.push(map);
+            Ok(ud)
+        });
+
+        methods.add_function("filter_complex", |_, (ud, graph): (AnyUserData, String)| {
+            ud.borrow_mut::<Self>()?.0.filter_complex = Some(graph);
+            Ok(ud)
+        });
+
+        methods.add_function("metadata", |_, (ud, key, value): (AnyUserData, String, String)| {
+            ud.borrow_mut::<Self>()?.0.metadata.insert(key, value);
+            Ok(ud)
+        });
+
+        methods.add_function("option", |_, (ud, key, value): (AnyUserData, String, String)| {
+            ud.borrow_mut::<Self>()?.0.options.insert(key.trim_start_matches('-').to_string(), value);
+            Ok(ud)
+        });
+
+        methods.add_function("output", |_, (ud, path): (AnyUserData, String)| {
+            ud.borrow_mut::<Self>()?.0.output = Some(path);
+            Ok(ud)
+        });
+
+        methods.add_method("args", |lua, this, ()| {
+            lua.create_sequence_from(this.args()?)
+        });
+
+        methods.add_method("command", |lua, this, ()| {
+            let program = TrahlRuntimeCtx::get_ref(lua)?.tools.ffmpeg.to_string_lossy().into_owned();
+            this.0
+                .command_line(&program)
+                .map_err(|e| Error::external(format!("ffmpeg builder: {}", e)))
+        });
+
+        // Same options as _trahl.ffmpeg
+        methods.add_async_method("run", async |lua, this: UserDataRef<Self>, opts: Option<Table>| {
+            let args = this.args()?;
+            let program = TrahlRuntimeCtx::get_ref(&lua)?.tools.ffmpeg.to_string_lossy().into_owned();
+            if let Ok(cmdline) = this.0.command_line(&program) {
+                info!("Running {}", cmdline);
+            }
+            drop(this);
+
+            let args = lua.create_sequence_from(args)?;
+            _ffmpeg(lua, (Value::Table(args), opts)).await
+        });
+    }
+}
@@ -0,0 +1,373 @@
+use globset::GlobBuilder;
+use mlua::{Error, Lua, Result, Table, Value};
+use std::io::ErrorKind;
+use std::os::unix::fs::PermissionsExt;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant, UNIX_EPOCH};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use walkdir::WalkDir;
+
+use crate::lua::{dry_run, testing, TrahlRuntimeCtx};
+use crate::rpc::{JobStatusMsg, TranscodeProgress};
+use crate::utils;
+
+const COPY_BUFFER: usize = 8 * 1024 * 1024;
+const PROGRESS_INTERVAL: Duration = Duration::from_secs(1);
+
+// Backs _trahl.fs
+pub fn create_table(luactx: &Lua) -> Result<Table> {
+    let table = luactx.create_table()?;
+    table.set("stat", luactx.create_async_function(_stat)?)?;
+    table.set("list", luactx.create_async_function(_list)?)?;
+    table.set("glob", luactx.create_async_function(_glob)?)?;
+    table.set("mkdir_p", luactx.create_async_function(_mkdir_p)?)?;
+    table.set("move", luactx.create_async_function(_move)?)?;
+    table.set("copy", luactx.create_async_function(_copy)?)?;
+    table.set("remove", luactx.create_async_function(_remove)?)?;
+    table.set("free_space", luactx.create_async_function(_free_space)?)?;
+    Ok(table)
+}
+
+// Scripts may pass master paths, and sandboxed ones only reach the job paths
+fn job_path(ctx: &TrahlRuntimeCtx, path: &str, write: bool) -> Result<PathBuf> {
+    let path = utils::remap_to_worker(Path::new(path), &ctx.remaps);
+    check_path(ctx, &path, write)?;
+    Ok(path)
+}
+
+fn check_path(ctx: &TrahlRuntimeCtx, path: &Path, write: bool) -> Result<()> {
+    super::sandbox::check(ctx, path, write)
+}
+
+fn fs_error(op: &str, path: &Path, e: impl std::fmt::Display) -> Error {
+    Error::external(format!("fs.{}: {}: {}", op, path.display(), e))
+}
+
+fn stat_table(luactx: &Lua, meta: &std::fs::Metadata) -> Result<Table> {
+    let table = luactx.create_table()?;
+    table.set("size", meta.len())?;
+    table.set("is_file", meta.is_file())?;
+    table.set("is_dir", meta.is_dir())?;
+    table.set("mode", meta.permissions().mode())?;
+    table.set("modified", meta.modified()
+        .ok()
+        .and_then(|m| m.duration_since(UNIX_EPOCH).ok())
+        .map(|d| d.as_secs()))?;
+    Ok(table)
+}
+
+// Returns nil when the path does not exist
+async fn _stat(luactx: Lua, path: String) -> Result<Value> {
+    if let Some(mocked) = testing::intercept(&luactx, "fs.stat", path.as_str()).await? {
+        return luactx.unpack_multi(mocked);
+    }
+    let ctx = TrahlRuntimeCtx::get_ref(&luactx)?;
+    let path = job_path(&ctx, &path, false)?;
+
+    match tokio::fs::metadata(&path).await {
+        Ok(meta) => Ok(Value::Table(stat_table(&luactx, &meta)?)),
+        Err(e) if e.kind() == ErrorKind::NotFound => Ok(Value::Nil),
+        Err(e) => Err(fs_error("stat", &path, e)),
+    }
+}
+
+// Entries are sorted by name, each with the fields of stat plus name and path
+async fn _list(luactx: Lua, dir: String) -> Result<Table> {
+    if let Some(mocked) = testing::intercept(&luactx, "fs.list", dir.as_str()).await? {
+        return luactx.unpack_multi(mocked);
+    }
+    let ctx = TrahlRuntimeCtx::get_ref(&luactx)?;
+    let dir = job_path(&ctx, &dir, false)?;
+
+    let mut entries = Vec::new();
+    let mut read_dir = tokio::fs::read_dir(&dir).await.map_err(|e| fs_error("list", &dir, e))?;
+    while let Some(entry) = read_dir.next_entry().await.map_err(|e| fs_error("list", &dir, e))? {
+        let meta = entry.metadata().await.map_err(|e| fs_error("list", &entry.path(), e))?;
+        entries.push((entry.file_name().to_string_lossy().into_owned(), entry.path(), meta));
+    }
+    entries.sort_by(|a, b| a.0.cmp(&b.0));
+
+    let result = luactx.create_table()?;
+    for (name, path, meta) in entries {
+        let table = stat_table(&luactx, &meta)?;
+        table.set("name", name)?;
+        table.set("path", path.to_string_lossy().into_owned())?;
+        result.push(table)?;
+    }
+    Ok(result)
+}
+
+// Leading components without glob syntax, where the walk starts
+fn glob_base(pattern: &Path) -> PathBuf {
+    pattern
+        .components()
+        .take_while(|c| !c.as_os_str().to_string_lossy().contains(['*', '?', '[', '{']))
+        .collect()
+}
+
+fn glob_paths(pattern: &Path) -> std::result::Result<Vec<PathBuf>, String> {
+    let pattern_str = pattern.to_string_lossy();
+    let matcher = GlobBuilder::new(&pattern_str)
+        .literal_separator(true)
+        .build()
+        .map_err(|e| e.to_string())?
+        .compile_matcher();
+
+    let base = glob_base(pattern);
+    let relative = base.as_os_str().is_empty();
+    let mut walker = WalkDir::new(if relative { Path::new(".") } else { &base }).min_depth(1);
+    if !pattern_str.contains("**") {
+        walker = walker.max_depth(pattern.components().count() - base.components().count());
+    }
+
+    let mut paths: Vec<PathBuf> = walker
+        .into_iter()
+        .filter_map(|e| e.ok())
+        .map(|e| e.into_path())
+        .map(|p| if relative { p.strip_prefix(".").map(Path::to_path_buf).unwrap_or(p) } else { p })
+        .filter(|p| matcher.is_match(p))
+        .collect();
+    paths.sort();
+    Ok(paths)
+}
+
+// `*` stays within a directory, `**` crosses them
+async fn _glob(luactx: Lua, pattern: String) -> Result<Table> {
+    if let Some(mocked) = testing::intercept(&luactx, "fs.glob", pattern.as_str()).await? {
+        return luactx.unpack_multi(mocked);
+    }
+    let ctx = TrahlRuntimeCtx::get_ref(&luactx)?;
+    let pattern = utils::remap_to_worker(Path::new(&pattern), &ctx.remaps);
+    let base = glob_base(&pattern);
+    check_path(&ctx, &base, false)?;
+
+    let paths = tokio::task::spawn_blocking(move || glob_paths(&pattern))
+        .await
+        .map_err(Error::external)?
+        .map_err(|e| fs_error("glob", &base, e))?;
+
+    luactx.create_sequence_from(paths.iter().map(|p| p.to_string_lossy().into_owned()))
+}
+
+async fn _mkdir_p(luactx: Lua, path: String) -> Result<()> {
+    if testing::intercept(&luactx, "fs.mkdir_p", path.as_str()).await?.is_some() {
+        return Ok(());
+    }
+    let ctx = TrahlRuntimeCtx::get_ref(&luactx)?;
+    let path = job_path(&ctx, &path, true)?;
+    if dry_run::skips_write(&ctx, &path) {
+        return dry_run::record(&ctx, format!("fs.mkdir_p {}", path.display())).await;
+    }
+    tokio::fs::create_dir_all(&path).await.map_err(|e| fs_error("mkdir_p", &path, e))
+}
+
+fn copy_progress(copied: u64, total: u64, elapsed: Duration) -> TranscodeProgress {
+    TranscodeProgress {
+        frame: None,
+        fps: None,
+        cur_time: Some(elapsed),
+        percentage: (total > 0).then(|| copied as f64 * 100.0 / total as f64),
+        eta: (copied > 0 && total >= copied)
+            .then(|| elapsed.mul_f64((total - copied) as f64 / copied as f64)),
+        bitrate: None,
+        speed: None,
+    }
+}
+
+// Both paths canonicalize to the same file, a missing destination never does
+async fn same_file(src: &Path, dst: &Path) -> std::io::Result<bool> {
+    let src = tokio::fs::canonicalize(src).await?;
+    match tokio::fs::canonicalize(dst).await {
+        Ok(dst) => Ok(src == dst),
+        Err(e) if e.kind() == ErrorKind::NotFound => Ok(false),
+        Err(e) => Err(e),
+    }
+}
+
+// Hidden sibling of dst, renamed into place once the copy is complete
+fn partial_path(dst: &Path) -> PathBuf {
+    let name = dst.file_name().map(|n| n.to_string_lossy().into_owned()).unwrap_or_default();
+    dst.with_file_name(format!(".{}.trahl-partial", name))
+}
+
+// Copies into a partial file first so a failed copy never leaves a truncated dst behind
+async fn copy_file(ctx: &TrahlRuntimeCtx, src: &Path, dst: &Path, progress: bool) -> std::io::Result<u64> {
+    if same_file(src, dst).await? {
+        return Err(std::io::Error::new(ErrorKind::InvalidInput, "source and destination are the same file"));
+    }
+
+    let partial = partial_path(dst);
+    let res = match copy_to(ctx, src, &partial, progress).await {
+        Ok(copied) => tokio::fs::rename(&partial, dst).await.map(|_| copied),
+        Err(e) => Err(e),
+    };
+    if res.is_err() {
+        let _ = tokio::fs::remove_file(&partial).await;
+    }
+    res
+}
+
+async fn copy_to(ctx: &TrahlRuntimeCtx, src: &Path, dst: &Path, progress: bool) -> std::io::Result<u64> {
+    let mut reader = tokio::fs::File::open(src).await?;
+    let meta = reader.metadata().await?;
+    let total = meta.len();
+    let mut writer = tokio::fs::File::create(dst).await?;
+
+    let mut buffer = vec![0u8; COPY_BUFFER];
+    let mut copied = 0u64;
+    let started = Instant::now();
+    let mut last_report = started;
+    loop {
+        let n = reader.read(&mut buffer).await?;
+        if n == 0 {
+            break;
+        }
+        writer.write_all(&buffer[..n]).await?;
+        copied += n as u64;
+
+        if progress && last_report.elapsed() >= PROGRESS_INTERVAL {
+            last_report = Instant::now();
+            let _ = ctx.status_tx
+                .send(JobStatusMsg::job_progress(ctx.job_id, copy_progress(copied, total, started.elapsed())))
+                .await;
+        }
+    }
+
+    writer.sync_all().await?;
+    tokio::fs::set_permissions(dst, meta.permissions()).await?;
+    Ok(copied)
+}
+
+// opts: { progress = true }, returns the number of bytes copied
+async fn _copy(luactx: Lua, (src, dst, opts): (String, String, Option<Table>)) -> Result<u64> {
+    if let Some(mocked) = testing::intercept(&luactx, "fs.copy", (src.as_str(), dst.as_str(), opts.clone())).await? {
+        return luactx.unpack_multi(mocked);
+    }
+    let ctx = TrahlRuntimeCtx::get_ref(&luactx)?;
+    let src = job_path(&ctx, &src, false)?;
+    let dst = job_path(&ctx, &dst, true)?;
+    let progress = match &opts {
+        Some(o) => o.get::<Option<bool>>("progress")?.unwrap_or(true),
+        None => true,
+    };
+
+    if dry_run::skips_write(&ctx, &dst) {
+        dry_run::record(&ctx, format!("fs.copy {} {}", src.display(), dst.display())).await?;
+        return Ok(tokio::fs::metadata(&src).await.map(|m| m.len()).unwrap_or(0));
+    }
+
+    copy_file(&ctx, &src, &dst, progress).await.map_err(|e| fs_error("copy", &src, e))
+}
+
+// Falls back to copy and delete when the destination is on another filesystem
+async fn _move(luactx: Lua, (src, dst): (String, String)) -> Result<()> {
+    if testing::intercept(&luactx, "fs.move", (src.as_str(), dst.as_str())).await?.is_some() {
+        return Ok(());
+    }
+    let ctx = TrahlRuntimeCtx::get_ref(&luactx)?;
+    let src = job_path(&ctx, &src, true)?;
+    let dst = job_path(&ctx, &dst, true)?;
+    if dry_run::skips_write(&ctx, &src) || dry_run::skips_write(&ctx, &dst) {
+        return dry_run::record(&ctx, format!("fs.move {} {}", src.display(), dst.display())).await;
+    }
+
+    match tokio::fs::rename(&src, &dst).await {
+        Ok(()) => Ok(()),
+        Err(e) if e.kind() == ErrorKind::CrossesDevices => {
+            copy_file(&ctx, &src, &dst, true).await.map_err(|e| fs_error("move", &src, e))?;
+            tokio::fs::remove_file(&src).await.map_err(|e| fs_error("move", &src, e))
+        },
+        Err(e) => Err(fs_error("move", &src, e)),
+    }
+}
+
+// opts: { recursive = false }, returns false when there was nothing to remove
+async fn _remove(luactx: Lua, (path, opts): (String, Option<Table>)) -> Result<bool> {
+    if let Some(mocked) = testing::intercept(&luactx, "fs.remove", (path.as_str(), opts.clone())).await? {
+        return luactx.unpack_multi(mocked);
+    }
+    let ctx = TrahlRuntimeCtx::get_ref(&luactx)?;
+    let path = job_path(&ctx, &path, true)?;
+    let recursive = match &opts {
+        Some(o) => o.get::<Option<bool>>("recursive")?.unwrap_or(false),
+        None => false,
+    };
+
+    let meta = match tokio::fs::symlink_metadata(&path).await {
+        Ok(meta) => meta,
+        Err(e) if e.kind() == ErrorKind::NotFound => return Ok(false),
+        Err(e) => return Err(fs_error("remove", &path, e)),
+    };
+
+    if dry_run::skips_write(&ctx, &path) {
+        dry_run::record(&ctx, format!("fs.remove {}", path.display())).await?;
+        return Ok(true);
+    }
+
+    let res = if !meta.is_dir() {
+        tokio::fs::remove_file(&path).await
+    } else if recursive {
+        tokio::fs::remove_dir_all(&path).await
+    } else {
+        tokio::fs::remove_dir(&path).await
+    };
+    res.map_err(|e| fs_error("remove", &path, e))?;
+    Ok(true)
+}
+
+// Bytes available to the worker user on the filesystem holding path
+async fn _free_space(luactx: Lua, path: String) -> Result<u64> {
+    if let Some(mocked) = testing::intercept(&luactx, "fs.free_space", path.as_str()).await? {
+        return luactx.unpack_multi(mocked);
+    }
+    let ctx = TrahlRuntimeCtx::get_ref(&luactx)?;
+    let path = job_path(&ctx, &path, false)?;
+
+    let stat = rustix::fs::statvfs(&path).map_err(|e| fs_error("free_space", &path, e))?;
+    Ok(stat.f_bavail * stat.f_frsize)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_glob_paths() {
+        let dir = tempfile::tempdir().unwrap();
+        let root = dir.path();
+        std::fs::create_dir_all(root.join("season 1")).unwrap();
+        for file in ["a.mkv", "b.srt", "season 1/c.mkv"] {
+            std::fs::write(root.join(file), b"").unwrap();
+        }
+
+        assert_eq!(glob_base(&root.join("*.mkv")), root);
+        assert_eq!(glob_paths(&root.join("*.mkv")).unwrap(), vec![root.join("a.mkv")]);
+        assert_eq!(
+            glob_paths(&root.join("**/*.mkv")).unwrap(),
+            vec![root.join("a.mkv"), root.join("season 1/c.mkv")]
+        );
+        assert!(glob_paths(&root.join("[.mkv")).is_err());
+    }
+
+    #[test]
+    fn test_copy_progress() {
+        let tp = copy_progress(25, 100, Duration::from_secs(10));
+        assert_eq!(tp.percentage, Some(25.0));
+        assert_eq!(tp.eta, Some(Duration::from_secs(30)));
+        assert_eq!(copy_progress(0, 0, Duration::ZERO).percentage, None);
+    }
+
+    #[tokio::test]
+    async fn test_same_file() {
+        let dir = tempfile::tempdir().unwrap();
+        let root = dir.path();
+        std::fs::create_dir_all(root.join("sub")).unwrap();
+        std::fs::write(root.join("a.mkv"), b"0123456789").unwrap();
+
+        assert!(same_file(&root.join("a.mkv"), &root.join("a.mkv")).await.unwrap());
+        assert!(same_file(&root.join("a.mkv"), &root.join("sub/../a.mkv")).await.unwrap());
+        assert!(!same_file(&root.join("a.mkv"), &root.join("b.mkv")).await.unwrap());
+        assert!(same_file(&root.join("missing.mkv"), &root.join("b.mkv")).await.is_err());
+        assert_eq!(partial_path(&root.join("sub/b.mkv")), root.join("sub/.b.mkv.trahl-partial"));
+    }
+}
@@ -1,7 +1,9 @@
 use mlua::{Error, Lua, Result, Table};
 use reqwest::header::{HeaderMap, HeaderName, HeaderValue};
 
-pub async fn _http_request(_: Lua,
+use crate::lua::testing;
+
+pub async fn _http_request(luactx: Lua,
     (
         method_str,
         url,
@@ -9,6 +11,11 @@ pub async fn _http_request(_: Lua,
         raw_body
     ): (String, String, Option<Table>, Option<String>)
 ) -> Result<(u16, String)> {
+    let args = (method_str.as_str(), url.as_str(), headers_t.clone(), raw_body.as_deref());
+    if let Some(mocked) = testing::intercept(&luactx, "http_request", args).await? {
+        return luactx.unpack_multi(mocked);
+    }
+
     let method = method_str
         .parse::<reqwest::Method>()
         .map_err(Error::external)?;
@@ -1,21 +1,31 @@
-use mlua::{Error, Lua, LuaSerdeExt, Result, Table, Value};
+use mlua::{Error, Lua, LuaSerdeExt, Result, SerializeOptions, Table, Value};
 use tokio::process::Command;
-use std::path::PathBuf;
+use serde_json::Value as JsonValue;
+use std::path::{Path, PathBuf};
 use std::process::Stdio;
 use std::time::Duration;
 use std::collections::HashMap;
 use tokio::io::{AsyncBufReadExt, BufReader};
-use tracing::{error, info};
+use tracing::{error, info, warn};
 
 use crate::extcmd::ffprobe::{ffprobe, FFProbeError};
-use crate::lua::TrahlRuntimeCtx;
+use crate::extcmd::ffmpeg;
+use crate::extcmd::handbrake::ProgressParser;
+use crate::extcmd::probe::MediaInfo;
+use crate::lua::{dry_run, sandbox, testing, TrahlRuntimeCtx};
 use crate::rpc::{JobStatusMsg, TranscodeProgress};
+use crate::utils;
 
 pub async fn _ffprobe(luactx: Lua, mediapath: String) -> Result<Value> {
+    if let Some(mocked) = testing::intercept(&luactx, "ffprobe", mediapath.as_str()).await? {
+        return luactx.unpack_multi(mocked);
+    }
+
     let runtimectx = TrahlRuntimeCtx::get_ref(&luactx)?.clone();
 
-    let cmdpath = PathBuf::from("ffprobe");
+    let cmdpath = runtimectx.tools.ffprobe.clone();
     let mediapath = PathBuf::from(mediapath);
+    sandbox::check(&runtimectx, &mediapath, false)?;
     let out = ffprobe(&cmdpath, &mediapath).await;
     match out {
         Ok(json) => {
@@ -34,7 +44,62 @@ pub async fn _ffprobe(luactx: Lua, mediapath: String) -> Result<Value> {
     }
 }
 
-pub async fn _ffmpeg(luactx: Lua, (duration, args): (f64, Table)) -> Result<()> {
+// Backs require("media"): ffprobe output normalized into MediaInfo
+pub fn create_module(luactx: &Lua) -> Result<Table> {
+    let module = luactx.create_table()?;
+    module.set("probe", luactx.create_async_function(_media_probe)?)?;
+    module.set("from_ffprobe", luactx.create_function(_media_from_ffprobe)?)?;
+    Ok(module)
+}
+
+async fn _media_probe(luactx: Lua, mediapath: String) -> Result<Value> {
+    let json = _ffprobe(luactx.clone(), mediapath).await?;
+    _media_from_ffprobe(&luactx, json)
+}
+
+fn _media_from_ffprobe(luactx: &Lua, json: Value) -> Result<Value> {
+    let json: JsonValue = luactx.from_value(json)?;
+    // Missing fields become nil rather than the json null sentinel
+    let options = SerializeOptions::new().serialize_none_to_null(false);
+    luactx.to_value_with(&MediaInfo::from_ffprobe(&json), options)
+}
+
+// Probes the first input and applies the -ss/-t/-to trims in the args
+async fn ffmpeg_duration(cmdpath: &PathBuf, args: &[String]) -> Option<f64> {
+    let input = PathBuf::from(ffmpeg::input_file(args)?);
+    let json = ffprobe(cmdpath, &input).await.ok()?;
+    let info = MediaInfo::from_ffprobe(&json);
+    Some(ffmpeg::output_duration(args, info.duration?))
+}
+
+// Durations the progress can use, anything else counts as unknown
+fn valid_duration(d: f64) -> Option<f64> {
+    Some(d).filter(|d| d.is_finite() && *d > 0.0)
+}
+
+// Called as ffmpeg(args, { duration = ... }), where the duration is optional,
+// or as the older ffmpeg(duration, args)
+pub async fn _ffmpeg(luactx: Lua, (first, second): (Value, Option<Table>)) -> Result<()> {
+    if testing::intercept(&luactx, "ffmpeg", (first.clone(), second.clone())).await?.is_some() {
+        return Ok(());
+    }
+
+    let (args, mut duration) = match first {
+        Value::Table(args) => {
+            let duration = match second {
+                Some(opts) => opts.get::<Option<f64>>("duration")?.and_then(valid_duration),
+                None => None,
+            };
+            (args, duration)
+        },
+        Value::Integer(_) | Value::Number(_) => {
+            let args = second.ok_or_else(|| Error::external("ffmpeg: missing args table"))?;
+            let duration = luactx.unpack::<f64>(first)?;
+            (args, valid_duration(duration))
+        },
+        _ => return Err(Error::external("ffmpeg: expected an args table")),
+    };
+
     let mut args_vec = Vec::new();
     let mut i = 1;
     while let Ok(val) = args.get::<String>(i) {
@@ -42,18 +107,42 @@ pub async fn _ffmpeg(luactx: Lua, (duration, args): (f64, Table)) -> Result<()>
         i += 1;
     }
 
-    let total_duration = Duration::from_secs_f64(duration);
+    let runtimectx = TrahlRuntimeCtx::get_ref(&luactx)?.clone();
+    sandbox::check_ffmpeg_args(&runtimectx, &args_vec)?;
+
+    if duration.is_none() {
+        duration = ffmpeg_duration(&runtimectx.tools.ffprobe, &args_vec).await.and_then(valid_duration);
+        if duration.is_none() {
+            warn!("Cannot find the duration of the ffmpeg input, progress will have no percentage");
+        }
+    }
+    let total_duration = duration.map(Duration::from_secs_f64);
+
+    if runtimectx.dry_run.is_some() {
+        let mut action = ffmpeg::quote_command(&runtimectx.tools.ffmpeg.to_string_lossy(), &args_vec);
+        let estimate = duration.and_then(|d| ffmpeg::estimated_size(&args_vec, d));
+        if let (Some(size), Some(output)) = (estimate, args_vec.last()) {
+            dry_run::estimate(&runtimectx, Path::new(output), size);
+            action.push_str(&format!(" (estimated output {})", utils::human_size(size)));
+        }
+        return dry_run::record(&runtimectx, action).await;
+    }
 
     args_vec.push("-progress".to_string());
     args_vec.push("pipe:1".to_string());
     args_vec.push("-nostats".to_string());
     args_vec.push("-y".to_string());
 
-    let cmdpath = PathBuf::from("ffmpeg");
-    let mut child = Command::new(cmdpath)
+    let mut command = Command::new(&runtimectx.tools.ffmpeg);
+    // Relative paths were checked against CACHEDIR, so resolve them there
+    if let Some(cache_dir) = &runtimectx.cache_dir {
+        command.current_dir(cache_dir);
+    }
+    let mut child = command
         .args(&args_vec)
         .stdout(Stdio::piped())
         .stderr(Stdio::piped())
+        .kill_on_drop(true)
         .spawn()?;
     
     info!("Started FFMPEG: {:?}", args_vec);
@@ -63,8 +152,6 @@ pub async fn _ffmpeg(luactx: Lua, (duration, args): (f64, Table)) -> Result<()>
     let mut err_reader = BufReader::new(stderr).lines();
     let mut reader = BufReader::new(stdout).lines();
     let mut block = HashMap::new();
-    
-    let runtimectx = TrahlRuntimeCtx::get_ref(&luactx)?.clone();
 
     loop {
         tokio::select! {
@@ -104,18 +191,18 @@ pub async fn _ffmpeg(luactx: Lua, (duration, args): (f64, Table)) -> Result<()>
                                     .and_then(|f: &String| f.parse::<u64>().ok())
                                     .map(Duration::from_micros); //out_time_ms is actually
                                                                  //microseconds
-                                let percentage = cur_time.map(|ct| {
-                                    let pct = (ct.as_secs_f64() / total_duration.as_secs_f64()) * 100.0;
+                                let percentage = cur_time.zip(total_duration).map(|(ct, total)| {
+                                    let pct = (ct.as_secs_f64() / total.as_secs_f64()) * 100.0;
                                     pct.min(100.0).ceil()
                                 });
 
-                                let eta = cur_time.and_then(|ct| speed.map(|s| {
+                                let eta = cur_time.zip(total_duration).and_then(|(ct, total)| speed.map(|s| {
                                     if let Some(pct) = percentage {
-                                        if (pct - 100.0).abs() < f64::EPSILON {
+                                        if (pct - 100.0).abs() < f64::EPSILON || s <= 0.0 {
                                             Duration::from_secs(0)
                                         } else {
-                                            let remaining = total_duration.saturating_sub(ct);
-                                            Duration::from_secs_f64(remaining.as_secs_f64() / s)
+                                            let remaining = total.saturating_sub(ct);
+                                            Duration::try_from_secs_f64(remaining.as_secs_f64() / s).unwrap_or_default()
                                         }
                                     } else {
                                         Duration::from_secs(0)
@@ -168,6 +255,99 @@ pub async fn _ffmpeg(luactx: Lua, (duration, args): (f64, Table)) -> Result<()>
     Ok(())
 }
 
+pub async fn _handbrake(luactx: Lua, args: Table) -> Result<()> {
+    if testing::intercept(&luactx, "handbrake", &args).await?.is_some() {
+        return Ok(());
+    }
+
+    let mut args_vec = Vec::new();
+    let mut i = 1;
+    while let Ok(val) = args.get::<String>(i) {
+        args_vec.push(val);
+        i += 1;
+    }
+
+    args_vec.push("--json".to_string());
+
+    let runtimectx = TrahlRuntimeCtx::get_ref(&luactx)?.clone();
+    sandbox::check_handbrake_args(&runtimectx, &args_vec)?;
+
+    if runtimectx.dry_run.is_some() {
+        let action = ffmpeg::quote_command(&runtimectx.tools.handbrake.to_string_lossy(), &args_vec);
+        return dry_run::record(&runtimectx, action).await;
+    }
+
+    let mut command = Command::new(&runtimectx.tools.handbrake);
+    // Relative paths were checked against CACHEDIR, so resolve them there
+    if let Some(cache_dir) = &runtimectx.cache_dir {
+        command.current_dir(cache_dir);
+    }
+    let mut child = command
+        .args(&args_vec)
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .kill_on_drop(true)
+        .spawn()?;
+
+    info!("Started HandBrake: {:?}", args_vec);
+
+    let stdout = child.stdout.take().expect("Stdout is piped");
+    let stderr = child.stderr.take().expect("Stderr is piped");
+    let mut err_reader = BufReader::new(stderr).lines();
+    let mut reader = BufReader::new(stdout).lines();
+    let mut parser = ProgressParser::new();
+    let mut stderr_open = true;
+
+    loop {
+        tokio::select! {
+            line = err_reader.next_line(), if stderr_open => {
+                match line {
+                    Ok(Some(line)) => {
+                        if line.trim().is_empty() {
+                            continue;
+                        }
+                        runtimectx.status_tx
+                            .send(JobStatusMsg::job_log(runtimectx.job_id, line))
+                            .await
+                            .map_err(Error::external)?;
+                    },
+                    Ok(None) | Err(_) => {
+                        stderr_open = false;
+                    }
+                }
+            },
+            line = reader.next_line() => {
+                match line {
+                    Ok(Some(line)) => {
+                        if let Some(tp) = parser.push_line(&line) {
+                            runtimectx.status_tx
+                                .send(JobStatusMsg::job_progress(runtimectx.job_id, tp))
+                                .await
+                                .map_err(Error::external)?;
+                        }
+                    }
+                    Ok(None) => {
+                        break;
+                    }
+                    Err(e) => {
+                        error!("HandBrake stdout read error: {:?}", e);
+                    }
+                }
+            }
+        }
+    }
+
+    let status = child.wait()
+        .await
+        .map_err(Error::external)?;
+
+    if !status.success() {
+        return Err(Error::external("HandBrake failed"));
+    }
+
+    Ok(())
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -196,4 +376,13 @@ mod tests {
         Ok(())
     }
 */
+
+    #[test]
+    fn test_valid_duration() {
+        assert_eq!(valid_duration(60.5), Some(60.5));
+        assert_eq!(valid_duration(0.0), None);
+        assert_eq!(valid_duration(-1.0), None);
+        assert_eq!(valid_duration(f64::NAN), None);
+        assert_eq!(valid_duration(f64::INFINITY), None);
+    }
 }
@@ -0,0 +1,214 @@
+use mlua::{Error, Lua, LuaSerdeExt, Result, Table, Value};
+use serde_json::Value as JsonValue;
+use std::ffi::OsStr;
+use std::path::Path;
+use std::process::Stdio;
+use tokio::io::{AsyncBufReadExt, AsyncReadExt, BufReader};
+use tokio::process::Command;
+use tracing::info;
+
+use crate::extcmd::ffmpeg::quote_command;
+use crate::lua::{dry_run, sandbox, testing, TrahlRuntimeCtx};
+use crate::rpc::JobStatusMsg;
+
+// Runs a tool, forwarding stderr as job logs, and returns its stdout
+async fn run_tool<S: AsRef<OsStr>>(luactx: &Lua, cmdpath: &std::path::Path, args: &[S]) -> Result<Vec<u8>> {
+    let runtimectx = TrahlRuntimeCtx::get_ref(luactx)?.clone();
+    let name = cmdpath
+        .file_name()
+        .map(|n| n.to_string_lossy().into_owned())
+        .unwrap_or_default();
+
+    let mut child = Command::new(cmdpath)
+        .args(args)
+        .stdin(Stdio::null())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .kill_on_drop(true)
+        .spawn()
+        .map_err(|e| Error::external(format!("cannot run {}: {}", cmdpath.display(), e)))?;
+
+    let mut stdout = child.stdout.take().expect("Stdout is piped");
+    let stderr = child.stderr.take().expect("Stderr is piped");
+    let mut err_reader = BufReader::new(stderr).lines();
+
+    let mut out = Vec::new();
+    let mut last_err = String::new();
+    let (read, ()) = tokio::join!(
+        stdout.read_to_end(&mut out),
+        async {
+            while let Ok(Some(line)) = err_reader.next_line().await {
+                if line.trim().is_empty() {
+                    continue;
+                }
+                last_err = line.clone();
+                let _ = runtimectx.status_tx
+                    .send(JobStatusMsg::job_log(runtimectx.job_id, line))
+                    .await;
+            }
+        }
+    );
+    read?;
+
+    let status = child.wait().await?;
+    if !status.success() {
+        return Err(Error::external(format!("{} failed: {}", name, last_err)));
+    }
+
+    Ok(out)
+}
+
+fn parse_json(out: &[u8]) -> Result<JsonValue> {
+    serde_json::from_slice(out).map_err(Error::external)
+}
+
+pub async fn _mediainfo(luactx: Lua, mediapath: String) -> Result<Value> {
+    if let Some(mocked) = testing::intercept(&luactx, "mediainfo", mediapath.as_str()).await? {
+        return luactx.unpack_multi(mocked);
+    }
+    let runtimectx = TrahlRuntimeCtx::get_ref(&luactx)?;
+    sandbox::check(&runtimectx, Path::new(&mediapath), false)?;
+    let cmdpath = runtimectx.tools.mediainfo.clone();
+    let out = run_tool(&luactx, &cmdpath, &["--Output=JSON", mediapath.as_str()]).await?;
+    luactx.to_value(&parse_json(&out)?)
+}
+
+pub async fn _exiftool(luactx: Lua, mediapath: String) -> Result<Value> {
+    if let Some(mocked) = testing::intercept(&luactx, "exiftool", mediapath.as_str()).await? {
+        return luactx.unpack_multi(mocked);
+    }
+    let runtimectx = TrahlRuntimeCtx::get_ref(&luactx)?;
+    sandbox::check(&runtimectx, Path::new(&mediapath), false)?;
+    let cmdpath = runtimectx.tools.exiftool.clone();
+    let out = run_tool(&luactx, &cmdpath, &["-json", "-n", mediapath.as_str()]).await?;
+
+    // exiftool answers with one object per file
+    let json = match parse_json(&out)? {
+        JsonValue::Array(mut files) if !files.is_empty() => files.swap_remove(0),
+        other => other,
+    };
+    luactx.to_value(&json)
+}
+
+pub async fn _mkv_identify(luactx: Lua, mediapath: String) -> Result<Value> {
+    if let Some(mocked) = testing::intercept(&luactx, "mkv_identify", mediapath.as_str()).await? {
+        return luactx.unpack_multi(mocked);
+    }
+    let runtimectx = TrahlRuntimeCtx::get_ref(&luactx)?;
+    sandbox::check(&runtimectx, Path::new(&mediapath), false)?;
+    let cmdpath = runtimectx.tools.mkvmerge.clone();
+    let out = run_tool(&luactx, &cmdpath, &["-J", mediapath.as_str()]).await?;
+    luactx.to_value(&parse_json(&out)?)
+}
+
+// Turns a list of edits into mkvpropedit arguments. Each edit selects a
+// track ("v1", "a2", "s1", "track:3" or "info") and sets some of its
+// properties, e.g. { track = "a1", language = "eng", default = true }
+fn mkvpropedit_args(edits: &Table) -> Result<Vec<String>> {
+    let mut args = Vec::new();
+
+    for edit in edits.sequence_values::<Table>() {
+        let edit = edit?;
+        let track: String = edit.get("track")?;
+        let selector = if track == "info" || track.starts_with("track:") {
+            track
+        } else {
+            format!("track:{}", track)
+        };
+        args.push("--edit".to_string());
+        args.push(selector);
+
+        let mut set = |prop: &str, value: String| {
+            args.push("--set".to_string());
+            args.push(format!("{}={}", prop, value));
+        };
+
+        for (key, prop) in [("name", "name"), ("title", "title"), ("language", "language")] {
+            if let Some(value) = edit.get::<Option<String>>(key)? {
+                set(prop, value);
+            }
+        }
+
+        for (key, prop) in [("default", "flag-default"), ("forced", "flag-forced"), ("enabled", "flag-enabled")] {
+            if let Some(value) = edit.get::<Option<bool>>(key)? {
+                set(prop, if value { "1" } else { "0" }.to_string());
+            }
+        }
+    }
+
+    if args.is_empty() {
+        return Err(Error::external("mkvpropedit: no edits given"));
+    }
+
+    Ok(args)
+}
+
+pub async fn _mkvpropedit(luactx: Lua, (mediapath, edits): (String, Table)) -> Result<()> {
+    if testing::intercept(&luactx, "mkvpropedit", (mediapath.as_str(), &edits)).await?.is_some() {
+        return Ok(());
+    }
+
+    let runtimectx = TrahlRuntimeCtx::get_ref(&luactx)?;
+    sandbox::check(&runtimectx, Path::new(&mediapath), true)?;
+    let cmdpath = runtimectx.tools.mkvpropedit.clone();
+
+    let mut args = vec![mediapath];
+    args.extend(mkvpropedit_args(&edits)?);
+    if dry_run::skips_write(&runtimectx, Path::new(&args[0])) {
+        return dry_run::record(&runtimectx, quote_command(&cmdpath.to_string_lossy(), &args)).await;
+    }
+    info!("Started mkvpropedit: {:?}", args);
+
+    run_tool(&luactx, &cmdpath, &args).await?;
+    Ok(())
+}
+
+pub async fn _ccextractor(luactx: Lua, (mediapath, outpath): (String, String)) -> Result<()> {
+    if testing::intercept(&luactx, "ccextractor", (mediapath.as_str(), outpath.as_str())).await?.is_some() {
+        return Ok(());
+    }
+
+    let runtimectx = TrahlRuntimeCtx::get_ref(&luactx)?;
+    sandbox::check(&runtimectx, Path::new(&mediapath), false)?;
+    sandbox::check(&runtimectx, Path::new(&outpath), true)?;
+    let cmdpath = runtimectx.tools.ccextractor.clone();
+
+    let args = [mediapath.as_str(), "-o", outpath.as_str()];
+    if dry_run::skips_write(&runtimectx, Path::new(&outpath)) {
+        let args: Vec<String> = args.iter().map(|a| a.to_string()).collect();
+        return dry_run::record(&runtimectx, quote_command(&cmdpath.to_string_lossy(), &args)).await;
+    }
+    info!("Started ccextractor: {:?}", args);
+
+    run_tool(&luactx, &cmdpath, &args).await?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_mkvpropedit_args() -> Result<()> {
+        let lua = Lua::new();
+        let edits: Table = lua.load(r#"
+            return {
+                { track = "info", title = "Movie" },
+                { track = "a1", language = "eng", default = true },
+                { track = "s2", forced = false, name = "Signs" },
+            }
+        "#).eval()?;
+
+        let args = mkvpropedit_args(&edits)?;
+        assert_eq!(args, vec![
+            "--edit", "info", "--set", "title=Movie",
+            "--edit", "track:a1", "--set", "language=eng", "--set", "flag-default=1",
+            "--edit", "track:s2", "--set", "name=Signs", "--set", "flag-forced=0",
+        ]);
+
+        let empty: Table = lua.load("return {}").eval()?;
+        assert!(mkvpropedit_args(&empty).is_err());
+
+        Ok(())
+    }
+}
@@ -0,0 +1,436 @@
+use mlua::{Error, Function, HookTriggers, IntoLuaMulti, Lua, MultiValue, Result, Table, Value, Variadic, VmState};
+use std::collections::HashMap;
+use std::path::{Component, Path, PathBuf};
+use std::sync::Arc;
+use std::sync::atomic::{AtomicU64, Ordering};
+
+use crate::config::WorkerConfig;
+use crate::lua::TrahlRuntimeCtx;
+
+const HOOK_INTERVAL: u32 = 10_000;
+const READ_ROOTS: &[&str] = &["CACHEDIR", "SRCFILE", "DSTDIR", "LIBRARYROOT"];
+const WRITE_ROOTS: &[&str] = &["CACHEDIR", "DSTDIR"];
+
+// ffmpeg options without a value, anything else starting with '-' takes one
+const FFMPEG_FLAGS: &[&str] = &[
+    "-y", "-n", "-hide_banner", "-nostdin", "-stdin", "-stats", "-nostats",
+    "-an", "-vn", "-sn", "-dn", "-shortest", "-copyts", "-start_at_zero", "-re",
+    "-benchmark", "-benchmark_all", "-ignore_unknown", "-copy_unknown", "-debug_ts",
+    "-xerror", "-dump", "-hex", "-autorotate", "-noautorotate", "-autoscale",
+    "-noautoscale", "-accurate_seek", "-noaccurate_seek", "-seek_timestamp", "-vstats",
+];
+// ffmpeg options reading the file they are given
+const FFMPEG_READS: &[&str] = &["-i", "-attach"];
+// Filters, and filter options, opening files or loading plugins of their own
+const FFMPEG_FILTER_FILES: &[&str] = &[
+    "movie", "amovie", "textfile", "subtitles", "ass", "filename", "sendcmd", "asendcmd",
+    "frei0r", "frei0r_src", "ladspa", "lv2",
+];
+const HANDBRAKE_READS: &[&str] = &[
+    "-i", "--input", "--srt-file", "--ssa-file", "--preset-import-file", "--queue-import-file",
+];
+
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Sandbox {
+    pub memory_limit: usize,        // Bytes, 0 disables the limit
+    pub instruction_limit: u64,     // 0 disables the limit
+}
+
+impl From<&WorkerConfig> for Sandbox {
+    fn from(config: &WorkerConfig) -> Self {
+        Self {
+            memory_limit: config.lua_memory_limit_mb * 1024 * 1024,
+            instruction_limit: config.lua_instruction_limit,
+        }
+    }
+}
+
+// Directories a sandboxed script may read from and write to, reads are
+// unrestricted when read is None
+pub struct Roots {
+    read: Option<Vec<PathBuf>>,
+    write: Vec<PathBuf>,
+    cwd: Option<PathBuf>,       // CACHEDIR, where tools run and relative tool paths point
+}
+
+fn collect_roots(vars: &HashMap<String, String>, keys: &[&str]) -> Vec<PathBuf> {
+    keys.iter()
+        .filter_map(|k| vars.get(*k))
+        .filter(|p| !p.is_empty())
+        .map(|p| resolve(Path::new(p)).unwrap_or_else(|| PathBuf::from(p)))
+        .collect()
+}
+
+impl Roots {
+    pub fn from_vars(vars: &HashMap<String, String>) -> Self {
+        Self {
+            read: Some(collect_roots(vars, READ_ROOTS)),
+            write: collect_roots(vars, WRITE_ROOTS),
+            cwd: collect_roots(vars, &["CACHEDIR"]).pop(),
+        }
+    }
+
+    // What io may touch in a dry run: reads as usual, trusted scripts anywhere,
+    // and writes only under CACHEDIR
+    pub fn dry_run(vars: &HashMap<String, String>, sandboxed: bool) -> Self {
+        Self {
+            read: sandboxed.then(|| collect_roots(vars, READ_ROOTS)),
+            write: collect_roots(vars, &["CACHEDIR"]),
+            cwd: collect_roots(vars, &["CACHEDIR"]).pop(),
+        }
+    }
+
+    pub fn check(&self, path: &Path, write: bool) -> std::result::Result<(), String> {
+        let roots = match (write, &self.read) {
+            (true, _) => &self.write,
+            (false, Some(read)) => read,
+            (false, None) => return Ok(()),
+        };
+        match resolve(path) {
+            Some(resolved) if roots.iter().any(|r| resolved.starts_with(r)) => Ok(()),
+            _ => Err(format!("{}: access denied by sandbox", path.display())),
+        }
+    }
+
+    // Tools run in CACHEDIR, their relative paths are checked from there
+    fn check_tool(&self, path: &Path, write: bool) -> std::result::Result<(), String> {
+        match &self.cwd {
+            Some(cwd) if path.is_relative() => self.check(&cwd.join(path), write),
+            _ => self.check(path, write),
+        }
+    }
+}
+
+// Check for the bindings of sandboxed scripts, a no-op for trusted ones
+pub(super) fn check(ctx: &TrahlRuntimeCtx, path: &Path, write: bool) -> Result<()> {
+    match &ctx.roots {
+        Some(roots) => roots.check(path, write).map_err(Error::runtime),
+        None => Ok(()),
+    }
+}
+
+// Pipes are not files, file: urls are checked as paths, and other protocols
+// are refused since some of them wrap files (concat:, subfile:, cache:...)
+fn check_url(roots: &Roots, url: &str, write: bool) -> std::result::Result<(), String> {
+    if url == "-" || url.starts_with("pipe:") {
+        return Ok(());
+    }
+    if let Some(path) = url.strip_prefix("file:") {
+        return roots.check_tool(Path::new(path), write);
+    }
+    let scheme = url.split_once(':').map(|(scheme, _)| scheme).unwrap_or_default();
+    if scheme.starts_with(|c: char| c.is_ascii_alphabetic())
+        && scheme.chars().all(|c| c.is_ascii_alphanumeric() || "+-.".contains(c)) {
+        return Err(format!("{}: protocol denied by sandbox", url));
+    }
+    roots.check_tool(Path::new(url), write)
+}
+
+// Filter graphs can open files on their own, movie=/etc/shadow or
+// drawtext=textfile=..., so those filters and options are refused, also with
+// an instance name (movie@in=) or spaces before the '='. Escapes and quotes
+// are dropped first, mo\vie is still movie to ffmpeg.
+fn check_filter_graph(graph: &str) -> std::result::Result<(), String> {
+    let (mut word, mut last) = (String::new(), String::new());
+    for c in graph.chars().filter(|c| !matches!(c, '\\' | '\'' | '"')) {
+        if c.is_ascii_alphanumeric() || c == '_' {
+            word.push(c);
+            continue;
+        }
+        if !word.is_empty() {
+            last = std::mem::take(&mut word);
+        }
+        if c.is_whitespace() {
+            continue;
+        }
+        if matches!(c, '=' | '@') && FFMPEG_FILTER_FILES.contains(&last.as_str()) {
+            return Err(format!("{}: filter reading files denied by sandbox", last));
+        }
+        last.clear();
+    }
+    Ok(())
+}
+
+pub(super) fn check_ffmpeg_args(ctx: &TrahlRuntimeCtx, args: &[String]) -> Result<()> {
+    match &ctx.roots {
+        Some(roots) => ffmpeg_paths(roots, args).map_err(Error::runtime),
+        None => Ok(()),
+    }
+}
+
+pub(super) fn check_handbrake_args(ctx: &TrahlRuntimeCtx, args: &[String]) -> Result<()> {
+    match &ctx.roots {
+        Some(roots) => handbrake_paths(roots, args).map_err(Error::runtime),
+        None => Ok(()),
+    }
+}
+
+// ffmpeg reads the -i values and writes its outputs, the arguments that are
+// neither options nor option values. Other option values must be writable
+// when taken as paths from CACHEDIR, whatever the option does with them, and
+// filter graphs must not open files.
+fn ffmpeg_paths(roots: &Roots, args: &[String]) -> std::result::Result<(), String> {
+    let mut format = None;
+    let mut i = 0;
+    while i < args.len() {
+        let arg = &args[i];
+        if arg.len() > 1 && arg.starts_with('-') {
+            if FFMPEG_FLAGS.contains(&arg.as_str()) {
+                i += 1;
+                continue;
+            }
+            // Graphs read from files, and -/option values loaded from files, can't be checked
+            if arg.starts_with("-/") || arg.starts_with("-filter_script") || arg.starts_with("-filter_complex_script") {
+                return Err(format!("{}: denied by sandbox", arg));
+            }
+            if let Some(value) = args.get(i + 1) {
+                match arg.as_str() {
+                    // tee writes to every output listed in its value
+                    "-f" if value == "tee" => return Err("tee muxer denied by sandbox".to_string()),
+                    "-f" => format = Some(value.as_str()),
+                    // Inputs of the lavfi device are filter graphs
+                    "-i" if format == Some("lavfi") => {
+                        check_filter_graph(value)?;
+                        format = None;
+                    },
+                    a if FFMPEG_READS.contains(&a) => {
+                        check_url(roots, value, false)?;
+                        format = None;
+                    },
+                    "-vf" | "-af" | "-lavfi" => check_filter_graph(value)?,
+                    a if a.starts_with("-filter") => check_filter_graph(value)?,
+                    _ => check_url(roots, value, true)?,
+                }
+            }
+            i += 2;
+        } else {
+            check_url(roots, arg, true)?;
+            format = None;
+            i += 1;
+        }
+    }
+    Ok(())
+}
+
+// Same for HandBrakeCLI, whose options also come as --name=value and whose
+// file lists are separated by commas
+fn handbrake_paths(roots: &Roots, args: &[String]) -> std::result::Result<(), String> {
+    let check = |value: &str, write: bool| {
+        value.split(',').try_for_each(|part| roots.check_tool(Path::new(part), write))
+    };
+
+    let mut i = 0;
+    while i < args.len() {
+        let arg = &args[i];
+        i += 1;
+        if !arg.starts_with('-') {
+            check(arg, true)?;
+            continue;
+        }
+
+        let (option, value) = match arg.split_once('=') {
+            Some((option, value)) if option.starts_with("--") => (option, value),
+            _ => match args.get(i) {
+                Some(value) if !value.starts_with('-') => {
+                    i += 1;
+                    (arg.as_str(), value.as_str())
+                },
+                _ => continue,
+            },
+        };
+
+        check(value, !HANDBRAKE_READS.contains(&option))?;
+    }
+    Ok(())
+}
+
+// Paths that don't exist yet are resolved through their closest existing
+// ancestor. Dangling symlinks are refused, they could point anywhere once
+// created, and so is `..` after a missing directory.
+pub(super) fn resolve(path: &Path) -> Option<PathBuf> {
+    if let Ok(p) = path.canonicalize() {
+        return Some(p);
+    }
+    if path.symlink_metadata().is_ok() {
+        return None;
+    }
+
+    let Some(Component::Normal(name)) = path.components().next_back() else {
+        return None;
+    };
+    let parent = match path.parent() {
+        Some(p) if !p.as_os_str().is_empty() => p,
+        _ => Path::new("."),
+    };
+    Some(resolve(parent)?.join(name))
+}
+
+fn is_write_mode(mode: Option<&str>) -> bool {
+    mode.is_some_and(|m| m.contains(['w', 'a', '+']))
+}
+
+pub fn apply(luactx: &Lua, sandbox: &Sandbox) -> Result<()> {
+    restrict_loading(luactx)?;
+
+    if sandbox.memory_limit > 0 {
+        luactx.set_memory_limit(sandbox.memory_limit)?;
+    }
+
+    if sandbox.instruction_limit > 0 {
+        let limit = sandbox.instruction_limit;
+        let count = AtomicU64::new(0);
+        luactx.set_global_hook(
+            HookTriggers::new().every_nth_instruction(HOOK_INTERVAL),
+            move |_, _| {
+                if count.fetch_add(HOOK_INTERVAL.into(), Ordering::Relaxed) >= limit {
+                    return Err(Error::runtime(format!("Script exceeded the limit of {} instructions", limit)));
+                }
+                Ok(VmState::Continue)
+            },
+        )?;
+    }
+
+    Ok(())
+}
+
+// io functions taking a file name go through the roots check, the
+// original functions are kept as upvalues of the wrappers
+pub fn restrict_io(luactx: &Lua, roots: Arc<Roots>) -> Result<()> {
+    let io: Table = luactx.globals().get("io")?;
+
+    let open: Function = io.get("open")?;
+    let check = roots.clone();
+    io.set("open", luactx.create_function(move |lua, (path, mode): (String, Option<String>)| {
+        if let Err(msg) = check.check(Path::new(&path), is_write_mode(mode.as_deref())) {
+            return (Value::Nil, msg).into_lua_multi(lua);
+        }
+        open.call::<MultiValue>((path, mode))
+    })?)?;
+
+    let lines: Function = io.get("lines")?;
+    let check = roots.clone();
+    io.set("lines", luactx.create_function(move |_, (path, formats): (Option<String>, Variadic<Value>)| {
+        if let Some(path) = &path {
+            check.check(Path::new(path), false).map_err(Error::runtime)?;
+        }
+        lines.call::<MultiValue>((path, formats))
+    })?)?;
+
+    for (name, write) in [("input", false), ("output", true)] {
+        let orig: Function = io.get(name)?;
+        let check = roots.clone();
+        io.set(name, luactx.create_function(move |_, file: Value| {
+            if let Value::String(path) = &file {
+                check.check(Path::new(&*path.to_str()?), write).map_err(Error::runtime)?;
+            }
+            orig.call::<MultiValue>(file)
+        })?)?;
+    }
+
+    io.set("popen", Value::Nil)?;
+    Ok(())
+}
+
+// Only preloaded modules can be required, and nothing is read from disk
+fn restrict_loading(luactx: &Lua) -> Result<()> {
+    let globals = luactx.globals();
+    globals.set("loadfile", Value::Nil)?;
+    globals.set("dofile", Value::Nil)?;
+
+    let package: Table = globals.get("package")?;
+    package.set("path", "")?;
+    package.set("cpath", "")?;
+    package.set("loadlib", Value::Nil)?;
+
+    let searchers: Table = package.get("searchers")?;
+    let preload: Function = searchers.get(1)?;
+    package.set("searchers", luactx.create_sequence_from([preload])?)?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_roots_check() {
+        let dir = tempfile::tempdir().unwrap();
+        let cache = dir.path().join("cache");
+        let library = dir.path().join("library");
+        std::fs::create_dir(&cache).unwrap();
+        std::fs::create_dir(&library).unwrap();
+        std::fs::write(library.join("movie.mkv"), b"").unwrap();
+
+        let vars = HashMap::from([
+            ("CACHEDIR".to_string(), cache.to_string_lossy().into_owned()),
+            ("LIBRARYROOT".to_string(), library.to_string_lossy().into_owned()),
+        ]);
+        let roots = Roots::from_vars(&vars);
+
+        assert!(roots.check(&library.join("movie.mkv"), false).is_ok());
+        assert!(roots.check(&library.join("movie.mkv"), true).is_err());
+        assert!(roots.check(&cache.join("new.mkv"), true).is_ok());
+        assert!(roots.check(&cache.join("missing/new.mkv"), true).is_ok());
+        assert!(roots.check(&cache.join("../library/movie.mkv"), true).is_err());
+        assert!(roots.check(&cache.join("missing/../../escape.mkv"), true).is_err());
+        assert!(roots.check(Path::new("/etc/passwd"), false).is_err());
+
+        let dry_run = Roots::dry_run(&vars, false);
+        assert!(dry_run.check(Path::new("/etc/passwd"), false).is_ok());
+        assert!(dry_run.check(&cache.join("new.mkv"), true).is_ok());
+        assert!(dry_run.check(&library.join("movie.mkv"), true).is_err());
+        assert!(Roots::dry_run(&vars, true).check(Path::new("/etc/passwd"), false).is_err());
+    }
+
+    #[test]
+    fn test_tool_paths() {
+        let dir = tempfile::tempdir().unwrap();
+        let cache = dir.path().join("cache");
+        let library = dir.path().join("library");
+        std::fs::create_dir(&cache).unwrap();
+        std::fs::create_dir(&library).unwrap();
+        let roots = Roots::from_vars(&HashMap::from([
+            ("CACHEDIR".to_string(), cache.to_string_lossy().into_owned()),
+            ("LIBRARYROOT".to_string(), library.to_string_lossy().into_owned()),
+        ]));
+        let movie = library.join("movie.mkv").to_string_lossy().into_owned();
+        let out = cache.join("out.mkv").to_string_lossy().into_owned();
+        let args = |list: &[&str]| list.iter().map(|a| a.to_string()).collect::<Vec<_>>();
+
+        assert!(ffmpeg_paths(&roots, &args(&["-y", "-i", &movie, "-c:v", "libx265", "-an", &out])).is_ok());
+        assert!(ffmpeg_paths(&roots, &args(&["-f", "lavfi", "-i", "color=black", "-f", "null", "-"])).is_ok());
+        assert!(ffmpeg_paths(&roots, &args(&["-i", &movie, "/etc/evil"])).is_err());
+        assert!(ffmpeg_paths(&roots, &args(&["-i", &movie, "file:/etc/evil"])).is_err());
+        assert!(ffmpeg_paths(&roots, &args(&["-i", &movie, &movie])).is_err());
+        assert!(ffmpeg_paths(&roots, &args(&["-i", "/etc/passwd", &out])).is_err());
+        assert!(ffmpeg_paths(&roots, &args(&["-i", &movie, "-passlogfile", "/etc/log", &out])).is_err());
+        // An option missing from the flag list still cannot hide an absolute output
+        assert!(ffmpeg_paths(&roots, &args(&["-i", &movie, "-unknown_flag", "/etc/evil"])).is_err());
+        // Relative paths are taken from CACHEDIR, where the tools run
+        assert!(ffmpeg_paths(&roots, &args(&["-i", &movie, "-c:v", "libx265", "-map", "0:a", "out.mkv"])).is_ok());
+        assert!(ffmpeg_paths(&roots, &args(&["-i", &movie, "-passlogfile", "../../x", &out])).is_err());
+        assert!(ffmpeg_paths(&roots, &args(&["-dump_attachment:t", "../x", "-i", &movie])).is_err());
+        assert!(ffmpeg_paths(&roots, &args(&["-i", &movie, "-vstats_file", "rel/../..", &out])).is_err());
+        assert!(ffmpeg_paths(&roots, &args(&["-i", "../library/movie.mkv", "../evil.mkv"])).is_err());
+        // Filter graphs opening files
+        assert!(ffmpeg_paths(&roots, &args(&["-i", &movie, "-vf", "scale=1280:-2,format=yuv420p", &out])).is_ok());
+        assert!(ffmpeg_paths(&roots, &args(&["-i", &movie, "-vf", "drawtext=textfile=/etc/shadow", &out])).is_err());
+        assert!(ffmpeg_paths(&roots, &args(&["-i", &movie, "-filter_complex", "movie=/etc/x[a];[0][a]overlay", &out])).is_err());
+        assert!(ffmpeg_paths(&roots, &args(&["-i", &movie, "-filter:v", "mo\\vie@in = /etc/x", &out])).is_err());
+        assert!(ffmpeg_paths(&roots, &args(&["-i", &movie, "-af", "amovie=/etc/x", &out])).is_err());
+        assert!(ffmpeg_paths(&roots, &args(&["-f", "lavfi", "-i", "movie=/etc/x", &out])).is_err());
+        assert!(ffmpeg_paths(&roots, &args(&["-i", &movie, "-filter_script:v", &out, &out])).is_err());
+        assert!(ffmpeg_paths(&roots, &args(&["-i", &movie, "-/vf", &out, &out])).is_err());
+        // Protocols and muxers reaching other files
+        assert!(ffmpeg_paths(&roots, &args(&["-i", "concat:/etc/shadow|/etc/passwd", &out])).is_err());
+        assert!(ffmpeg_paths(&roots, &args(&["-i", &movie, "-f", "tee", "[f=matroska]/etc/evil.mkv"])).is_err());
+
+        assert!(handbrake_paths(&roots, &args(&["-i", &movie, "-o", &out, "--encoder=x265", "--all-audio"])).is_ok());
+        assert!(handbrake_paths(&roots, &args(&["-i", &movie, &format!("--output={}", movie)])).is_err());
+        assert!(handbrake_paths(&roots, &args(&["--input", "/etc/passwd", "-o", &out])).is_err());
+        assert!(handbrake_paths(&roots, &args(&["-i", &movie, "-o", &out, "--srt-file", "/etc/x.srt"])).is_err());
+        assert!(handbrake_paths(&roots, &args(&["-i", &movie, "-o", "../evil.mkv"])).is_err());
+        assert!(handbrake_paths(&roots, &args(&["-i", &movie, "-o", &out, "--srt-file", "a.srt,../../x.srt"])).is_err());
+        assert!(handbrake_paths(&roots, &args(&["-i", &movie, "-o", "out.mkv", "--aencoder", "copy:aac", "--audio", "1,2"])).is_ok());
+    }
+}
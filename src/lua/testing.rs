@@ -0,0 +1,96 @@
+use mlua::{Error, IntoLuaMulti, Lua, MultiValue, Result, Table, Value};
+
+use crate::lua::TrahlRuntimeCtx;
+
+const TESTING_LUA: &str = include_str!("../../lualib/testing.lua");
+
+// Registry tables shared with require("testing")
+const MOCKS: &str = "__trahl_mocks";
+const CALLS: &str = "__trahl_calls";
+const RESULTS: &str = "__trahl_test_results";
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct TestResult {
+    pub name: String,
+    pub passed: bool,
+    pub message: Option<String>,
+}
+
+// Registers require("testing"), which gets the mock, call and result
+// tables along with accessors for the state the bindings keep in the registry
+pub fn setup(lua: &Lua, preload: &Table) -> Result<()> {
+    for name in [MOCKS, CALLS, RESULTS] {
+        lua.set_named_registry_value(name, lua.create_table()?)?;
+    }
+
+    let loader = lua.create_function(|lua, ()| {
+        let native = lua.create_table()?;
+        native.set("mocks", lua.named_registry_value::<Table>(MOCKS)?)?;
+        native.set("calls", lua.named_registry_value::<Table>(CALLS)?)?;
+        native.set("results", lua.named_registry_value::<Table>(RESULTS)?)?;
+        native.set("outputs", lua.create_function(|lua, ()| {
+            lua.named_registry_value::<Value>("outputs")
+        })?)?;
+        native.set("validation", lua.create_function(|lua, ()| {
+            lua.named_registry_value::<Value>("validation")
+        })?)?;
+        native.set("reset", lua.create_function(|lua, ()| {
+            lua.set_named_registry_value("outputs", Value::Nil)?;
+            lua.set_named_registry_value("validation", Value::Nil)
+        })?)?;
+
+        lua.load(TESTING_LUA)
+            .set_name("=testing")
+            .call::<Table>(native)
+    })?;
+    preload.set("testing", loader)
+}
+
+// Under test, records the call and answers with the mock instead of running
+// the binding: a function is called with the same arguments, any other value
+// is returned as is. Bindings without a mock fail.
+pub async fn intercept(lua: &Lua, name: &str, args: impl IntoLuaMulti) -> Result<Option<MultiValue>> {
+    if !TrahlRuntimeCtx::get_ref(lua).is_ok_and(|ctx| ctx.testing) {
+        return Ok(None);
+    }
+
+    // Kept with their count so nil arguments in between survive
+    let args = args.into_lua_multi(lua)?;
+    let call = lua.create_table()?;
+    for (i, arg) in args.iter().enumerate() {
+        call.raw_set(i + 1, arg.clone())?;
+    }
+    call.set("n", args.len())?;
+
+    let calls: Table = lua.named_registry_value(CALLS)?;
+    let list = match calls.get::<Option<Table>>(name)? {
+        Some(list) => list,
+        None => {
+            let list = lua.create_table()?;
+            calls.set(name, &list)?;
+            list
+        }
+    };
+    list.push(call)?;
+
+    let mocks: Table = lua.named_registry_value(MOCKS)?;
+    match mocks.get::<Value>(name)? {
+        Value::Function(mock) => mock.call_async(args).await.map(Some),
+        Value::Nil => Err(Error::runtime(format!("{} is not mocked", name))),
+        value => Ok(Some(MultiValue::from_iter([value]))),
+    }
+}
+
+pub fn results(lua: &Lua) -> Result<Vec<TestResult>> {
+    lua.named_registry_value::<Table>(RESULTS)?
+        .sequence_values::<Table>()
+        .map(|entry| {
+            let entry = entry?;
+            Ok(TestResult {
+                name: entry.get("name")?,
+                passed: entry.get("passed")?,
+                message: entry.get("message")?,
+            })
+        })
+        .collect()
+}
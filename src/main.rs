@@ -8,27 +8,28 @@ mod extcmd;
 mod rpc;
 mod utils;
 
-use crate::config::SystemConfig;
-use crate::args::parse_args;
+use crate::config::{LogConfig, SystemConfig};
+use crate::args::{parse_args, Command};
 use crate::logs::init_logging;
 use crate::master::master_thread;
-use crate::worker::worker_thread;
+use crate::worker::{run_script, test_scripts, worker_thread};
 
 use std::io::Error;
 use tracing::{info, error};
 use signal_hook::iterator::Signals;
 use signal_hook::consts::signal::{SIGINT, SIGTERM, SIGHUP};
 use std::sync::{Arc, OnceLock, RwLock};
-use std::sync::atomic::AtomicBool;
+use std::sync::atomic::{AtomicBool, AtomicU64};
 use std::sync::atomic::Ordering;
 use std::thread;
 
 pub static CONFIG: OnceLock<Arc<RwLock<SystemConfig>>> = OnceLock::new();
 pub static S_TERMINATE: OnceLock<Arc<AtomicBool>> = OnceLock::new();
-pub static S_RELOAD: OnceLock<Arc<AtomicBool>> = OnceLock::new();
+// Incremented on every SIGHUP, master and worker each track the last value they handled
+pub static S_RELOAD: OnceLock<Arc<AtomicU64>> = OnceLock::new();
 
 fn main() -> Result<(), Error> {
-    let args = match parse_args() {
+    let mut args = match parse_args() {
         Ok(args) => args,
         Err(e) => {
             eprintln!("Failed to parse arguments: {}", e);
@@ -53,10 +54,20 @@ fn main() -> Result<(), Error> {
         std::process::exit(0);
     }
 
+    // Commands print their own output, the log only carries warnings
+    if let Some(command) = args.command.take() {
+        let _guard = init_logging(&LogConfig { level: "warn".to_string(), file: None });
+        let code = match command {
+            Command::RunScript(run_args) => run_script(run_args),
+            Command::TestScripts(test_args) => test_scripts(test_args),
+        };
+        std::process::exit(code);
+    }
+
     let _guard = init_logging(&config_ref.read().unwrap().log);
 
     S_TERMINATE.set(Arc::new(AtomicBool::new(false))).unwrap();
-    S_RELOAD.set(Arc::new(AtomicBool::new(false))).unwrap();
+    S_RELOAD.set(Arc::new(AtomicU64::new(0))).unwrap();
 
     let config_clone = CONFIG.get().unwrap().clone();
     let mut signals = Signals::new(&[SIGHUP, SIGINT, SIGTERM])?;
@@ -80,8 +91,8 @@ fn main() -> Result<(), Error> {
                     info!("Configuration reloaded");
                 }
 
-                if let Some(flag) = S_RELOAD.get() {
-                    flag.store(true, Ordering::Relaxed);
+                if let Some(generation) = S_RELOAD.get() {
+                    generation.fetch_add(1, Ordering::Relaxed);
                 }
             }
         }
@@ -0,0 +1,303 @@
+use anyhow::{Result, anyhow};
+use argon2::{
+    Argon2,
+    password_hash::{PasswordHash, PasswordHasher, PasswordVerifier, SaltString},
+};
+use chrono::{Duration, Utc};
+use rand::RngCore;
+use serde::Serialize;
+use sha2::{Digest, Sha256};
+use tokio::task;
+use tracing::{info, warn};
+
+use super::db::DB;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Role {
+    Admin,
+    ReadOnly,
+}
+
+impl Role {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Role::Admin => "admin",
+            Role::ReadOnly => "readonly",
+        }
+    }
+
+    pub fn parse(s: &str) -> Option<Self> {
+        match s {
+            "admin" => Some(Role::Admin),
+            "readonly" => Some(Role::ReadOnly),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct AuthUser {
+    pub id: i64,
+    pub username: String,
+    pub role: Role,
+}
+
+pub fn random_token() -> String {
+    let mut bytes = [0u8; 32];
+    rand::rng().fill_bytes(&mut bytes);
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+pub fn hash_token(token: &str) -> String {
+    let digest = Sha256::digest(token.as_bytes());
+    digest.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+pub async fn hash_password(password: String) -> Result<String> {
+    task::spawn_blocking(move || {
+        let mut salt = [0u8; 16];
+        rand::rng().fill_bytes(&mut salt);
+        let salt = SaltString::encode_b64(&salt).map_err(|e| anyhow!("{}", e))?;
+        let hash = Argon2::default()
+            .hash_password(password.as_bytes(), &salt)
+            .map_err(|e| anyhow!("{}", e))?;
+        Ok(hash.to_string())
+    })
+    .await?
+}
+
+pub async fn verify_password(password: String, hash: String) -> bool {
+    task::spawn_blocking(move || {
+        PasswordHash::new(&hash)
+            .map(|parsed| Argon2::default()
+                .verify_password(password.as_bytes(), &parsed)
+                .is_ok())
+            .unwrap_or(false)
+    })
+    .await
+    .unwrap_or(false)
+}
+
+// Creates the initial admin account when the users table is empty.
+// Without a configured password a random one is generated and logged once.
+pub async fn ensure_admin_user(password: Option<String>) {
+    let pool = DB.get().unwrap();
+
+    let users = sqlx::query_scalar!("SELECT COUNT(*) FROM users")
+        .fetch_one(pool)
+        .await
+        .expect("Failed to count users");
+
+    if users > 0 {
+        return;
+    }
+
+    let generated = password.is_none();
+    let password = password.unwrap_or_else(|| random_token()[..16].to_string());
+    let hash = hash_password(password.clone())
+        .await
+        .expect("Failed to hash admin password");
+    let role = Role::Admin.as_str();
+
+    sqlx::query!(
+        r#"
+        INSERT INTO users (username, password_hash, role)
+        VALUES ('admin', ?, ?)
+        "#,
+        hash,
+        role
+    )
+    .execute(pool)
+    .await
+    .expect("Failed to create admin user");
+
+    if generated {
+        warn!("Created user 'admin' with password '{}', change it with POST /api/v1/me/password", password);
+    } else {
+        info!("Created user 'admin' with the configured admin_password");
+    }
+}
+
+pub async fn login(username: &str, password: &str, ttl_hours: u32) -> Result<Option<String>> {
+    let pool = DB.get().unwrap();
+
+    let user = sqlx::query!(
+        "SELECT id, password_hash FROM users WHERE username = ?",
+        username
+    )
+    .fetch_optional(pool)
+    .await?;
+
+    let Some(user) = user else {
+        return Ok(None);
+    };
+
+    if !verify_password(password.to_string(), user.password_hash).await {
+        return Ok(None);
+    }
+
+    let token = random_token();
+    let token_hash = hash_token(&token);
+    let expires_at = Utc::now() + Duration::hours(ttl_hours.into());
+
+    sqlx::query!(
+        r#"
+        INSERT INTO sessions (token_hash, user_id, expires_at)
+        VALUES (?, ?, ?)
+        "#,
+        token_hash,
+        user.id,
+        expires_at
+    )
+    .execute(pool)
+    .await?;
+
+    Ok(Some(token))
+}
+
+// Returns false when the current password is wrong. Other sessions of the
+// user are closed, the one in keep_session stays open.
+pub async fn change_password(
+    user_id: i64,
+    current: &str,
+    new: &str,
+    keep_session: Option<&str>,
+) -> Result<bool> {
+    let pool = DB.get().unwrap();
+
+    let hash = sqlx::query_scalar!("SELECT password_hash FROM users WHERE id = ?", user_id)
+        .fetch_one(pool)
+        .await?;
+    if !verify_password(current.to_string(), hash).await {
+        return Ok(false);
+    }
+
+    let new_hash = hash_password(new.to_string()).await?;
+    let keep_hash = keep_session.map(hash_token);
+    let mut tx = pool.begin().await?;
+    sqlx::query!("UPDATE users SET password_hash = ? WHERE id = ?", new_hash, user_id)
+        .execute(&mut *tx)
+        .await?;
+    sqlx::query!(
+        "DELETE FROM sessions WHERE user_id = ? AND token_hash IS NOT ?",
+        user_id,
+        keep_hash
+    )
+    .execute(&mut *tx)
+    .await?;
+    tx.commit().await?;
+
+    Ok(true)
+}
+
+pub async fn logout(session_token: &str) -> Result<()> {
+    let pool = DB.get().unwrap();
+    let token_hash = hash_token(session_token);
+
+    sqlx::query!("DELETE FROM sessions WHERE token_hash = ?", token_hash)
+        .execute(pool)
+        .await?;
+
+    Ok(())
+}
+
+pub async fn user_from_session(session_token: &str) -> Result<Option<AuthUser>> {
+    let pool = DB.get().unwrap();
+    let token_hash = hash_token(session_token);
+    let now = Utc::now();
+
+    let row = sqlx::query!(
+        r#"
+        SELECT users.id AS "id!", users.username, users.role
+        FROM sessions
+        JOIN users ON users.id = sessions.user_id
+        WHERE sessions.token_hash = ?
+        AND sessions.expires_at > ?
+        "#,
+        token_hash,
+        now
+    )
+    .fetch_optional(pool)
+    .await?;
+
+    Ok(row.and_then(|r| Role::parse(&r.role).map(|role| AuthUser {
+        id: r.id,
+        username: r.username,
+        role,
+    })))
+}
+
+pub async fn user_from_api_token(token: &str) -> Result<Option<AuthUser>> {
+    let pool = DB.get().unwrap();
+    let token_hash = hash_token(token);
+    let now = Utc::now();
+
+    let row = sqlx::query!(
+        r#"
+        UPDATE api_tokens
+        SET last_used_at = ?
+        WHERE token_hash = ?
+        RETURNING user_id
+        "#,
+        now,
+        token_hash
+    )
+    .fetch_optional(pool)
+    .await?;
+
+    let Some(row) = row else {
+        return Ok(None);
+    };
+
+    let user = sqlx::query!(
+        "SELECT id, username, role FROM users WHERE id = ?",
+        row.user_id
+    )
+    .fetch_optional(pool)
+    .await?;
+
+    Ok(user.and_then(|r| Role::parse(&r.role).map(|role| AuthUser {
+        id: r.id,
+        username: r.username,
+        role,
+    })))
+}
+
+pub async fn purge_expired_sessions() -> Result<u64> {
+    let pool = DB.get().unwrap();
+    let now = Utc::now();
+
+    let deleted = sqlx::query!("DELETE FROM sessions WHERE expires_at <= ?", now)
+        .execute(pool)
+        .await?;
+
+    Ok(deleted.rows_affected())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_password_roundtrip() {
+        let hash = hash_password("hunter2".to_string()).await.unwrap();
+        assert!(verify_password("hunter2".to_string(), hash.clone()).await);
+        assert!(!verify_password("hunter3".to_string(), hash).await);
+    }
+
+    #[test]
+    fn test_token_hash() {
+        let token = random_token();
+        assert_eq!(token.len(), 64);
+        assert_ne!(token, random_token());
+        assert_eq!(hash_token("abc"), "ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad");
+    }
+
+    #[test]
+    fn test_role_parse() {
+        assert_eq!(Role::parse("admin"), Some(Role::Admin));
+        assert_eq!(Role::parse("readonly"), Some(Role::ReadOnly));
+        assert_eq!(Role::parse("root"), None);
+    }
+}
@@ -1,5 +1,6 @@
 pub mod model;
 
+use std::collections::HashMap;
 use std::path::{Path, PathBuf};
 use std::sync::OnceLock;
 use tracing::info;
@@ -10,12 +11,20 @@ use sqlx::{
     Sqlite,
     SqlitePool,
 };
+use anyhow::{Context, Result};
 use chrono::Utc;
 use xxhash_rust::xxh3::xxh3_64;
 use crate::config::JobConfig;
+use model::{RecycleEntry, Worker};
+use crate::rpc::{JobOutput, LibraryInfo, RecycledFile};
 
 pub static DB: OnceLock<Pool<Sqlite>> = OnceLock::new();
 
+// Values of workers.status
+pub const WORKER_PENDING: &str = "pending";
+pub const WORKER_APPROVED: &str = "approved";
+pub const WORKER_REVOKED: &str = "revoked";
+
 pub async fn init_db(path: PathBuf) {
     let connstr = format!("sqlite://{}", path.to_string_lossy());
     if !Sqlite::database_exists(&connstr).await.unwrap_or(false) {
@@ -52,23 +61,26 @@ pub async fn init_db(path: PathBuf) {
     DB.set(db).expect("Failed to set global DB pool");
 }
 
-async fn load_lua_script(path: &PathBuf) -> (String, String) {
+async fn load_lua_script(path: &PathBuf) -> Result<(String, String)> {
     let content = tokio::fs::read_to_string(path)
         .await
-        .unwrap_or_else(|_| panic!("Failed to read Lua script: {}", path.display()));
+        .with_context(|| format!("Failed to read Lua script: {}", path.display()))?;
 
     // Compute fast 64-bit xxHash and convert to hex
     let hash = format!("{:016x}", xxh3_64(content.as_bytes()));
 
-    (content, hash)
+    Ok((content, hash))
 }
 
-pub async fn merge_libs_config(configs: &[JobConfig]) {
+// Syncs the configured libraries into the database and returns the ids of
+// the enabled libraries that are new or whose path, script or variables changed
+pub async fn merge_libs_config(configs: &[JobConfig]) -> Result<Vec<i64>> {
     let pool = DB.get().unwrap();
     let config_names: Vec<&str> = configs.iter().map(|c| c.name.as_str()).collect();
+    let mut changed = Vec::new();
 
     for cfg in configs {
-        let (script_contents, script_hash) = load_lua_script(&cfg.lua_script).await;
+        let (script_contents, script_hash) = load_lua_script(&cfg.lua_script).await?;
         let script_source = format!("file://{}", cfg.lua_script.display());
         let script_name = cfg
             .lua_script
@@ -76,47 +88,30 @@ pub async fn merge_libs_config(configs: &[JobConfig]) {
             .and_then(|s| s.to_str())
             .unwrap_or("unknown");
 
-        // Check if script exists with same hash
+        // A file back to an earlier content reuses that version
         let existing_script = sqlx::query!(
             r#"
-            SELECT id, hash FROM script
-            WHERE name = ? AND source = ?
+            SELECT id AS "id!" FROM script
+            WHERE name = ? AND source = ? AND hash = ?
             "#,
             script_name,
-            script_source
+            script_source,
+            script_hash
         )
         .fetch_optional(pool)
-        .await
-        .expect("Failed to query existing script");
+        .await?;
 
         let script_id: i64 = match existing_script {
-            Some(row) if row.hash == script_hash => {
-                // Script unchanged — skip update
-                row.id.expect("ID is never null")
-            }
-            Some(row) => {
-                // Script changed, update
-                sqlx::query!(
-                    r#"
-                    UPDATE script
-                    SET script = ?, hash = ?, updated_at = CURRENT_TIMESTAMP
-                    WHERE id = ?
-                    "#,
-                    script_contents,
-                    script_hash,
-                    row.id
-                )
-                .execute(pool)
-                .await
-                .expect("Failed to update script");
-                row.id.expect("ID is never null")
-            }
+            Some(row) => row.id,
             None => {
-                // Script doesnt exist, insert new one
-                sqlx::query!(
+                // Script changed or new, older versions are kept
+                let inserted = sqlx::query!(
                     r#"
-                    INSERT INTO script (name, hash, script, source)
-                    VALUES (?, ?, ?, ?)
+                    INSERT INTO script (name, hash, script, source, version)
+                    VALUES (?1, ?2, ?3, ?4, (
+                        SELECT COALESCE(MAX(version), 0) + 1 FROM script
+                        WHERE name = ?1 AND source = ?4
+                    ))
                     "#,
                     script_name,
                     script_hash,
@@ -124,50 +119,95 @@ pub async fn merge_libs_config(configs: &[JobConfig]) {
                     script_source,
                 )
                 .execute(pool)
-                .await
-                .expect("Failed to insert new script")
-                .last_insert_rowid()
+                .await?
+                .last_insert_rowid();
+                info!("Stored a new version of script '{}'", script_name);
+                inserted
             }
         };
 
         // Upsert library
         let enabled_int = if cfg.enabled { 1 } else { 0 };
+        let trusted_int = if cfg.trusted { 1 } else { 0 };
+        let dry_run_int = if cfg.dry_run { 1 } else { 0 };
         let now = Utc::now();
         let dest_str = cfg.destination_path.to_string_lossy().to_string();
         let src_str = cfg.source_path.to_string_lossy().to_string();
+        let recycle_dir = cfg.recycle_dir.as_ref().map(|p| p.to_string_lossy().to_string());
+        let recycle_days = cfg.recycle_days.map(i64::from);
+        let recycle_max_bytes = cfg.recycle_max_gb.map(|gb| (gb * 1024 * 1024 * 1024) as i64);
+
+        let existing_library = sqlx::query!(
+            r#"
+            SELECT path, destination, enabled, script_id, trusted, dry_run FROM library
+            WHERE name = ? AND source = 'conf'
+            "#,
+            cfg.name
+        )
+        .fetch_optional(pool)
+        .await?;
+
+        let leaves_dry_run = existing_library
+            .as_ref()
+            .is_some_and(|row| row.dry_run != 0 && !cfg.dry_run);
+
+        let mut library_changed = existing_library.is_none_or(|row| {
+            row.path != src_str
+                || row.destination != dest_str
+                || row.enabled != enabled_int
+                || row.script_id != script_id
+                || row.trusted != trusted_int
+                || row.dry_run != dry_run_int
+        });
 
         let updated = sqlx::query!(
             r#"
             UPDATE library
-            SET path = ?, destination = ?, enabled = ?, script_id = ?, last_scanned_at = ?
+            SET path = ?, destination = ?, enabled = ?, script_id = ?, trusted = ?, last_scanned_at = ?,
+                recycle_dir = ?, recycle_days = ?, recycle_max_bytes = ?, dry_run = ?,
+                pinned_script_id = CASE
+                    WHEN pinned_script_id IN (SELECT id FROM script WHERE name = ? AND source = ?)
+                    THEN pinned_script_id
+                END
             WHERE name = ? AND source = 'conf'
             "#,
             src_str,
             dest_str,
             enabled_int,
             script_id,
+            trusted_int,
             now,
+            recycle_dir,
+            recycle_days,
+            recycle_max_bytes,
+            dry_run_int,
+            script_name,
+            script_source,
             cfg.name
         )
         .execute(pool)
-        .await
-        .expect("Failed to update library");
+        .await?;
 
         let library_id: i64 = if updated.rows_affected() == 0 {
             sqlx::query!(
                 r#"
-                INSERT INTO library (name, source, destination, enabled, path, script_id)
-                VALUES (?, 'conf', ?, ?, ?, ?)
+                INSERT INTO library (name, source, destination, enabled, path, script_id, trusted,
+                    recycle_dir, recycle_days, recycle_max_bytes, dry_run)
+                VALUES (?, 'conf', ?, ?, ?, ?, ?, ?, ?, ?, ?)
                 "#,
                 cfg.name,
                 dest_str,
                 enabled_int,
                 src_str,
-                script_id
+                script_id,
+                trusted_int,
+                recycle_dir,
+                recycle_days,
+                recycle_max_bytes,
+                dry_run_int
             )
             .execute(pool)
-            .await
-            .expect("Failed to insert library")
+            .await?
             .last_insert_rowid()
         } else {
             sqlx::query!(
@@ -175,16 +215,51 @@ pub async fn merge_libs_config(configs: &[JobConfig]) {
                 cfg.name
             )
             .fetch_one(pool)
-            .await
-            .expect("Failed to fetch library ID")
+            .await?
             .id.expect("ID is never null")
         };
 
+        let old_variables: HashMap<String, String> = sqlx::query!(
+            "SELECT key, value FROM variables WHERE library_id = ?",
+            library_id
+        )
+        .fetch_all(pool)
+        .await?
+        .into_iter()
+        .filter_map(|row| row.value.map(|value| (row.key, value)))
+        .collect();
+        library_changed |= old_variables != cfg.variables;
+
+        // Files only dry-run so far get processed for real, except those that
+        // already have a queued job since a file has at most one
+        if leaves_dry_run {
+            let requeued = sqlx::query!(
+                r#"
+                UPDATE job
+                SET status = 'queued',
+                    output_file = NULL,
+                    output_size = NULL,
+                    started_at = NULL,
+                    finished_at = NULL
+                WHERE status = 'dry_run'
+                AND file_id IN (SELECT id FROM file_entry WHERE library_id = ?)
+                AND NOT EXISTS (
+                    SELECT 1 FROM job j2
+                    WHERE j2.file_id = job.file_id
+                    AND j2.status = 'queued'
+                )
+                "#,
+                library_id
+            )
+            .execute(pool)
+            .await?;
+            info!("Library '{}' left dry run, requeued {} jobs", cfg.name, requeued.rows_affected());
+        }
+
         // Replace variables
         sqlx::query!("DELETE FROM variables WHERE library_id = ?", library_id)
             .execute(pool)
-            .await
-            .expect("Failed to clear old variables");
+            .await?;
 
         for (key, value) in &cfg.variables {
             sqlx::query!(
@@ -197,15 +272,18 @@ pub async fn merge_libs_config(configs: &[JobConfig]) {
                 library_id
             )
             .execute(pool)
-            .await
-            .expect("Failed to insert variable");
+            .await?;
+        }
+
+        if library_changed && cfg.enabled {
+            changed.push(library_id);
         }
 
         info!("Synced library '{}' (script: '{}')", cfg.name, script_name);
     }
 
     // Disable libraries missing from config
-    let json_names = serde_json::to_string(&config_names).unwrap();
+    let json_names = serde_json::to_string(&config_names)?;
     let disabled = sqlx::query!(
         r#"
         UPDATE library
@@ -218,15 +296,16 @@ pub async fn merge_libs_config(configs: &[JobConfig]) {
         json_names
     )
     .execute(pool)
-    .await
-    .expect("Failed to disable missing libraries");
+    .await?;
 
     if disabled.rows_affected() > 0 {
         info!("Disabled {} libraries not in config.", disabled.rows_affected());
     }
+
+    Ok(changed)
 }
 
-pub async fn upsert_worker(identifier: &str) {
+pub async fn upsert_worker(identifier: &str) -> i64 {
     let pool = DB.get().unwrap();
     let now = Utc::now();
 
@@ -235,23 +314,271 @@ pub async fn upsert_worker(identifier: &str) {
         UPDATE workers
         SET last_conn_at = ?
         WHERE identifier = ?
+        RETURNING id
         "#,
         now,
         identifier
-    ).execute(pool)
+    ).fetch_optional(pool)
     .await
     .expect("Failed to update table workers");
 
-    if updated.rows_affected() == 0 {
+    match updated {
+        Some(row) => row.id.expect("ID is never null"),
+        None => {
+            sqlx::query!(
+                r#"
+                INSERT INTO workers (identifier, last_conn_at)
+                VALUES (?, ?)
+                "#,
+                identifier, now
+            )
+            .execute(pool)
+            .await
+            .expect("Failed to insert into table workers")
+            .last_insert_rowid()
+        }
+    }
+}
+
+pub async fn find_worker(identifier: &str) -> Result<Option<Worker>, sqlx::Error> {
+    let pool = DB.get().unwrap();
+
+    sqlx::query_as!(
+        Worker,
+        r#"
+        SELECT id AS "id!", identifier, last_conn_at, status, auth_token
+        FROM workers
+        WHERE identifier = ?
+        "#,
+        identifier
+    )
+    .fetch_optional(pool)
+    .await
+}
+
+pub async fn list_workers() -> Result<Vec<Worker>, sqlx::Error> {
+    let pool = DB.get().unwrap();
+
+    sqlx::query_as!(
+        Worker,
+        r#"
+        SELECT id AS "id!", identifier, last_conn_at, status, auth_token
+        FROM workers
+        ORDER BY identifier
+        "#
+    )
+    .fetch_all(pool)
+    .await
+}
+
+pub async fn register_worker(identifier: &str, status: &str) -> Result<i64, sqlx::Error> {
+    let pool = DB.get().unwrap();
+
+    let id = sqlx::query!(
+        "INSERT INTO workers (identifier, status) VALUES (?, ?)",
+        identifier,
+        status
+    )
+    .execute(pool)
+    .await?
+    .last_insert_rowid();
+
+    Ok(id)
+}
+
+// Returns the worker identifier, or None if the id doesn't exist
+pub async fn set_worker_status(id: i64, status: &str) -> Result<Option<String>, sqlx::Error> {
+    let pool = DB.get().unwrap();
+
+    let row = sqlx::query!(
+        "UPDATE workers SET status = ? WHERE id = ? RETURNING identifier",
+        status,
+        id
+    )
+    .fetch_optional(pool)
+    .await?;
+
+    Ok(row.map(|r| r.identifier))
+}
+
+pub async fn set_worker_token(id: i64, token: Option<&str>) -> Result<bool, sqlx::Error> {
+    let pool = DB.get().unwrap();
+
+    let updated = sqlx::query!(
+        "UPDATE workers SET auth_token = ? WHERE id = ?",
+        token,
+        id
+    )
+    .execute(pool)
+    .await?;
+
+    Ok(updated.rows_affected() > 0)
+}
+
+pub async fn library_infos() -> Result<Vec<LibraryInfo>> {
+    let pool = DB.get().unwrap();
+
+    let rows = sqlx::query!(
+        r#"
+        SELECT library.id AS "id!", library.name, library.enabled, script.hash
+        FROM library
+        JOIN script ON script.id = COALESCE(library.pinned_script_id, library.script_id)
+        ORDER BY library.id
+        "#
+    )
+    .fetch_all(pool)
+    .await?;
+
+    Ok(rows
+        .into_iter()
+        .map(|r| LibraryInfo {
+            id: r.id,
+            name: r.name,
+            enabled: r.enabled != 0,
+            script_hash: r.hash,
+        })
+        .collect())
+}
+
+// Replaces the outputs of a previous run, the first one is the main output
+pub async fn record_job_outputs(job_id: i64, outputs: &[JobOutput]) -> Result<()> {
+    let pool = DB.get().unwrap();
+    let mut tx = pool.begin().await?;
+
+    sqlx::query!("DELETE FROM job_output WHERE job_id = ?", job_id)
+        .execute(&mut *tx)
+        .await?;
+
+    for output in outputs {
+        let size = output.size.map(|s| s as i64);
+        sqlx::query!(
+            "INSERT INTO job_output (job_id, path, role, size) VALUES (?, ?, ?, ?)",
+            job_id,
+            output.path,
+            output.role,
+            size
+        )
+        .execute(&mut *tx)
+        .await?;
+    }
+
+    if let Some(main) = outputs.first() {
+        let size = main.size.map(|s| s as i64);
+        sqlx::query!(
+            "UPDATE job SET output_file = ?, output_size = ? WHERE id = ?",
+            main.path,
+            size,
+            job_id
+        )
+        .execute(&mut *tx)
+        .await?;
+    }
+
+    tx.commit().await?;
+    Ok(())
+}
+
+pub async fn record_recycled(job_id: i64, files: &[RecycledFile]) -> Result<()> {
+    let pool = DB.get().unwrap();
+    let mut tx = pool.begin().await?;
+
+    for file in files {
+        let size = file.size.map(|s| s as i64);
         sqlx::query!(
             r#"
-            INSERT INTO workers (identifier, last_conn_at)
-            VALUES (?, ?)
+            INSERT INTO recycle_entry (library_id, job_id, original_path, recycled_path, size)
+            SELECT file_entry.library_id, job.id, ?, ?, ?
+            FROM job
+            JOIN file_entry ON file_entry.id = job.file_id
+            WHERE job.id = ?
             "#,
-            identifier, now
+            file.original,
+            file.recycled,
+            size,
+            job_id
         )
-        .execute(pool)
-        .await
-        .expect("Failed to insert into table workers");
+        .execute(&mut *tx)
+        .await?;
+    }
+
+    tx.commit().await?;
+    Ok(())
+}
+
+pub async fn list_recycled(library_id: Option<i64>) -> Result<Vec<RecycleEntry>, sqlx::Error> {
+    let pool = DB.get().unwrap();
+
+    sqlx::query_as!(
+        RecycleEntry,
+        r#"
+        SELECT id AS "id!", library_id, job_id, original_path, recycled_path, size, created_at
+        FROM recycle_entry
+        WHERE ?1 IS NULL OR library_id = ?1
+        ORDER BY created_at DESC, id DESC
+        "#,
+        library_id
+    )
+    .fetch_all(pool)
+    .await
+}
+
+pub async fn find_recycled(id: i64) -> Result<Option<RecycleEntry>, sqlx::Error> {
+    let pool = DB.get().unwrap();
+
+    sqlx::query_as!(
+        RecycleEntry,
+        "SELECT * FROM recycle_entry WHERE id = ?",
+        id
+    )
+    .fetch_optional(pool)
+    .await
+}
+
+// The restored file takes the place of the output in the library, its entry gets
+// the new size and hash without a job, and the job that replaced it is flagged
+pub async fn record_restored(entry: &RecycleEntry, size: i64, hash: &str) -> Result<()> {
+    let pool = DB.get().unwrap();
+    let mut tx = pool.begin().await?;
+
+    let root = sqlx::query_scalar!("SELECT path FROM library WHERE id = ?", entry.library_id)
+        .fetch_one(&mut *tx)
+        .await?;
+    if let Ok(relative) = Path::new(&entry.original_path).strip_prefix(&root) {
+        let file_path = relative.to_string_lossy();
+        sqlx::query!(
+            r#"
+            INSERT INTO file_entry (library_id, file_path, file_size, hash)
+            VALUES (?, ?, ?, ?)
+            ON CONFLICT(library_id, file_path) DO UPDATE
+            SET file_size = excluded.file_size,
+                hash = excluded.hash
+            "#,
+            entry.library_id,
+            file_path,
+            size,
+            hash
+        )
+        .execute(&mut *tx)
+        .await?;
     }
+
+    sqlx::query!("UPDATE job SET restored_at = CURRENT_TIMESTAMP WHERE id = ?", entry.job_id)
+        .execute(&mut *tx)
+        .await?;
+
+    sqlx::query!("DELETE FROM recycle_entry WHERE id = ?", entry.id)
+        .execute(&mut *tx)
+        .await?;
+
+    tx.commit().await?;
+    Ok(())
+}
+
+pub async fn delete_recycled(id: i64) -> Result<(), sqlx::Error> {
+    let pool = DB.get().unwrap();
+
+    sqlx::query!("DELETE FROM recycle_entry WHERE id = ?", id)
+        .execute(pool)
+        .await?;
+    Ok(())
 }
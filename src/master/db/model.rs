@@ -1,14 +1,18 @@
 use chrono::NaiveDateTime;
+use serde::Serialize;
 use sqlx::FromRow;
 
-#[derive(Debug, Clone, FromRow)]
+#[derive(Debug, Clone, FromRow, Serialize)]
 pub struct Worker {
     pub id: i64,
     pub identifier: String,
     pub last_conn_at: Option<NaiveDateTime>,
+    pub status: String,
+    #[serde(skip)]
+    pub auth_token: Option<String>,
 }
 
-#[derive(Debug, Clone, FromRow)]
+#[derive(Debug, Clone, FromRow, Serialize)]
 pub struct Script {
     pub id: i64,
     pub name: String,
@@ -17,9 +21,10 @@ pub struct Script {
     pub source: String,
     pub description: Option<String>,
     pub updated_at: Option<NaiveDateTime>,
+    pub version: i64,
 }
 
-#[derive(Debug, Clone, FromRow)]
+#[derive(Debug, Clone, FromRow, Serialize)]
 pub struct Library {
     pub id: i64,
     pub name: String,
@@ -29,6 +34,23 @@ pub struct Library {
     pub destination: String,
     pub script_id: i64,
     pub last_scanned_at: Option<NaiveDateTime>,
+    pub trusted: i64,
+    pub recycle_dir: Option<String>,
+    pub recycle_days: Option<i64>,
+    pub recycle_max_bytes: Option<i64>,
+    pub dry_run: i64,
+    pub pinned_script_id: Option<i64>,
+}
+
+#[derive(Debug, Clone, FromRow, Serialize)]
+pub struct RecycleEntry {
+    pub id: i64,
+    pub library_id: i64,
+    pub job_id: Option<i64>,
+    pub original_path: String,
+    pub recycled_path: String,
+    pub size: Option<i64>,
+    pub created_at: NaiveDateTime,
 }
 
 #[derive(Debug, Clone, FromRow)]
@@ -61,4 +83,7 @@ pub struct Job {
     pub created_at: NaiveDateTime,
     pub started_at: Option<NaiveDateTime>,
     pub finished_at: Option<NaiveDateTime>,
+    pub priority: i64,
+    pub script_id: Option<i64>,
+    pub restored_at: Option<NaiveDateTime>,
 }
@@ -188,6 +188,10 @@ async fn scan_folder(pool: &Pool<Sqlite>, library: &Library, path_override: Opti
         let path = entry.path();
 
         if path.is_dir() {
+            // A recycle bin inside the library holds originals that were already processed
+            if library.recycle_dir.as_deref().is_some_and(|r| path.starts_with(r)) {
+                continue;
+            }
             debug!("Entering subdirectory {}", path.strip_prefix(&library.path).unwrap_or(&path).display());
             num_files += Box::pin(scan_folder(pool, &library, Some(&path))).await?;
             continue;
@@ -206,16 +210,20 @@ async fn scan_folder(pool: &Pool<Sqlite>, library: &Library, path_override: Opti
 
         let exists = sqlx::query_scalar::<_, i64>(
             r#"
-            SELECT file_entry.id
-            FROM file_entry
-            LEFT JOIN job ON job.output_file = file_entry.file_path
-            WHERE file_entry.file_path = ?
-            OR job.output_file = ?
-            LIMIT 1
+            SELECT 1
+            WHERE EXISTS (
+                SELECT file_entry.id
+                FROM file_entry
+                LEFT JOIN job ON job.output_file = file_entry.file_path
+                WHERE file_entry.file_path = ?
+                OR job.output_file = ?
+            )
+            OR EXISTS (SELECT 1 FROM job_output WHERE job_output.path = ?)
             "#
         )
         .bind(&file_path)
         .bind(&file_path)
+        .bind(path.to_string_lossy())
         .fetch_optional(pool)
         .await?
         .is_some();
@@ -1,8 +1,10 @@
+pub mod commands;
 pub mod events;
 
 use std::collections::HashMap;
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
 use std::sync::Arc;
+use tokio::io::AsyncWriteExt;
 use tokio::time::{interval, Duration};
 use tokio::sync::{broadcast, mpsc};
 use tracing::{
@@ -21,10 +23,12 @@ use sqlx::{
 use super::MasterCtx;
 use crate::master::peers::{PeerId, RxManagerMsg};
 use crate::rpc::WorkerInfo;
+use crate::utils;
 use super::socket_server::SocketEvent;
 use super::peers::TxManagerMsg;
 use crate::rpc::JobStatus as RpcJobStatus;
-use crate::rpc::{JobMsg, Message};
+use crate::rpc::{ConfigUpdateMsg, ToolInfo, FEATURE_CANCEL_JOB, FEATURE_CONFIG_UPDATE, FEATURE_DRY_RUN, FEATURE_TRUSTED_JOBS, JobMsg, Message};
+use commands::{ActiveJob, ManagerCommand, WorkerSummary};
 use events::ManagerEvent;
 use super::db::{
     self,
@@ -46,7 +50,9 @@ static JOB_LOCK: OnceLock<Mutex<()>> = OnceLock::new();
 struct PeerInfo {
     tx: mpsc::Sender<RxManagerMsg>, // To send message to peer
     info: WorkerInfo,
-    jobs: HashMap<i64, JobTracking>
+    db_id: i64,
+    jobs: HashMap<i64, JobTracking>,
+    tools: Vec<ToolInfo>,
 }
 
 impl PeerInfo {
@@ -59,6 +65,40 @@ impl PeerInfo {
             ))
             .collect()
     }
+
+    fn summary(&self) -> WorkerSummary {
+        let active_jobs = self.active_jobs()
+            .iter()
+            .map(|j| {
+                let milestone = j.events.iter().rev().find_map(|e| match e {
+                    RpcJobStatus::Milestone(m) => Some(m.clone()),
+                    _ => None,
+                });
+                let progress = j.events.iter().rev().find_map(|e| match e {
+                    RpcJobStatus::Progress(p) => Some(p),
+                    _ => None,
+                });
+                ActiveJob {
+                    job_id: j.contract.id,
+                    file: j.contract.src_file.to_string_lossy().into_owned(),
+                    status: j.status.as_str().to_string(),
+                    milestone,
+                    percentage: None,
+                    eta_secs: None,
+                }.with_progress(progress)
+            })
+            .collect();
+
+        WorkerSummary {
+            identifier: self.info.identifier.clone(),
+            simultaneous_jobs: self.info.simultaneous_jobs,
+            sw_version: self.info.sw_version.clone(),
+            protocol_version: self.info.protocol_version,
+            features: self.info.features.clone(),
+            tools: self.tools.clone(),
+            active_jobs,
+        }
+    }
 }
 
 enum JobStatus {
@@ -67,10 +107,21 @@ enum JobStatus {
     Ended,
 }
 
+impl JobStatus {
+    fn as_str(&self) -> &'static str {
+        match self {
+            JobStatus::Sent => "sent",
+            JobStatus::Running => "running",
+            JobStatus::Ended => "ended",
+        }
+    }
+}
+
 struct JobTracking {
     contract: JobContract,
     events: Vec<RpcJobStatus>,
-    status: JobStatus
+    status: JobStatus,
+    log_path: PathBuf,
 }
 
 #[derive(Clone)]
@@ -81,10 +132,11 @@ pub struct JobContract {
     vars: HashMap<String, String>,
     script: String,
     library_root: PathBuf,
+    trusted: bool,
 }
 
 impl JobContract {
-    pub fn new(id: i64, library_root: PathBuf, src_file: PathBuf, dst_dir: PathBuf, vars: HashMap<String, String>, script: String) -> Self {
+    pub fn new(id: i64, library_root: PathBuf, src_file: PathBuf, dst_dir: PathBuf, vars: HashMap<String, String>, script: String, trusted: bool) -> Self {
         Self {
             id,
             src_file,
@@ -92,6 +144,7 @@ impl JobContract {
             vars,
             script,
             library_root,
+            trusted,
         }
     }
 }
@@ -100,60 +153,159 @@ pub struct JobManager {
     tx_events: broadcast::Sender<ManagerEvent>,
     rx_from_peer: mpsc::Receiver<TxManagerMsg>,
     rx_socket_events: mpsc::Receiver<SocketEvent>,
+    rx_commands: mpsc::Receiver<ManagerCommand>,
+    tx_fullscan: mpsc::Sender<i64>,
     peer_registry: HashMap<PeerId, PeerInfo>,
+    max_jobs: Option<u8>,
 }
 
 impl JobManager {
     pub fn new(
         rx_from_peer: mpsc::Receiver<TxManagerMsg>,
         rx_socket_events: mpsc::Receiver<SocketEvent>,
+        rx_commands: mpsc::Receiver<ManagerCommand>,
+        tx_fullscan: mpsc::Sender<i64>,
         tx_events: broadcast::Sender<ManagerEvent>,
     ) -> Self {
         Self {
             rx_from_peer,
             rx_socket_events,
+            rx_commands,
+            tx_fullscan,
             peer_registry: HashMap::new(),
+            max_jobs: None,
             tx_events,
         }
     }
 
+    // Re-syncs libraries from the reloaded configuration, rescans the ones
+    // that changed and tells the workers. Running jobs are left alone.
+    async fn reload(&mut self, ctx: &MasterCtx) {
+        let (jobs, max_jobs) = {
+            let cfg = ctx.config.read().unwrap();
+            (cfg.jobs.clone(), cfg.master.max_jobs_per_worker)
+        };
+        self.max_jobs = max_jobs;
+
+        match db::merge_libs_config(&jobs).await {
+            Ok(changed) => {
+                for lib_id in changed {
+                    info!("Library id={} changed, queueing a full scan", lib_id);
+                    let _ = self.tx_fullscan.send(lib_id).await;
+                }
+            },
+            Err(e) => {
+                error!("Failed to reload libraries: {:#}", e);
+            }
+        }
+
+        self.broadcast_config().await;
+    }
+
+    async fn broadcast_config(&self) {
+        let Some(update) = self.config_update().await else {
+            return;
+        };
+        for peer in self.peer_registry.values() {
+            if peer.info.supports(FEATURE_CONFIG_UPDATE) {
+                let _ = peer.tx.send(Message::config_update(update.clone())).await;
+            }
+        }
+    }
+
+    async fn config_update(&self) -> Option<ConfigUpdateMsg> {
+        match db::library_infos().await {
+            Ok(libraries) => Some(ConfigUpdateMsg {
+                max_jobs: self.max_jobs,
+                libraries,
+            }),
+            Err(e) => {
+                error!("Cannot build configuration update: {}", e);
+                None
+            }
+        }
+    }
+
+    fn capacity(&self, peer: &PeerInfo) -> usize {
+        let limit = match self.max_jobs {
+            Some(max) => peer.info.simultaneous_jobs.min(max),
+            None => peer.info.simultaneous_jobs,
+        };
+        limit.into()
+    }
+
     pub async fn run(mut self, ctx: Arc<MasterCtx>) {
         let mut ch_term = ctx.ch_terminate.1.clone();
         let mut ch_reload = ctx.ch_reload.1.clone();
         let mut dispatch_timer = interval(Duration::from_secs(2));
 
         let pool = db::DB.get().unwrap();
+        self.max_jobs = ctx.config.read().unwrap().master.max_jobs_per_worker;
+
+        let log_dir = {
+            ctx.config
+                .read()
+                .unwrap()
+                .master.job_log_dir
+                .clone()
+        };
+        if let Err(e) = tokio::fs::create_dir_all(&log_dir).await {
+            error!("Cannot create job log directory {}: {}", log_dir.display(), e);
+        }
 
         loop {
             tokio::select!(
                 _ = dispatch_timer.tick() => {
                     let selected_peer = self.peer_registry
-                        .iter_mut()
-                        .filter(|(_, p)| p.active_jobs().len() < p.info.simultaneous_jobs.into())
-                        .min_by_key(|(_, p)| p.active_jobs().len());
-
-                    if let Some((_id, peer)) = selected_peer {
-                        if let Ok(Some(job)) = build_job_from_db().await {
-                            let jobmsg = JobMsg {
-                                job_id: job.id,
-                                script: job.script.clone(),
-                                vars: job.vars.clone(),
-                                file: job.src_file.clone().into_os_string().to_string_lossy().into_owned(),
-                                dst_dir: job.dst_dir.clone().to_string_lossy().to_string(),
-                                library_root: job.library_root.clone().to_string_lossy().into_owned(),
-                            };
-
-                            info!("Sent job id {} to worker {}", jobmsg.job_id, peer.info.identifier);
-                            
-                            let msg = Message::job(jobmsg); 
-                            _ = peer.tx.send(msg).await;
-
-                            peer.jobs.insert(job.id, JobTracking {
-                                events: Vec::new(),
-                                status: JobStatus::Sent,
-                                contract: job,
-                            });
-                        }
+                        .iter()
+                        .filter(|(_, p)| p.active_jobs().len() < self.capacity(p))
+                        .min_by_key(|(_, p)| p.active_jobs().len())
+                        .map(|(id, _)| id.clone());
+
+                    if let Some(peer) = selected_peer.and_then(|id| self.peer_registry.get_mut(&id))
+                        && let Ok(Some(job)) = build_job_from_db(peer.info.supports(FEATURE_DRY_RUN)).await {
+                        let jobmsg = JobMsg {
+                            job_id: job.id,
+                            script: job.script.clone(),
+                            vars: job.vars.clone(),
+                            file: job.src_file.clone().into_os_string().to_string_lossy().into_owned(),
+                            dst_dir: job.dst_dir.clone().to_string_lossy().to_string(),
+                            library_root: job.library_root.clone().to_string_lossy().into_owned(),
+                        };
+
+                        info!("Sent job id {} to worker {}", jobmsg.job_id, peer.info.identifier);
+                        
+                        // Workers without the feature have no sandbox to skip
+                        let msg = if job.trusted && peer.info.supports(FEATURE_TRUSTED_JOBS) {
+                            Message::trusted_job(jobmsg)
+                        } else {
+                            Message::job(jobmsg)
+                        };
+                        _ = peer.tx.send(msg).await;
+
+                        let log_path = log_dir.join(format!("{}.log", job.id));
+                        let log_path_str = log_path.to_string_lossy().to_string();
+                        let _ = sqlx::query!(
+                            r#"
+                            UPDATE job
+                            SET worker_id = ?,
+                                log_path = ?
+                            WHERE id = ?
+                            "#,
+                            peer.db_id,
+                            log_path_str,
+                            job.id
+                        )
+                        .execute(pool)
+                        .await
+                        .inspect_err(|e| error!("Cannot assign job {}: {}", job.id, e));
+
+                        peer.jobs.insert(job.id, JobTracking {
+                            events: Vec::new(),
+                            status: JobStatus::Sent,
+                            contract: job,
+                            log_path,
+                        });
                     }
                 },
                 Some((peer_id, msg)) = self.rx_from_peer.recv() => {
@@ -166,14 +318,19 @@ impl JobManager {
                 Some(event) = self.rx_socket_events.recv() => {
                     match event {
                         SocketEvent::PeerConnected(peer_id, tx, info) => {
-                            let identifier = info.identifier.clone();
+                            let db_id = db::upsert_worker(info.identifier.as_str()).await;
+                            if info.supports(FEATURE_CONFIG_UPDATE)
+                                && let Some(update) = self.config_update().await {
+                                let _ = tx.send(Message::config_update(update)).await;
+                            }
                             let peer_info = PeerInfo {
                                 tx,
                                 info,
+                                db_id,
                                 jobs: HashMap::new(),
+                                tools: Vec::new(),
                             };
                             self.peer_registry.insert(peer_id, peer_info);
-                            db::upsert_worker(identifier.as_str()).await;
                         },
                         SocketEvent::PeerDisconnected(peer_id) => {
                             if let Some(peer) = self.peer_registry.get(&peer_id) {
@@ -199,10 +356,55 @@ impl JobManager {
                         }
                     }
                 },
+                Some(cmd) = self.rx_commands.recv() => {
+                    match cmd {
+                        ManagerCommand::ListWorkers(reply) => {
+                            let workers = self.peer_registry
+                                .values()
+                                .map(|p| p.summary())
+                                .collect();
+                            let _ = reply.send(workers);
+                        },
+                        ManagerCommand::CancelJob(job_id, reply) => {
+                            let peer = self.peer_registry
+                                .values_mut()
+                                .find(|p| p.jobs.contains_key(&job_id));
+
+                            let cancelled = match peer {
+                                Some(peer) if !peer.info.supports(FEATURE_CANCEL_JOB) => {
+                                    warn!("Worker {} cannot cancel job {}", peer.info.identifier, job_id);
+                                    false
+                                },
+                                Some(peer) => {
+                                    info!("Cancelling job {} on worker {}", job_id, peer.info.identifier);
+                                    if let Some(job) = peer.jobs.remove(&job_id) {
+                                        append_job_log(&job.log_path, msg_timestamp(), "CANCELLED", "Job cancelled").await;
+                                    }
+                                    let _ = peer.tx.send(Message::cancel_job(job_id)).await;
+                                    true
+                                },
+                                None => false,
+                            };
+                            let _ = reply.send(cancelled);
+                        },
+                        ManagerCommand::DisconnectWorker(identifier, reason) => {
+                            let peer = self.peer_registry
+                                .values()
+                                .find(|p| p.info.identifier == identifier);
+
+                            if let Some(peer) = peer {
+                                info!("Disconnecting worker {}: {}", identifier, reason);
+                                let _ = peer.tx.send(Message::reject(&reason)).await;
+                            }
+                        },
+                        ManagerCommand::LibrariesChanged => {
+                            self.broadcast_config().await;
+                        },
+                    }
+                },
                 _ = ch_reload.changed() => {
                     if *ch_reload.borrow() {
-                        //todo! send configupdate
-                        //maybe cancel all jobs and resend
+                        self.reload(&ctx).await;
                     }
                 },
                 _ = ch_term.changed() => {
@@ -229,6 +431,35 @@ async fn msg_from_peer(peer: &mut PeerInfo, msg: Message) {
                 job_tracking.events
                     .push(msg.status.clone());
 
+                let log_line = match &msg.status {
+                    RpcJobStatus::Ack => Some(("ACK", format!("Accepted by worker {}", peer.info.identifier))),
+                    RpcJobStatus::Declined(reason) => Some(("DECLINED", reason.clone())),
+                    RpcJobStatus::Log(line) => Some(("LOG", line.clone())),
+                    RpcJobStatus::Milestone(descr) => Some(("MILESTONE", descr.clone())),
+                    RpcJobStatus::Error(descr) => Some(("ERROR", descr.clone())),
+                    RpcJobStatus::Done { file } => Some(("DONE", format!("output={:?}", file))),
+                    RpcJobStatus::Copying => Some(("COPYING", "Copying output files".to_string())),
+                    RpcJobStatus::Outputs(outputs) => Some(("OUTPUTS", outputs
+                        .iter()
+                        .map(|o| format!("{}={}", o.role, o.path))
+                        .collect::<Vec<_>>()
+                        .join(", "))),
+                    RpcJobStatus::Recycled(files) => Some(("RECYCLED", files
+                        .iter()
+                        .map(|f| format!("{} -> {}", f.original, f.recycled))
+                        .collect::<Vec<_>>()
+                        .join(", "))),
+                    RpcJobStatus::DryRun(outputs) => Some(("DRYRUN", outputs
+                        .iter()
+                        .map(|o| format!("{}={} ({})", o.role, o.path, o.size.map_or("unknown size".to_string(), utils::human_size)))
+                        .collect::<Vec<_>>()
+                        .join(", "))),
+                    RpcJobStatus::Progress(_) => None,
+                };
+                if let Some((kind, line)) = log_line {
+                    append_job_log(&job_tracking.log_path, msg.timestamp, kind, &line).await;
+                }
+
                 match msg.status {
                     RpcJobStatus::Ack => {
                         debug!("Job {} ack on worker {}", msg.job_id, peer.info.identifier);
@@ -265,7 +496,8 @@ async fn msg_from_peer(peer: &mut PeerInfo, msg: Message) {
                         let _ = sqlx::query!(
                             r#"
                             UPDATE job
-                            SET status = 'failure'
+                            SET status = 'failure',
+                                finished_at = CURRENT_TIMESTAMP
                             WHERE id = ?
                             "#,
                             job_id
@@ -276,12 +508,16 @@ async fn msg_from_peer(peer: &mut PeerInfo, msg: Message) {
                     RpcJobStatus::Done { file } => {
                         info!("Job {} completed successfuly on worker {}, output={:?}", msg.job_id, peer.info.identifier, file);
                         job_tracking.status = JobStatus::Ended;
+                        // The main output, also all that workers without FEATURE_JOB_OUTPUTS report
                         let _ = sqlx::query!(
                             r#"
                             UPDATE job
-                            SET status = 'success'
+                            SET status = 'success',
+                                output_file = ?,
+                                finished_at = CURRENT_TIMESTAMP
                             WHERE id = ?
                             "#,
+                            file,
                             job_id
                         )
                         .execute(pool)
@@ -291,21 +527,107 @@ async fn msg_from_peer(peer: &mut PeerInfo, msg: Message) {
                     RpcJobStatus::Copying => {
                         info!("Job {} is copying files on worker {}", msg.job_id, peer.info.identifier);
                     },
+                    RpcJobStatus::Outputs(outputs) => {
+                        let _ = db::record_job_outputs(job_id, &outputs)
+                            .await
+                            .inspect_err(|e| error!("Cannot record outputs of job {}: {}", job_id, e));
+                    },
+                    RpcJobStatus::DryRun(outputs) => {
+                        info!("Job {} dry run completed on worker {}", msg.job_id, peer.info.identifier);
+                        job_tracking.status = JobStatus::Ended;
+                        // The would-be main output and its estimated size
+                        let main = outputs.first();
+                        let file = main.map(|o| o.path.clone());
+                        let size = main.and_then(|o| o.size).map(|s| s as i64);
+                        let _ = sqlx::query!(
+                            r#"
+                            UPDATE job
+                            SET status = 'dry_run',
+                                output_file = ?,
+                                output_size = ?,
+                                finished_at = CURRENT_TIMESTAMP
+                            WHERE id = ?
+                            "#,
+                            file,
+                            size,
+                            job_id
+                        )
+                        .execute(pool)
+                        .await;
+                    },
+                    RpcJobStatus::Recycled(files) => {
+                        let _ = db::record_recycled(job_id, &files)
+                            .await
+                            .inspect_err(|e| error!("Cannot record recycled files of job {}: {}", job_id, e));
+                    },
                 }
             } else {
                 warn!("Received updates for a unknown job: {}", msg.job_id);
             }
 
         },
+        Message::Capacity(simultaneous_jobs) => {
+            info!(
+                "Worker {} now runs up to {} jobs",
+                peer.info.identifier,
+                simultaneous_jobs
+            );
+            peer.info.simultaneous_jobs = simultaneous_jobs;
+        },
+        Message::ToolReport(tools) => {
+            for tool in &tools {
+                match (&tool.version, &tool.error) {
+                    (Some(version), _) => debug!("Worker {} {}: {}", peer.info.identifier, tool.name, version),
+                    (None, error) => warn!(
+                        "Worker {} cannot run {} at {}: {}",
+                        peer.info.identifier,
+                        tool.name,
+                        tool.path,
+                        error.as_deref().unwrap_or("unknown error")
+                    ),
+                }
+            }
+            peer.tools = tools;
+        },
         _ => {}
     }
 }
 
+fn msg_timestamp() -> u64 {
+    chrono::Utc::now().timestamp() as u64
+}
+
+async fn append_job_log(path: &Path, timestamp: u64, kind: &str, line: &str) {
+    let ts = chrono::DateTime::from_timestamp(timestamp as i64, 0)
+        .unwrap_or_default()
+        .format("%Y-%m-%d %H:%M:%S");
+    let entry = format!("[{}] {}: {}\n", ts, kind, line);
+
+    let file = tokio::fs::OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(path)
+        .await;
+
+    match file {
+        Ok(mut file) => {
+            if let Err(e) = file.write_all(entry.as_bytes()).await {
+                error!("Cannot write job log {}: {}", path.display(), e);
+            }
+        },
+        Err(e) => {
+            error!("Cannot open job log {}: {}", path.display(), e);
+        }
+    }
+}
+
 fn job_lock() -> &'static Mutex<()> {
     JOB_LOCK.get_or_init(|| Mutex::new(()))
 }
 
-async fn build_job_from_db() -> Result<Option<JobContract>> {
+// Workers without FEATURE_DRY_RUN would run dry-run jobs for real, so they
+// only get jobs of other libraries
+async fn build_job_from_db(dry_run_ok: bool) -> Result<Option<JobContract>> {
     let Ok(_guard) = job_lock().try_lock() else {
         info!("Another instance of build_job_from_db is already running");
         return Err(anyhow!("resource locked"));
@@ -316,11 +638,15 @@ async fn build_job_from_db() -> Result<Option<JobContract>> {
     let res =  sqlx::query_as!(
         Job,
         r#"
-        SELECT * FROM job
-        WHERE status = 'queued'
-        ORDER BY created_at ASC
+        SELECT job.* FROM job
+        JOIN file_entry ON file_entry.id = job.file_id
+        JOIN library ON library.id = file_entry.library_id
+        WHERE job.status = 'queued'
+        AND (? OR library.dry_run = 0)
+        ORDER BY job.priority DESC, job.created_at ASC
         LIMIT 1
-        "#
+        "#,
+        dry_run_ok
     )
     .fetch_optional(pool)
     .await?;
@@ -355,12 +681,14 @@ async fn build_job_from_db() -> Result<Option<JobContract>> {
     .fetch_one(pool)
     .await?;
 
+    // A pinned version runs instead of the current one
+    let script_id = library.pinned_script_id.unwrap_or(library.script_id);
     let script = sqlx::query_as!(
         Script,
         r#"
         SELECT * FROM script WHERE id = ?
         "#,
-        library.script_id
+        script_id
     )
     .fetch_one(pool)
     .await?;
@@ -377,20 +705,30 @@ async fn build_job_from_db() -> Result<Option<JobContract>> {
     .fetch_all(pool)
     .await?;
 
-    let variables_map: HashMap<String, String> = variables
+    let mut variables_map: HashMap<String, String> = variables
         .into_iter()
         .filter_map(|v| {
             v.value.map(|val| (v.key, val))
         }).collect();
+    variables_map.remove("RECYCLEDIR");
+    if let Some(recycle_dir) = &library.recycle_dir {
+        variables_map.insert("RECYCLEDIR".to_string(), recycle_dir.clone());
+    }
+    variables_map.remove("DRYRUN");
+    if library.dry_run != 0 {
+        variables_map.insert("DRYRUN".to_string(), "1".to_string());
+    }
 
 
     sqlx::query!(
         r#"
         UPDATE job
         SET status = 'processing',
-            started_at = CURRENT_TIMESTAMP
+            started_at = CURRENT_TIMESTAMP,
+            script_id = ?
         WHERE id = ?
         "#,
+        script.id,
         job.id
     )
     .execute(pool)
@@ -406,6 +744,7 @@ async fn build_job_from_db() -> Result<Option<JobContract>> {
         library.destination.into(),
         variables_map,
         script.script,
+        library.trusted != 0,
     );
         
     Ok(Some(jc))
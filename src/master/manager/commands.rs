@@ -0,0 +1,42 @@
+use serde::Serialize;
+use tokio::sync::oneshot;
+
+use crate::rpc::{ToolInfo, TranscodeProgress};
+
+pub enum ManagerCommand {
+    ListWorkers(oneshot::Sender<Vec<WorkerSummary>>),
+    CancelJob(i64, oneshot::Sender<bool>),  // replies false if no worker runs the job
+    DisconnectWorker(String, String),       // identifier, reason sent to the worker
+    LibrariesChanged,                       // resends the configuration to the workers
+}
+
+#[derive(Clone, Serialize)]
+pub struct WorkerSummary {
+    pub identifier: String,
+    pub simultaneous_jobs: u8,
+    pub sw_version: String,
+    pub protocol_version: u16,
+    pub features: Vec<String>,
+    pub tools: Vec<ToolInfo>,
+    pub active_jobs: Vec<ActiveJob>,
+}
+
+#[derive(Clone, Serialize)]
+pub struct ActiveJob {
+    pub job_id: i64,
+    pub file: String,
+    pub status: String,
+    pub milestone: Option<String>,
+    pub percentage: Option<f64>,
+    pub eta_secs: Option<u64>,
+}
+
+impl ActiveJob {
+    pub fn with_progress(mut self, tp: Option<&TranscodeProgress>) -> Self {
+        if let Some(tp) = tp {
+            self.percentage = tp.percentage;
+            self.eta_secs = tp.eta.map(|eta| eta.as_secs());
+        }
+        self
+    }
+}
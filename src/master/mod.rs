@@ -1,6 +1,8 @@
+mod auth;
 mod db;
 mod web;
 mod librarian;
+mod recycler;
 mod socket_server;
 mod peers;
 mod manager;
@@ -21,7 +23,7 @@ use socket_server::SocketServer;
 use manager::JobManager;
 use librarian::Librarian;
 use crate::config::SystemConfig;
-use crate::master::manager::{JobContract, events::ManagerEvent};
+use crate::master::manager::{commands::ManagerCommand, events::ManagerEvent};
 use crate::master::peers::TxManagerMsg;
 use crate::{CONFIG, S_TERMINATE, S_RELOAD};
 
@@ -61,7 +63,22 @@ async fn master_runtime() {
             .clone()
     };
     db::init_db(dbpath).await;
-    db::merge_libs_config(&ctx.config.read().unwrap().jobs).await;
+    let jobs = ctx.config.read().unwrap().jobs.clone();
+    db::merge_libs_config(&jobs)
+        .await
+        .expect("Failed to sync libraries from configuration");
+
+    let admin_password = {
+        ctx.config
+            .read()
+            .unwrap()
+            .master.admin_password
+            .clone()
+    };
+    auth::ensure_admin_user(admin_password).await;
+    if let Ok(purged) = auth::purge_expired_sessions().await && purged > 0 {
+        info!("Purged {} expired sessions", purged);
+    }
     
     let (
         tx_fullscan,
@@ -82,12 +99,19 @@ async fn master_runtime() {
         tx_socketserver,
         rx_socketserver
     ) = mpsc::channel::<SocketEvent>(8);
+
+    let (
+        tx_commands,
+        rx_commands
+    ) = mpsc::channel::<ManagerCommand>(8);
     
     let librarian = Librarian::new(rx_fullscan);
     
     let manager = JobManager::new(
         rx_manager,
         rx_socketserver,
+        rx_commands,
+        tx_fullscan.clone(),
         tx_events.clone(),
     );
     
@@ -96,7 +120,7 @@ async fn master_runtime() {
         tx_socketserver,
     );
 
-    tokio::spawn(web_service(ctx.clone(), tx_events));
+    tokio::spawn(web_service(ctx.clone(), tx_events, tx_fullscan, tx_commands));
 
     //let _ = tx_fullscan.send(1).await;
 
@@ -104,6 +128,7 @@ async fn master_runtime() {
         socket_server.run(ctx.clone()),
         manager.run(ctx.clone()),
         tokio::spawn(librarian.run(ctx.clone())),
+        tokio::spawn(recycler::run(ctx.clone())),
         job_propagate_signals(ctx.clone()),
     );
 
@@ -111,6 +136,8 @@ async fn master_runtime() {
 }
 
 async fn job_propagate_signals(ctx: Arc<MasterCtx>) {
+    let mut reload_generation = 0;
+
     loop {
         let s_term = S_TERMINATE
             .get()
@@ -128,11 +155,10 @@ async fn job_propagate_signals(ctx: Arc<MasterCtx>) {
             break;
         }
 
-        if s_hup {
+        if s_hup != reload_generation {
             info!("Received reload signal");
-            if let Some(flag) = S_RELOAD.get() {
-                flag.store(false, Ordering::Relaxed);
-            }
+            reload_generation = s_hup;
+            let _ = ctx.ch_reload.0.send(true);
         }
 
         sleep(Duration::from_millis(100)).await;
@@ -0,0 +1,159 @@
+use anyhow::{anyhow, Result};
+use chrono::{NaiveDateTime, TimeDelta, Utc};
+use std::io::ErrorKind;
+use std::path::Path;
+use std::sync::Arc;
+use tokio::time::{interval, Duration};
+use tracing::{error, info, warn};
+
+use crate::utils;
+use super::db::{self, DB, model::{Library, RecycleEntry}};
+use super::MasterCtx;
+
+const PURGE_INTERVAL: Duration = Duration::from_secs(3600);
+
+// Applies the retention of every library's recycle bin, first at startup
+pub async fn run(ctx: Arc<MasterCtx>) {
+    let mut ch_term = ctx.ch_terminate.1.clone();
+    let mut purge_timer = interval(PURGE_INTERVAL);
+
+    loop {
+        tokio::select! {
+            _ = purge_timer.tick() => {
+                if let Err(e) = purge().await {
+                    error!("Cannot purge recycle bins: {}", e);
+                }
+            }
+
+            _ = ch_term.changed() => {
+                if *ch_term.borrow() {
+                    break;
+                }
+            }
+        }
+    }
+}
+
+async fn purge() -> Result<()> {
+    let pool = DB.get().unwrap();
+
+    let libraries = sqlx::query_as!(
+        Library,
+        r#"
+        SELECT * FROM library
+        WHERE recycle_days IS NOT NULL
+        OR recycle_max_bytes IS NOT NULL
+        "#
+    )
+    .fetch_all(pool)
+    .await?;
+
+    let now = Utc::now().naive_utc();
+    for library in libraries {
+        let entries = db::list_recycled(Some(library.id)).await?;
+        let mut purged = 0;
+        for entry in expired(&entries, library.recycle_days, library.recycle_max_bytes, now) {
+            match discard(entry).await {
+                Ok(()) => purged += 1,
+                Err(e) => warn!("Cannot purge {}: {}", entry.recycled_path, e),
+            }
+        }
+        if purged > 0 {
+            info!("Purged {} files from the recycle bin of library {}", purged, library.name);
+        }
+    }
+
+    Ok(())
+}
+
+// Entries come newest first. Past max_days, or once the running total
+// exceeds max_bytes, they are due for removal.
+fn expired(
+    entries: &[RecycleEntry],
+    max_days: Option<i64>,
+    max_bytes: Option<i64>,
+    now: NaiveDateTime,
+) -> Vec<&RecycleEntry> {
+    let cutoff = max_days.map(|days| now - TimeDelta::days(days));
+    let mut total = 0;
+
+    entries
+        .iter()
+        .filter(|e| {
+            total += e.size.unwrap_or(0);
+            cutoff.is_some_and(|c| e.created_at < c) || max_bytes.is_some_and(|max| total > max)
+        })
+        .collect()
+}
+
+// Each file sits in a directory named after its job, dropped once empty
+async fn remove_job_dir(recycled: &Path) {
+    if let Some(dir) = recycled.parent() {
+        let _ = tokio::fs::remove_dir(dir).await;
+    }
+}
+
+pub async fn discard(entry: &RecycleEntry) -> Result<()> {
+    let recycled = Path::new(&entry.recycled_path);
+    match tokio::fs::remove_file(recycled).await {
+        Ok(()) => remove_job_dir(recycled).await,
+        Err(e) if e.kind() == ErrorKind::NotFound => {},
+        Err(e) => return Err(anyhow!("cannot remove {}: {}", recycled.display(), e)),
+    }
+
+    db::delete_recycled(entry.id).await?;
+    Ok(())
+}
+
+// Puts the original back, replacing the output that took its place
+pub async fn restore(entry: &RecycleEntry) -> Result<()> {
+    let recycled = Path::new(&entry.recycled_path);
+    let original = Path::new(&entry.original_path);
+    if let Some(parent) = original.parent() {
+        tokio::fs::create_dir_all(parent).await?;
+    }
+
+    utils::place_file(recycled, original, None).await?;
+    tokio::fs::remove_file(recycled).await?;
+    remove_job_dir(recycled).await;
+
+    // Known with its new size and hash, the librarian leaves it alone
+    let size = tokio::fs::metadata(original).await?.len() as i64;
+    let owned = original.to_path_buf();
+    let hash = tokio::task::spawn_blocking(move || utils::chunked_hash(owned)).await??;
+    db::record_restored(entry, size, &hash).await?;
+    info!("Restored {} from the recycle bin", original.display());
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn entry(id: i64, size: i64, age_days: i64, now: NaiveDateTime) -> RecycleEntry {
+        RecycleEntry {
+            id,
+            library_id: 1,
+            job_id: Some(id),
+            original_path: format!("/lib/{}.mkv", id),
+            recycled_path: format!("/recycle/{}/{}.mkv", id, id),
+            size: Some(size),
+            created_at: now - TimeDelta::days(age_days),
+        }
+    }
+
+    #[test]
+    fn test_expired() {
+        let now = Utc::now().naive_utc();
+        let entries = vec![entry(4, 10, 1, now), entry(3, 10, 5, now), entry(2, 10, 20, now), entry(1, 10, 40, now)];
+        let ids = |max_days, max_bytes| -> Vec<i64> {
+            expired(&entries, max_days, max_bytes, now).iter().map(|e| e.id).collect()
+        };
+
+        assert_eq!(ids(None, None), Vec::<i64>::new());
+        assert_eq!(ids(Some(30), None), vec![1]);
+        assert_eq!(ids(None, Some(25)), vec![2, 1]);
+        assert_eq!(ids(Some(3), Some(25)), vec![3, 2, 1]);
+        assert_eq!(ids(None, Some(0)), vec![4, 3, 2, 1]);
+    }
+}
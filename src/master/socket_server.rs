@@ -5,16 +5,33 @@ use tokio::sync::mpsc;
 use tokio::task::JoinHandle;
 use tokio;
 use std::collections::HashMap;
+use std::time::{Duration, Instant};
+use anyhow::Result;
+use x25519_dalek::{PublicKey, StaticSecret};
+use zeromq::RouterSocket;
 
-use crate::rpc::WorkerInfo;
+use crate::rpc::{self, MasterInfo, WorkerInfo};
 use crate::rpc::{
+    auth,
+    crypto::{self, Session},
     zmq_helper,
     Message,
 };
+use super::db::{self, model::Worker};
 use super::peers::*;
 use super::MasterCtx;
 
 const CHANNEL_BUFFER_SIZE: usize = 64;
+const AUTH_TIMEOUT: Duration = Duration::from_secs(30);
+
+// Peer that said Hello and still has to answer the challenge
+struct PendingPeer {
+    hello: WorkerInfo,
+    known: Option<Worker>,
+    nonce: Vec<u8>,
+    key: String,
+    since: Instant,
+}
 
 #[derive(Debug, Clone)]
 pub enum SocketEvent {
@@ -24,6 +41,12 @@ pub enum SocketEvent {
 
 pub struct SocketServer {
     peer_map: HashMap<PeerId, (mpsc::Sender<Message>, JoinHandle<()>, String)>,
+    pending: HashMap<PeerId, PendingPeer>,
+    sessions: HashMap<PeerId, Session>,
+    secret: Option<StaticSecret>,
+    require_encryption: bool,
+    psk: Option<String>,
+    auto_approve: bool,
     tx_event: mpsc::Sender<SocketEvent>,
     tx_to_manager: mpsc::Sender<TxManagerMsg>,
 }
@@ -35,17 +58,49 @@ impl SocketServer {
     ) -> Self {
         SocketServer {
             peer_map: HashMap::new(),
+            pending: HashMap::new(),
+            sessions: HashMap::new(),
+            secret: None,
+            require_encryption: false,
+            psk: None,
+            auto_approve: true,
             tx_event,
             tx_to_manager,
         }
     }
 
     pub async fn run(mut self, ctx: Arc<MasterCtx>) {
-        let bind_addr = format!("tcp://{}",
-            &ctx.config
+        let master_config = {
+            let cfg = &ctx.config
             .read()
-            .unwrap()
-            .master.orch_bind_addr);
+            .unwrap();
+            cfg.master.clone()
+        };
+        let bind_addr = format!("tcp://{}", master_config.orch_bind_addr);
+
+        self.psk = master_config.worker_psk;
+        self.auto_approve = master_config.auto_approve_workers;
+        if self.psk.is_none() {
+            warn!("No worker_psk configured, workers without a token connect unauthenticated");
+        }
+
+        if let Some(path) = &master_config.orch_secret_key_file {
+            match crypto::load_or_generate_secret(path) {
+                Ok(secret) => self.secret = Some(secret),
+                Err(e) => {
+                    error!("Cannot load orchestration key {}: {}", path.display(), e);
+                    let _ = ctx.ch_terminate.0.send(true);
+                    return;
+                }
+            }
+        }
+
+        self.require_encryption = master_config.orch_require_encryption;
+        if self.require_encryption && self.secret.is_none() {
+            error!("orch_require_encryption is set but orch_secret_key_file is not configured");
+            let _ = ctx.ch_terminate.0.send(true);
+            return;
+        }
 
         let mut router = zeromq::RouterSocket::new();
         if let Err(e) = router.bind(&bind_addr).await {
@@ -65,53 +120,122 @@ impl SocketServer {
 
         loop {
             tokio::select!(
-                recv = zmq_helper::recv_msg(&mut router, true) => {
+                recv = zmq_helper::recv_raw(&mut router, true) => {
                     match recv {
-                        Ok((peer_id, msg)) => {
+                        Ok((peer_id, payload)) => {
                             let peer_id = peer_id.unwrap();
+                            let Some(msg) = self.decode(&mut router, &peer_id, &payload).await else {
+                                continue;
+                            };
+                            let msg = match msg {
+                                Message::KeyExchange(key) => {
+                                    self.key_exchange(&mut router, &peer_id, &key).await;
+                                    continue;
+                                },
+                                Message::Sealed(sealed) => {
+                                    let Some(session) = self.sessions.get_mut(&peer_id) else {
+                                        warn!("Dropped encrypted message from a peer without session");
+                                        continue;
+                                    };
+                                    match session.open_raw(&sealed) {
+                                        Ok(payload) => match self.decode(&mut router, &peer_id, &payload).await {
+                                            Some(msg) => msg,
+                                            None => continue,
+                                        },
+                                        Err(e) => {
+                                            warn!("Dropped encrypted message: {}", e);
+                                            // Most likely the worker trusts another master key
+                                            if !self.peer_map.contains_key(&peer_id) {
+                                                self.sessions.remove(&peer_id);
+                                                let reject = Message::reject("Key exchange failed, check master_public_key_file");
+                                                let _ = self.send(&mut router, &peer_id, &reject).await;
+                                            }
+                                            continue;
+                                        }
+                                    }
+                                },
+                                _ if self.sessions.contains_key(&peer_id) => {
+                                    warn!("Dropped plaintext message from an encrypted peer");
+                                    continue;
+                                },
+                                Message::Hello(hm) if self.require_encryption => {
+                                    warn!("Rejected peer {}: encryption is required", hm.identifier);
+                                    let reject = Message::reject("Encryption is required");
+                                    let _ = self.send(&mut router, &peer_id, &reject).await;
+                                    continue;
+                                },
+                                _ if self.require_encryption => continue,
+                                msg => msg,
+                            };
+
                             match msg {
                                 Message::Hello(hm) => {
-                                    if !self.peer_map.contains_key(&peer_id) {
-                                        info!("New peer connected: {}", hm.identifier);
-                                        
-                                        if hm.sw_version != env!("CARGO_PKG_VERSION_MAJOR") {
-                                            warn!("Peer {} has incompatible software version: {}", hm.identifier, hm.sw_version);
+                                    if self.peer_map.contains_key(&peer_id) {
+                                        continue;
+                                    }
+
+                                    if !rpc::supported_version(hm.protocol_version) {
+                                        let reason = version_rejection(hm.protocol_version);
+                                        warn!("Rejected peer {} (version {}): {}", hm.identifier, hm.sw_version, reason);
+                                        let _ = self.send(&mut router, &peer_id, &Message::reject(&reason)).await;
+                                        continue;
+                                    }
+
+                                    self.pending.retain(|_, p| p.since.elapsed() < AUTH_TIMEOUT);
+
+                                    let known = match db::find_worker(&hm.identifier).await {
+                                        Ok(known) => known,
+                                        Err(e) => {
+                                            error!("Cannot look up worker {}: {}", hm.identifier, e);
                                             continue;
                                         }
+                                    };
 
-                                        let (
-                                            tx_sock_to_peer,
-                                            rx_sock_to_peer
-                                        ) = mpsc::channel::<Message>(CHANNEL_BUFFER_SIZE);
-                                        
-                                        let (
-                                            tx_manager_to_peer,
-                                            rx_manager_to_peer
-                                        ) = mpsc::channel::<Message>(CHANNEL_BUFFER_SIZE);
-                                        
-                                        let p = Peer::new(
-                                            hm.clone(),
-                                            peer_id.to_vec(),
-                                            tx_peer_to_sock.clone(),
-                                            rx_sock_to_peer,
-                                            self.tx_to_manager.clone(),
-                                            rx_manager_to_peer,
-                                        );
-                                        
-                                        let ph = tokio::spawn(p.run());
+                                    let key = known
+                                        .as_ref()
+                                        .and_then(|w| w.auth_token.clone())
+                                        .or_else(|| self.psk.clone());
 
-                                        self.peer_map
-                                            .insert(peer_id.to_vec(), (tx_sock_to_peer, ph, hm.identifier.clone()));
+                                    match key {
+                                        Some(key) => {
+                                            let nonce = auth::new_nonce();
+                                            let challenge = Message::auth_challenge(nonce.clone());
+                                            self.pending.insert(peer_id.to_vec(), PendingPeer {
+                                                hello: hm,
+                                                known,
+                                                nonce,
+                                                key,
+                                                since: Instant::now(),
+                                            });
+                                            let _ = self.send(&mut router, &peer_id, &challenge).await;
+                                        },
+                                        None => {
+                                            if let Some(reject) = self.admit(peer_id.to_vec(), hm, known, &tx_peer_to_sock).await {
+                                                let _ = self.send(&mut router, &peer_id, &reject).await;
+                                            }
+                                        }
+                                    }
+                                },
+                                Message::AuthResponse(response) => {
+                                    let Some(pending) = self.pending.remove(&peer_id) else {
+                                        continue;
+                                    };
 
-                                        let _ = self.tx_event.send(SocketEvent::PeerConnected(
-                                            peer_id.to_vec(),
-                                            tx_manager_to_peer,
-                                            hm,
-                                        )).await;
-                                        let _ = tx_peer_to_sock.send((peer_id.to_owned(), Message::ack())).await;
+                                    let identifier = &pending.hello.identifier;
+                                    if !auth::verify_challenge(&pending.key, &pending.nonce, identifier, &response) {
+                                        warn!("Rejected peer {}: authentication failed", identifier);
+                                        let reject = Message::reject("Authentication failed");
+                                        let _ = self.send(&mut router, &peer_id, &reject).await;
+                                        continue;
                                     }
-                                }
+
+                                    if let Some(reject) = self.admit(peer_id.to_vec(), pending.hello, pending.known, &tx_peer_to_sock).await {
+                                        let _ = self.send(&mut router, &peer_id, &reject).await;
+                                    }
+                                },
                                 Message::Bye => {
+                                    self.pending.remove(&peer_id);
+                                    self.sessions.remove(&peer_id);
                                     if let Some(val) = self.peer_map
                                         .remove(&peer_id) {
                                         val.1.abort();
@@ -138,8 +262,9 @@ impl SocketServer {
                     }
                 },
                 Some((peer_id, msg)) = rx_peer_to_sock.recv() => {
-                    if let Err(e) = zmq_helper::send_msg(&mut router, Some(&peer_id), &msg).await {
+                    if let Err(e) = self.send(&mut router, &peer_id, &msg).await {
                         error!("Disconnected failed peer: {}", e);
+                        self.sessions.remove(&peer_id);
 
                         if let Some(val) = self.peer_map
                             .remove(&peer_id) {
@@ -150,7 +275,8 @@ impl SocketServer {
                         )).await;
                     }
 
-                    if msg == Message::Bye {
+                    if matches!(msg, Message::Bye | Message::Reject(_)) {
+                        self.sessions.remove(&peer_id);
                         if let Some(val) = self.peer_map
                             .remove(&peer_id) {
                             val.1.abort();
@@ -170,16 +296,175 @@ impl SocketServer {
 
         info!("Stopping orchestration service");
 
-        for (peer_id, v) in self.peer_map.iter() {
-            let ph = &v.1;
+        let peers: Vec<(PeerId, JoinHandle<()>)> = self.peer_map
+            .drain()
+            .map(|(peer_id, v)| (peer_id, v.1))
+            .collect();
+
+        for (peer_id, ph) in peers {
             let _ = self.tx_event.send(SocketEvent::PeerDisconnected(
                 peer_id.to_vec(),
             )).await;
 
             //let _ = tx_peer_to_sock.send((peer_id.to_owned(), Message::Bye)).await;
-            let _ = zmq_helper::send_msg(&mut router, Some(&peer_id), &Message::bye()).await;
+            let _ = self.send(&mut router, &peer_id, &Message::bye()).await;
 
             ph.abort();
         }
     }
+
+    // Undecodable messages are dropped, unless they are a Hello from a
+    // worker speaking another protocol version, which is told why
+    async fn decode(&mut self, router: &mut RouterSocket, peer_id: &[u8], payload: &[u8]) -> Option<Message> {
+        match rpc::decode(payload) {
+            Ok(msg) => Some(msg),
+            Err(e) => {
+                match rpc::peek_hello_version(payload) {
+                    Some(version) if !rpc::supported_version(version) => {
+                        let reason = version_rejection(version);
+                        warn!("Rejected peer: {}", reason);
+                        let _ = self.send(router, peer_id, &Message::reject(&reason)).await;
+                    },
+                    _ => warn!("Dropped undecodable message: {}", e),
+                }
+                None
+            }
+        }
+    }
+
+    // Messages to peers that exchanged keys are sealed with their session
+    async fn send(&mut self, router: &mut RouterSocket, peer_id: &[u8], msg: &Message) -> Result<()> {
+        match self.sessions.get_mut(peer_id) {
+            Some(session) => {
+                let sealed = session.seal(msg)?;
+                zmq_helper::send_msg(router, Some(peer_id), &sealed).await
+            },
+            None => zmq_helper::send_msg(router, Some(peer_id), msg).await,
+        }
+    }
+
+    async fn key_exchange(&mut self, router: &mut RouterSocket, peer_id: &[u8], worker_key: &[u8]) {
+        if self.peer_map.contains_key(peer_id) || self.sessions.contains_key(peer_id) {
+            return;
+        }
+
+        let Some(secret) = &self.secret else {
+            warn!("Peer requested encryption but orch_secret_key_file is not configured");
+            let reject = Message::reject("Encryption is not enabled on master");
+            let _ = self.send(router, peer_id, &reject).await;
+            return;
+        };
+
+        let worker_key = match crypto::public_key_from_bytes(worker_key) {
+            Ok(key) => key,
+            Err(e) => {
+                warn!("Invalid key exchange from peer: {}", e);
+                return;
+            }
+        };
+
+        let ephemeral = crypto::generate_secret();
+        let session = Session::master(secret, &ephemeral, &worker_key);
+        let reply = Message::key_exchange(PublicKey::from(&ephemeral).as_bytes().to_vec());
+
+        // The reply itself goes out in plain, everything after it is sealed
+        if let Err(e) = self.send(router, peer_id, &reply).await {
+            error!("Key exchange failed: {}", e);
+            return;
+        }
+        self.sessions.insert(peer_id.to_vec(), session);
+    }
+
+    // Checks the admission state of an authenticated worker and connects it.
+    // Returns the rejection to send when the worker is not allowed in.
+    async fn admit(
+        &mut self,
+        peer_id: PeerId,
+        hm: WorkerInfo,
+        known: Option<Worker>,
+        tx_peer_to_sock: &mpsc::Sender<TxSocketMsg>,
+    ) -> Option<Message> {
+        let status = match known {
+            Some(worker) => worker.status,
+            None => {
+                let status = if self.auto_approve {
+                    db::WORKER_APPROVED
+                } else {
+                    db::WORKER_PENDING
+                };
+                if let Err(e) = db::register_worker(&hm.identifier, status).await {
+                    error!("Cannot register worker {}: {}", hm.identifier, e);
+                    return Some(Message::reject("Internal error"));
+                }
+                status.to_string()
+            }
+        };
+
+        match status.as_str() {
+            db::WORKER_APPROVED => {},
+            db::WORKER_PENDING => {
+                info!("Worker {} is awaiting approval", hm.identifier);
+                return Some(Message::reject("Worker is awaiting approval"));
+            },
+            _ => {
+                warn!("Rejected peer {}: worker is {}", hm.identifier, status);
+                return Some(Message::reject("Worker has been revoked"));
+            }
+        }
+
+        // From here on the peer is known by the features both sides support
+        let negotiated = rpc::negotiate_features(&hm.features);
+        let hm = WorkerInfo {
+            features: negotiated.clone(),
+            ..hm
+        };
+        info!("New peer connected: {} (protocol {}, features {:?})", hm.identifier, hm.protocol_version, hm.features);
+
+        let (
+            tx_sock_to_peer,
+            rx_sock_to_peer
+        ) = mpsc::channel::<Message>(CHANNEL_BUFFER_SIZE);
+        
+        let (
+            tx_manager_to_peer,
+            rx_manager_to_peer
+        ) = mpsc::channel::<Message>(CHANNEL_BUFFER_SIZE);
+        
+        let p = Peer::new(
+            hm.clone(),
+            peer_id.clone(),
+            tx_peer_to_sock.clone(),
+            rx_sock_to_peer,
+            self.tx_to_manager.clone(),
+            rx_manager_to_peer,
+        );
+        
+        let ph = tokio::spawn(p.run());
+
+        self.peer_map
+            .insert(peer_id.clone(), (tx_sock_to_peer, ph, hm.identifier.clone()));
+
+        let _ = self.tx_event.send(SocketEvent::PeerConnected(
+            peer_id.clone(),
+            tx_manager_to_peer,
+            hm,
+        )).await;
+        let ack = Message::ack(MasterInfo {
+            protocol_version: rpc::PROTOCOL_VERSION,
+            sw_version: env!("CARGO_PKG_VERSION").to_string(),
+            features: negotiated,
+        });
+        let _ = tx_peer_to_sock.send((peer_id, ack)).await;
+
+        None
+    }
+}
+
+fn version_rejection(version: u16) -> String {
+    format!(
+        "Protocol version {} is not supported, master supports {} to {}",
+        version,
+        rpc::MIN_PROTOCOL_VERSION,
+        rpc::PROTOCOL_VERSION
+    )
 }
@@ -2,11 +2,17 @@ mod sse;
 mod window;
 mod index;
 mod control_panel;
+mod api;
+mod auth;
+mod workers;
+mod recycle;
+mod scripts;
 
 use axum::{
     http,
     Router,
-    routing::get,
+    middleware,
+    routing::{get, post},
     response::IntoResponse,
 };
 use tower_http::{
@@ -20,10 +26,10 @@ use tower_http::{
 use maud::{html, Markup};
 use reqwest::header;
 use std::sync::Arc;
-use tokio::{net::TcpListener, sync::broadcast};
+use tokio::{net::TcpListener, sync::{broadcast, mpsc}};
 use tracing::{info, Level};
 
-use crate::master::manager::events::ManagerEvent;
+use crate::master::manager::{commands::ManagerCommand, events::ManagerEvent};
 
 use super::MasterCtx;
 
@@ -35,9 +41,17 @@ const WEB_UI_STYLE: &'static str = ASSETS_STYLE_BTTF_CSS;
 #[derive(Clone)]
 struct AppState {
     broadcast: broadcast::Sender<ManagerEvent>,
+    tx_fullscan: mpsc::Sender<i64>,
+    tx_manager: mpsc::Sender<ManagerCommand>,
+    session_ttl_hours: u32,
 }
 
-pub async fn web_service(ctx: Arc<MasterCtx>, ev: broadcast::Sender<ManagerEvent>) {
+pub async fn web_service(
+    ctx: Arc<MasterCtx>,
+    ev: broadcast::Sender<ManagerEvent>,
+    tx_fullscan: mpsc::Sender<i64>,
+    tx_manager: mpsc::Sender<ManagerCommand>,
+) {
     let master_config = {
         let cfg = &ctx.config
         .read()
@@ -46,10 +60,16 @@ pub async fn web_service(ctx: Arc<MasterCtx>, ev: broadcast::Sender<ManagerEvent
     };
 
     let state = AppState {
-        broadcast: ev
+        broadcast: ev,
+        tx_fullscan,
+        tx_manager,
+        session_ttl_hours: master_config.session_ttl_hours,
     };
 
     let router = Router::new()
+            .route("/login", get(auth::login_page).post(auth::login))
+            .route("/logout", post(auth::logout))
+            .nest("/api/v1", api::router())
             .route("/sse/clock", get(sse::clock))
             .route("/sse/manager_events", get(sse::manager_events))
             .route("/", get(index::index()))
@@ -57,12 +77,19 @@ pub async fn web_service(ctx: Arc<MasterCtx>, ev: broadcast::Sender<ManagerEvent
             .route("/windows/window-control", get(control_panel::window()))
             .route("/windows/window-activity", get(activity_window()))
             .route("/windows/window-statistics", get(statistics_window()))
+            .route("/windows/window-workers", get(workers::window))
+            .route("/windows/window-workers/{id}/{action}", post(workers::action))
+            .route("/windows/window-recycle", get(recycle::window))
+            .route("/windows/window-recycle/{id}/{action}", post(recycle::action))
+            .route("/windows/window-scripts", get(scripts::window))
+            .route("/windows/window-scripts/{library}/{script}/{action}", post(scripts::action))
             .route("/favicon.ico", get(|| async { serve_binary_asset(ASSETS_FAVICON_ICO, "image/x-icon") } ))
             .route("/static/htmx.min.js", get(|| async { serve_cached_asset(ASSETS_HTMX_MIN_2_0_7_JS, "application/javascript") } ))
             .route("/static/htmx-ext-sse.min.js", get(|| async { serve_cached_asset(ASSETS_HTMX_EXT_SSE_MIN_2_2_2_JS, "application/javascript") } ))
             .route("/static/style.css", get(|| async { serve_cached_asset(WEB_UI_STYLE, "text/css") } ))
             .route("/static/libwm.js", get(|| async { serve_cached_asset(ASSETS_LIBWM_JS, "application/javascript") } ))
             .route("/static/favicon.ico", get(|| async { serve_binary_asset(ASSETS_FAVICON_ICO, "image/x-icon") } ))
+            .layer(middleware::from_fn(auth::require_auth))
             .layer(TraceLayer::new_for_http()
                 .make_span_with(DefaultMakeSpan::new().level(Level::INFO))
                 .on_response(DefaultOnResponse::new().level(Level::INFO)))
@@ -0,0 +1,1048 @@
+use axum::{
+    Extension,
+    Json,
+    Router,
+    extract::{Path, Query, State},
+    http::{HeaderMap, StatusCode},
+    middleware,
+    response::{IntoResponse, Response},
+    routing::{delete, get, post},
+};
+use chrono::NaiveDateTime;
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use sqlx::{Pool, Sqlite, SqliteConnection};
+use tokio::sync::oneshot;
+use tracing::error;
+
+use crate::master::auth::{self, AuthUser, Role};
+use crate::master::db::{self, model::{Library, RecycleEntry, Script, Worker}};
+use crate::master::manager::commands::{ManagerCommand, WorkerSummary};
+use crate::master::recycler;
+use crate::utils;
+use super::AppState;
+
+const DEFAULT_PAGE_SIZE: i64 = 100;
+const MAX_PAGE_SIZE: i64 = 1000;
+
+pub fn router() -> Router<AppState> {
+    Router::new()
+        .route("/jobs", get(list_jobs))
+        .route("/jobs/{id}", get(get_job))
+        .route("/jobs/{id}/requeue", post(requeue_job))
+        .route("/jobs/{id}/cancel", post(cancel_job))
+        .route("/jobs/{id}/priority", post(set_job_priority))
+        .route("/jobs/{id}/logs", get(job_logs))
+        .route("/jobs/{id}/outputs", get(job_outputs))
+        .route("/libraries", get(list_libraries))
+        .route("/libraries/{id}", get(get_library))
+        .route("/libraries/{id}/scan", post(scan_library))
+        .route("/libraries/{id}/enable", post(enable_library))
+        .route("/libraries/{id}/disable", post(disable_library))
+        .route("/libraries/{id}/dry-run", get(library_dry_run))
+        .route("/libraries/{id}/scripts", get(list_library_scripts))
+        .route("/libraries/{id}/pin", post(pin_library_script))
+        .route("/libraries/{id}/rollback", post(rollback_library_script))
+        .route("/recycle", get(list_recycled))
+        .route("/recycle/{id}", delete(discard_recycled))
+        .route("/recycle/{id}/restore", post(restore_recycled))
+        .route("/workers", get(list_workers))
+        .route("/workers/known", get(list_known_workers))
+        .route("/workers/{id}/approve", post(approve_worker))
+        .route("/workers/{id}/revoke", post(revoke_worker))
+        .route("/workers/{id}/token", post(create_worker_token).delete(delete_worker_token))
+        .route("/scripts", get(list_scripts))
+        .route("/scripts/{id}", get(get_script))
+        .route("/scripts/{id}/diff", get(script_diff))
+        .route("/me", get(me))
+        .route("/me/password", post(change_password))
+        .route("/tokens", get(list_tokens).post(create_token))
+        .route("/tokens/{id}", delete(delete_token))
+        .merge(Router::new()
+            .route("/users", get(list_users).post(create_user))
+            .route("/users/{id}", delete(delete_user))
+            .route_layer(middleware::from_fn(super::auth::require_admin)))
+}
+
+pub struct ApiError {
+    status: StatusCode,
+    message: String,
+}
+
+impl ApiError {
+    pub fn new(status: StatusCode, message: impl Into<String>) -> Self {
+        Self {
+            status,
+            message: message.into(),
+        }
+    }
+
+    pub fn not_found(what: &str) -> Self {
+        Self::new(StatusCode::NOT_FOUND, format!("{} not found", what))
+    }
+
+    pub fn conflict(message: impl Into<String>) -> Self {
+        Self::new(StatusCode::CONFLICT, message)
+    }
+
+    pub fn unavailable(message: impl Into<String>) -> Self {
+        Self::new(StatusCode::SERVICE_UNAVAILABLE, message)
+    }
+}
+
+impl From<sqlx::Error> for ApiError {
+    fn from(e: sqlx::Error) -> Self {
+        if let Some(dbe) = e.as_database_error()
+            && dbe.is_unique_violation() {
+            return Self::conflict(dbe.message().to_string());
+        }
+        error!("API database error: {}", e);
+        Self::new(StatusCode::INTERNAL_SERVER_ERROR, "Database error")
+    }
+}
+
+impl IntoResponse for ApiError {
+    fn into_response(self) -> Response {
+        (self.status, Json(json!({ "error": self.message }))).into_response()
+    }
+}
+
+pub type ApiResult<T> = Result<Json<T>, ApiError>;
+
+fn pool() -> &'static Pool<Sqlite> {
+    db::DB.get().unwrap()
+}
+
+#[derive(Serialize)]
+pub struct JobEntry {
+    pub id: i64,
+    pub status: String,
+    pub priority: i64,
+    pub library_id: i64,
+    pub library: String,
+    pub file: String,
+    pub worker: Option<String>,
+    pub output_file: Option<String>,
+    pub output_size: Option<i64>,
+    pub script_id: Option<i64>,
+    pub script_version: Option<i64>,
+    pub created_at: NaiveDateTime,
+    pub started_at: Option<NaiveDateTime>,
+    pub finished_at: Option<NaiveDateTime>,
+    pub restored_at: Option<NaiveDateTime>,
+}
+
+#[derive(Deserialize)]
+pub struct JobFilter {
+    status: Option<String>,
+    library: Option<i64>,
+    worker: Option<String>,
+    script: Option<i64>,
+    limit: Option<i64>,
+    offset: Option<i64>,
+}
+
+async fn list_jobs(Query(filter): Query<JobFilter>) -> ApiResult<Vec<JobEntry>> {
+    let limit = filter.limit
+        .unwrap_or(DEFAULT_PAGE_SIZE)
+        .clamp(1, MAX_PAGE_SIZE);
+    let offset = filter.offset.unwrap_or(0).max(0);
+
+    let jobs = sqlx::query_as!(
+        JobEntry,
+        r#"
+        SELECT
+            job.id AS "id!",
+            job.status,
+            job.priority,
+            library.id AS "library_id!",
+            library.name AS library,
+            file_entry.file_path AS file,
+            workers.identifier AS "worker?",
+            job.output_file,
+            job.output_size,
+            job.script_id,
+            script.version AS "script_version?",
+            job.created_at,
+            job.started_at,
+            job.finished_at,
+            job.restored_at
+        FROM job
+        JOIN file_entry ON file_entry.id = job.file_id
+        JOIN library ON library.id = file_entry.library_id
+        LEFT JOIN workers ON workers.id = job.worker_id
+        LEFT JOIN script ON script.id = job.script_id
+        WHERE (?1 IS NULL OR job.status = ?1)
+        AND (?2 IS NULL OR library.id = ?2)
+        AND (?3 IS NULL OR workers.identifier = ?3)
+        AND (?4 IS NULL OR job.script_id = ?4)
+        ORDER BY job.priority DESC, job.created_at ASC
+        LIMIT ?5 OFFSET ?6
+        "#,
+        filter.status,
+        filter.library,
+        filter.worker,
+        filter.script,
+        limit,
+        offset
+    )
+    .fetch_all(pool())
+    .await?;
+
+    Ok(Json(jobs))
+}
+
+async fn fetch_job(id: i64) -> Result<JobEntry, ApiError> {
+    sqlx::query_as!(
+        JobEntry,
+        r#"
+        SELECT
+            job.id AS "id!",
+            job.status,
+            job.priority,
+            library.id AS "library_id!",
+            library.name AS library,
+            file_entry.file_path AS file,
+            workers.identifier AS "worker?",
+            job.output_file,
+            job.output_size,
+            job.script_id,
+            script.version AS "script_version?",
+            job.created_at,
+            job.started_at,
+            job.finished_at,
+            job.restored_at
+        FROM job
+        JOIN file_entry ON file_entry.id = job.file_id
+        JOIN library ON library.id = file_entry.library_id
+        LEFT JOIN workers ON workers.id = job.worker_id
+        LEFT JOIN script ON script.id = job.script_id
+        WHERE job.id = ?
+        "#,
+        id
+    )
+    .fetch_optional(pool())
+    .await?
+    .ok_or_else(|| ApiError::not_found("Job"))
+}
+
+async fn get_job(Path(id): Path<i64>) -> ApiResult<JobEntry> {
+    Ok(Json(fetch_job(id).await?))
+}
+
+// A file has at most one queued and one processing job, returns the other
+// job of the file already in that status
+async fn sibling_job(conn: &mut SqliteConnection, id: i64, status: &str) -> Result<Option<i64>, ApiError> {
+    let row = sqlx::query!(
+        r#"
+        SELECT other.id AS "id!"
+        FROM job
+        JOIN job AS other ON other.file_id = job.file_id
+        WHERE job.id = ? AND other.status = ? AND other.id != job.id
+        "#,
+        id,
+        status
+    )
+    .fetch_optional(conn)
+    .await?;
+
+    Ok(row.map(|r| r.id))
+}
+
+async fn requeue_job(Path(id): Path<i64>) -> ApiResult<JobEntry> {
+    let job = fetch_job(id).await?;
+    if job.status == "queued" || job.status == "processing" {
+        return Err(ApiError::conflict(format!("Job is {}", job.status)));
+    }
+    // Checked and updated in one transaction, a file has at most one queued job
+    let mut tx = pool().begin().await?;
+    if let Some(other) = sibling_job(&mut tx, id, "queued").await? {
+        return Err(ApiError::conflict(format!("File already has queued job {}", other)));
+    }
+    sqlx::query!(
+        r#"
+        UPDATE job
+        SET status = 'queued',
+            started_at = NULL,
+            finished_at = NULL
+        WHERE id = ?
+        "#,
+        id
+    )
+    .execute(&mut *tx)
+    .await?;
+    tx.commit().await?;
+
+    Ok(Json(fetch_job(id).await?))
+}
+
+async fn cancel_job(
+    State(state): State<AppState>,
+    Path(id): Path<i64>,
+) -> ApiResult<JobEntry> {
+    let job = fetch_job(id).await?;
+    if job.status != "queued" && job.status != "processing" {
+        return Err(ApiError::conflict(format!("Job is {}", job.status)));
+    }
+
+    sqlx::query!(
+        r#"
+        UPDATE job
+        SET status = 'cancelled',
+            finished_at = CURRENT_TIMESTAMP
+        WHERE id = ?
+        "#,
+        id
+    )
+    .execute(pool())
+    .await?;
+
+    if job.status == "processing" {
+        let (tx, rx) = oneshot::channel();
+        state.tx_manager
+            .send(ManagerCommand::CancelJob(id, tx))
+            .await
+            .map_err(|_| ApiError::unavailable("Job manager is not running"))?;
+        let _ = rx.await;
+    }
+
+    Ok(Json(fetch_job(id).await?))
+}
+
+#[derive(Deserialize)]
+pub struct PriorityRequest {
+    priority: i64,
+}
+
+async fn set_job_priority(
+    Path(id): Path<i64>,
+    Json(req): Json<PriorityRequest>,
+) -> ApiResult<JobEntry> {
+    let updated = sqlx::query!(
+        "UPDATE job SET priority = ? WHERE id = ?",
+        req.priority,
+        id
+    )
+    .execute(pool())
+    .await?;
+
+    if updated.rows_affected() == 0 {
+        return Err(ApiError::not_found("Job"));
+    }
+
+    Ok(Json(fetch_job(id).await?))
+}
+
+#[derive(Deserialize)]
+pub struct LogQuery {
+    tail: Option<usize>,
+}
+
+#[derive(Serialize)]
+pub struct JobLog {
+    job_id: i64,
+    lines: Vec<String>,
+}
+
+#[derive(Serialize)]
+pub struct JobOutputEntry {
+    pub path: String,
+    pub role: String,
+    pub size: Option<i64>,
+    pub created_at: NaiveDateTime,
+}
+
+async fn job_outputs(Path(id): Path<i64>) -> ApiResult<Vec<JobOutputEntry>> {
+    fetch_job(id).await?;
+
+    let outputs = sqlx::query_as!(
+        JobOutputEntry,
+        "SELECT path, role, size, created_at FROM job_output WHERE job_id = ? ORDER BY id",
+        id
+    )
+    .fetch_all(pool())
+    .await?;
+
+    Ok(Json(outputs))
+}
+
+async fn job_logs(
+    Path(id): Path<i64>,
+    Query(query): Query<LogQuery>,
+) -> ApiResult<JobLog> {
+    let job = sqlx::query!("SELECT log_path FROM job WHERE id = ?", id)
+        .fetch_optional(pool())
+        .await?
+        .ok_or_else(|| ApiError::not_found("Job"))?;
+
+    let mut lines: Vec<String> = match job.log_path {
+        Some(path) => match tokio::fs::read_to_string(&path).await {
+            Ok(content) => content.lines().map(str::to_string).collect(),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Vec::new(),
+            Err(e) => {
+                error!("Cannot read job log {}: {}", path, e);
+                return Err(ApiError::new(StatusCode::INTERNAL_SERVER_ERROR, "Cannot read job log"));
+            }
+        },
+        None => Vec::new(),
+    };
+
+    if let Some(tail) = query.tail {
+        let skip = lines.len().saturating_sub(tail);
+        lines.drain(..skip);
+    }
+
+    Ok(Json(JobLog {
+        job_id: id,
+        lines,
+    }))
+}
+
+async fn list_libraries() -> ApiResult<Vec<Library>> {
+    let libraries = sqlx::query_as!(
+        Library,
+        r#"
+        SELECT id AS "id!", name, source, enabled, path, destination, script_id, last_scanned_at, trusted,
+            recycle_dir, recycle_days, recycle_max_bytes, dry_run, pinned_script_id
+        FROM library
+        ORDER BY name
+        "#
+    )
+    .fetch_all(pool())
+    .await?;
+
+    Ok(Json(libraries))
+}
+
+async fn fetch_library(id: i64) -> Result<Library, ApiError> {
+    sqlx::query_as!(
+        Library,
+        "SELECT * FROM library WHERE id = ?",
+        id
+    )
+    .fetch_optional(pool())
+    .await?
+    .ok_or_else(|| ApiError::not_found("Library"))
+}
+
+async fn get_library(Path(id): Path<i64>) -> ApiResult<Library> {
+    Ok(Json(fetch_library(id).await?))
+}
+
+async fn scan_library(
+    State(state): State<AppState>,
+    Path(id): Path<i64>,
+) -> Result<impl IntoResponse, ApiError> {
+    let library = fetch_library(id).await?;
+    if library.enabled == 0 {
+        return Err(ApiError::conflict("Library is disabled"));
+    }
+
+    state.tx_fullscan
+        .send(id)
+        .await
+        .map_err(|_| ApiError::unavailable("Librarian is not running"))?;
+
+    Ok((StatusCode::ACCEPTED, Json(json!({ "library_id": id, "scan": "queued" }))))
+}
+
+async fn set_library_enabled(id: i64, enabled: bool) -> ApiResult<Library> {
+    let enabled_int = if enabled { 1 } else { 0 };
+    let updated = sqlx::query!(
+        "UPDATE library SET enabled = ? WHERE id = ?",
+        enabled_int,
+        id
+    )
+    .execute(pool())
+    .await?;
+
+    if updated.rows_affected() == 0 {
+        return Err(ApiError::not_found("Library"));
+    }
+
+    Ok(Json(fetch_library(id).await?))
+}
+
+async fn enable_library(Path(id): Path<i64>) -> ApiResult<Library> {
+    set_library_enabled(id, true).await
+}
+
+async fn disable_library(Path(id): Path<i64>) -> ApiResult<Library> {
+    set_library_enabled(id, false).await
+}
+
+#[derive(Deserialize)]
+pub struct RecycleFilter {
+    library: Option<i64>,
+}
+
+async fn list_recycled(Query(filter): Query<RecycleFilter>) -> ApiResult<Vec<RecycleEntry>> {
+    Ok(Json(db::list_recycled(filter.library).await?))
+}
+
+pub async fn recycle_action(id: i64, restore: bool) -> Result<(), ApiError> {
+    let entry = db::find_recycled(id)
+        .await?
+        .ok_or_else(|| ApiError::not_found("Recycled file"))?;
+
+    let result = if restore {
+        recycler::restore(&entry).await
+    } else {
+        recycler::discard(&entry).await
+    };
+    result.map_err(|e| ApiError::new(StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))
+}
+
+async fn restore_recycled(Path(id): Path<i64>) -> Result<StatusCode, ApiError> {
+    recycle_action(id, true).await?;
+    Ok(StatusCode::NO_CONTENT)
+}
+
+async fn discard_recycled(Path(id): Path<i64>) -> Result<StatusCode, ApiError> {
+    recycle_action(id, false).await?;
+    Ok(StatusCode::NO_CONTENT)
+}
+
+#[derive(Serialize)]
+pub struct DryRunEntry {
+    pub job_id: i64,
+    pub file: String,
+    pub source_size: Option<i64>,
+    pub output_file: Option<String>,
+    pub estimated_size: Option<i64>,
+    pub finished_at: Option<NaiveDateTime>,
+}
+
+// Totals only count the jobs with an estimate, so the sizes compare
+#[derive(Serialize)]
+pub struct DryRunReport {
+    library_id: i64,
+    jobs: usize,
+    estimated_jobs: usize,
+    source_size: i64,
+    estimated_size: i64,
+    entries: Vec<DryRunEntry>,
+}
+
+async fn library_dry_run(Path(id): Path<i64>) -> ApiResult<DryRunReport> {
+    fetch_library(id).await?;
+
+    let entries = sqlx::query_as!(
+        DryRunEntry,
+        r#"
+        SELECT
+            job.id AS "job_id!",
+            file_entry.file_path AS file,
+            file_entry.file_size AS source_size,
+            job.output_file,
+            job.output_size AS estimated_size,
+            job.finished_at
+        FROM job
+        JOIN file_entry ON file_entry.id = job.file_id
+        WHERE job.status = 'dry_run'
+        AND file_entry.library_id = ?
+        ORDER BY file_entry.file_path
+        "#,
+        id
+    )
+    .fetch_all(pool())
+    .await?;
+
+    let estimated: Vec<&DryRunEntry> = entries
+        .iter()
+        .filter(|e| e.estimated_size.is_some())
+        .collect();
+
+    Ok(Json(DryRunReport {
+        library_id: id,
+        jobs: entries.len(),
+        estimated_jobs: estimated.len(),
+        source_size: estimated.iter().filter_map(|e| e.source_size).sum(),
+        estimated_size: estimated.iter().filter_map(|e| e.estimated_size).sum(),
+        entries,
+    }))
+}
+
+async fn list_workers(State(state): State<AppState>) -> ApiResult<Vec<WorkerSummary>> {
+    let (tx, rx) = oneshot::channel();
+    state.tx_manager
+        .send(ManagerCommand::ListWorkers(tx))
+        .await
+        .map_err(|_| ApiError::unavailable("Job manager is not running"))?;
+
+    let workers = rx
+        .await
+        .map_err(|_| ApiError::unavailable("Job manager is not running"))?;
+
+    Ok(Json(workers))
+}
+
+#[derive(Serialize)]
+pub struct WorkerEntry {
+    id: i64,
+    identifier: String,
+    status: String,
+    has_token: bool,
+    last_conn_at: Option<NaiveDateTime>,
+}
+
+impl From<Worker> for WorkerEntry {
+    fn from(w: Worker) -> Self {
+        Self {
+            id: w.id,
+            identifier: w.identifier,
+            status: w.status,
+            has_token: w.auth_token.is_some(),
+            last_conn_at: w.last_conn_at,
+        }
+    }
+}
+
+async fn list_known_workers() -> ApiResult<Vec<WorkerEntry>> {
+    let workers = db::list_workers()
+        .await?
+        .into_iter()
+        .map(WorkerEntry::from)
+        .collect();
+
+    Ok(Json(workers))
+}
+
+pub async fn set_worker_status(state: &AppState, id: i64, status: &str) -> Result<(), ApiError> {
+    let identifier = db::set_worker_status(id, status)
+        .await?
+        .ok_or_else(|| ApiError::not_found("Worker"))?;
+
+    if status == db::WORKER_REVOKED {
+        let _ = state.tx_manager
+            .send(ManagerCommand::DisconnectWorker(identifier, "Worker has been revoked".to_string()))
+            .await;
+    }
+
+    Ok(())
+}
+
+async fn approve_worker(
+    State(state): State<AppState>,
+    Path(id): Path<i64>,
+) -> Result<StatusCode, ApiError> {
+    set_worker_status(&state, id, db::WORKER_APPROVED).await?;
+    Ok(StatusCode::NO_CONTENT)
+}
+
+async fn revoke_worker(
+    State(state): State<AppState>,
+    Path(id): Path<i64>,
+) -> Result<StatusCode, ApiError> {
+    set_worker_status(&state, id, db::WORKER_REVOKED).await?;
+    Ok(StatusCode::NO_CONTENT)
+}
+
+// The master needs the plain token to verify the worker's HMAC, so unlike
+// API tokens it is stored as is
+async fn create_worker_token(Path(id): Path<i64>) -> Result<impl IntoResponse, ApiError> {
+    let token = auth::random_token();
+    if !db::set_worker_token(id, Some(&token)).await? {
+        return Err(ApiError::not_found("Worker"));
+    }
+
+    Ok((StatusCode::CREATED, Json(json!({ "id": id, "token": token }))))
+}
+
+async fn delete_worker_token(Path(id): Path<i64>) -> Result<StatusCode, ApiError> {
+    if !db::set_worker_token(id, None).await? {
+        return Err(ApiError::not_found("Worker"));
+    }
+
+    Ok(StatusCode::NO_CONTENT)
+}
+
+#[derive(Serialize)]
+pub struct ScriptEntry {
+    id: i64,
+    name: String,
+    hash: String,
+    source: String,
+    version: i64,
+    description: Option<String>,
+    updated_at: Option<NaiveDateTime>,
+}
+
+async fn list_scripts() -> ApiResult<Vec<ScriptEntry>> {
+    let scripts = sqlx::query_as!(
+        ScriptEntry,
+        r#"
+        SELECT id AS "id!", name, hash, source, version, description, updated_at
+        FROM script
+        ORDER BY name, source, version
+        "#
+    )
+    .fetch_all(pool())
+    .await?;
+
+    Ok(Json(scripts))
+}
+
+async fn fetch_script(id: i64) -> Result<Script, ApiError> {
+    sqlx::query_as!(
+        Script,
+        "SELECT * FROM script WHERE id = ?",
+        id
+    )
+    .fetch_optional(pool())
+    .await?
+    .ok_or_else(|| ApiError::not_found("Script"))
+}
+
+async fn get_script(Path(id): Path<i64>) -> ApiResult<Script> {
+    Ok(Json(fetch_script(id).await?))
+}
+
+#[derive(Deserialize)]
+pub struct DiffQuery {
+    from: Option<i64>,
+}
+
+#[derive(Serialize)]
+pub struct ScriptDiff {
+    pub from_id: Option<i64>,
+    pub from_version: Option<i64>,
+    pub to_id: i64,
+    pub to_version: i64,
+    pub diff: String,
+}
+
+// Without from, the version before this one, or an empty file for the first
+pub async fn diff_script(id: i64, from: Option<i64>) -> Result<ScriptDiff, ApiError> {
+    let to = fetch_script(id).await?;
+    let from = match from {
+        Some(from) => Some(fetch_script(from).await?),
+        None => sqlx::query_as!(
+            Script,
+            r#"
+            SELECT id AS "id!", name, hash, script, source, description, updated_at, version
+            FROM script
+            WHERE name = ? AND source = ? AND version < ?
+            ORDER BY version DESC
+            LIMIT 1
+            "#,
+            to.name,
+            to.source,
+            to.version
+        )
+        .fetch_optional(pool())
+        .await?,
+    };
+
+    let label = |s: &Script| format!("{} v{}", s.name, s.version);
+    let diff = match &from {
+        Some(from) => utils::unified_diff(&from.script, &to.script, &label(from), &label(&to)),
+        None => utils::unified_diff("", &to.script, "/dev/null", &label(&to)),
+    };
+
+    Ok(ScriptDiff {
+        from_id: from.as_ref().map(|s| s.id),
+        from_version: from.as_ref().map(|s| s.version),
+        to_id: to.id,
+        to_version: to.version,
+        diff,
+    })
+}
+
+async fn script_diff(Path(id): Path<i64>, Query(query): Query<DiffQuery>) -> ApiResult<ScriptDiff> {
+    Ok(Json(diff_script(id, query.from).await?))
+}
+
+#[derive(Serialize)]
+pub struct ScriptVersion {
+    pub id: i64,
+    pub name: String,
+    pub version: i64,
+    pub hash: String,
+    pub updated_at: Option<NaiveDateTime>,
+    pub jobs: i64,          // jobs of this library that ran the version
+    pub current: bool,      // the version in the script file
+    pub pinned: bool,
+}
+
+// Every version of the library script, newest first
+pub async fn library_scripts(id: i64) -> Result<Vec<ScriptVersion>, ApiError> {
+    fetch_library(id).await?;
+
+    let versions = sqlx::query_as!(
+        ScriptVersion,
+        r#"
+        SELECT
+            script.id AS "id!",
+            script.name,
+            script.version,
+            script.hash,
+            script.updated_at,
+            (
+                SELECT COUNT(*) FROM job
+                JOIN file_entry ON file_entry.id = job.file_id
+                WHERE job.script_id = script.id
+                AND file_entry.library_id = library.id
+            ) AS "jobs!: i64",
+            script.id = library.script_id AS "current!: bool",
+            script.id IS library.pinned_script_id AS "pinned!: bool"
+        FROM library
+        JOIN script AS current ON current.id = library.script_id
+        JOIN script ON script.name = current.name AND script.source = current.source
+        WHERE library.id = ?
+        ORDER BY script.version DESC
+        "#,
+        id
+    )
+    .fetch_all(pool())
+    .await?;
+
+    Ok(versions)
+}
+
+async fn list_library_scripts(Path(id): Path<i64>) -> ApiResult<Vec<ScriptVersion>> {
+    Ok(Json(library_scripts(id).await?))
+}
+
+// Runs the library on a version of its script until unpinned with None.
+// Workers are told, new jobs use it and running ones are left alone.
+pub async fn pin_script(state: &AppState, library_id: i64, script_id: Option<i64>) -> Result<Library, ApiError> {
+    if let Some(script_id) = script_id
+        && !library_scripts(library_id).await?.iter().any(|v| v.id == script_id) {
+        return Err(ApiError::new(
+            StatusCode::BAD_REQUEST,
+            "Script is not a version of the library script",
+        ));
+    }
+
+    sqlx::query!(
+        "UPDATE library SET pinned_script_id = ? WHERE id = ?",
+        script_id,
+        library_id
+    )
+    .execute(pool())
+    .await?;
+
+    let _ = state.tx_manager.send(ManagerCommand::LibrariesChanged).await;
+    fetch_library(library_id).await
+}
+
+#[derive(Deserialize)]
+pub struct PinRequest {
+    script_id: Option<i64>,
+}
+
+async fn pin_library_script(
+    State(state): State<AppState>,
+    Path(id): Path<i64>,
+    Json(req): Json<PinRequest>,
+) -> ApiResult<Library> {
+    Ok(Json(pin_script(&state, id, req.script_id).await?))
+}
+
+// Pins the version before the one the library runs now
+pub async fn rollback_script(state: &AppState, library_id: i64) -> Result<Library, ApiError> {
+    let versions = library_scripts(library_id).await?;
+    let running = versions
+        .iter()
+        .find(|v| v.pinned)
+        .or_else(|| versions.iter().find(|v| v.current))
+        .map(|v| v.version)
+        .unwrap_or_default();
+
+    let previous = versions
+        .iter()
+        .find(|v| v.version < running)
+        .ok_or_else(|| ApiError::conflict("No earlier script version"))?;
+
+    pin_script(state, library_id, Some(previous.id)).await
+}
+
+async fn rollback_library_script(
+    State(state): State<AppState>,
+    Path(id): Path<i64>,
+) -> ApiResult<Library> {
+    Ok(Json(rollback_script(&state, id).await?))
+}
+
+async fn me(Extension(user): Extension<AuthUser>) -> ApiResult<AuthUser> {
+    Ok(Json(user))
+}
+
+#[derive(Deserialize)]
+pub struct PasswordChange {
+    current_password: String,
+    new_password: String,
+}
+
+// Other sessions of the user are closed, API tokens stay valid
+async fn change_password(
+    Extension(user): Extension<AuthUser>,
+    headers: HeaderMap,
+    Json(req): Json<PasswordChange>,
+) -> Result<StatusCode, ApiError> {
+    if req.new_password.len() < 8 {
+        return Err(ApiError::new(StatusCode::BAD_REQUEST, "Password needs at least 8 characters"));
+    }
+
+    let session = super::auth::session_cookie(&headers);
+    let changed = auth::change_password(user.id, &req.current_password, &req.new_password, session.as_deref())
+        .await
+        .map_err(|e| ApiError::new(StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))?;
+    if !changed {
+        return Err(ApiError::new(StatusCode::FORBIDDEN, "Current password is wrong"));
+    }
+
+    Ok(StatusCode::NO_CONTENT)
+}
+
+#[derive(Serialize)]
+pub struct UserEntry {
+    id: i64,
+    username: String,
+    role: String,
+    created_at: NaiveDateTime,
+}
+
+async fn list_users() -> ApiResult<Vec<UserEntry>> {
+    let users = sqlx::query_as!(
+        UserEntry,
+        r#"
+        SELECT id AS "id!", username, role, created_at
+        FROM users
+        ORDER BY username
+        "#
+    )
+    .fetch_all(pool())
+    .await?;
+
+    Ok(Json(users))
+}
+
+#[derive(Deserialize)]
+pub struct NewUser {
+    username: String,
+    password: String,
+    role: String,
+}
+
+async fn create_user(Json(req): Json<NewUser>) -> Result<impl IntoResponse, ApiError> {
+    let role = Role::parse(&req.role)
+        .ok_or_else(|| ApiError::new(StatusCode::BAD_REQUEST, "Role must be admin or readonly"))?
+        .as_str();
+
+    if req.username.is_empty() || req.password.len() < 8 {
+        return Err(ApiError::new(StatusCode::BAD_REQUEST, "Username is required and password needs at least 8 characters"));
+    }
+
+    let hash = auth::hash_password(req.password)
+        .await
+        .map_err(|e| ApiError::new(StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))?;
+
+    let id = sqlx::query!(
+        r#"
+        INSERT INTO users (username, password_hash, role)
+        VALUES (?, ?, ?)
+        "#,
+        req.username,
+        hash,
+        role
+    )
+    .execute(pool())
+    .await?
+    .last_insert_rowid();
+
+    Ok((StatusCode::CREATED, Json(json!({ "id": id, "username": req.username, "role": role }))))
+}
+
+async fn delete_user(
+    Extension(user): Extension<AuthUser>,
+    Path(id): Path<i64>,
+) -> Result<StatusCode, ApiError> {
+    if user.id == id {
+        return Err(ApiError::conflict("Cannot delete your own account"));
+    }
+
+    let deleted = sqlx::query!("DELETE FROM users WHERE id = ?", id)
+        .execute(pool())
+        .await?;
+
+    if deleted.rows_affected() == 0 {
+        return Err(ApiError::not_found("User"));
+    }
+
+    Ok(StatusCode::NO_CONTENT)
+}
+
+#[derive(Serialize)]
+pub struct TokenEntry {
+    id: i64,
+    name: String,
+    created_at: NaiveDateTime,
+    last_used_at: Option<NaiveDateTime>,
+}
+
+async fn list_tokens(Extension(user): Extension<AuthUser>) -> ApiResult<Vec<TokenEntry>> {
+    let tokens = sqlx::query_as!(
+        TokenEntry,
+        r#"
+        SELECT id AS "id!", name, created_at, last_used_at
+        FROM api_tokens
+        WHERE user_id = ?
+        ORDER BY created_at
+        "#,
+        user.id
+    )
+    .fetch_all(pool())
+    .await?;
+
+    Ok(Json(tokens))
+}
+
+#[derive(Deserialize)]
+pub struct NewToken {
+    name: String,
+}
+
+// The plain token is only returned here, the database keeps its hash
+async fn create_token(
+    Extension(user): Extension<AuthUser>,
+    Json(req): Json<NewToken>,
+) -> Result<impl IntoResponse, ApiError> {
+    let token = auth::random_token();
+    let token_hash = auth::hash_token(&token);
+
+    let id = sqlx::query!(
+        r#"
+        INSERT INTO api_tokens (name, token_hash, user_id)
+        VALUES (?, ?, ?)
+        "#,
+        req.name,
+        token_hash,
+        user.id
+    )
+    .execute(pool())
+    .await?
+    .last_insert_rowid();
+
+    Ok((StatusCode::CREATED, Json(json!({ "id": id, "name": req.name, "token": token }))))
+}
+
+async fn delete_token(
+    Extension(user): Extension<AuthUser>,
+    Path(id): Path<i64>,
+) -> Result<StatusCode, ApiError> {
+    let deleted = sqlx::query!(
+        "DELETE FROM api_tokens WHERE id = ? AND user_id = ?",
+        id,
+        user.id
+    )
+    .execute(pool())
+    .await?;
+
+    if deleted.rows_affected() == 0 {
+        return Err(ApiError::not_found("Token"));
+    }
+
+    Ok(StatusCode::NO_CONTENT)
+}
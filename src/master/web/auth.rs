@@ -0,0 +1,237 @@
+use axum::{
+    Extension,
+    Form,
+    extract::{Request, State},
+    http::{HeaderMap, Method, StatusCode, header},
+    middleware::Next,
+    response::{IntoResponse, Redirect, Response},
+};
+use maud::{html, Markup, DOCTYPE};
+use serde::Deserialize;
+use tracing::{error, warn};
+
+use crate::master::auth::{self, AuthUser, Role};
+use super::AppState;
+use super::api::ApiError;
+
+pub const SESSION_COOKIE: &str = "trahl_session";
+
+// Routes reachable without being logged in
+const PUBLIC_PATHS: &[&str] = &["/login", "/favicon.ico"];
+const PUBLIC_PREFIXES: &[&str] = &["/static/"];
+
+fn is_public(path: &str) -> bool {
+    PUBLIC_PATHS.contains(&path)
+        || PUBLIC_PREFIXES.iter().any(|p| path.starts_with(p))
+}
+
+// The caller's own account and tokens, reachable by every role
+const SELF_SERVICE_PREFIXES: &[&str] = &["/api/v1/me", "/api/v1/tokens"];
+
+fn is_api(path: &str) -> bool {
+    path.starts_with("/api/")
+}
+
+pub fn session_cookie(headers: &HeaderMap) -> Option<String> {
+    headers
+        .get_all(header::COOKIE)
+        .iter()
+        .filter_map(|v| v.to_str().ok())
+        .flat_map(|v| v.split(';'))
+        .filter_map(|kv| kv.trim().split_once('='))
+        .find(|(k, _)| *k == SESSION_COOKIE)
+        .map(|(_, v)| v.to_string())
+}
+
+fn bearer_token(headers: &HeaderMap) -> Option<String> {
+    headers
+        .get(header::AUTHORIZATION)
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.strip_prefix("Bearer "))
+        .map(|v| v.trim().to_string())
+}
+
+async fn authenticate(headers: &HeaderMap) -> anyhow::Result<Option<AuthUser>> {
+    if let Some(token) = bearer_token(headers) {
+        return auth::user_from_api_token(&token).await;
+    }
+
+    if let Some(token) = session_cookie(headers) {
+        return auth::user_from_session(&token).await;
+    }
+
+    Ok(None)
+}
+
+// Read-only users may only use safe methods, apart from managing their own
+// password and tokens
+fn allowed(role: Role, method: &Method, path: &str) -> bool {
+    match role {
+        Role::Admin => true,
+        Role::ReadOnly => matches!(*method, Method::GET | Method::HEAD | Method::OPTIONS)
+            || SELF_SERVICE_PREFIXES.iter().any(|p| path == *p || path.starts_with(&format!("{}/", p))),
+    }
+}
+
+pub async fn require_auth(mut req: Request, next: Next) -> Response {
+    let path = req.uri().path().to_string();
+    if is_public(&path) {
+        return next.run(req).await;
+    }
+
+    let user = match authenticate(req.headers()).await {
+        Ok(user) => user,
+        Err(e) => {
+            error!("Authentication failed: {}", e);
+            return StatusCode::INTERNAL_SERVER_ERROR.into_response();
+        }
+    };
+
+    let Some(user) = user else {
+        if is_api(&path) {
+            return ApiError::new(StatusCode::UNAUTHORIZED, "Authentication required").into_response();
+        }
+        return Redirect::to("/login").into_response();
+    };
+
+    if !allowed(user.role, req.method(), &path) {
+        warn!("User {} denied {} {}", user.username, req.method(), path);
+        return ApiError::new(StatusCode::FORBIDDEN, "Insufficient permissions").into_response();
+    }
+
+    req.extensions_mut().insert(user);
+    next.run(req).await
+}
+
+pub async fn require_admin(
+    Extension(user): Extension<AuthUser>,
+    req: Request,
+    next: Next,
+) -> Response {
+    if user.role != Role::Admin {
+        return ApiError::new(StatusCode::FORBIDDEN, "Administrator role required").into_response();
+    }
+    next.run(req).await
+}
+
+#[derive(Deserialize)]
+pub struct LoginForm {
+    username: String,
+    password: String,
+}
+
+pub async fn login_page() -> Markup {
+    login_markup(None)
+}
+
+pub async fn login(
+    State(state): State<AppState>,
+    Form(form): Form<LoginForm>,
+) -> Response {
+    match auth::login(&form.username, &form.password, state.session_ttl_hours).await {
+        Ok(Some(token)) => {
+            let cookie = format!(
+                "{}={}; Path=/; HttpOnly; SameSite=Strict; Max-Age={}",
+                SESSION_COOKIE,
+                token,
+                u64::from(state.session_ttl_hours) * 3600
+            );
+            (
+                [(header::SET_COOKIE, cookie)],
+                Redirect::to("/"),
+            ).into_response()
+        },
+        Ok(None) => {
+            warn!("Failed login attempt for user {}", form.username);
+            (StatusCode::UNAUTHORIZED, login_markup(Some("Invalid username or password"))).into_response()
+        },
+        Err(e) => {
+            error!("Login failed: {}", e);
+            StatusCode::INTERNAL_SERVER_ERROR.into_response()
+        }
+    }
+}
+
+pub async fn logout(headers: HeaderMap) -> Response {
+    if let Some(token) = session_cookie(&headers)
+        && let Err(e) = auth::logout(&token).await {
+        error!("Logout failed: {}", e);
+    }
+
+    let cookie = format!("{}=; Path=/; HttpOnly; SameSite=Strict; Max-Age=0", SESSION_COOKIE);
+    (
+        [(header::SET_COOKIE, cookie)],
+        Redirect::to("/login"),
+    ).into_response()
+}
+
+fn login_markup(error: Option<&str>) -> Markup {
+    html! {
+        (DOCTYPE)
+        html lang="en" {
+            head {
+                meta charset="UTF-8";
+                meta name="viewport" content="width=device-width, initial-scale=1.0";
+                title { "Trahl - Login" }
+                link rel="stylesheet" href="/static/style.css" {}
+            }
+            body {
+                div.mdi-window.modal
+                    style="left: 50%; top: 50%; width: 320px; height: 220px; transform: translate(-50%, -50%);" {
+                    div.title-bar.no-move {
+                        span.title-text { "Log on to Trahl" }
+                    }
+                    div.window-content {
+                        form method="post" action="/login" style="padding: 16px; display: flex; flex-direction: column; gap: 8px;" {
+                            label { "Username" input type="text" name="username" autofocus required; }
+                            label { "Password" input type="password" name="password" required; }
+                            @if let Some(error) = error {
+                                div style="color: #ff0000; font-size: 11px;" { (error) }
+                            }
+                            div style="display: flex; justify-content: center;" {
+                                button.button type="submit" { "Ok" }
+                            }
+                        }
+                    }
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_session_cookie() {
+        let mut headers = HeaderMap::new();
+        headers.insert(header::COOKIE, "a=1; trahl_session=abc; b=2".parse().unwrap());
+        assert_eq!(session_cookie(&headers), Some("abc".to_string()));
+
+        let mut headers = HeaderMap::new();
+        headers.insert(header::COOKIE, "a=1".parse().unwrap());
+        assert_eq!(session_cookie(&headers), None);
+    }
+
+    #[test]
+    fn test_readonly_methods() {
+        assert!(allowed(Role::ReadOnly, &Method::GET, "/api/v1/jobs"));
+        assert!(!allowed(Role::ReadOnly, &Method::POST, "/api/v1/jobs/1/requeue"));
+        assert!(!allowed(Role::ReadOnly, &Method::DELETE, "/api/v1/users/2"));
+        assert!(allowed(Role::Admin, &Method::POST, "/api/v1/jobs/1/requeue"));
+
+        assert!(allowed(Role::ReadOnly, &Method::POST, "/api/v1/tokens"));
+        assert!(allowed(Role::ReadOnly, &Method::DELETE, "/api/v1/tokens/3"));
+        assert!(allowed(Role::ReadOnly, &Method::POST, "/api/v1/me/password"));
+        assert!(!allowed(Role::ReadOnly, &Method::POST, "/api/v1/tokensx"));
+    }
+
+    #[test]
+    fn test_public_paths() {
+        assert!(is_public("/login"));
+        assert!(is_public("/static/style.css"));
+        assert!(!is_public("/"));
+        assert!(!is_public("/api/v1/jobs"));
+    }
+}
@@ -33,10 +33,16 @@ fn taskbar() -> Markup {
                     li.start-menu-item data-window="window-queue" { "Queue" }
                     li.start-menu-item data-window="window-activity" { "Activity" }
                     li.start-menu-item data-window="window-control" { "Control" }
+                    li.start-menu-item data-window="window-workers" { "Workers" }
+                    li.start-menu-item data-window="window-recycle" { "Recycle Bin" }
+                    li.start-menu-item data-window="window-scripts" { "Scripts" }
                     li.start-menu-item data-window="window-syslog" { "System Logs" }
                 }
             }
             div.taskbar-items { }
+            form.taskbar-logout method="post" action="/logout" {
+                button.button type="submit" { "Log off" }
+            }
             div.taskbar-clock
                 id="clock"
                 hx-ext="sse"
@@ -0,0 +1,92 @@
+use axum::{
+    extract::Path,
+    http::StatusCode,
+    response::{IntoResponse, Response},
+};
+use maud::{html, Markup};
+use tracing::error;
+
+use crate::master::db::{self, model::RecycleEntry};
+use super::api::recycle_action;
+use super::window;
+
+pub async fn window() -> Markup {
+    let content = window::create_content(html! {
+        table.table {
+            thead {
+                tr {
+                    th { "ORIGINAL" }
+                    th { "SIZE" }
+                    th { "RECYCLED" }
+                    th { "" }
+                }
+            }
+            tbody #recycle-tbody {
+                (rows().await)
+            }
+        }
+    });
+
+    window::create_window(
+        "window-recycle",
+        "Recycle Bin",
+        "left: 200px; top: 160px; width: 620px; height: 300px;",
+        true,
+        content
+    )
+}
+
+async fn rows() -> Markup {
+    match db::list_recycled(None).await {
+        Ok(entries) if entries.is_empty() => html! {
+            tr { td colspan="4" { "The recycle bin is empty" } }
+        },
+        Ok(entries) => html! {
+            @for e in &entries {
+                (row(e))
+            }
+        },
+        Err(e) => {
+            error!("Cannot list recycled files: {}", e);
+            html! { tr { td colspan="4" { "Cannot list recycled files" } } }
+        }
+    }
+}
+
+fn row(e: &RecycleEntry) -> Markup {
+    let action = |name: &str, label: &str| html! {
+        button.button
+            hx-post=(format!("/windows/window-recycle/{}/{}", e.id, name))
+            hx-target="#recycle-tbody" { (label) }
+    };
+
+    html! {
+        tr {
+            td title=(e.recycled_path) { (e.original_path) }
+            td {
+                @if let Some(size) = e.size {
+                    (format!("{:.1} MB", size as f64 / (1024.0 * 1024.0)))
+                }
+            }
+            td { (e.created_at.format("%Y-%m-%d %H:%M")) }
+            td {
+                (action("restore", "Restore"))
+                (action("delete", "Delete"))
+            }
+        }
+    }
+}
+
+pub async fn action(Path((id, action)): Path<(i64, String)>) -> Response {
+    let result = match action.as_str() {
+        "restore" => recycle_action(id, true).await,
+        "delete" => recycle_action(id, false).await,
+        _ => return StatusCode::NOT_FOUND.into_response(),
+    };
+
+    if let Err(e) = result {
+        return e.into_response();
+    }
+
+    rows().await.into_response()
+}
@@ -0,0 +1,127 @@
+use axum::{
+    extract::{Path, State},
+    http::StatusCode,
+    response::{IntoResponse, Response},
+};
+use maud::{html, Markup};
+use tracing::error;
+
+use crate::master::db;
+use super::api::{diff_script, library_scripts, pin_script, ScriptVersion};
+use super::window;
+use super::AppState;
+
+pub async fn window() -> Markup {
+    let content = window::create_content(html! {
+        div #scripts-body {
+            (body().await)
+        }
+        div #scripts-diff { }
+    });
+
+    window::create_window(
+        "window-scripts",
+        "Scripts",
+        "left: 240px; top: 120px; width: 640px; height: 420px;",
+        true,
+        content
+    )
+}
+
+// One table of versions per library, newest first
+async fn body() -> Markup {
+    let libraries = match db::library_infos().await {
+        Ok(libraries) => libraries,
+        Err(e) => {
+            error!("Cannot list libraries: {}", e);
+            return html! { div { "Cannot list libraries" } };
+        }
+    };
+
+    html! {
+        @for library in &libraries {
+            h3 { (library.name) }
+            table.table {
+                thead {
+                    tr {
+                        th { "VERSION" }
+                        th { "HASH" }
+                        th { "UPDATED" }
+                        th { "JOBS" }
+                        th { "" }
+                        th { "" }
+                    }
+                }
+                tbody {
+                    @match library_scripts(library.id).await {
+                        Ok(versions) => {
+                            @for v in &versions {
+                                (row(library.id, v))
+                            }
+                        },
+                        Err(_) => tr { td colspan="6" { "Cannot list script versions" } },
+                    }
+                }
+            }
+        }
+    }
+}
+
+fn row(library_id: i64, v: &ScriptVersion) -> Markup {
+    let action = |name: &str, label: &str, target: &str| html! {
+        button.button
+            hx-post=(format!("/windows/window-scripts/{}/{}/{}", library_id, v.id, name))
+            hx-target=(target) { (label) }
+    };
+
+    html! {
+        tr {
+            td { (format!("{} v{}", v.name, v.version)) }
+            td title=(v.hash) { (v.hash.get(..8).unwrap_or(&v.hash)) }
+            td {
+                @if let Some(updated_at) = v.updated_at {
+                    (updated_at.format("%Y-%m-%d %H:%M"))
+                }
+            }
+            td { (v.jobs) }
+            td {
+                @if v.pinned { "pinned" } @else if v.current { "current" }
+            }
+            td {
+                @if v.pinned {
+                    (action("unpin", "Unpin", "#scripts-body"))
+                } @else {
+                    (action("pin", "Pin", "#scripts-body"))
+                }
+                (action("diff", "Diff", "#scripts-diff"))
+            }
+        }
+    }
+}
+
+pub async fn action(
+    State(state): State<AppState>,
+    Path((library_id, script_id, action)): Path<(i64, i64, String)>,
+) -> Response {
+    let result = match action.as_str() {
+        "pin" => pin_script(&state, library_id, Some(script_id)).await,
+        "unpin" => pin_script(&state, library_id, None).await,
+        "diff" => {
+            return match diff_script(script_id, None).await {
+                Ok(diff) => html! {
+                    pre style="font-size: 11px; padding: 4px; overflow: auto;" {
+                        @if diff.diff.is_empty() { "No changes" } @else { (diff.diff) }
+                    }
+                }.into_response(),
+                Err(e) => e.into_response(),
+            };
+        },
+        _ => return StatusCode::NOT_FOUND.into_response(),
+    };
+
+    if let Err(e) = result {
+        return e.into_response();
+    }
+
+    body().await.into_response()
+}
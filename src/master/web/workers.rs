@@ -0,0 +1,126 @@
+use axum::{
+    extract::{Path, State},
+    http::StatusCode,
+    response::{IntoResponse, Response},
+};
+use maud::{html, Markup};
+use tracing::error;
+
+use crate::master::auth;
+use crate::master::db::{self, model::Worker};
+use super::api::set_worker_status;
+use super::window;
+use super::AppState;
+
+pub async fn window() -> Markup {
+    let content = window::create_content(html! {
+        div #workers-notice {}
+        table.table {
+            thead {
+                tr {
+                    th { "IDENTIFIER" }
+                    th { "STATUS" }
+                    th { "TOKEN" }
+                    th { "LAST SEEN" }
+                    th { "" }
+                }
+            }
+            tbody #workers-tbody {
+                (rows().await)
+            }
+        }
+    });
+
+    window::create_window(
+        "window-workers",
+        "Workers",
+        "left: 160px; top: 120px; width: 560px; height: 300px;",
+        true,
+        content
+    )
+}
+
+async fn rows() -> Markup {
+    match db::list_workers().await {
+        Ok(workers) => html! {
+            @for w in &workers {
+                (row(w))
+            }
+        },
+        Err(e) => {
+            error!("Cannot list workers: {}", e);
+            html! { tr { td colspan="5" { "Cannot list workers" } } }
+        }
+    }
+}
+
+fn row(w: &Worker) -> Markup {
+    let badge = match w.status.as_str() {
+        db::WORKER_APPROVED => "status-success",
+        db::WORKER_PENDING => "status-warning",
+        _ => "status-error",
+    };
+    let action = |name: &str, label: &str| html! {
+        button.button
+            hx-post=(format!("/windows/window-workers/{}/{}", w.id, name))
+            hx-target="#workers-tbody" { (label) }
+    };
+
+    html! {
+        tr {
+            td { (w.identifier) }
+            td { span.status-badge.(badge) { (w.status.to_uppercase()) } }
+            td { @if w.auth_token.is_some() { "yes" } @else { "psk" } }
+            td {
+                @if let Some(ts) = w.last_conn_at {
+                    (ts.format("%Y-%m-%d %H:%M"))
+                }
+            }
+            td {
+                @if w.status != db::WORKER_APPROVED { (action("approve", "Approve")) }
+                @if w.status != db::WORKER_REVOKED { (action("revoke", "Revoke")) }
+                (action("token", "New token"))
+            }
+        }
+    }
+}
+
+pub async fn action(
+    State(state): State<AppState>,
+    Path((id, action)): Path<(i64, String)>,
+) -> Response {
+    let mut notice = None;
+
+    let result = match action.as_str() {
+        "approve" => set_worker_status(&state, id, db::WORKER_APPROVED).await,
+        "revoke" => set_worker_status(&state, id, db::WORKER_REVOKED).await,
+        "token" => {
+            let token = auth::random_token();
+            match db::set_worker_token(id, Some(&token)).await {
+                Ok(_) => {
+                    notice = Some(token);
+                    Ok(())
+                },
+                Err(e) => Err(e.into()),
+            }
+        },
+        _ => return StatusCode::NOT_FOUND.into_response(),
+    };
+
+    if let Err(e) = result {
+        return e.into_response();
+    }
+
+    // The new token is only shown once, set it as auth_key on the worker
+    html! {
+        (rows().await)
+        div #workers-notice hx-swap-oob="true" {
+            @if let Some(token) = notice {
+                div style="padding: 4px; font-size: 11px;" {
+                    "New worker token, set it as auth_key: "
+                    code { (token) }
+                }
+            }
+        }
+    }.into_response()
+}
@@ -1,13 +1,76 @@
+pub mod auth;
+pub mod crypto;
 pub mod zmq_helper;
 
 use std::time::{Duration, SystemTime, UNIX_EPOCH};
 use std::collections::HashMap;
+use anyhow::Result;
 use bincode::{Decode, Encode};
+use serde::Serialize;
+
+// Bump whenever the encoding of any Message changes, the golden tests
+// below fail until the new encoding is recorded with UPDATE_GOLDEN=1.
+// Hello must stay the first variant with protocol_version as the first
+// field of WorkerInfo, and Reject must keep its position, so that
+// incompatible peers can still be told why they are refused.
+pub const PROTOCOL_VERSION: u16 = 1;
+pub const MIN_PROTOCOL_VERSION: u16 = 1;
+
+// Optional capabilities, the intersection of both sides is used
+pub const FEATURE_CANCEL_JOB: &str = "cancel_job";
+pub const FEATURE_CONFIG_UPDATE: &str = "config_update";
+pub const FEATURE_CAPACITY_UPDATE: &str = "capacity_update";
+pub const FEATURE_TOOL_REPORT: &str = "tool_report";
+pub const FEATURE_TRUSTED_JOBS: &str = "trusted_jobs";
+pub const FEATURE_JOB_OUTPUTS: &str = "job_outputs";
+pub const FEATURE_RECYCLE_BIN: &str = "recycle_bin";
+pub const FEATURE_DRY_RUN: &str = "dry_run";
+pub const FEATURES: &[&str] = &[
+    FEATURE_CANCEL_JOB,
+    FEATURE_CONFIG_UPDATE,
+    FEATURE_CAPACITY_UPDATE,
+    FEATURE_TOOL_REPORT,
+    FEATURE_TRUSTED_JOBS,
+    FEATURE_JOB_OUTPUTS,
+    FEATURE_RECYCLE_BIN,
+    FEATURE_DRY_RUN,
+];
+
+pub fn supported_version(version: u16) -> bool {
+    (MIN_PROTOCOL_VERSION..=PROTOCOL_VERSION).contains(&version)
+}
+
+pub fn local_features() -> Vec<String> {
+    FEATURES.iter().map(|f| f.to_string()).collect()
+}
+
+pub fn negotiate_features(remote: &[String]) -> Vec<String> {
+    FEATURES
+        .iter()
+        .filter(|f| remote.iter().any(|r| r == *f))
+        .map(|f| f.to_string())
+        .collect()
+}
+
+pub fn encode(msg: &Message) -> Result<Vec<u8>> {
+    Ok(bincode::encode_to_vec(msg, bincode::config::standard())?)
+}
+
+pub fn decode(payload: &[u8]) -> Result<Message> {
+    Ok(bincode::decode_from_slice(payload, bincode::config::standard())?.0)
+}
+
+// Reads the protocol version of a Hello that can't be decoded in full
+pub fn peek_hello_version(payload: &[u8]) -> Option<u16> {
+    let ((variant, version), _): ((u32, u16), usize) =
+        bincode::decode_from_slice(payload, bincode::config::standard()).ok()?;
+    (variant == 0).then_some(version)
+}
 
 #[derive(Debug, Encode, Decode, Clone, PartialEq)]
 pub enum Message {
     Hello(WorkerInfo),              // Worker -> Master, with worker capabilities
-    HelloAck,                       // Master -> Worker
+    HelloAck(MasterInfo),           // Master -> Worker, with negotiated features
     CancelJobs,                     // Master -> Worker, cancel all running/pending jobs
     Ping,                           // Master -> Worker
     Pong,                           // Worker -> Master
@@ -19,6 +82,16 @@ pub enum Message {
     FileTransferStatus,             // Worker -> Master
 */
     Bye,
+    CancelJob(i64),                 // Master -> Worker, cancel a single job
+    AuthChallenge(Vec<u8>),         // Master -> Worker, nonce to be signed
+    AuthResponse(Vec<u8>),          // Worker -> Master, HMAC of nonce and identifier
+    Reject(String),                 // Master -> Worker, connection refused with reason
+    KeyExchange(Vec<u8>),           // Both ways, ephemeral X25519 public key
+    Sealed(SealedMsg),              // Both ways, encrypted Message once keys are exchanged
+    ConfigUpdate(ConfigUpdateMsg),  // Master -> Worker, after connecting and on every reload
+    Capacity(u8),                   // Worker -> Master, simultaneous jobs after a reload
+    ToolReport(Vec<ToolInfo>),      // Worker -> Master, external tools self-check
+    TrustedJob(JobMsg),             // Master -> Worker, job whose script runs without sandbox
 }
 
 impl Message {
@@ -26,8 +99,8 @@ impl Message {
         Self::Hello(wi)
     }
     
-    pub fn ack() -> Self {
-        Self::HelloAck
+    pub fn ack(mi: MasterInfo) -> Self {
+        Self::HelloAck(mi)
     }
     
     pub fn cancel_jobs() -> Self {
@@ -42,6 +115,42 @@ impl Message {
         Self::Job(jm)
     }
     
+    pub fn cancel_job(job_id: i64) -> Self {
+        Self::CancelJob(job_id)
+    }
+    
+    pub fn auth_challenge(nonce: Vec<u8>) -> Self {
+        Self::AuthChallenge(nonce)
+    }
+    
+    pub fn auth_response(mac: Vec<u8>) -> Self {
+        Self::AuthResponse(mac)
+    }
+    
+    pub fn reject(reason: &str) -> Self {
+        Self::Reject(reason.to_string())
+    }
+    
+    pub fn key_exchange(public_key: Vec<u8>) -> Self {
+        Self::KeyExchange(public_key)
+    }
+    
+    pub fn config_update(cu: ConfigUpdateMsg) -> Self {
+        Self::ConfigUpdate(cu)
+    }
+
+    pub fn capacity(simultaneous_jobs: u8) -> Self {
+        Self::Capacity(simultaneous_jobs)
+    }
+
+    pub fn tool_report(tools: Vec<ToolInfo>) -> Self {
+        Self::ToolReport(tools)
+    }
+
+    pub fn trusted_job(jm: JobMsg) -> Self {
+        Self::TrustedJob(jm)
+    }
+    
     pub fn ping() -> Self {
         Self::Ping
     }
@@ -57,9 +166,54 @@ impl Message {
 
 #[derive(Debug, Encode, Decode, Clone, PartialEq)]
 pub struct WorkerInfo {
+    pub protocol_version: u16,      // must stay the first field
     pub identifier: String,
     pub simultaneous_jobs: u8,
     pub sw_version: String,
+    pub features: Vec<String>,
+}
+
+impl WorkerInfo {
+    pub fn supports(&self, feature: &str) -> bool {
+        self.features.iter().any(|f| f == feature)
+    }
+}
+
+#[derive(Debug, Encode, Decode, Clone, PartialEq)]
+pub struct MasterInfo {
+    pub protocol_version: u16,
+    pub sw_version: String,
+    pub features: Vec<String>,
+}
+
+// Settings the master pushes to workers. Running jobs keep what they were
+// started with, new jobs get the scripts and variables current at dispatch.
+#[derive(Debug, Encode, Decode, Clone, PartialEq)]
+pub struct ConfigUpdateMsg {
+    pub max_jobs: Option<u8>,
+    pub libraries: Vec<LibraryInfo>,
+}
+
+#[derive(Debug, Encode, Decode, Clone, PartialEq)]
+pub struct LibraryInfo {
+    pub id: i64,
+    pub name: String,
+    pub enabled: bool,
+    pub script_hash: String,
+}
+
+#[derive(Debug, Encode, Decode, Clone, PartialEq, Serialize)]
+pub struct ToolInfo {
+    pub name: String,
+    pub path: String,
+    pub version: Option<String>,
+    pub error: Option<String>,
+}
+
+#[derive(Debug, Encode, Decode, Clone, PartialEq)]
+pub struct SealedMsg {
+    pub counter: u64,
+    pub ciphertext: Vec<u8>,
 }
 
 #[derive(Debug, Encode, Decode, Clone, PartialEq)]
@@ -124,6 +278,18 @@ impl JobStatusMsg {
     pub fn job_done(job_id: i64, file: Option<String>) -> Self {
         JobStatusMsg::new(job_id, JobStatus::Done {file})
     }
+
+    pub fn job_outputs(job_id: i64, outputs: Vec<JobOutput>) -> Self {
+        JobStatusMsg::new(job_id, JobStatus::Outputs(outputs))
+    }
+
+    pub fn job_recycled(job_id: i64, files: Vec<RecycledFile>) -> Self {
+        JobStatusMsg::new(job_id, JobStatus::Recycled(files))
+    }
+
+    pub fn job_dry_run(job_id: i64, outputs: Vec<JobOutput>) -> Self {
+        JobStatusMsg::new(job_id, JobStatus::DryRun(outputs))
+    }
 }
 
 #[derive(Debug, Encode, Decode, Clone, PartialEq)]
@@ -138,6 +304,24 @@ pub enum JobStatus {
     Done {
         file: Option<String>,
     },
+    Outputs(Vec<JobOutput>),    // Sent before Done, only to masters with FEATURE_JOB_OUTPUTS
+    Recycled(Vec<RecycledFile>), // Only to masters with FEATURE_RECYCLE_BIN
+    DryRun(Vec<JobOutput>),     // Ends a dry run instead of Done, with the would-be outputs
+}
+
+#[derive(Debug, Encode, Decode, Clone, PartialEq)]
+pub struct JobOutput {
+    pub path: String,           // Master side path
+    pub role: String,
+    pub size: Option<u64>,
+}
+
+// A file replaced by an output and kept in the library recycle dir
+#[derive(Debug, Encode, Decode, Clone, PartialEq)]
+pub struct RecycledFile {
+    pub original: String,       // Master side paths
+    pub recycled: String,
+    pub size: Option<u64>,
 }
 
 #[derive(Debug, Encode, Decode, Clone, PartialEq)]
@@ -167,3 +351,227 @@ pub struct FileChunkMsg {
     chunk: u64,
 }
 */
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::path::PathBuf;
+
+    // Exhaustive on purpose, a new variant fails to build until it gets a sample
+    fn variant_name(msg: &Message) -> &'static str {
+        match msg {
+            Message::Hello(_) => "hello",
+            Message::HelloAck(_) => "hello_ack",
+            Message::CancelJobs => "cancel_jobs",
+            Message::Ping => "ping",
+            Message::Pong => "pong",
+            Message::Job(_) => "job",
+            Message::JobStatus(msg) => match msg.status {
+                // Appended variants, sampled on their own so a reorder shows up
+                JobStatus::Outputs(_) => "job_status_outputs",
+                JobStatus::Recycled(_) => "job_status_recycled",
+                JobStatus::DryRun(_) => "job_status_dry_run",
+                _ => "job_status",
+            },
+            Message::Bye => "bye",
+            Message::CancelJob(_) => "cancel_job",
+            Message::AuthChallenge(_) => "auth_challenge",
+            Message::AuthResponse(_) => "auth_response",
+            Message::Reject(_) => "reject",
+            Message::KeyExchange(_) => "key_exchange",
+            Message::Sealed(_) => "sealed",
+            Message::ConfigUpdate(_) => "config_update",
+            Message::Capacity(_) => "capacity",
+            Message::ToolReport(_) => "tool_report",
+            Message::TrustedJob(_) => "trusted_job",
+        }
+    }
+
+    fn samples() -> Vec<Message> {
+        vec![
+            Message::hello(WorkerInfo {
+                protocol_version: PROTOCOL_VERSION,
+                identifier: "worker".to_string(),
+                simultaneous_jobs: 2,
+                sw_version: "0.1.0".to_string(),
+                features: vec![FEATURE_CANCEL_JOB.to_string()],
+            }),
+            Message::ack(MasterInfo {
+                protocol_version: PROTOCOL_VERSION,
+                sw_version: "0.1.0".to_string(),
+                features: vec![FEATURE_CANCEL_JOB.to_string()],
+            }),
+            Message::cancel_jobs(),
+            Message::ping(),
+            Message::pong(),
+            Message::job(JobMsg {
+                job_id: 42,
+                script: "return 1".to_string(),
+                vars: HashMap::from([("KEY".to_string(), "value".to_string())]),
+                file: "/lib/movie.mkv".to_string(),
+                library_root: "/lib".to_string(),
+                dst_dir: "/out".to_string(),
+            }),
+            Message::job_status(JobStatusMsg {
+                timestamp: 1700000000,
+                job_id: 42,
+                status: JobStatus::Progress(TranscodeProgress {
+                    frame: Some(100),
+                    fps: Some(25),
+                    cur_time: Some(Duration::from_secs(4)),
+                    percentage: Some(50.0),
+                    eta: Some(Duration::from_secs(4)),
+                    bitrate: Some("1000kbits/s".to_string()),
+                    speed: Some(1.5),
+                }),
+            }),
+            Message::job_status(JobStatusMsg {
+                timestamp: 1700000000,
+                job_id: 42,
+                status: JobStatus::Outputs(vec![
+                    JobOutput {
+                        path: "/out/movie.mkv".to_string(),
+                        role: "main".to_string(),
+                        size: Some(1000),
+                    },
+                    JobOutput {
+                        path: "/out/movie.srt".to_string(),
+                        role: "sidecar".to_string(),
+                        size: None,
+                    },
+                ]),
+            }),
+            Message::job_status(JobStatusMsg {
+                timestamp: 1700000000,
+                job_id: 42,
+                status: JobStatus::Recycled(vec![RecycledFile {
+                    original: "/lib/movie.mkv".to_string(),
+                    recycled: "/lib/.recycle/42/movie.mkv".to_string(),
+                    size: Some(2000),
+                }]),
+            }),
+            Message::job_status(JobStatusMsg {
+                timestamp: 1700000000,
+                job_id: 42,
+                status: JobStatus::DryRun(vec![JobOutput {
+                    path: "/out/movie.mkv".to_string(),
+                    role: "main".to_string(),
+                    size: Some(1000),
+                }]),
+            }),
+            Message::bye(),
+            Message::cancel_job(42),
+            Message::auth_challenge(vec![1, 2, 3, 4]),
+            Message::auth_response(vec![5, 6, 7, 8]),
+            Message::reject("Protocol version 0 is not supported"),
+            Message::key_exchange(vec![9; 32]),
+            Message::Sealed(SealedMsg {
+                counter: 7,
+                ciphertext: vec![10, 11, 12],
+            }),
+            Message::config_update(ConfigUpdateMsg {
+                max_jobs: Some(2),
+                libraries: vec![LibraryInfo {
+                    id: 1,
+                    name: "Movies".to_string(),
+                    enabled: true,
+                    script_hash: "0123456789abcdef".to_string(),
+                }],
+            }),
+            Message::capacity(3),
+            Message::tool_report(vec![
+                ToolInfo {
+                    name: "ffmpeg".to_string(),
+                    path: "/usr/bin/ffmpeg".to_string(),
+                    version: Some("ffmpeg version 6.1.1".to_string()),
+                    error: None,
+                },
+                ToolInfo {
+                    name: "handbrake".to_string(),
+                    path: "handbrake".to_string(),
+                    version: None,
+                    error: Some("No such file or directory (os error 2)".to_string()),
+                },
+            ]),
+            Message::trusted_job(JobMsg {
+                job_id: 43,
+                script: "return 1".to_string(),
+                vars: HashMap::new(),
+                file: "/lib/movie.mkv".to_string(),
+                library_root: "/lib".to_string(),
+                dst_dir: "/out".to_string(),
+            }),
+        ]
+    }
+
+    fn golden_dir() -> PathBuf {
+        PathBuf::from(env!("CARGO_MANIFEST_DIR")).join("src/rpc/golden")
+    }
+
+    fn to_hex(bytes: &[u8]) -> String {
+        bytes.iter().map(|b| format!("{:02x}", b)).collect()
+    }
+
+    // Run with UPDATE_GOLDEN=1 after bumping PROTOCOL_VERSION
+    #[test]
+    fn test_golden_encoding() {
+        let update = std::env::var("UPDATE_GOLDEN").is_ok();
+        let mut failures = Vec::new();
+
+        for msg in samples() {
+            let name = variant_name(&msg);
+            let path = golden_dir().join(format!("{}.hex", name));
+            let encoded = to_hex(&encode(&msg).unwrap());
+
+            if update {
+                std::fs::create_dir_all(golden_dir()).unwrap();
+                std::fs::write(&path, format!("{}\n", encoded)).unwrap();
+                continue;
+            }
+
+            let expected = std::fs::read_to_string(&path)
+                .unwrap_or_else(|_| panic!("missing golden file {}", path.display()));
+            if expected.trim() != encoded {
+                failures.push(name);
+            }
+        }
+
+        assert!(
+            failures.is_empty(),
+            "encoding changed for {:?}, bump PROTOCOL_VERSION and rerun with UPDATE_GOLDEN=1",
+            failures
+        );
+    }
+
+    #[test]
+    fn test_roundtrip() {
+        for msg in samples() {
+            assert_eq!(decode(&encode(&msg).unwrap()).unwrap(), msg);
+        }
+    }
+
+    #[test]
+    fn test_peek_hello_version() {
+        let samples = samples();
+        let hello = encode(&samples[0]).unwrap();
+        assert_eq!(peek_hello_version(&hello), Some(PROTOCOL_VERSION));
+
+        // A future Hello with an unknown layout still reveals its version
+        let mut future = bincode::encode_to_vec((0u32, 99u16), bincode::config::standard()).unwrap();
+        future.extend_from_slice(&[0xff, 0xfe]);
+        assert!(decode(&future).is_err());
+        assert_eq!(peek_hello_version(&future), Some(99));
+
+        let ping = encode(&Message::ping()).unwrap();
+        assert_eq!(peek_hello_version(&ping), None);
+    }
+
+    #[test]
+    fn test_negotiate_features() {
+        let remote = vec![FEATURE_CANCEL_JOB.to_string(), "unknown".to_string()];
+        assert_eq!(negotiate_features(&remote), vec![FEATURE_CANCEL_JOB.to_string()]);
+        assert!(negotiate_features(&[]).is_empty());
+        assert!(supported_version(PROTOCOL_VERSION));
+        assert!(!supported_version(PROTOCOL_VERSION + 1));
+    }
+}
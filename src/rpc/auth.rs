@@ -0,0 +1,53 @@
+use hmac::{Hmac, Mac};
+use rand::RngCore;
+use sha2::Sha256;
+
+type HmacSha256 = Hmac<Sha256>;
+
+pub const NONCE_LEN: usize = 32;
+
+pub fn new_nonce() -> Vec<u8> {
+    let mut nonce = vec![0u8; NONCE_LEN];
+    rand::rng().fill_bytes(&mut nonce);
+    nonce
+}
+
+// The identifier is bound into the MAC so a response can't be replayed
+// by a worker announcing itself under another name
+fn mac(key: &str, nonce: &[u8], identifier: &str) -> HmacSha256 {
+    let mut mac = HmacSha256::new_from_slice(key.as_bytes())
+        .expect("HMAC accepts keys of any size");
+    mac.update(nonce);
+    mac.update(identifier.as_bytes());
+    mac
+}
+
+pub fn sign_challenge(key: &str, nonce: &[u8], identifier: &str) -> Vec<u8> {
+    mac(key, nonce, identifier)
+        .finalize()
+        .into_bytes()
+        .to_vec()
+}
+
+pub fn verify_challenge(key: &str, nonce: &[u8], identifier: &str, response: &[u8]) -> bool {
+    mac(key, nonce, identifier)
+        .verify_slice(response)
+        .is_ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_challenge_roundtrip() {
+        let nonce = new_nonce();
+        assert_eq!(nonce.len(), NONCE_LEN);
+
+        let response = sign_challenge("secret", &nonce, "worker1");
+        assert!(verify_challenge("secret", &nonce, "worker1", &response));
+        assert!(!verify_challenge("other", &nonce, "worker1", &response));
+        assert!(!verify_challenge("secret", &nonce, "worker2", &response));
+        assert!(!verify_challenge("secret", &new_nonce(), "worker1", &response));
+    }
+}
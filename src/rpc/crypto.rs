@@ -0,0 +1,298 @@
+use anyhow::{Result, anyhow, bail};
+use chacha20poly1305::{
+    ChaCha20Poly1305,
+    KeyInit,
+    Nonce,
+    aead::Aead,
+};
+use hkdf::Hkdf;
+use rand::RngCore;
+use sha2::Sha256;
+use std::path::Path;
+use tracing::info;
+use x25519_dalek::{PublicKey, StaticSecret};
+
+use super::{Message, SealedMsg, decode, encode};
+
+const KDF_INFO: &[u8] = b"trahl-orch-v1";
+
+pub fn generate_secret() -> StaticSecret {
+    let mut bytes = [0u8; 32];
+    rand::rng().fill_bytes(&mut bytes);
+    StaticSecret::from(bytes)
+}
+
+fn to_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+fn key_from_hex(s: &str) -> Result<[u8; 32]> {
+    let s = s.trim();
+    if s.len() != 64 {
+        bail!("key must be 64 hex characters");
+    }
+
+    let mut key = [0u8; 32];
+    for (i, byte) in key.iter_mut().enumerate() {
+        *byte = u8::from_str_radix(&s[i * 2..i * 2 + 2], 16)?;
+    }
+    Ok(key)
+}
+
+pub fn public_key_from_bytes(bytes: &[u8]) -> Result<PublicKey> {
+    let key: [u8; 32] = bytes
+        .try_into()
+        .map_err(|_| anyhow!("public key must be 32 bytes"))?;
+    Ok(PublicKey::from(key))
+}
+
+// Loads the master secret key, creating it and its .pub companion on first use
+pub fn load_or_generate_secret(path: &Path) -> Result<StaticSecret> {
+    if path.exists() {
+        let contents = std::fs::read_to_string(path)?;
+        return Ok(StaticSecret::from(key_from_hex(&contents)?));
+    }
+
+    let secret = generate_secret();
+    let public = PublicKey::from(&secret);
+    let pub_path = path.with_extension("pub");
+
+    write_private(path, &to_hex(secret.as_bytes()))?;
+    std::fs::write(&pub_path, to_hex(public.as_bytes()))?;
+    info!("Generated orchestration key {}, copy {} to the workers", path.display(), pub_path.display());
+
+    Ok(secret)
+}
+
+#[cfg(unix)]
+fn write_private(path: &Path, contents: &str) -> Result<()> {
+    use std::io::Write;
+    use std::os::unix::fs::OpenOptionsExt;
+
+    let mut file = std::fs::OpenOptions::new()
+        .write(true)
+        .create_new(true)
+        .mode(0o600)
+        .open(path)?;
+    file.write_all(contents.as_bytes())?;
+    Ok(())
+}
+
+#[cfg(not(unix))]
+fn write_private(path: &Path, contents: &str) -> Result<()> {
+    std::fs::write(path, contents)?;
+    Ok(())
+}
+
+pub fn load_public_key(path: &Path) -> Result<PublicKey> {
+    let contents = std::fs::read_to_string(path)?;
+    Ok(PublicKey::from(key_from_hex(&contents)?))
+}
+
+// Encryption state of one link. Each direction has its own key and
+// a strictly increasing counter used as nonce, so replays are refused.
+pub struct Session {
+    tx: ChaCha20Poly1305,
+    rx: ChaCha20Poly1305,
+    tx_counter: u64,
+    rx_counter: Option<u64>,
+}
+
+impl Session {
+    // Both sides mix the worker ephemeral key with the master static key,
+    // which authenticates the master, and with the master ephemeral key,
+    // which keeps past sessions safe if the static key leaks.
+    fn derive(
+        dh_static: &[u8],
+        dh_ephemeral: &[u8],
+        worker_eph: &PublicKey,
+        master_eph: &PublicKey,
+    ) -> ([u8; 32], [u8; 32]) {
+        let mut ikm = Vec::with_capacity(64);
+        ikm.extend_from_slice(dh_static);
+        ikm.extend_from_slice(dh_ephemeral);
+
+        let mut salt = Vec::with_capacity(64);
+        salt.extend_from_slice(worker_eph.as_bytes());
+        salt.extend_from_slice(master_eph.as_bytes());
+
+        let hk = Hkdf::<Sha256>::new(Some(&salt), &ikm);
+        let mut okm = [0u8; 64];
+        hk.expand(KDF_INFO, &mut okm)
+            .expect("64 bytes is a valid HKDF output length");
+
+        let mut worker_to_master = [0u8; 32];
+        let mut master_to_worker = [0u8; 32];
+        worker_to_master.copy_from_slice(&okm[..32]);
+        master_to_worker.copy_from_slice(&okm[32..]);
+        (worker_to_master, master_to_worker)
+    }
+
+    fn new(tx_key: &[u8; 32], rx_key: &[u8; 32]) -> Self {
+        Self {
+            tx: ChaCha20Poly1305::new(tx_key.into()),
+            rx: ChaCha20Poly1305::new(rx_key.into()),
+            tx_counter: 0,
+            rx_counter: None,
+        }
+    }
+
+    pub fn worker(
+        worker_eph: &StaticSecret,
+        master_static: &PublicKey,
+        master_eph: &PublicKey,
+    ) -> Self {
+        let dh_static = worker_eph.diffie_hellman(master_static);
+        let dh_ephemeral = worker_eph.diffie_hellman(master_eph);
+        let (w2m, m2w) = Self::derive(
+            dh_static.as_bytes(),
+            dh_ephemeral.as_bytes(),
+            &PublicKey::from(worker_eph),
+            master_eph,
+        );
+        Self::new(&w2m, &m2w)
+    }
+
+    pub fn master(
+        master_static: &StaticSecret,
+        master_eph: &StaticSecret,
+        worker_eph: &PublicKey,
+    ) -> Self {
+        let dh_static = master_static.diffie_hellman(worker_eph);
+        let dh_ephemeral = master_eph.diffie_hellman(worker_eph);
+        let (w2m, m2w) = Self::derive(
+            dh_static.as_bytes(),
+            dh_ephemeral.as_bytes(),
+            worker_eph,
+            &PublicKey::from(master_eph),
+        );
+        Self::new(&m2w, &w2m)
+    }
+
+    fn nonce(counter: u64) -> Nonce {
+        let mut nonce = [0u8; 12];
+        nonce[4..].copy_from_slice(&counter.to_le_bytes());
+        nonce.into()
+    }
+
+    pub fn seal(&mut self, msg: &Message) -> Result<Message> {
+        let plain = encode(msg)?;
+        let counter = self.tx_counter;
+        self.tx_counter = counter
+            .checked_add(1)
+            .ok_or_else(|| anyhow!("nonce counter exhausted"))?;
+
+        let ciphertext = self.tx
+            .encrypt(&Self::nonce(counter), plain.as_slice())
+            .map_err(|_| anyhow!("encryption failed"))?;
+
+        Ok(Message::Sealed(SealedMsg { counter, ciphertext }))
+    }
+
+    pub fn open(&mut self, sealed: &SealedMsg) -> Result<Message> {
+        decode(&self.open_raw(sealed)?)
+    }
+
+    // Decrypts without decoding, for callers that handle undecodable payloads
+    pub fn open_raw(&mut self, sealed: &SealedMsg) -> Result<Vec<u8>> {
+        if self.rx_counter.is_some_and(|last| sealed.counter <= last) {
+            bail!("replayed message {}", sealed.counter);
+        }
+
+        let plain = self.rx
+            .decrypt(&Self::nonce(sealed.counter), sealed.ciphertext.as_slice())
+            .map_err(|_| anyhow!("message authentication failed"))?;
+        self.rx_counter = Some(sealed.counter);
+
+        Ok(plain)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn pair() -> (Session, Session) {
+        let master_static = generate_secret();
+        let master_eph = generate_secret();
+        let worker_eph = generate_secret();
+
+        let worker = Session::worker(
+            &worker_eph,
+            &PublicKey::from(&master_static),
+            &PublicKey::from(&master_eph),
+        );
+        let master = Session::master(
+            &master_static,
+            &master_eph,
+            &PublicKey::from(&worker_eph),
+        );
+        (worker, master)
+    }
+
+    fn unseal(msg: Message) -> SealedMsg {
+        match msg {
+            Message::Sealed(s) => s,
+            _ => panic!("message not sealed"),
+        }
+    }
+
+    #[test]
+    fn test_session_roundtrip() {
+        let (mut worker, mut master) = pair();
+
+        let sealed = unseal(worker.seal(&Message::ping()).unwrap());
+        assert_eq!(master.open(&sealed).unwrap(), Message::ping());
+
+        let sealed = unseal(master.seal(&Message::cancel_job(3)).unwrap());
+        assert_eq!(worker.open(&sealed).unwrap(), Message::cancel_job(3));
+    }
+
+    #[test]
+    fn test_session_replay_and_tamper() {
+        let (mut worker, mut master) = pair();
+
+        let first = unseal(worker.seal(&Message::pong()).unwrap());
+        let mut second = unseal(worker.seal(&Message::pong()).unwrap());
+        master.open(&first).unwrap();
+        assert!(master.open(&first).is_err());
+
+        second.ciphertext[0] ^= 1;
+        assert!(master.open(&second).is_err());
+    }
+
+    #[test]
+    fn test_session_wrong_master_key() {
+        let master_static = generate_secret();
+        let master_eph = generate_secret();
+        let worker_eph = generate_secret();
+
+        // Worker trusts a different master public key
+        let mut worker = Session::worker(
+            &worker_eph,
+            &PublicKey::from(&generate_secret()),
+            &PublicKey::from(&master_eph),
+        );
+        let mut master = Session::master(
+            &master_static,
+            &master_eph,
+            &PublicKey::from(&worker_eph),
+        );
+
+        let sealed = unseal(worker.seal(&Message::ping()).unwrap());
+        assert!(master.open(&sealed).is_err());
+    }
+
+    #[test]
+    fn test_key_files() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("orch.key");
+
+        let secret = load_or_generate_secret(&path).unwrap();
+        let public = load_public_key(&path.with_extension("pub")).unwrap();
+        assert_eq!(PublicKey::from(&secret), public);
+
+        let again = load_or_generate_secret(&path).unwrap();
+        assert_eq!(secret.as_bytes(), again.as_bytes());
+    }
+}
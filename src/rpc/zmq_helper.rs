@@ -3,7 +3,7 @@ use zeromq::{SocketRecv, SocketSend, ZmqMessage};
 
 use super::*;
 
-pub async fn recv_msg<S>(socket: &mut S, router: bool) -> Result<(Option<Vec<u8>>, Message)> 
+pub async fn recv_raw<S>(socket: &mut S, router: bool) -> Result<(Option<Vec<u8>>, Vec<u8>)> 
 where
     S: SocketRecv
 {
@@ -15,9 +15,15 @@ where
         (None, &frames[0])
     };
 
-    let msg: Message = bincode::decode_from_slice(payload, bincode::config::standard())?.0;
+    Ok((client_id, payload.to_vec()))
+}
 
-    Ok((client_id, msg))
+pub async fn recv_msg<S>(socket: &mut S, router: bool) -> Result<(Option<Vec<u8>>, Message)> 
+where
+    S: SocketRecv
+{
+    let (client_id, payload) = recv_raw(socket, router).await?;
+    Ok((client_id, decode(&payload)?))
 }
 
 pub async fn send_msg<S>(
@@ -28,7 +34,7 @@ pub async fn send_msg<S>(
 where
     S: SocketSend
 {
-    let bytes = bincode::encode_to_vec(msg, bincode::config::standard())?;
+    let bytes = encode(msg)?;
     let mut msg = ZmqMessage::from(bytes);
 
     if let Some(client_id) = client_id {
@@ -2,7 +2,7 @@ use std::path::{Path, PathBuf};
 use tokio::fs;
 use uuid::Uuid;
 use crate::config::FsRemap;
-use anyhow::Result;
+use anyhow::{anyhow, Result};
 use std::fs::File;
 use std::io::{BufReader, Read};
 use xxhash_rust::xxh3::Xxh3;
@@ -23,7 +23,9 @@ pub fn remap_to_master(path: &Path, remaps: &Option<Vec<FsRemap>>) -> PathBuf {
     }
 }
 
-pub async fn copy_preserve_structure(
+// Destination of a file placed with O_PRESERVE_DIR: the directory of the
+// original relative to the library root, recreated under dst_dir
+pub fn preserve_structure_path(
     original_file: &Path,
     src_file: &Path,
     library_root: &Path,
@@ -32,29 +34,162 @@ pub async fn copy_preserve_structure(
     // Compute the relative path from the library root
     let relative_path = original_file
         .strip_prefix(library_root)
-        .map_err(|_| anyhow::anyhow!(
+        .map_err(|_| anyhow!(
             "File {} is not under library root {}",
             original_file.display(),
             library_root.display()
         ))?;
 
-    // Build the full destination path
-    //let dst_path = dst_dir.join(relative_path);
-    let dst_path = dst_dir.join(
+    let file_name = src_file.file_name()
+        .ok_or_else(|| anyhow!("{} is not a file", src_file.display()))?;
+
+    Ok(dst_dir.join(
         relative_path.parent()
             .unwrap_or_else(|| Path::new(""))
-            .join(src_file.file_name().unwrap())
-    );
-    
-    // Ensure all parent directories exist
-    if let Some(parent) = dst_path.parent() {
+            .join(file_name)
+    ))
+}
+
+// Copies src next to dst under a temporary name, checks size and hash, then
+// renames it over dst: dst is either left alone or complete, never truncated.
+// When keep is given, an existing dst is first linked or copied there.
+pub async fn place_file(src: &Path, dst: &Path, keep: Option<&Path>) -> Result<()> {
+    let dir = match dst.parent() {
+        Some(p) if !p.as_os_str().is_empty() => p,
+        _ => Path::new("."),
+    };
+    let name = dst.file_name()
+        .ok_or_else(|| anyhow!("{} is not a file path", dst.display()))?;
+    let tmp = dir.join(format!(".{}.{}.trahl-tmp", name.to_string_lossy(), Uuid::new_v4()));
+
+    let res = async {
+        fs::copy(src, &tmp).await?;
+        fs::File::open(&tmp).await?.sync_all().await?;
+        verify_copy(src, &tmp).await?;
+
+        if let Some(keep) = keep && fs::try_exists(dst).await? {
+            keep_original(dst, keep).await?;
+        }
+
+        fs::rename(&tmp, dst).await?;
+        fs::File::open(dir).await?.sync_all().await?;
+        Ok(())
+    }.await;
+
+    if res.is_err() {
+        let _ = fs::remove_file(&tmp).await;
+    }
+    res
+}
+
+// An output put in place by place_file, with what is needed to undo it
+pub struct Placed {
+    pub dst: PathBuf,
+    pub keep: Option<PathBuf>,
+    pub existed: bool,
+}
+
+// Undoes placements, last first: kept originals go back over their destination
+// and destinations that did not exist before are removed. A destination that was
+// replaced without a keep stays as it is. Returns the first error once all were tried.
+pub async fn unplace_files(placed: &[Placed]) -> Result<()> {
+    let mut res = Ok(());
+    for p in placed.iter().rev() {
+        let undo = async {
+            match &p.keep {
+                Some(keep) => {
+                    place_file(keep, &p.dst, None).await?;
+                    fs::remove_file(keep).await?;
+                }
+                None if !p.existed => fs::remove_file(&p.dst).await?,
+                None => {}
+            }
+            Ok::<_, anyhow::Error>(())
+        }.await;
+        if let Err(e) = undo && res.is_ok() {
+            res = Err(anyhow!("Cannot undo placement of {}: {}", p.dst.display(), e));
+        }
+    }
+    res
+}
+
+async fn verify_copy(src: &Path, copy: &Path) -> Result<()> {
+    let (src_len, copy_len) = (fs::metadata(src).await?.len(), fs::metadata(copy).await?.len());
+    if src_len != copy_len {
+        return Err(anyhow!("Copy of {} has {} bytes instead of {}", src.display(), copy_len, src_len));
+    }
+
+    let (src_owned, copy_owned) = (src.to_path_buf(), copy.to_path_buf());
+    let (src_hash, copy_hash) = tokio::try_join!(
+        tokio::task::spawn_blocking(move || chunked_hash(src_owned)),
+        tokio::task::spawn_blocking(move || chunked_hash(copy_owned)),
+    )?;
+    if src_hash? != copy_hash? {
+        return Err(anyhow!("Copy of {} does not match the original", src.display()));
+    }
+    Ok(())
+}
+
+// A hard link is instant, a copy is needed across filesystems. Never replaces an
+// existing keep, which would lose the file kept by an earlier attempt.
+async fn keep_original(original: &Path, keep: &Path) -> Result<()> {
+    if let Some(parent) = keep.parent() {
         fs::create_dir_all(parent).await?;
     }
+    match fs::hard_link(original, keep).await {
+        Ok(()) => return Ok(()),
+        Err(e) if e.kind() == std::io::ErrorKind::AlreadyExists => {
+            return Err(anyhow!("{} is already kept", keep.display()));
+        },
+        Err(_) => {},
+    }
 
-    // Copy the file
-    fs::copy(src_file, &dst_path).await?;
+    let res = async {
+        let mut src = fs::File::open(original).await?;
+        let mut dst = fs::OpenOptions::new().write(true).create_new(true).open(keep).await?;
+        tokio::io::copy(&mut src, &mut dst).await?;
+        dst.sync_all().await?;
+        fs::set_permissions(keep, src.metadata().await?.permissions()).await?;
+        Ok::<_, std::io::Error>(())
+    }.await;
 
-    Ok(dst_path)
+    match res {
+        Ok(()) => Ok(()),
+        Err(e) if e.kind() == std::io::ErrorKind::AlreadyExists => {
+            Err(anyhow!("{} is already kept", keep.display()))
+        },
+        Err(e) => {
+            let _ = fs::remove_file(keep).await;
+            Err(e.into())
+        },
+    }
+}
+
+pub fn human_size(bytes: u64) -> String {
+    const UNITS: [&str; 5] = ["B", "KB", "MB", "GB", "TB"];
+    let mut size = bytes as f64;
+    let mut unit = 0;
+    while size >= 1024.0 && unit < UNITS.len() - 1 {
+        size /= 1024.0;
+        unit += 1;
+    }
+    if unit == 0 {
+        format!("{} B", bytes)
+    } else {
+        format!("{:.1} {}", size, UNITS[unit])
+    }
+}
+
+// Unified diff of two texts with 3 lines of context, empty when they match
+pub fn unified_diff(old: &str, new: &str, old_name: &str, new_name: &str) -> String {
+    let old_lines: Vec<String> = old.lines().map(|l| format!("{}\n", l)).collect();
+    let new_lines: Vec<String> = new.lines().map(|l| format!("{}\n", l)).collect();
+    let mut diff = difflib::unified_diff(&old_lines, &new_lines, old_name, new_name, "", "", 3);
+    // Headers come with a tab before the dates, which are left empty
+    for header in diff.iter_mut().take(2) {
+        *header = header.replace('\t', "");
+    }
+    diff.concat()
 }
 
 pub fn uuid_to_u128(value: Uuid) -> u128 {
@@ -84,3 +219,64 @@ pub fn chunked_hash(path: impl AsRef<Path>) -> Result<String> {
 
     Ok(format!("{:032x}", hasher.digest128()))
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_place_file() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let src = dir.path().join("new.mkv");
+        let dst = dir.path().join("movie.mkv");
+        let keep = dir.path().join("recycle/1/movie.mkv");
+        std::fs::write(&src, b"new content")?;
+        std::fs::write(&dst, b"old")?;
+
+        place_file(&src, &dst, Some(&keep)).await?;
+        assert_eq!(std::fs::read(&dst)?, b"new content");
+        assert_eq!(std::fs::read(&keep)?, b"old");
+
+        // An existing keep is never replaced, nor is the destination
+        std::fs::write(&src, b"newer content")?;
+        assert!(place_file(&src, &dst, Some(&keep)).await.is_err());
+        assert_eq!(std::fs::read(&dst)?, b"new content");
+        assert_eq!(std::fs::read(&keep)?, b"old");
+
+        // A failed placement leaves the destination and no temporary file
+        assert!(place_file(&dir.path().join("missing.mkv"), &dst, None).await.is_err());
+        assert_eq!(std::fs::read(&dst)?, b"new content");
+        let leftovers = std::fs::read_dir(dir.path())?
+            .filter_map(|e| e.ok())
+            .filter(|e| e.file_name().to_string_lossy().ends_with(".trahl-tmp"))
+            .count();
+        assert_eq!(leftovers, 0);
+        Ok(())
+    }
+
+    #[test]
+    fn test_unified_diff() {
+        let diff = unified_diff("a\nb\nc\n", "a\nB\nc\n", "v1", "v2");
+        assert_eq!(diff, "--- v1\n+++ v2\n@@ -1,3 +1,3 @@\n a\n-b\n+B\n c\n");
+        assert_eq!(unified_diff("same\n", "same\n", "v1", "v2"), "");
+    }
+
+    #[test]
+    fn test_human_size() {
+        assert_eq!(human_size(512), "512 B");
+        assert_eq!(human_size(1536), "1.5 KB");
+        assert_eq!(human_size(3 * 1024 * 1024 * 1024), "3.0 GB");
+    }
+
+    #[test]
+    fn test_preserve_structure_path() {
+        let dst = preserve_structure_path(
+            Path::new("/lib/show/s01/ep1.mkv"),
+            Path::new("/cache/ep1.srt"),
+            Path::new("/lib"),
+            Path::new("/out"),
+        ).unwrap();
+        assert_eq!(dst, PathBuf::from("/out/show/s01/ep1.srt"));
+        assert!(preserve_structure_path(Path::new("/other/ep1.mkv"), Path::new("/cache/ep1.mkv"), Path::new("/lib"), Path::new("/out")).is_err());
+    }
+}
@@ -1,36 +1,46 @@
+use std::collections::HashMap;
 use std::path::{Path, PathBuf};
+use std::sync::Arc;
 
 use anyhow::anyhow;
 use tempfile::TempDir;
 use tokio::sync::mpsc;
-use tokio::task::{self, JoinHandle};
-use tracing::{info, error};
+use tokio::task::{AbortHandle, JoinHandle, JoinSet};
+use tracing::{info, warn, error};
 
-use crate::config::FsRemap;
-use crate::lua::{TrahlRuntime, TrahlRuntimeBuilder};
-use crate::rpc::{JobMsg, JobStatusMsg};
+use crate::config::{FsRemap, ValidationConfig, WorkerConfig};
+use crate::extcmd::hwaccel::Capabilities;
+use crate::extcmd::tools::ToolPaths;
+use crate::lua::{OutputSpec, Sandbox, TrahlRuntime, TrahlRuntimeBuilder, O_FLAT, O_OVERWRITE, O_PRESERVE_DIR, ROLE_MAIN};
+use crate::rpc::{JobMsg, JobOutput, JobStatusMsg, RecycledFile};
 use crate::utils;
+use super::validation;
 
-struct RunnerMessage {
-    status_tx: mpsc::Sender::<JobStatusMsg>,
-    spec: JobMsg,
+enum RunnerMessage {
+    Spawn {
+        status_tx: mpsc::Sender::<JobStatusMsg>,
+        spec: JobMsg,
+        trusted: bool,
+    },
+    Cancel(Option<i64>),    // None cancels every running job
+    Limit(Option<u8>),      // Applies to new jobs, running ones keep going
+    Reconfigure(Box<WorkerConfig>),
+    Capabilities(Arc<Capabilities>),
 }
 
 pub struct JobRunner {
     tx: mpsc::Sender<RunnerMessage>,
     rx: Option<mpsc::Receiver<RunnerMessage>>,
-    tmpdir: PathBuf,
-    fsremaps: Option<Vec<FsRemap>>,
+    config: WorkerConfig,
 }
 
 impl JobRunner {
-    pub fn new(tmpdir: PathBuf, fsremaps: Option<Vec<FsRemap>>) -> Self {  
+    pub fn new(config: WorkerConfig) -> Self {  
         let (tx, rx) = mpsc::channel(8);
         Self { 
             tx,
             rx: Some(rx),
-            tmpdir,
-            fsremaps
+            config,
         }
     }
 
@@ -39,36 +49,95 @@ impl JobRunner {
             .take()
             .expect("JobSpanwer::run() can be called only once");
 
-        let tmpdir_clone = self.tmpdir.clone();
-        let remaps_clone = self.fsremaps.clone();
+        let mut config = self.config.clone();
 
         let handle = tokio::spawn(async move {
-            while let Some(msg) = rx.recv().await {
-                let job_id_clone = msg.spec.job_id;
-                let job = Job::new(
-                    msg.spec,
-                    tmpdir_clone.clone(),
-                    remaps_clone.clone(),
-                    msg.status_tx.clone(),
-                ).await;
-
-                match job {
-                    Ok(job) => {
-                        let _ = msg.status_tx.send(JobStatusMsg::job_ack(job_id_clone))
-                            .await
-                            .inspect_err(|e| { 
-                                error!("Error sending message: {}", e)
-                            });
-                        task::spawn(job.run());
+            let mut running: JoinSet<i64> = JoinSet::new();
+            let mut handles: HashMap<i64, AbortHandle> = HashMap::new();
+            let mut limit: Option<u8> = None;
+            let mut capabilities = Arc::new(Capabilities::default());
+
+            loop {
+                tokio::select! {
+                    msg = rx.recv() => {
+                        let Some(msg) = msg else {
+                            break;
+                        };
+
+                        match msg {
+                            RunnerMessage::Spawn { spec, status_tx, trusted } => {
+                                let job_id_clone = spec.job_id;
+                                if let Some(max) = limit && handles.len() >= max.into() {
+                                    warn!("Declining job {}: limit of {} jobs reached", job_id_clone, max);
+                                    let _ = status_tx.send(
+                                            JobStatusMsg::job_declined(job_id_clone, "Job limit reached".into())
+                                        )
+                                        .await
+                                        .inspect_err(|e| { error!("Error sending message: {}", e) });
+                                    continue;
+                                }
+
+                                let job = Job::new(
+                                    spec,
+                                    &config,
+                                    capabilities.clone(),
+                                    trusted,
+                                    status_tx.clone(),
+                                ).await;
+
+                                match job {
+                                    Ok(job) => {
+                                        let _ = status_tx.send(JobStatusMsg::job_ack(job_id_clone))
+                                            .await
+                                            .inspect_err(|e| { 
+                                                error!("Error sending message: {}", e)
+                                            });
+                                        let handle = running.spawn(async move {
+                                            job.run().await;
+                                            job_id_clone
+                                        });
+                                        handles.insert(job_id_clone, handle);
+                                    },
+                                    Err(e) => {
+                                        let err_str = format!("Job {} failed: {}", job_id_clone, e);
+                                        error!(err_str);
+                                        let _ = status_tx.send(
+                                                JobStatusMsg::job_declined(job_id_clone, e.to_string())
+                                            )
+                                            .await
+                                            .inspect_err(|e| { error!("Error sending message: {}", e) });
+                                    }
+                                }
+                            },
+                            RunnerMessage::Cancel(Some(job_id)) => {
+                                if let Some(handle) = handles.remove(&job_id) {
+                                    info!("Cancelling job {}", job_id);
+                                    handle.abort();
+                                } else {
+                                    warn!("Cannot cancel job {}: not running", job_id);
+                                }
+                            },
+                            RunnerMessage::Cancel(None) => {
+                                for (job_id, handle) in handles.drain() {
+                                    info!("Cancelling job {}", job_id);
+                                    handle.abort();
+                                }
+                            },
+                            RunnerMessage::Limit(max) => {
+                                limit = max;
+                            },
+                            RunnerMessage::Reconfigure(new_config) => {
+                                config = *new_config;
+                            },
+                            RunnerMessage::Capabilities(caps) => {
+                                capabilities = caps;
+                            },
+                        }
                     },
-                    Err(e) => {
-                        let err_str = format!("Job {} failed: {}", job_id_clone, e);
-                        error!(err_str);
-                        let _ = msg.status_tx.send(
-                                JobStatusMsg::job_declined(job_id_clone, e.to_string())
-                            )
-                            .await
-                            .inspect_err(|e| { error!("Error sending message: {}", e) });
+                    Some(res) = running.join_next() => {
+                        if let Ok(job_id) = res {
+                            handles.remove(&job_id);
+                        }
                     }
                 }
             }
@@ -77,31 +146,71 @@ impl JobRunner {
         (self, handle)
     }
     
-    pub async fn spawn_job(&self, spec: JobMsg, status_tx: mpsc::Sender<JobStatusMsg>) {
-        let msg = RunnerMessage { 
+    // Scripts run sandboxed unless the master marked the job as trusted
+    pub async fn spawn_job(&self, spec: JobMsg, status_tx: mpsc::Sender<JobStatusMsg>, trusted: bool) {
+        let msg = RunnerMessage::Spawn { 
             spec,
             status_tx,
+            trusted,
         };
 
         let _ = self.tx.send(msg).await.inspect_err(|_| error!("Runner closed"));
     }
+
+    pub async fn cancel_job(&self, job_id: i64) {
+        let _ = self.tx.send(RunnerMessage::Cancel(Some(job_id)))
+            .await
+            .inspect_err(|_| error!("Runner closed"));
+    }
+
+    pub async fn set_limit(&self, max: Option<u8>) {
+        let _ = self.tx.send(RunnerMessage::Limit(max))
+            .await
+            .inspect_err(|_| error!("Runner closed"));
+    }
+
+    // Only jobs started afterwards use the new settings
+    pub async fn reconfigure(&self, config: WorkerConfig) {
+        let _ = self.tx.send(RunnerMessage::Reconfigure(Box::new(config)))
+            .await
+            .inspect_err(|_| error!("Runner closed"));
+    }
+
+    pub async fn set_capabilities(&self, capabilities: Arc<Capabilities>) {
+        let _ = self.tx.send(RunnerMessage::Capabilities(capabilities))
+            .await
+            .inspect_err(|_| error!("Runner closed"));
+    }
+
+    pub async fn cancel_all(&self) {
+        let _ = self.tx.send(RunnerMessage::Cancel(None))
+            .await
+            .inspect_err(|_| error!("Runner closed"));
+    }
 }
 
-struct Job {
+pub(super) struct Job {
     spec: JobMsg,
     _tmpdir: TempDir,
     status_tx: mpsc::Sender<JobStatusMsg>,
     _runtime: TrahlRuntime,
     remaps: Option<Vec<FsRemap>>,
+    keep_dir: Option<PathBuf>,
+    recycle_tracked: bool,
+    dry_run: bool,
+    tools: ToolPaths,
+    validation: ValidationConfig,
 }
 
 impl Job {
     pub async fn new(spec: JobMsg,
-            tmpdir_path: PathBuf,
-            remaps: Option<Vec<FsRemap>>,
+            config: &WorkerConfig,
+            capabilities: Arc<Capabilities>,
+            trusted: bool,
             status_tx: mpsc::Sender<JobStatusMsg>,
         ) -> anyhow::Result<Self> {
-        let tmpdir = match TempDir::new_in(tmpdir_path.clone()) {
+        let remaps = config.fs_remaps.clone();
+        let tmpdir = match TempDir::new_in(&config.cache_dir) {
             Ok(t) => t,
             Err(e) => {
                 let err_str = format!("Job {} failed: {}", spec.job_id, e);
@@ -130,111 +239,373 @@ impl Job {
         let libroot = utils::remap_to_worker(&orig_libroot, &remaps);
         vars.insert("LIBRARYROOT".to_string(), libroot.to_string_lossy().to_string());
 
-        let runtime = TrahlRuntimeBuilder::new(
+        // The library recycle bin is tracked by the master, worker.recycle_dir is not
+        let (recycle_dir, recycle_tracked) = match spec.vars.get("RECYCLEDIR") {
+            Some(dir) => (Some(utils::remap_to_worker(Path::new(dir), &remaps)), true),
+            None => (config.recycle_dir.clone(), false),
+        };
+        // One directory per attempt, a requeued job must not reuse the previous keep
+        let keep_dir = recycle_dir.map(|dir| dir.join(format!(
+            "{}-{}",
+            spec.job_id,
+            chrono::Utc::now().format("%Y%m%d%H%M%S%3f")
+        )));
+
+        let tools = ToolPaths::from(config);
+        let mut builder = TrahlRuntimeBuilder::new(
             spec.job_id,
             status_tx.clone(),
             spec.script.clone())
             .add_vars(vars)
-            .build()
-            .unwrap();
+            .with_remaps(remaps.clone())
+            .with_tools(tools.clone())
+            .with_capabilities(capabilities);
+        if !trusted {
+            builder = builder.with_sandbox(Sandbox::from(config));
+        }
+        let dry_run = spec.vars.get("DRYRUN").is_some_and(|v| v == "1");
+        if dry_run {
+            builder = builder.with_dry_run();
+        }
+        let runtime = match builder.build() {
+            Ok(r) => r,
+            Err(e) => {
+                let err_str = format!("Job {} failed: {}", spec.job_id, e);
+                error!(err_str);
+                status_tx.send(
+                        JobStatusMsg::job_error(spec.job_id, err_str.clone())
+                    )
+                    .await
+                    .map_err(|e| anyhow!("status_tx failed: {}", e))?;
+                return Err(anyhow!(err_str));
+            }
+        };
 
         Ok(Job {
             spec,
             _runtime: runtime,
             status_tx,
             _tmpdir: tmpdir,
-            remaps
+            remaps,
+            keep_dir,
+            recycle_tracked,
+            dry_run,
+            tools,
+            validation: config.validation.clone(),
         })
     }
 
-    async fn run(self) {
-        let res = self._runtime.exec().await;
-        match res {
-            Ok(_) => {
-                info!("Job {} finished", self.spec.job_id);
-                let mut result: Option<String> = None;
-                match self._runtime.get_output() {
-                    Ok(file) => {
-                        let mode = self._runtime.get_output_mode().unwrap();
-                        /* MODE:
-                         * 1 = PRESERVE DIR
-                         * 2 = FLAT
-                         * 3 = OVERWRITE
-                         */
-
-                        let file = Path::new(&file);
-                        if !file.exists() {
-                            let log = format!("File {} does not exist!", file.display());
-                            error!("{}", log);
-                            let _ = self.status_tx.send(
-                                    JobStatusMsg::job_error(self.spec.job_id, log)
-                                ).await
-                                .inspect_err(|e| { error!("Error sending message: {}", e) });
-                            return;
-                        }
+    // Runs on the main output, before any file is placed
+    async fn validate_output(&self, outputs: &[OutputSpec]) -> anyhow::Result<()> {
+        let Some(main) = outputs.iter().find(|o| o.role == ROLE_MAIN) else {
+            return Ok(());
+        };
+        let spec = match self._runtime.get_validation()? {
+            Some(spec) => spec,
+            None => self.validation.clone(),
+        };
+        if !spec.enabled {
+            return Ok(());
+        }
 
-                        let _ = self.status_tx.send(
-                                JobStatusMsg::job_copying(self.spec.job_id)
-                            ).await
-                            .inspect_err(|e| { error!("Error sending message: {}", e) });
-
-                        let original_file_remapped = utils::remap_to_worker(Path::new(&self.spec.file), &self.remaps);
-                        let library_root_remapped = utils::remap_to_worker(Path::new(&self.spec.library_root), &self.remaps);
-                        let destination_dir_remapped = utils::remap_to_worker(Path::new(&self.spec.dst_dir), &self.remaps);
-
-                        match mode {
-                            1 => {
-                                let dst_path = match utils::copy_preserve_structure(
-                                    original_file_remapped.as_path(),
-                                    file,
-                                    library_root_remapped.as_path(),
-                                    destination_dir_remapped.as_path()).await
-                                {
-                                    Ok(d) => d,
-                                    Err(e) => {
-                                        let _ = self.status_tx.send(
-                                                JobStatusMsg::job_error(self.spec.job_id, e.to_string())
-                                            ).await
-                                            .inspect_err(|e| { error!("Error sending message: {}", e) });
-                                        return;
-                                    }
-                                };
-                                result = Some(dst_path.to_string_lossy().to_string());
-                            },
-                            2 => {
-                                let filename = file.file_name().unwrap();
-                                let dst_path = Path::new(&destination_dir_remapped).join(filename);
-                                tokio::fs::copy(file, &dst_path).await.unwrap();
-                                result = Some(dst_path.to_string_lossy().to_string());
-                            },
-                            3 => {
-                                tokio::fs::copy(file, original_file_remapped.clone()).await.unwrap();
-                                result = Some(self.spec.file);
-                            },
-                            _ => {
-                                let _ = self.status_tx.send(
-                                        JobStatusMsg::job_error(self.spec.job_id, "Unknown output mode".to_string())
-                                    ).await
-                                    .inspect_err(|e| { error!("Error sending message: {}", e) });
-                                return;
-                            }
-                        }
-                    },
-                    _ => {},
-                };
+        let _ = self.status_tx.send(
+                JobStatusMsg::job_milestone(self.spec.job_id, "Validating output".to_string())
+            ).await
+            .inspect_err(|e| { error!("Error sending message: {}", e) });
 
-                let _ = self.status_tx.send(
-                        JobStatusMsg::job_done(self.spec.job_id, result)
-                    ).await
-                    .inspect_err(|e| { error!("Error sending message: {}", e) });
-            }
-            Err(e) => {
-                error!("Job {} failed: {}", self.spec.job_id, e);
-                let _ = self.status_tx.send(
-                        JobStatusMsg::job_error(self.spec.job_id, e.to_string())
-                    ).await
-                    .inspect_err(|e| { error!("Error sending message: {}", e) });
+        let source = utils::remap_to_worker(Path::new(&self.spec.file), &self.remaps);
+        validation::validate(&spec, &self.tools, &source, Path::new(&main.path)).await
+    }
+
+    // OVERWRITE replaces the source file with the main output, other roles
+    // are placed next to the source under their own name
+    fn destination(&self, output: &OutputSpec) -> anyhow::Result<PathBuf> {
+        let file = Path::new(&output.path);
+        let file_name = file.file_name()
+            .ok_or_else(|| anyhow!("{} is not a file", file.display()))?;
+
+        let original_file_remapped = utils::remap_to_worker(Path::new(&self.spec.file), &self.remaps);
+        let library_root_remapped = utils::remap_to_worker(Path::new(&self.spec.library_root), &self.remaps);
+        let destination_dir_remapped = utils::remap_to_worker(Path::new(&self.spec.dst_dir), &self.remaps);
+
+        let dst_path = match output.mode {
+            O_PRESERVE_DIR => utils::preserve_structure_path(
+                original_file_remapped.as_path(),
+                file,
+                library_root_remapped.as_path(),
+                destination_dir_remapped.as_path())?,
+            O_FLAT => destination_dir_remapped.join(file_name),
+            O_OVERWRITE if output.role == ROLE_MAIN => original_file_remapped,
+            O_OVERWRITE => original_file_remapped.with_file_name(file_name),
+            mode => return Err(anyhow!("Unknown output mode {}", mode)),
+        };
+        Ok(dst_path)
+    }
+
+    // Returns where the output went and where the file it replaced was kept.
+    // Keeps are prefixed with the output index, outputs may share a file name.
+    async fn place_output(&self, index: usize, output: &OutputSpec) -> anyhow::Result<utils::Placed> {
+        let file = Path::new(&output.path);
+        if !file.exists() {
+            return Err(anyhow!("File {} does not exist!", file.display()));
+        }
+        let dst_path = self.destination(output)?;
+
+        if let Some(parent) = dst_path.parent() {
+            tokio::fs::create_dir_all(parent).await?;
+        }
+
+        let existed = dst_path.exists();
+        let keep = match (&self.keep_dir, dst_path.file_name()) {
+            (Some(dir), Some(name)) if existed => Some(dir.join(format!("{}-{}", index, name.to_string_lossy()))),
+            _ => None,
+        };
+        utils::place_file(file, &dst_path, keep.as_deref()).await?;
+        if let Some(keep) = &keep {
+            info!("Job {}: replaced {}, previous file kept as {}", self.spec.job_id, dst_path.display(), keep.display());
+        }
+        Ok(utils::Placed { dst: dst_path, keep, existed })
+    }
+
+    async fn send_log(&self, line: String) {
+        info!("Job {}: {}", self.spec.job_id, line);
+        let _ = self.status_tx.send(
+                JobStatusMsg::job_log(self.spec.job_id, line)
+            ).await
+            .inspect_err(|e| { error!("Error sending message: {}", e) });
+    }
+
+    // Reports where each output would go and its size, estimated from the
+    // skipped commands or taken from the file when the script wrote it
+    async fn finish_dry_run(&self, outputs: &[OutputSpec]) {
+        let log = self._runtime.take_dry_run_log().unwrap_or_default();
+        let mut report = Vec::new();
+
+        for output in outputs {
+            let dst_path = match self.destination(output) {
+                Ok(dst_path) => dst_path,
+                Err(e) => {
+                    let log = format!("Cannot place output {}: {}", output.path, e);
+                    error!("{}", log);
+                    let _ = self.status_tx.send(
+                            JobStatusMsg::job_error(self.spec.job_id, log)
+                        ).await
+                        .inspect_err(|e| { error!("Error sending message: {}", e) });
+                    return;
+                }
+            };
+            let size = match log.sizes.get(Path::new(&output.path)) {
+                Some(size) => Some(*size),
+                None => tokio::fs::metadata(&output.path).await.ok().map(|m| m.len()),
+            };
+
+            let replaces = if dst_path.exists() { ", replacing the existing file" } else { "" };
+            let size_str = size.map_or("unknown".to_string(), utils::human_size);
+            self.send_log(format!(
+                "DRY RUN: would place {} ({}) at {}{}",
+                output.path, size_str, dst_path.display(), replaces
+            )).await;
+
+            report.push(JobOutput {
+                path: utils::remap_to_master(&dst_path, &self.remaps).to_string_lossy().into_owned(),
+                role: output.role.clone(),
+                size,
+            });
+        }
+
+        let source = utils::remap_to_worker(Path::new(&self.spec.file), &self.remaps);
+        let source_size = tokio::fs::metadata(&source).await.ok().map(|m| m.len());
+        let estimated = report.first().and_then(|o| o.size);
+        self.send_log(format!(
+            "DRY RUN summary: {} skipped actions, {} outputs, main output {} (source {})",
+            log.actions.len(),
+            report.len(),
+            estimated.map_or("unknown".to_string(), utils::human_size),
+            source_size.map_or("unknown".to_string(), utils::human_size),
+        )).await;
+
+        let _ = self.status_tx.send(
+                JobStatusMsg::job_dry_run(self.spec.job_id, report)
+            ).await
+            .inspect_err(|e| { error!("Error sending message: {}", e) });
+    }
+
+    // Reports the keeps of placed outputs that are still in the recycle bin
+    async fn send_recycled(&self, placed: &[utils::Placed]) {
+        if !self.recycle_tracked {
+            return;
+        }
+        let mut recycled = Vec::new();
+        for p in placed {
+            let Some(keep) = &p.keep else { continue };
+            let Ok(meta) = tokio::fs::metadata(keep).await else { continue };
+            recycled.push(RecycledFile {
+                original: utils::remap_to_master(&p.dst, &self.remaps).to_string_lossy().into_owned(),
+                size: Some(meta.len()),
+                recycled: utils::remap_to_master(keep, &self.remaps).to_string_lossy().into_owned(),
+            });
+        }
+        if recycled.is_empty() {
+            return;
+        }
+        let _ = self.status_tx.send(
+                JobStatusMsg::job_recycled(self.spec.job_id, recycled)
+            ).await
+            .inspect_err(|e| { error!("Error sending message: {}", e) });
+    }
+
+    pub(super) async fn run(self) {
+        if let Err(e) = self._runtime.exec().await {
+            error!("Job {} failed: {}", self.spec.job_id, e);
+            let _ = self.status_tx.send(
+                    JobStatusMsg::job_error(self.spec.job_id, e.to_string())
+                ).await
+                .inspect_err(|e| { error!("Error sending message: {}", e) });
+            return;
+        }
+
+        info!("Job {} finished", self.spec.job_id);
+        let outputs = self._runtime.get_outputs().unwrap_or_else(|e| {
+            warn!("Job {}: cannot read outputs: {}", self.spec.job_id, e);
+            Vec::new()
+        });
+
+        if self.dry_run {
+            self.finish_dry_run(&outputs).await;
+            return;
+        }
+
+        if let Err(e) = self.validate_output(&outputs).await {
+            let log = format!("Output validation failed: {}", e);
+            error!("Job {}: {}", self.spec.job_id, log);
+            let _ = self.status_tx.send(
+                    JobStatusMsg::job_error(self.spec.job_id, log)
+                ).await
+                .inspect_err(|e| { error!("Error sending message: {}", e) });
+            return;
+        }
+
+        if !outputs.is_empty() {
+            let _ = self.status_tx.send(
+                    JobStatusMsg::job_copying(self.spec.job_id)
+                ).await
+                .inspect_err(|e| { error!("Error sending message: {}", e) });
+        }
+
+        let mut placed = Vec::new();
+        let mut undo = Vec::new();
+        for (index, output) in outputs.iter().enumerate() {
+            match self.place_output(index, output).await {
+                Ok(p) => {
+                    let size = tokio::fs::metadata(&p.dst).await.ok().map(|m| m.len());
+                    placed.push(JobOutput {
+                        path: utils::remap_to_master(&p.dst, &self.remaps).to_string_lossy().into_owned(),
+                        role: output.role.clone(),
+                        size,
+                    });
+                    undo.push(p);
+                },
+                Err(e) => {
+                    let mut log = format!("Cannot place output {}: {}", output.path, e);
+                    error!("{}", log);
+                    // All or nothing, put back what the earlier outputs replaced
+                    if let Err(e) = utils::unplace_files(&undo).await {
+                        error!("Job {}: {}", self.spec.job_id, e);
+                        log.push_str(&format!(", rollback failed: {}", e));
+                    }
+                    // Only keeps the rollback could not restore are left
+                    self.send_recycled(&undo).await;
+                    let _ = self.status_tx.send(
+                            JobStatusMsg::job_error(self.spec.job_id, log)
+                        ).await
+                        .inspect_err(|e| { error!("Error sending message: {}", e) });
+                    return;
+                }
             }
         }
+
+        self.send_recycled(&undo).await;
+
+        // Only the main output is the job result, sidecars alone leave it empty
+        let result = placed.iter().find(|o| o.role == ROLE_MAIN).map(|o| o.path.clone());
+        if !placed.is_empty() {
+            let _ = self.status_tx.send(
+                    JobStatusMsg::job_outputs(self.spec.job_id, placed)
+                ).await
+                .inspect_err(|e| { error!("Error sending message: {}", e) });
+        }
+
+        let _ = self.status_tx.send(
+                JobStatusMsg::job_done(self.spec.job_id, result)
+            ).await
+            .inspect_err(|e| { error!("Error sending message: {}", e) });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::rpc::JobStatus;
+
+    #[tokio::test]
+    async fn test_place_outputs_rollback() -> anyhow::Result<()> {
+        let dir = tempfile::tempdir()?;
+        let (lib, out, cache, recycle) = (dir.path().join("lib"), dir.path().join("out"), dir.path().join("cache"), dir.path().join("recycle"));
+        for d in [&lib, &out, &cache] {
+            std::fs::create_dir_all(d)?;
+        }
+        let src = lib.join("movie.mkv");
+        std::fs::write(&src, b"source")?;
+        std::fs::write(out.join("movie.mkv"), b"older copy")?;
+        // A non-empty directory where the subtitle goes makes the third output fail
+        std::fs::create_dir_all(out.join("movie.srt/busy"))?;
+
+        let config = WorkerConfig {
+            cache_dir: cache,
+            recycle_dir: Some(recycle.clone()),
+            validation: ValidationConfig { enabled: false, ..Default::default() },
+            ..Default::default()
+        };
+        // Two outputs named movie.mkv both replace a file, their keeps must not clash
+        let script = r#"
+            local function write(path, content)
+                local f = io.open(path, "w")
+                f:write(content)
+                f:close()
+            end
+            local cache = _trahl.vars.CACHEDIR
+            write(cache .. "/movie.mkv", "transcoded")
+            _trahl.fs.mkdir_p(cache .. "/copy")
+            write(cache .. "/copy/movie.mkv", "copy")
+            write(cache .. "/movie.srt", "subtitle")
+            _trahl.set_output(cache .. "/movie.mkv", _trahl.O_OVERWRITE)
+            _trahl.add_output(cache .. "/copy/movie.mkv", _trahl.O_FLAT, "copy")
+            _trahl.add_output(cache .. "/movie.srt", _trahl.O_FLAT, "subtitle")
+        "#;
+        let spec = JobMsg {
+            job_id: 1,
+            script: script.to_string(),
+            vars: HashMap::new(),
+            file: src.to_string_lossy().into_owned(),
+            library_root: lib.to_string_lossy().into_owned(),
+            dst_dir: out.to_string_lossy().into_owned(),
+        };
+        let (tx, mut rx) = mpsc::channel(16);
+        let job = Job::new(spec, &config, Arc::new(Capabilities::default()), true, tx).await?;
+        job.run().await;
+
+        let mut last = None;
+        while let Ok(msg) = rx.try_recv() {
+            last = Some(msg.status);
+        }
+        assert!(matches!(&last, Some(JobStatus::Error(e)) if e.contains("movie.srt") && !e.contains("rollback")), "{:?}", last);
+        assert_eq!(std::fs::read(&src)?, b"source");
+        assert_eq!(std::fs::read(out.join("movie.mkv"))?, b"older copy");
+        let kept = std::fs::read_dir(&recycle)?
+            .filter_map(|e| e.ok())
+            .map(|e| std::fs::read_dir(e.path()).map(|d| d.count()).unwrap_or(0))
+            .sum::<usize>();
+        assert_eq!(kept, 0);
+        Ok(())
     }
 }
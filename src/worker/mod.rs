@@ -1,23 +1,32 @@
 mod jobrunner;
+mod validation;
+mod run_script;
+mod test_scripts;
 mod rpc_client;
 
-use tracing::{error, info};
+use tracing::{error, info, warn};
 use std::sync::atomic::Ordering;
 use tokio;
 use tokio::sync::{mpsc, watch};
 use tokio::sync::watch::{Receiver, Sender};
 use tokio::time::{sleep, Duration};
+use std::collections::HashMap;
+use std::path::PathBuf;
 use std::sync::{Arc, RwLock};
 
 use crate::config::SystemConfig;
-use crate::rpc::{JobStatusMsg, Message};
+use crate::extcmd::hwaccel;
+use crate::extcmd::tools::{check_tools, ToolPaths};
+use crate::rpc::{auth, JobStatus, JobStatusMsg, Message, ToolInfo, FEATURE_CAPACITY_UPDATE, FEATURE_JOB_OUTPUTS, FEATURE_RECYCLE_BIN, FEATURE_TOOL_REPORT};
 use crate::{CONFIG, S_TERMINATE, S_RELOAD};
 use jobrunner::JobRunner;
+pub use run_script::run_script;
+pub use test_scripts::test_scripts;
 use rpc_client::rpc_client;
 
 pub struct WorkerCtx {
     pub ch_terminate: (Sender<bool>, Receiver<bool>),
-    pub _ch_reload: (Sender<bool>, Receiver<bool>),
+    pub ch_reload: (Sender<bool>, Receiver<bool>),
     pub config: Arc<RwLock<SystemConfig>>,
 }
 
@@ -39,7 +48,7 @@ pub fn worker_thread() {
 async fn worker_runtime() {
     let ctx = Arc::new(WorkerCtx {
         ch_terminate: watch::channel(false),
-        _ch_reload: watch::channel(false),
+        ch_reload: watch::channel(false),
         config: CONFIG.get().expect("configuration not initialized").clone(),
     });
 
@@ -58,22 +67,23 @@ async fn worker_runtime() {
         mut rx_from_job
     ) = mpsc::channel::<JobStatusMsg>(8);
 
-    let ctx_clone = ctx.clone();
-    let cache_dir = {
-        let cfg = ctx_clone.config.read().unwrap();
-        cfg.worker.cache_dir.clone()
-    };
-
-    let remaps = {
-        let cfg = ctx_clone.config.read().unwrap();
-        cfg.worker.fs_remaps.clone()
-    };
+    let (
+        tx_capabilities,
+        mut rx_capabilities
+    ) = mpsc::channel::<(PathBuf, hwaccel::Capabilities)>(1);
 
-    let (job_runner, _jrh) = JobRunner::new(cache_dir, remaps).run();
+    let mut worker_config = ctx.config.read().unwrap().worker.clone();
+    let (job_runner, _jrh) = JobRunner::new(worker_config.clone()).run();
 
     let ctx_clone = ctx.clone();
     let manager = async move {
         let mut ch_term = ctx_clone.ch_terminate.1.clone();
+        let mut ch_reload = ctx_clone.ch_reload.1.clone();
+        let mut master_features: Vec<String> = Vec::new();
+        let mut script_hashes: HashMap<i64, String> = HashMap::new();
+        let mut tool_report = self_check(&ToolPaths::from(&worker_config)).await;
+        let mut hardware_report = None;
+        spawn_probe(&tx_capabilities, worker_config.ffmpeg_path.clone());
 
         loop {
             tokio::select!(
@@ -82,25 +92,146 @@ async fn worker_runtime() {
                         Message::Bye => {
                             info!("BYE received from master");
                         },
-                        Message::HelloAck => {
-                            info!("Successfuly connected to master");
+                        Message::HelloAck(mi) => {
+                            info!(
+                                "Successfuly connected to master {} (protocol {}, features {:?})",
+                                mi.sw_version,
+                                mi.protocol_version,
+                                mi.features
+                            );
+                            master_features = mi.features;
+                            if master_features.iter().any(|f| f == FEATURE_TOOL_REPORT) {
+                                _ = tx_to_socket.send(Message::tool_report(tool_report.clone())).await;
+                            }
+                        },
+                        Message::AuthChallenge(nonce) => {
+                            let (identifier, auth_key) = {
+                                let cfg = ctx_clone.config.read().unwrap();
+                                (cfg.worker.identifier.clone(), cfg.worker.auth_key.clone())
+                            };
+
+                            match auth_key {
+                                Some(key) => {
+                                    let mac = auth::sign_challenge(&key, &nonce, &identifier);
+                                    _ = tx_to_socket.send(Message::auth_response(mac)).await;
+                                },
+                                None => {
+                                    error!("Master requires authentication but no auth_key is configured");
+                                    let _ = ctx_clone.ch_terminate.0.send(true);
+                                }
+                            }
+                        },
+                        Message::Reject(reason) => {
+                            error!("Master rejected this worker: {}", reason);
+                            job_runner.cancel_all().await;
+                            let _ = ctx_clone.ch_terminate.0.send(true);
                         },
                         Message::Ping => {
                             _ = tx_to_socket.send(Message::pong()).await;
                         },
                         Message::Job(jobmsg) => {
                             info!("Job received: {}", jobmsg.job_id);
-                            job_runner.spawn_job(jobmsg, tx_from_job.clone()).await;
+                            job_runner.spawn_job(jobmsg, tx_from_job.clone(), false).await;
+                        },
+                        Message::TrustedJob(jobmsg) => {
+                            info!("Trusted job received: {}", jobmsg.job_id);
+                            job_runner.spawn_job(jobmsg, tx_from_job.clone(), true).await;
+                        },
+                        Message::CancelJob(job_id) => {
+                            info!("Cancel received for job {}", job_id);
+                            job_runner.cancel_job(job_id).await;
+                        },
+                        Message::ConfigUpdate(update) => {
+                            info!(
+                                "Configuration update from master: max_jobs={:?}, {} libraries",
+                                update.max_jobs,
+                                update.libraries.len()
+                            );
+                            for lib in &update.libraries {
+                                if let Some(hash) = script_hashes.insert(lib.id, lib.script_hash.clone())
+                                    && hash != lib.script_hash {
+                                    info!("Script of library \"{}\" changed, new jobs will use it", lib.name);
+                                }
+                            }
+                            job_runner.set_limit(update.max_jobs).await;
+                        },
+                        Message::CancelJobs => {
+                            info!("Cancel received for all jobs");
+                            job_runner.cancel_all().await;
                         },
                         _ => {
                             info!("Unknown message received: {:#?}", msg);
                         },
                     }
                 },
+                Some((ffmpeg, capabilities)) = rx_capabilities.recv() => {
+                    // A probe of an ffmpeg replaced by a reload meanwhile
+                    if ffmpeg != worker_config.ffmpeg_path {
+                        continue;
+                    }
+                    let report = hwaccel::report(&ffmpeg, &capabilities);
+                    tool_report.retain(|t| t.name != report.name);
+                    tool_report.push(report.clone());
+                    hardware_report = Some(report);
+                    job_runner.set_capabilities(Arc::new(capabilities)).await;
+                    if master_features.iter().any(|f| f == FEATURE_TOOL_REPORT) {
+                        _ = tx_to_socket.send(Message::tool_report(tool_report.clone())).await;
+                    }
+                },
                 Some(msg) = rx_from_job.recv() => {
+                    // Older masters cannot decode these, Done still carries the main output
+                    let required = match msg.status {
+                        JobStatus::Outputs(_) => Some(FEATURE_JOB_OUTPUTS),
+                        JobStatus::Recycled(_) => Some(FEATURE_RECYCLE_BIN),
+                        _ => None,
+                    };
+                    if let Some(feature) = required
+                        && !master_features.iter().any(|f| f == feature) {
+                        continue;
+                    }
                     let msg = Message::job_status(msg);
                     _ = tx_to_socket.send(msg).await;
                 },
+                _ = ch_reload.changed() => {
+                    if !*ch_reload.borrow() {
+                        continue;
+                    }
+
+                    let new_config = ctx_clone.config.read().unwrap().worker.clone();
+                    if new_config.identifier != worker_config.identifier
+                        || new_config.master_addr != worker_config.master_addr
+                        || new_config.auth_key != worker_config.auth_key
+                        || new_config.master_public_key_file != worker_config.master_public_key_file {
+                        warn!("Connection settings changed, they will apply after a restart");
+                    }
+
+                    if new_config.parallel_jobs != worker_config.parallel_jobs {
+                        info!("Parallel jobs changed to {}", new_config.parallel_jobs);
+                        if master_features.iter().any(|f| f == FEATURE_CAPACITY_UPDATE) {
+                            _ = tx_to_socket.send(Message::capacity(new_config.parallel_jobs)).await;
+                        } else {
+                            warn!("Master does not support capacity updates, restart to apply parallel_jobs");
+                        }
+                    }
+
+                    if new_config.ffmpeg_path != worker_config.ffmpeg_path {
+                        hardware_report = None;
+                        spawn_probe(&tx_capabilities, new_config.ffmpeg_path.clone());
+                    }
+
+                    let tools = ToolPaths::from(&new_config);
+                    if tools != ToolPaths::from(&worker_config) {
+                        tool_report = self_check(&tools).await;
+                        tool_report.extend(hardware_report.clone());
+                        if master_features.iter().any(|f| f == FEATURE_TOOL_REPORT) {
+                            _ = tx_to_socket.send(Message::tool_report(tool_report.clone())).await;
+                        }
+                    }
+
+                    job_runner.reconfigure(new_config.clone()).await;
+                    worker_config = new_config;
+                    info!("Worker configuration applied to new jobs");
+                },
                 _ = ch_term.changed() => {
                     if *ch_term.borrow() {
                         break;
@@ -117,8 +248,43 @@ async fn worker_runtime() {
     );
 }
 
+// Test encodes can take minutes, the result comes back over tx while the
+// worker keeps handling jobs with the capabilities it had
+fn spawn_probe(tx: &mpsc::Sender<(PathBuf, hwaccel::Capabilities)>, ffmpeg: PathBuf) {
+    let tx = tx.clone();
+    tokio::spawn(async move {
+        let capabilities = hwaccel::probe(&ffmpeg).await;
+        let _ = tx.send((ffmpeg, capabilities)).await;
+    });
+}
+
+// Runs every configured tool once so a wrong path shows up at startup
+// rather than in the middle of a job
+async fn self_check(tools: &ToolPaths) -> Vec<ToolInfo> {
+    let report = check_tools(tools).await;
+    for tool in &report {
+        match (&tool.version, &tool.error) {
+            (Some(version), _) => info!("Found {} at {}: {}", tool.name, tool.path, version),
+            (None, error) => warn!(
+                "Cannot run {} at {}: {}",
+                tool.name,
+                tool.path,
+                error.as_deref().unwrap_or("unknown error")
+            ),
+        }
+    }
+    report
+}
+
 async fn task_propagate_signals(ctx: Arc<WorkerCtx>) {
+    let mut reload_generation = 0;
+
     loop {
+        // Terminated from within, e.g. rejected by the master
+        if *ctx.ch_terminate.1.borrow() {
+            break;
+        }
+
         let s_term = S_TERMINATE
             .get()
             .unwrap()
@@ -135,11 +301,10 @@ async fn task_propagate_signals(ctx: Arc<WorkerCtx>) {
             break;
         }
 
-        if s_hup {
+        if s_hup != reload_generation {
             info!("Received reload signal");
-            if let Some(flag) = S_RELOAD.get() {
-                flag.store(false, Ordering::Relaxed);
-            }
+            reload_generation = s_hup;
+            let _ = ctx.ch_reload.0.send(true);
         }
 
         sleep(Duration::from_millis(100)).await;
@@ -1,14 +1,41 @@
+use anyhow::{Result, anyhow};
+use std::path::Path;
 use std::sync::Arc;
-use tokio::sync::mpsc;
+use tokio::sync::{mpsc, watch};
+use tokio::time::{sleep, timeout, Duration};
+use x25519_dalek::PublicKey;
 use zeromq::prelude::*;
 use zeromq::DealerSocket;
-use tracing::{info, error};
+use tracing::{info, error, warn};
 
-use crate::rpc::WorkerInfo;
+use crate::config::WorkerConfig;
+use crate::rpc::{self, WorkerInfo};
 use crate::rpc::Message;
+use crate::rpc::crypto::{self, Session};
 use crate::rpc::zmq_helper;
 use super::WorkerCtx;
 
+const KEY_EXCHANGE_TIMEOUT: Duration = Duration::from_secs(10);
+const RECONNECT_MIN: Duration = Duration::from_secs(1);
+const RECONNECT_MAX: Duration = Duration::from_secs(60);
+
+// Why a connection to the master ended
+enum Disconnect {
+    Terminated,
+    Reconnect(String),
+}
+
+// A message from the master once opened
+#[derive(Debug, PartialEq)]
+enum Inbound {
+    Message(Message),
+    Dropped,
+    // A plaintext Reject on an encrypted link is unauthenticated, anyone on
+    // the path can forge one: it only earns a new connection, never the
+    // shutdown a sealed Reject triggers
+    Unsealed(String),
+}
+
 pub async fn rpc_client(
     ctx: Arc<WorkerCtx>,
     mut rx: mpsc::Receiver::<Message>,
@@ -24,61 +51,208 @@ pub async fn rpc_client(
     let master_addr = format!("tcp://{}",
         worker_config.master_addr);
 
-    let mut socket = DealerSocket::new();
-    if let Err(e) = socket.connect(&master_addr).await {
-        error!("Failed to connect to master at {}: {}", master_addr, e);
-        let _ = ctx.ch_terminate.0.send(true);
-        return;
+    let mut ch_term = ctx.ch_terminate.1.clone();
+    let mut delay = RECONNECT_MIN;
+    let mut connected_once = false;
+
+    loop {
+        let mut socket = DealerSocket::new();
+        let session = match connect(&mut socket, &master_addr, &worker_config).await {
+            Ok(session) => session,
+            // The first attempt fails on configuration problems, reported and fatal
+            Err(e) if !connected_once => {
+                error!("{}", e);
+                let _ = ctx.ch_terminate.0.send(true);
+                return;
+            },
+            Err(e) => {
+                warn!("{}, retrying in {:?}", e, delay);
+                if wait_reconnect(&mut ch_term, &mut delay).await {
+                    return;
+                }
+                continue;
+            },
+        };
+        connected_once = true;
+
+        match serve(&mut socket, session, &mut rx, &tx, &mut ch_term, &mut delay).await {
+            Disconnect::Terminated => {
+                info!("Disconnected");
+                return;
+            },
+            Disconnect::Reconnect(reason) => {
+                warn!("Dropped the connection to master: {}, reconnecting in {:?}", reason, delay);
+                if wait_reconnect(&mut ch_term, &mut delay).await {
+                    return;
+                }
+            },
+        }
     }
+}
+
+// Connects, exchanges keys when encryption is configured and says Hello
+async fn connect(socket: &mut DealerSocket, master_addr: &str, worker_config: &WorkerConfig) -> Result<Option<Session>> {
+    socket.connect(master_addr)
+        .await
+        .map_err(|e| anyhow!("Failed to connect to master at {}: {}", master_addr, e))?;
 
     info!("Connected to master at {}", master_addr);
 
+    let mut session = match &worker_config.master_public_key_file {
+        Some(path) => {
+            let session = key_exchange(socket, path)
+                .await
+                .map_err(|e| anyhow!("Key exchange with master failed: {}", e))?;
+            info!("Orchestration channel encrypted");
+            Some(session)
+        },
+        None => None,
+    };
+
     let msg = Message::hello(WorkerInfo {
-        identifier: worker_config.identifier,
+        protocol_version: rpc::PROTOCOL_VERSION,
+        identifier: worker_config.identifier.clone(),
         simultaneous_jobs: worker_config.parallel_jobs,
-        sw_version: env!("CARGO_PKG_VERSION_MAJOR").to_string(),
+        sw_version: env!("CARGO_PKG_VERSION").to_string(),
+        features: rpc::local_features(),
     });
 
-    match zmq_helper::send_msg(
-            &mut socket,
-            None,
-            &msg).await {
-        Ok(()) => {
-        }
-        Err(e) => {
-            error!("error while sending message: {}", e);
-        }
-    };
+    if let Err(e) = send(socket, &mut session, &msg).await {
+        error!("error while sending message: {}", e);
+    }
+    Ok(session)
+}
 
-    let mut ch_term = ctx.ch_terminate.1.clone();
+async fn serve(
+    socket: &mut DealerSocket,
+    mut session: Option<Session>,
+    rx: &mut mpsc::Receiver::<Message>,
+    tx: &mpsc::Sender::<Message>,
+    ch_term: &mut watch::Receiver<bool>,
+    delay: &mut Duration,
+) -> Disconnect {
     loop {
         tokio::select!(
-            msg = zmq_helper::recv_msg(&mut socket, false) => {
-                match msg {
-                    Ok((_, msg)) => {
+            msg = zmq_helper::recv_msg(socket, false) => {
+                match msg.and_then(|(_, msg)| open(&mut session, msg)) {
+                    Ok(Inbound::Message(msg)) => {
+                        // The master is reachable and understands us again
+                        *delay = RECONNECT_MIN;
                         _ = tx.send(msg).await;
                     }
+                    Ok(Inbound::Dropped) => {}
+                    Ok(Inbound::Unsealed(reason)) => {
+                        return Disconnect::Reconnect(format!("unauthenticated reject: {}", reason));
+                    }
                     Err(e) => {
                         error!("Error while receiving message: {}", e);
                     }
                 }
             },
             Some(rxmsg) = rx.recv() => {
-                _ = zmq_helper::send_msg(&mut socket, None, &rxmsg).await;
+                _ = send(socket, &mut session, &rxmsg).await;
             }
             _ = ch_term.changed() => {
                 if *ch_term.borrow() {
-                    break;
+                    let _ = send(socket, &mut session, &Message::bye()).await;
+                    return Disconnect::Terminated;
                 }
             }
         );
     }
+}
+
+// Waits before the next attempt, doubling the delay up to RECONNECT_MAX.
+// Returns true when the worker is shutting down meanwhile.
+async fn wait_reconnect(ch_term: &mut watch::Receiver<bool>, delay: &mut Duration) -> bool {
+    tokio::select!(
+        _ = sleep(*delay) => {},
+        _ = ch_term.changed() => {},
+    );
+    *delay = (*delay * 2).min(RECONNECT_MAX);
+    *ch_term.borrow()
+}
+
+async fn key_exchange(socket: &mut DealerSocket, master_key_file: &Path) -> Result<Session> {
+    let master_key = crypto::load_public_key(master_key_file)
+        .map_err(|e| anyhow!("cannot load {}: {}", master_key_file.display(), e))?;
+
+    let ephemeral = crypto::generate_secret();
+    let msg = Message::key_exchange(PublicKey::from(&ephemeral).as_bytes().to_vec());
+    zmq_helper::send_msg(socket, None, &msg).await?;
+
+    let (_, reply) = timeout(KEY_EXCHANGE_TIMEOUT, zmq_helper::recv_msg(socket, false))
+        .await
+        .map_err(|_| anyhow!("no answer from master"))??;
+
+    match reply {
+        Message::KeyExchange(master_eph) => {
+            let master_eph = crypto::public_key_from_bytes(&master_eph)?;
+            Ok(Session::worker(&ephemeral, &master_key, &master_eph))
+        },
+        Message::Reject(reason) => Err(anyhow!("rejected by master: {}", reason)),
+        other => Err(anyhow!("unexpected reply {:?}", other)),
+    }
+}
+
+async fn send(socket: &mut DealerSocket, session: &mut Option<Session>, msg: &Message) -> Result<()> {
+    match session {
+        Some(session) => {
+            let sealed = session.seal(msg)?;
+            zmq_helper::send_msg(socket, None, &sealed).await
+        },
+        None => zmq_helper::send_msg(socket, None, msg).await,
+    }
+}
+
+// Once encrypted, plaintext from the master is never trusted
+fn open(session: &mut Option<Session>, msg: Message) -> Result<Inbound> {
+    match (session, msg) {
+        (Some(session), Message::Sealed(sealed)) => Ok(Inbound::Message(session.open(&sealed)?)),
+        // Sent in plain when the master can't decrypt us, or forged
+        (Some(_), Message::Reject(reason)) => Ok(Inbound::Unsealed(reason)),
+        (Some(_), msg) => {
+            warn!("Dropped plaintext message from master: {:?}", msg);
+            Ok(Inbound::Dropped)
+        },
+        (None, Message::Sealed(_)) => Err(anyhow!("encrypted message without session")),
+        (None, msg) => Ok(Inbound::Message(msg)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use x25519_dalek::PublicKey;
+
+    fn pair() -> (Session, Session) {
+        let master_static = crypto::generate_secret();
+        let master_eph = crypto::generate_secret();
+        let worker_eph = crypto::generate_secret();
+        let worker = Session::worker(
+            &worker_eph,
+            &PublicKey::from(&master_static),
+            &PublicKey::from(&master_eph),
+        );
+        let master = Session::master(&master_static, &master_eph, &PublicKey::from(&worker_eph));
+        (worker, master)
+    }
 
+    // Only messages opened as Inbound::Message reach the worker loop, the one
+    // place a Reject cancels jobs and stops the worker
+    #[test]
+    fn test_open_plaintext_reject() {
+        let (worker, mut master) = pair();
+        let mut session = Some(worker);
 
-    info!("Disconnected");
-    let msg = Message::bye();
-    let _ = zmq_helper::send_msg(
-            &mut socket,
-            None,
-            &msg).await;
+        let forged = open(&mut session, Message::reject("Go away")).unwrap();
+        assert_eq!(forged, Inbound::Unsealed("Go away".to_string()));
+        assert_eq!(open(&mut session, Message::cancel_jobs()).unwrap(), Inbound::Dropped);
+
+        let sealed = master.seal(&Message::reject("Revoked")).unwrap();
+        assert_eq!(open(&mut session, sealed).unwrap(), Inbound::Message(Message::reject("Revoked")));
+
+        // Without encryption there is nothing to authenticate
+        assert_eq!(open(&mut None, Message::reject("Bad version")).unwrap(), Inbound::Message(Message::reject("Bad version")));
+    }
 }
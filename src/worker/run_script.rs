@@ -0,0 +1,224 @@
+use std::collections::HashMap;
+use std::io::Write;
+use std::path::{absolute, Path, PathBuf};
+use std::sync::Arc;
+use std::time::Duration;
+
+use anyhow::{anyhow, Result};
+use tokio::sync::mpsc;
+
+use crate::args::RunScriptArgs;
+use crate::extcmd::hwaccel;
+use crate::rpc::{JobMsg, JobOutput, JobStatus, JobStatusMsg, TranscodeProgress};
+use crate::utils;
+use crate::CONFIG;
+use super::jobrunner::Job;
+
+const LOCAL_JOB_ID: i64 = 0;
+
+// Runs a script on a local file the way a worker runs a job, printing its
+// statuses as they come. Returns the process exit code.
+pub fn run_script(args: RunScriptArgs) -> i32 {
+    let rt = tokio::runtime::Builder::new_multi_thread()
+        .enable_all()
+        .build();
+
+    let result = match rt {
+        Ok(rt) => rt.block_on(run_script_runtime(args)),
+        Err(e) => Err(anyhow!("Failed to build tokio runtime: {}", e)),
+    };
+
+    match result {
+        Ok(true) => 0,
+        Ok(false) => 1,
+        Err(e) => {
+            eprintln!("{}", e);
+            1
+        }
+    }
+}
+
+fn absolute_path(path: &Path) -> Result<PathBuf> {
+    absolute(path).map_err(|e| anyhow!("Invalid path {}: {}", path.display(), e))
+}
+
+fn job_spec(args: RunScriptArgs, script: String) -> Result<JobMsg> {
+    let file = absolute_path(&args.file)?;
+    if !file.is_file() {
+        return Err(anyhow!("{} is not a file", file.display()));
+    }
+    let library_root = match &args.library_root {
+        Some(root) => absolute_path(root)?,
+        None => file.parent().map(Path::to_path_buf).unwrap_or_default(),
+    };
+    let dst_dir = match &args.dst_dir {
+        Some(dir) => absolute_path(dir)?,
+        None => library_root.clone(),
+    };
+
+    let mut vars: HashMap<String, String> = args.vars.into_iter().collect();
+    if args.dry_run {
+        vars.insert("DRYRUN".to_string(), "1".to_string());
+    }
+
+    Ok(JobMsg {
+        job_id: LOCAL_JOB_ID,
+        script,
+        vars,
+        file: file.to_string_lossy().into_owned(),
+        library_root: library_root.to_string_lossy().into_owned(),
+        dst_dir: dst_dir.to_string_lossy().into_owned(),
+    })
+}
+
+async fn run_script_runtime(args: RunScriptArgs) -> Result<bool> {
+    let config = CONFIG.get().expect("configuration not initialized").read().unwrap().worker.clone();
+    let script = tokio::fs::read_to_string(&args.script)
+        .await
+        .map_err(|e| anyhow!("Cannot read {}: {}", args.script.display(), e))?;
+    let script_path = args.script.clone();
+    let trusted = args.trusted;
+    let spec = job_spec(args, script)?;
+
+    println!("Running {} on {}", script_path.display(), spec.file);
+    let capabilities = Arc::new(hwaccel::probe(&config.ffmpeg_path).await);
+    let (status_tx, mut status_rx) = mpsc::channel::<JobStatusMsg>(8);
+
+    // A failed setup already sent its error, printed below
+    if let Ok(job) = Job::new(spec, &config, capabilities, trusted, status_tx).await {
+        tokio::spawn(job.run());
+    }
+
+    let mut printer = StatusPrinter::default();
+    while let Some(msg) = status_rx.recv().await {
+        if let Some(success) = printer.print(msg.status) {
+            return Ok(success);
+        }
+    }
+    Err(anyhow!("The job ended without a result"))
+}
+
+fn format_duration(d: Duration) -> String {
+    let secs = d.as_secs();
+    format!("{:02}:{:02}:{:02}", secs / 3600, secs / 60 % 60, secs % 60)
+}
+
+fn format_progress(p: &TranscodeProgress) -> String {
+    let mut parts = vec![match p.percentage {
+        Some(pct) => format!("{:5.1}%", pct),
+        None => "    -%".to_string(),
+    }];
+    if let Some(cur_time) = p.cur_time {
+        parts.push(format_duration(cur_time));
+    }
+    if let Some(fps) = p.fps {
+        parts.push(format!("{} fps", fps));
+    }
+    if let Some(speed) = p.speed {
+        parts.push(format!("{:.2}x", speed));
+    }
+    if let Some(eta) = p.eta {
+        parts.push(format!("ETA {}", format_duration(eta)));
+    }
+    parts.join("  ")
+}
+
+fn format_output(output: &JobOutput) -> String {
+    let size = output.size.map_or("unknown size".to_string(), utils::human_size);
+    format!("{}: {} ({})", output.role, output.path, size)
+}
+
+// Progress is redrawn in place, other statuses get their own line
+#[derive(Default)]
+struct StatusPrinter {
+    progress_shown: bool,
+}
+
+impl StatusPrinter {
+    fn line(&mut self, line: String) {
+        if self.progress_shown {
+            println!();
+            self.progress_shown = false;
+        }
+        println!("{}", line);
+    }
+
+    // Some(success) once the job is over
+    fn print(&mut self, status: JobStatus) -> Option<bool> {
+        match status {
+            JobStatus::Progress(p) => {
+                print!("\r{:<72}", format_progress(&p));
+                let _ = std::io::stdout().flush();
+                self.progress_shown = true;
+            },
+            JobStatus::Ack => {},
+            JobStatus::Declined(reason) => {
+                self.line(format!("Declined: {}", reason));
+                return Some(false);
+            },
+            JobStatus::Copying => self.line("== Placing outputs".to_string()),
+            JobStatus::Milestone(m) => self.line(format!("== {}", m)),
+            JobStatus::Log(l) => self.line(l),
+            JobStatus::Error(e) => {
+                self.line(format!("Error: {}", e));
+                return Some(false);
+            },
+            JobStatus::Outputs(outputs) => {
+                self.line("Placed outputs:".to_string());
+                for output in &outputs {
+                    self.line(format!("  {}", format_output(output)));
+                }
+            },
+            JobStatus::Recycled(files) => {
+                for file in files {
+                    self.line(format!("Replaced {}, previous file kept as {}", file.original, file.recycled));
+                }
+            },
+            JobStatus::DryRun(outputs) => {
+                self.line("Dry run, outputs that would be placed:".to_string());
+                for output in &outputs {
+                    self.line(format!("  {}", format_output(output)));
+                }
+                return Some(true);
+            },
+            JobStatus::Done { file } => {
+                match file {
+                    Some(file) => self.line(format!("Done, main output at {}", file)),
+                    None => self.line("Done, no output".to_string()),
+                }
+                return Some(true);
+            },
+        }
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_format_progress() {
+        let p = TranscodeProgress {
+            frame: Some(1200),
+            fps: Some(48),
+            cur_time: Some(Duration::from_secs(50)),
+            percentage: Some(42.25),
+            eta: Some(Duration::from_secs(3725)),
+            bitrate: None,
+            speed: Some(2.0),
+        };
+        assert_eq!(format_progress(&p), " 42.2%  00:00:50  48 fps  2.00x  ETA 01:02:05");
+
+        let empty = TranscodeProgress {
+            frame: None,
+            fps: None,
+            cur_time: None,
+            percentage: None,
+            eta: None,
+            bitrate: None,
+            speed: None,
+        };
+        assert_eq!(format_progress(&empty), "    -%");
+    }
+}
@@ -0,0 +1,208 @@
+use std::collections::HashMap;
+use std::path::{absolute, Path, PathBuf};
+
+use anyhow::{anyhow, Result};
+use tokio::sync::mpsc;
+use walkdir::WalkDir;
+
+use crate::args::TestScriptsArgs;
+use crate::extcmd::tools::ToolPaths;
+use crate::lua::{TestResult, TrahlRuntimeBuilder};
+use crate::rpc::JobStatusMsg;
+use crate::CONFIG;
+
+const TEST_SUFFIX: &str = "_test.lua";
+
+// Runs every *_test.lua file and prints its results. Returns the process
+// exit code, 1 when a test failed or a file could not run.
+pub fn test_scripts(args: TestScriptsArgs) -> i32 {
+    let rt = tokio::runtime::Builder::new_multi_thread()
+        .enable_all()
+        .build();
+
+    match rt {
+        Ok(rt) => rt.block_on(test_scripts_runtime(args)),
+        Err(e) => {
+            eprintln!("Failed to build tokio runtime: {}", e);
+            1
+        }
+    }
+}
+
+// Files given directly run whatever their name
+fn find_tests(paths: &[PathBuf]) -> Vec<PathBuf> {
+    let mut found = Vec::new();
+    for path in paths {
+        if path.is_file() {
+            found.push(path.clone());
+            continue;
+        }
+        found.extend(
+            WalkDir::new(path)
+                .into_iter()
+                .filter_map(|e| e.ok())
+                .filter(|e| e.file_type().is_file())
+                .filter(|e| e.file_name().to_string_lossy().ends_with(TEST_SUFFIX))
+                .map(|e| e.into_path())
+        );
+    }
+    found.sort();
+    found.dedup();
+    found
+}
+
+// Each file gets its own runtime, with job vars pointing to a fake library.
+// Tests that ran before an error in the file are still returned.
+async fn run_test_file(tools: &ToolPaths, path: &Path) -> Result<(Vec<TestResult>, Option<anyhow::Error>)> {
+    let code = tokio::fs::read_to_string(path)
+        .await
+        .map_err(|e| anyhow!("cannot read {}: {}", path.display(), e))?;
+    let cache_dir = tempfile::tempdir()?;
+    let test_dir = absolute(path)?
+        .parent()
+        .map(Path::to_path_buf)
+        .unwrap_or_default();
+
+    let vars = HashMap::from([
+        ("CACHEDIR".to_string(), cache_dir.path().to_string_lossy().into_owned()),
+        ("SRCFILE".to_string(), "/library/movie.mkv".to_string()),
+        ("LIBRARYROOT".to_string(), "/library".to_string()),
+        ("DSTDIR".to_string(), "/output".to_string()),
+        ("TESTDIR".to_string(), test_dir.to_string_lossy().into_owned()),
+    ]);
+
+    // Milestones and progress are not needed here
+    let (status_tx, mut status_rx) = mpsc::channel::<JobStatusMsg>(8);
+    let drain = tokio::spawn(async move {
+        while status_rx.recv().await.is_some() {}
+    });
+
+    let runtime = TrahlRuntimeBuilder::new(0, status_tx, code)
+        .add_vars(vars)
+        .with_tools(tools.clone())
+        .with_testing()
+        .with_chunk_name(path.display().to_string())
+        .build()?;
+    let error = runtime.exec().await.err();
+    let results = runtime.test_results()?;
+
+    drop(runtime);
+    let _ = drain.await;
+    Ok((results, error))
+}
+
+async fn test_scripts_runtime(args: TestScriptsArgs) -> i32 {
+    let tools = ToolPaths::from(&CONFIG.get().expect("configuration not initialized").read().unwrap().worker);
+    let files = find_tests(&args.paths);
+    if files.is_empty() {
+        eprintln!("No *{} files found", TEST_SUFFIX);
+        return 1;
+    }
+
+    let (mut passed, mut failed, mut errors) = (0, 0, 0);
+    for file in &files {
+        println!("{}", file.display());
+        let (results, error) = match run_test_file(&tools, file).await {
+            Ok(report) => report,
+            Err(e) => (Vec::new(), Some(e)),
+        };
+        for result in results {
+            if result.passed {
+                passed += 1;
+                println!("  ok    {}", result.name);
+            } else {
+                failed += 1;
+                println!("  FAIL  {}", result.name);
+                if let Some(message) = result.message {
+                    println!("        {}", message);
+                }
+            }
+        }
+        if let Some(e) = error {
+            errors += 1;
+            println!("  ERROR {}", e);
+        }
+    }
+
+    println!();
+    println!("{} passed, {} failed, {} files with errors", passed, failed, errors);
+    if failed > 0 || errors > 0 { 1 } else { 0 }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_run_test_file() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        std::fs::create_dir(dir.path().join("fixtures"))?;
+        std::fs::write(
+            dir.path().join("fixtures/probe.json"),
+            r#"{ "format": { "duration": "60.0" }, "streams": [ { "index": 0, "codec_type": "video", "codec_name": "h264" } ] }"#,
+        )?;
+        std::fs::write(dir.path().join("transcode.lua"), r#"
+            local media = require("media")
+            local info = media.probe(_trahl.vars.SRCFILE)
+            if info.video[1].codec == "hevc" then
+                return
+            end
+            local out = _trahl.vars.CACHEDIR .. "/out.mkv"
+            _trahl.ffmpeg({ "-i", _trahl.vars.SRCFILE, "-c:v", "libx265", out })
+            _trahl.fs.remove(_trahl.vars.DSTDIR .. "/old.mkv")
+            local status = _trahl.http_request("POST", "http://arr/api", nil, "{}")
+            _trahl.log(_trahl.INFO, "notified " .. status)
+            _trahl.set_output(out, _trahl.O_OVERWRITE)
+        "#)?;
+
+        let test_file = dir.path().join("transcode_test.lua");
+        std::fs::write(&test_file, r#"
+            local t = require("testing")
+
+            t.test("transcodes h264", function()
+                t.mock_ffprobe(t.fixture("fixtures/probe.json"))
+                t.mock_http(201)
+                t.file("/output/old.mkv", { size = 10 })
+                t.run("transcode.lua")
+                t.assert_contains(t.ffmpeg_args(), "libx265")
+                t.assert_eq(t.calls("http_request")[1][2], "http://arr/api")
+                t.assert_nil(_trahl.fs.stat("/output/old.mkv"))
+                t.assert_logged("notified 201")
+                t.assert_eq(t.output().mode, _trahl.O_OVERWRITE)
+            end)
+
+            t.test("fails without a probe fixture", function()
+                t.assert_error(function() t.run("transcode.lua") end, "ffprobe is not mocked")
+                t.assert_called("ffmpeg", 0)
+                t.assert_nil(t.output())
+            end)
+
+            t.test("reports failures", function()
+                t.assert_eq({ 1, 2 }, { 1, 3 })
+            end)
+        "#)?;
+
+        let (results, error) = run_test_file(&ToolPaths::default(), &test_file).await?;
+        assert!(error.is_none(), "{:?}", error);
+        assert_eq!(results.len(), 3);
+        assert!(results[0].passed, "{:?}", results[0]);
+        assert!(results[1].passed, "{:?}", results[1]);
+        assert!(!results[2].passed);
+        assert!(results[2].message.as_ref().unwrap().contains("expected { 1, 3 }, got { 1, 2 }"));
+        Ok(())
+    }
+
+    #[test]
+    fn test_find_tests() {
+        let dir = tempfile::tempdir().unwrap();
+        std::fs::create_dir(dir.path().join("sub")).unwrap();
+        for file in ["a_test.lua", "a.lua", "sub/b_test.lua", "other.lua"] {
+            std::fs::write(dir.path().join(file), "").unwrap();
+        }
+
+        assert_eq!(
+            find_tests(&[dir.path().to_path_buf(), dir.path().join("other.lua")]),
+            vec![dir.path().join("a_test.lua"), dir.path().join("other.lua"), dir.path().join("sub/b_test.lua")]
+        );
+    }
+}
@@ -0,0 +1,162 @@
+use anyhow::{anyhow, Result};
+use std::path::{Path, PathBuf};
+use std::process::Stdio;
+use tokio::process::Command;
+use tracing::{info, warn};
+
+use crate::config::ValidationConfig;
+use crate::extcmd::ffprobe::ffprobe;
+use crate::extcmd::probe::MediaInfo;
+use crate::extcmd::tools::ToolPaths;
+
+// Every failed check, empty when the output is acceptable
+fn check(
+    spec: &ValidationConfig,
+    source: Option<&MediaInfo>,
+    source_size: u64,
+    output: &MediaInfo,
+    output_size: u64,
+) -> Vec<String> {
+    let mut failures = Vec::new();
+
+    if spec.min_size_percent > 0 && source_size > 0 {
+        let min = source_size * u64::from(spec.min_size_percent) / 100;
+        if output_size < min {
+            failures.push(format!(
+                "output is {} bytes, less than {}% of the {} bytes source",
+                output_size, spec.min_size_percent, source_size
+            ));
+        }
+    }
+
+    if spec.check_duration
+        && let Some(src_duration) = source.and_then(|s| s.duration)
+    {
+        match output.duration {
+            Some(d) if (d - src_duration).abs() <= f64::from(spec.duration_tolerance_secs) => {},
+            Some(d) => failures.push(format!(
+                "output lasts {:.1}s, source {:.1}s (tolerance {}s)",
+                d, src_duration, spec.duration_tolerance_secs
+            )),
+            None => failures.push("output has no duration".to_string()),
+        }
+    }
+
+    let min_audio = spec.min_audio_streams
+        .unwrap_or_else(|| source.map_or(0, |s| s.audio.len().min(1)));
+    let counts = [
+        ("video", output.video.len(), spec.min_video_streams),
+        ("audio", output.audio.len(), min_audio),
+        ("subtitle", output.subtitles.len(), spec.min_subtitle_streams),
+    ];
+    for (kind, found, min) in counts {
+        if found < min {
+            failures.push(format!("output has {} {} streams, expected at least {}", found, kind, min));
+        }
+    }
+
+    failures
+}
+
+async fn probe(tools: &ToolPaths, path: &Path) -> Result<MediaInfo> {
+    let json = ffprobe(&tools.ffprobe, &path.to_path_buf())
+        .await
+        .map_err(|e| anyhow!("cannot probe {}: {}", path.display(), e))?;
+    Ok(MediaInfo::from_ffprobe(&json))
+}
+
+// Decodes the first seconds of the file, stopping at the first error
+async fn decode_test(ffmpeg: &PathBuf, path: &Path, seconds: u32) -> Result<()> {
+    let out = Command::new(ffmpeg)
+        .args(["-hide_banner", "-v", "error", "-xerror", "-t", &seconds.to_string(), "-i"])
+        .arg(path)
+        .args(["-f", "null", "-"])
+        .stdin(Stdio::null())
+        .kill_on_drop(true)
+        .output()
+        .await?;
+
+    if !out.status.success() {
+        return Err(anyhow!("decode test failed: {}", String::from_utf8_lossy(&out.stderr).trim()));
+    }
+    Ok(())
+}
+
+pub async fn validate(spec: &ValidationConfig, tools: &ToolPaths, source: &Path, output: &Path) -> Result<()> {
+    let output_info = probe(tools, output).await?;
+    let output_size = tokio::fs::metadata(output).await?.len();
+
+    // A source ffprobe can't read only disables the comparisons
+    let source_info = probe(tools, source)
+        .await
+        .inspect_err(|e| warn!("Validation without source details: {}", e))
+        .ok();
+    let source_size = tokio::fs::metadata(source).await.map(|m| m.len()).unwrap_or(0);
+
+    let mut failures = check(spec, source_info.as_ref(), source_size, &output_info, output_size);
+
+    if spec.decode_seconds > 0
+        && let Err(e) = decode_test(&tools.ffmpeg, output, spec.decode_seconds).await
+    {
+        failures.push(e.to_string());
+    }
+
+    if !failures.is_empty() {
+        return Err(anyhow!("{}", failures.join("; ")));
+    }
+
+    info!("Output {} passed validation", output.display());
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::extcmd::probe::{AudioStream, VideoStream};
+
+    fn media(duration: f64, audio: usize) -> MediaInfo {
+        MediaInfo {
+            duration: Some(duration),
+            video: vec![VideoStream {
+                index: 0,
+                codec: Some("hevc".to_string()),
+                profile: None,
+                width: Some(1920),
+                height: Some(1080),
+                bit_depth: Some(10),
+                pix_fmt: None,
+                frame_rate: None,
+                hdr: None,
+                dolby_vision_profile: None,
+                default: true,
+            }],
+            audio: (0..audio).map(|i| AudioStream {
+                index: i as u64 + 1,
+                codec: Some("aac".to_string()),
+                language: None,
+                title: None,
+                channels: Some(2),
+                channel_layout: None,
+                default: i == 0,
+            }).collect(),
+            ..MediaInfo::default()
+        }
+    }
+
+    #[test]
+    fn test_check() {
+        let spec = ValidationConfig { enabled: true, ..ValidationConfig::default() };
+        let source = media(3600.0, 2);
+
+        assert!(check(&spec, Some(&source), 1000, &media(3601.5, 1), 500).is_empty());
+
+        let failures = check(&spec, Some(&source), 100_000, &media(1200.0, 0), 200);
+        assert_eq!(failures.len(), 3, "{:?}", failures);
+
+        // Without source details only the output itself is checked
+        assert!(check(&spec, None, 0, &media(10.0, 0), 200).is_empty());
+
+        let no_duration = ValidationConfig { check_duration: false, min_audio_streams: Some(0), ..spec };
+        assert!(check(&no_duration, Some(&source), 1000, &media(10.0, 0), 500).is_empty());
+    }
+}